@@ -0,0 +1,129 @@
+//
+// Copyright 2024 Formata, Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+use std::collections::BTreeMap;
+use axum::{extract::{Path, Query, State}, http::{header::CONTENT_TYPE, HeaderMap, StatusCode}, response::IntoResponse};
+use bytes::Bytes;
+use nanoid::nanoid;
+use stof::{SDoc, SVal};
+use crate::{response::StofResponse, run::export_node, server::ServerState, users::auth::auth_admin};
+use super::{admin_delete_subscription, admin_set_subscription, valid_topic};
+
+
+/// Alphabet for subscription IDs (used as field names in the subscriptions document).
+const ID_ALPHABET: [char; 36] = [
+    'a', 'b', 'c', 'd', 'e', 'f', 'g', 'h', 'i', 'j', 'k', 'l', 'm', 'n', 'o', 'p', 'q', 'r', 's', 't', 'u', 'v', 'w', 'x', 'y', 'z',
+    '0', '1', '2', '3', '4', '5', '6', '7', '8', '9',
+];
+
+
+/// Create/update a subscription.
+/// Body fields: "topic", "package", and optionally "id" (update) and "handler" (function in the package, default is "handle").
+pub(crate) async fn admin_set_subscription_handler(State(state): State<ServerState>, headers: HeaderMap, body: Bytes) -> impl IntoResponse {
+    if !auth_admin(&state, &headers, false).await {
+        return StofResponse::error(StatusCode::FORBIDDEN, "access denied");
+    }
+
+    let mut content_type = String::from("stof");
+    if let Some(ctype) = headers.get(CONTENT_TYPE) {
+        match ctype.to_str() {
+            Ok(ctype) => content_type = ctype.to_owned(),
+            Err(_) => return StofResponse::error(StatusCode::BAD_REQUEST, "invalid content type"),
+        }
+    }
+    if let Ok(doc) = SDoc::bytes(body, &content_type) && let Some(topic) = doc.field("root.topic", None) && let Some(package) = doc.field("root.package", None) {
+        let topic = topic.to_string();
+        if !valid_topic(&topic, true) {
+            return StofResponse::error(StatusCode::BAD_REQUEST, "invalid topic");
+        }
+
+        let mut id = nanoid!(12, &ID_ALPHABET);
+        if let Some(id_field) = doc.field("root.id", None) {
+            id = id_field.to_string();
+            if id.is_empty() || !id.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+                return StofResponse::error(StatusCode::BAD_REQUEST, "invalid subscription id");
+            }
+        }
+
+        let mut handler = String::from("handle");
+        if let Some(handler_field) = doc.field("root.handler", None) {
+            handler = handler_field.to_string();
+            if handler.is_empty() {
+                return StofResponse::error(StatusCode::BAD_REQUEST, "invalid handler");
+            }
+        }
+
+        let mut subscriptions = state.subscriptions.lock().await;
+        if admin_set_subscription(&mut subscriptions, &id, &topic, &package.to_string(), &handler) {
+            return StofResponse::stof(StatusCode::OK, &format!("str id: '{}'", id));
+        }
+    }
+    StofResponse::error(StatusCode::BAD_REQUEST, "not a valid subscription body")
+}
+
+
+/// Get all subscriptions (with delivery history).
+/// Uses the "export" query to determine the format (default is "json").
+pub(crate) async fn admin_get_subscriptions_handler(State(state): State<ServerState>, Query(query): Query<BTreeMap<String, String>>, headers: HeaderMap) -> impl IntoResponse {
+    if !auth_admin(&state, &headers, true).await {
+        return StofResponse::error(StatusCode::FORBIDDEN, "access denied");
+    }
+
+    let mut export_format = String::from("json");
+    if let Some(format) = query.get("export") {
+        export_format = format.clone();
+    }
+
+    let subscriptions = state.subscriptions.lock().await;
+    if let Some(root) = subscriptions.graph.root_by_name("Subscriptions") {
+        return export_node(&subscriptions, &export_format, &root);
+    }
+    StofResponse::error(StatusCode::INTERNAL_SERVER_ERROR, "subscriptions not found")
+}
+
+
+/// Get a subscription (with delivery history).
+/// Uses the "export" query to determine the format (default is "json").
+pub(crate) async fn admin_get_subscription_handler(State(state): State<ServerState>, Path(id): Path<String>, Query(query): Query<BTreeMap<String, String>>, headers: HeaderMap) -> impl IntoResponse {
+    if !auth_admin(&state, &headers, true).await {
+        return StofResponse::error(StatusCode::FORBIDDEN, "access denied");
+    }
+
+    let mut export_format = String::from("json");
+    if let Some(format) = query.get("export") {
+        export_format = format.clone();
+    }
+
+    let subscriptions = state.subscriptions.lock().await;
+    if !id.contains('.') && let Some(field) = subscriptions.field(&format!("Subscriptions.{}", id), None) && let SVal::Object(nref) = &field.value {
+        return export_node(&subscriptions, &export_format, nref);
+    }
+    StofResponse::error(StatusCode::NOT_FOUND, "subscription not found")
+}
+
+
+/// Delete a subscription.
+pub(crate) async fn admin_delete_subscription_handler(State(state): State<ServerState>, Path(id): Path<String>, headers: HeaderMap) -> impl IntoResponse {
+    if !auth_admin(&state, &headers, false).await {
+        return StofResponse::error(StatusCode::FORBIDDEN, "access denied");
+    }
+
+    let mut subscriptions = state.subscriptions.lock().await;
+    if !id.contains('.') && admin_delete_subscription(&mut subscriptions, &id) {
+        return StofResponse::msg(StatusCode::OK, "deleted subscription");
+    }
+    StofResponse::error(StatusCode::NOT_FOUND, "subscription not found")
+}
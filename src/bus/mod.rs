@@ -0,0 +1,494 @@
+//
+// Copyright 2024 Formata, Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+use std::{fs, sync::{atomic::{AtomicU32, Ordering}, Arc}, time::Instant};
+use axum::http::StatusCode;
+use bytes::Bytes;
+use chrono::Utc;
+use nanoid::nanoid;
+use stof::{SData, SDoc, SField, SFunc, SVal};
+use tokio::{runtime::Handle, sync::mpsc::{UnboundedReceiver, UnboundedSender}, task::spawn_blocking, time::timeout};
+use crate::{config::{bus_enabled, bus_max_deliveries, bus_max_hops, bus_max_size, opaque_errors, registry_path, run_enabled, run_timeout, sandbox_required}, kv::{kv_namespace, run_kv}, libraries::run_libraries, metrics::increment_server_run_count, run::{cancel::RunCancel, egress::EgressPolicy, import_package, initialize_document, kv_lib::decode_value}, server::ServerState, sql::run_sql, wasm::WasmPolicy};
+pub(crate) mod api;
+
+
+const BUS_INTERFACE: &str = r#"
+// make sure the Subscriptions root exists
+root Subscriptions: {}
+
+type Subscription {
+    id: str;
+
+    // topic to receive messages from ("orders.*" for every topic that starts with "orders.")
+    topic: str;
+
+    // registry package to deliver messages to (Ex. "@scope/name")
+    package: str;
+
+    // function in the package called with each message: (message, topic?, source?)
+    handler: str = 'handle';
+
+    created: ms = Time.now();
+    deliveries: int = 0;
+    failures: int = 0;
+
+    // recent deliveries
+    history: obj = new {};
+
+    fn record(time: ms, duration: ms, status: int, message: str, topic: str, source: str, error: str) {
+        self.deliveries += 1;
+        if (status != 200) self.failures += 1;
+
+        let history = self.history;
+        history.set(`delivery${self.deliveries}`, new {
+            time: time,
+            duration: duration,
+            status: status,
+            message: message,
+            topic: topic,
+            source: source,
+            error: error,
+        });
+        history.removeField(`delivery${self.deliveries - root.Config.max_history}`, true);
+    }
+}
+
+Config: {
+    // saved by the runner after each change
+    save_path: 'registry/__bus__.bstof'
+    max_history: 20
+}
+
+// set a subscription
+fn set_subscription(id: str, topic: str, package: str, handler: str = 'handle'): bool {
+    Subscriptions.removeField(id, true);
+    let res = Subscriptions.set(id, new Subscription {
+        id: id,
+        topic: topic,
+        package: package,
+        handler: handler,
+        history: new {},
+    });
+    return res;
+}
+
+// delete a subscription by id
+fn delete_subscription(id: str): bool {
+    let res = Subscriptions.removeField(id, true);
+    return res;
+}
+
+// record a delivery for a subscription
+fn record(id: str, time: ms, duration: ms, status: int, message: str, topic: str, source: str, error: str): bool {
+    let subscription: Subscription = Subscriptions.at(id);
+    if (subscription) {
+        subscription.record(time, duration, status, message, topic, source, error);
+        return true;
+    }
+    return false;
+}
+"#;
+
+
+/// Load subscriptions file.
+pub(crate) fn load_subscriptions(config: &SDoc) -> SDoc {
+    let registry_name = registry_path(config);
+    let bus_file_path = format!("{}/__bus__.bstof", registry_name);
+
+    if let Ok(exists) = fs::exists(&bus_file_path) && exists && let Ok(doc) = SDoc::file(&bus_file_path, "bstof") {
+        return doc;
+    }
+
+    let mut doc = SDoc::default();
+    let _ = doc.string_import("main", "stof", BUS_INTERFACE, "");
+
+    if let Some(field_ref) = SField::field_ref(&doc.graph, "root.Config.save_path", '.', None) && let Some(field) = SData::get_mut::<SField>(&mut doc.graph, &field_ref) {
+        field.value = bus_file_path.into();
+    }
+    doc
+}
+
+
+/// Save the subscriptions document.
+pub(crate) fn save_subscriptions(subscriptions: &SDoc) {
+    if let Some(save_path) = subscriptions.field("root.Config.save_path", None) && let Ok(bytes) = subscriptions.export_bytes("main", "bstof", None) {
+        let _ = fs::write(save_path.to_string(), bytes);
+    }
+}
+
+
+/// Is this a valid topic?
+/// Topics contain alphanumeric characters, '.', '-', '_', '/', and ':'.
+/// Subscription topics can end with '*' to receive every topic that starts with the rest ("*" on its own for all topics).
+pub(crate) fn valid_topic(topic: &str, subscription: bool) -> bool {
+    let mut name = topic;
+    if subscription {
+        if topic == "*" {
+            return true;
+        }
+        name = topic.strip_suffix('*').unwrap_or(topic);
+    }
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_' | '/' | ':'))
+}
+
+
+/// Does a subscription topic match a message topic?
+fn topic_matches(pattern: &str, topic: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => topic.starts_with(prefix),
+        None => pattern == topic,
+    }
+}
+
+
+/// Subscription to a topic.
+pub(crate) struct Subscription {
+    pub id: String,
+    pub package: String,
+    pub handler: String,
+}
+
+
+/// Get the subscriptions that match a message topic.
+pub(crate) fn topic_subscriptions(subscriptions: &SDoc, topic: &str) -> Vec<Subscription> {
+    let mut matches = Vec::new();
+    if let Some(root) = subscriptions.graph.root_by_name("Subscriptions") {
+        for field in SField::fields(&subscriptions.graph, &root) {
+            if let SVal::Object(nref) = &field.value {
+                let get = |name: &str| {
+                    if let Some(field) = SField::field(&subscriptions.graph, name, '.', Some(nref)) {
+                        return field.to_string();
+                    }
+                    String::default()
+                };
+                if topic_matches(&get("topic"), topic) {
+                    matches.push(Subscription {
+                        id: get("id"),
+                        package: get("package"),
+                        handler: get("handler"),
+                    });
+                }
+            }
+        }
+    }
+    matches
+}
+
+
+/// ADMIN set a subscription.
+pub(crate) fn admin_set_subscription(subscriptions: &mut SDoc, id: &str, topic: &str, package: &str, handler: &str) -> bool {
+    if let Ok(res) = subscriptions.call_func("root.set_subscription", None, vec![id.into(), topic.into(), package.into(), handler.into()]) {
+        save_subscriptions(subscriptions);
+        return res.truthy();
+    }
+    false
+}
+
+
+/// ADMIN delete a subscription.
+pub(crate) fn admin_delete_subscription(subscriptions: &mut SDoc, id: &str) -> bool {
+    if let Ok(res) = subscriptions.call_func("root.delete_subscription", None, vec![id.into()]) {
+        save_subscriptions(subscriptions);
+        return res.truthy();
+    }
+    false
+}
+
+
+/// Record a delivery for a subscription.
+pub(crate) fn record_delivery(subscriptions: &mut SDoc, id: &str, time: i64, duration: i64, status: i64, message: &BusMessage, error: &str) -> bool {
+    let params = vec![id.into(), time.into(), duration.into(), status.into(), message.id.as_str().into(), message.topic.as_str().into(), message.source.as_str().into(), error.into()];
+    if let Ok(res) = subscriptions.call_func("root.record", None, params) {
+        save_subscriptions(subscriptions);
+        return res.truthy();
+    }
+    false
+}
+
+
+/// Message published to the bus.
+#[derive(Clone)]
+pub(crate) struct BusMessage {
+    pub id: String,
+    pub topic: String,
+
+    /// Value (bstof document with a "value" field).
+    pub value: Bytes,
+
+    /// Who published it ("user:<name>" or "scope:<scope>", "anonymous" otherwise).
+    pub source: String,
+
+    /// Number of handlers this message was passed through before being published.
+    pub hops: u32,
+
+    /// Deliveries of the message this one originated from (shared with every message passed on from it).
+    pub deliveries: Arc<AtomicU32>,
+
+    /// User that caused the message to be published (none if unauthenticated).
+    /// Handlers get the same library restrictions as this user.
+    pub user: Option<(String, bool)>,
+}
+
+
+/// Bus access for a run.
+#[derive(Clone, Default)]
+pub(crate) struct BusAccess {
+    /// Bus to publish to (none if the bus is not available).
+    pub sender: Option<UnboundedSender<BusMessage>>,
+
+    /// Who messages are published by.
+    pub source: String,
+
+    /// Hops for messages published by this run (one more than the message being handled).
+    pub hops: u32,
+
+    /// Deliveries of the message being handled (none if this run isn't a handler, so each message starts its own count).
+    pub deliveries: Option<Arc<AtomicU32>>,
+
+    /// User running it (none if unauthenticated).
+    pub user: Option<(String, bool)>,
+
+    /// Max hops before messages are no longer passed on.
+    pub max_hops: u32,
+
+    /// Max size of a message (bytes).
+    pub max_size: usize,
+}
+impl BusAccess {
+    /// Publish a message, returning its ID.
+    pub fn publish(&self, topic: &str, value: Bytes) -> Result<String, String> {
+        
+        let sender = match &self.sender {
+            Some(bus) => bus,
+            None => return Err("bus is not available".into()),
+        };
+        if !valid_topic(topic, false) {
+            return Err(format!("'{}' is not a valid topic", topic));
+        }
+        if self.hops > self.max_hops {
+            return Err(format!("message was passed on too many times (max is {} hops)", self.max_hops));
+        }
+        if value.len() > self.max_size {
+            return Err(format!("message is too large (max is {} bytes)", self.max_size));
+        }
+        let id = nanoid!();
+        let message = BusMessage {
+            id: id.clone(),
+            topic: topic.to_owned(),
+            value,
+            source: self.source.clone(),
+            hops: self.hops,
+            deliveries: self.deliveries.clone().unwrap_or_default(),
+            user: self.user.clone(),
+        };
+        if sender.send(message).is_err() {
+            return Err("bus is not available".into());
+        }
+        Ok(id)
+    }
+}
+
+
+/// Bus access for a run by a user (if authenticated), of a registry package (if any).
+pub(crate) async fn run_bus(state: &ServerState, user: &Option<(String, bool)>, package: Option<&str>) -> BusAccess {
+    let mut access = BusAccess::default();
+    {
+        let config = state.config.lock().await;
+        if !bus_enabled(&config) {
+            return access;
+        }
+        access.max_hops = bus_max_hops(&config);
+        access.max_size = bus_max_size(&config);
+    }
+    access.sender = Some(state.bus.clone());
+    access.source = kv_namespace(user, package).unwrap_or(String::from("anonymous"));
+    access.user = user.clone();
+    access
+}
+
+
+/// Start the bus.
+/// Delivers each published message to every subscription with a matching topic.
+/// Once a message and the messages passed on from it reach the max deliveries, the rest are recorded as failures instead.
+pub(crate) fn start_bus(state: ServerState, mut receiver: UnboundedReceiver<BusMessage>) {
+    tokio::spawn(async move {
+        while let Some(message) = receiver.recv().await {
+            let max_deliveries;
+            {
+                let config = state.config.lock().await;
+                max_deliveries = bus_max_deliveries(&config);
+            }
+            let matches;
+            {
+                let subscriptions = state.subscriptions.lock().await;
+                matches = topic_subscriptions(&subscriptions, &message.topic);
+            }
+            for subscription in matches {
+                if message.deliveries.fetch_add(1, Ordering::SeqCst) >= max_deliveries {
+                    let error = format!("message was delivered too many times (max is {} deliveries)", max_deliveries);
+                    let mut subscriptions = state.subscriptions.lock().await;
+                    record_delivery(&mut subscriptions, &subscription.id, Utc::now().timestamp_millis(), 0, StatusCode::TOO_MANY_REQUESTS.as_u16() as i64, &message, &error);
+                    continue;
+                }
+                tokio::spawn(deliver(state.clone(), subscription, message.clone()));
+            }
+        }
+    });
+}
+
+
+/// Deliver a message to a subscription and record the result in its history.
+/// The handler is called with the message value, then the topic and source if it takes them.
+async fn deliver(state: ServerState, subscription: Subscription, message: BusMessage) {
+    let opaque_stof_errors;
+    let run_time;
+    let registry;
+    let egress;
+    let wasm;
+    {
+        let mut config = state.config.lock().await;
+        // Handlers can't run in the sandbox, so they don't run at all when it's required
+        if !run_enabled(&config) || sandbox_required(&config) {
+            return;
+        }
+        opaque_stof_errors = opaque_errors(&config);
+        run_time = run_timeout(&mut config);
+        registry = registry_path(&config);
+        egress = EgressPolicy::from_config(&mut config);
+        wasm = WasmPolicy::from_config(&config);
+    }
+    let package = subscription.package.clone();
+    let kv = run_kv(&state, &None, Some(&package)).await;
+    let sql = run_sql(&state, &None, Some(&package)).await;
+    let mut bus = run_bus(&state, &message.user, Some(&package)).await;
+    bus.hops = message.hops + 1;
+    bus.deliveries = Some(message.deliveries.clone());
+    // Subscribers get the libraries of the user that published the message (the unauthenticated list if none), and the package's scope
+    let libraries = run_libraries(&state, &message.user, Some(&package)).await;
+
+    // metrics
+    {
+        let mut metrics = state.metrics.lock().await;
+        increment_server_run_count(&mut metrics);
+    }
+
+    let time = Utc::now().timestamp_millis();
+    let start = Instant::now();
+    let handler = subscription.handler.clone();
+    let value = message.value.clone();
+    let topic = message.topic.clone();
+    let source = message.source.clone();
+    let cancel = RunCancel::default();
+    let run_cancel = cancel.clone();
+    let handle = spawn_blocking(move || Handle::current().block_on(async move {
+        let mut doc = SDoc::default();
+        initialize_document(&mut doc, &registry, &egress, &wasm, &kv, &sql, &bus).await;
+        libraries.restrict(&mut doc);
+        if let Err(error) = import_package(&mut doc, &package) {
+            if !opaque_stof_errors {
+                return (StatusCode::BAD_REQUEST, error.to_string(&doc.graph));
+            }
+            return (StatusCode::BAD_REQUEST, String::from("error importing package"));
+        }
+        run_cancel.guard(&mut doc);
+
+        let mut handler_path = handler;
+        if !handler_path.contains('.') {
+            handler_path = format!("root.{}", handler_path);
+        }
+        let mut param_count = None;
+        if let Some(func_ref) = SFunc::func_ref(&doc.graph, &handler_path, '.', None) && let Some(func) = SData::get::<SFunc>(&doc.graph, &func_ref) {
+            param_count = Some(func.params.len());
+        }
+        if param_count.is_none() {
+            return (StatusCode::NOT_FOUND, format!("handler '{}' not found", handler_path));
+        }
+
+        let mut arguments = Vec::new();
+        match decode_value(&mut doc, value, "Message") {
+            Some(value) => arguments.push(value),
+            None => return (StatusCode::BAD_REQUEST, String::from("could not read the message")),
+        }
+        arguments.push(SVal::String(topic));
+        arguments.push(SVal::String(source));
+        arguments.truncate(param_count.unwrap_or_default());
+
+        match doc.call_func(&handler_path, None, arguments) {
+            Ok(_) => (StatusCode::OK, String::default()),
+            Err(error) => {
+                if !opaque_stof_errors {
+                    return (StatusCode::BAD_REQUEST, error.to_string(&doc.graph));
+                }
+                (StatusCode::BAD_REQUEST, String::from("error calling handler"))
+            }
+        }
+    }));
+
+    let status;
+    let error;
+    match timeout(run_time, handle).await {
+        Ok(Ok((res_status, res_error))) => {
+            status = res_status;
+            error = res_error;
+        },
+        Ok(Err(_)) => {
+            status = StatusCode::INTERNAL_SERVER_ERROR;
+            error = String::from("error running handler");
+        },
+        Err(_) => {
+            cancel.cancel();
+            status = StatusCode::REQUEST_TIMEOUT;
+            error = String::from("timeout while running handler");
+        }
+    }
+    let duration = start.elapsed().as_millis() as i64;
+    {
+        let mut subscriptions = state.subscriptions.lock().await;
+        record_delivery(&mut subscriptions, &subscription.id, time, duration, status.as_u16() as i64, &message, &error);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{atomic::Ordering, Arc};
+    use bytes::Bytes;
+    use tokio::sync::mpsc::unbounded_channel;
+    use super::BusAccess;
+
+    #[test]
+    fn passed_on_deliveries() {
+        let (sender, mut receiver) = unbounded_channel();
+        let mut access = BusAccess { sender: Some(sender), max_hops: 8, max_size: 100, ..Default::default() };
+
+        // Messages published outside of a handler each start their own count
+        access.publish("orders", Bytes::new()).unwrap();
+        access.publish("orders", Bytes::new()).unwrap();
+        let first = receiver.try_recv().unwrap();
+        let second = receiver.try_recv().unwrap();
+        first.deliveries.fetch_add(1, Ordering::SeqCst);
+        assert_eq!(second.deliveries.load(Ordering::SeqCst), 0);
+
+        // Messages passed on by a handler share the count of the message it handled
+        access.deliveries = Some(first.deliveries.clone());
+        access.hops = 1;
+        access.publish("orders.done", Bytes::new()).unwrap();
+        let passed_on = receiver.try_recv().unwrap();
+        assert!(Arc::ptr_eq(&passed_on.deliveries, &first.deliveries));
+        assert_eq!(passed_on.deliveries.load(Ordering::SeqCst), 1);
+        assert_eq!(passed_on.hops, 1);
+    }
+}
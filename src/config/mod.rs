@@ -14,7 +14,7 @@
 // limitations under the License.
 //
 
-use std::time::Duration;
+use std::{fs, path::Path, time::Duration};
 use stof::{SDoc, SField, SUnits, SVal};
 
 
@@ -35,10 +35,28 @@ type Server {
     run_stof: bool = true;
 
     // Run timeout.
+    // A run that times out is cancelled, so its library calls (kv, sql, HTTP, bus, etc.) fail from then on,
+    // but its thread can't be stopped, so this is not a limit on computation (use the sandbox for that).
     run_timeout: s = 10s;
     fn timeout(): s {
         return self.run_timeout;
     }
+
+    // Max size of the output captured from a run (bytes).
+    #[schema((value: int): bool => value >= 0)]
+    max_log_size: int = 65536;
+
+    // Max size of the files in a run's scratch directory (bytes).
+    #[schema((value: int): bool => value >= 0)]
+    max_scratch_size: int = 10485760;
+
+    // Max number of documents in a batch run.
+    #[schema((value: int): bool => value > 0)]
+    max_batch_size: int = 100;
+
+    // Max number of documents from a batch run that execute at the same time.
+    #[schema((value: int): bool => value > 0)]
+    max_batch_parallel: int = 4;
 }
 
 type Registry {
@@ -48,7 +66,32 @@ type Registry {
     #[schema((value: str): bool => value.len() > 0)]
     path: str = 'registry';
 
+    // Names in the registry directory that start with '__' are reserved for the runner, and cannot be read by runs.
+    // Names without it get it added (Ex. 'users.json' is kept as '__users.json'), moving an existing file at startup.
     users: str = '__users__.json';
+
+    // Library allow lists for package scopes (see "libraries").
+    scopes: str = '__scopes__.json';
+}
+
+type Sessions {
+    // Can create stateful document sessions?
+    enabled: bool = true;
+
+    // Sessions not used within this time get evicted.
+    idle_timeout: s = 10min;
+
+    // Max number of sessions in memory at once (new sessions are rejected beyond this).
+    #[schema((value: int): bool => value > 0)]
+    max_sessions: int = 1000;
+
+    // Persist sessions as bstof files (in the registry directory)?
+    // Persisted sessions are reloaded on demand after being evicted.
+    persist: bool = false;
+
+    // Directory within the registry directory (reserved, so '__' is added if it doesn't start with it).
+    #[schema((value: str): bool => value.len() > 0)]
+    path: str = '__sessions__';
 }
 
 // By default, the runner is unprotected
@@ -60,6 +103,221 @@ type Admin {
     unauth_perms: int = 0b0000;
 }
 
+// Outbound HTTP requests made by runs (HTTP library).
+type Egress {
+    // Hosts ('api.example.com', '*.example.com') and CIDRs ('203.0.113.0/24') that runs can reach.
+    // When empty, any public address can be reached.
+    allow: vec = [];
+
+    // Hosts and CIDRs that runs can never reach (checked before the allow list).
+    deny: vec = [];
+
+    // Can runs reach private, loopback, and link-local addresses (Ex. this runner or cloud metadata endpoints)?
+    // CIDRs in the allow list are reachable either way.
+    allow_private: bool = false;
+
+    // Max HTTP requests per run.
+    #[schema((value: int): bool => value >= 0)]
+    max_requests: int = 100;
+
+    // Max size of an HTTP response (bytes).
+    #[schema((value: int): bool => value >= 0)]
+    max_response_size: int = 10485760;
+
+    // Max timeout for a single HTTP request.
+    timeout: s = 10s;
+}
+
+// Secrets that runs can read with "secrets.get(name)".
+// Secrets are managed by the admin and saved encrypted in the secrets directory.
+// Secrets are redacted from run responses, but not from what a run stores or sends elsewhere (kv, sql, bus, HTTP),
+// so only give secrets to packages that are trusted with them.
+type Secrets {
+    // Can runs read secrets?
+    enabled: bool = true;
+
+    // Encryption key for the secrets file (any string).
+    // If empty, a random key is generated and saved next to the secrets file ("__secrets__.key").
+    key: str = '';
+
+    // Directory for the secrets file (and generated key).
+    // Keep this outside of the registry directory, which runs can read.
+    #[schema((value: str): bool => value.len() > 0)]
+    path: str = 'secrets';
+}
+
+// Cache for run results (opt-in per run with "?cache=true").
+type Cache {
+    // Can run results be cached?
+    enabled: bool = true;
+
+    // How long a cached result is kept.
+    ttl: s = 5min;
+
+    // Max total size of the cached results (bytes).
+    #[schema((value: int): bool => value >= 0)]
+    max_size: int = 67108864;
+}
+
+// Pipelines of registry packages ("POST /pipelines/run").
+type Pipelines {
+    // Can pipelines be run?
+    enabled: bool = true;
+
+    // Max number of steps in a pipeline.
+    #[schema((value: int): bool => value > 0)]
+    max_steps: int = 50;
+
+    // Max number of steps that run at the same time.
+    #[schema((value: int): bool => value > 0)]
+    max_parallel: int = 4;
+
+    // Max number of retries for a single step.
+    #[schema((value: int): bool => value >= 0)]
+    max_retries: int = 3;
+}
+
+// Key-value store for runs ("kv" library).
+type Kv {
+    // Can runs use the key-value store?
+    enabled: bool = true;
+
+    // Max number of keys in each namespace (user or package scope).
+    #[schema((value: int): bool => value >= 0)]
+    max_keys: int = 1000;
+
+    // Max total size of the keys and values in each namespace (bytes).
+    #[schema((value: int): bool => value >= 0)]
+    max_size: int = 1048576;
+}
+
+// SQLite databases for runs ("sql" library), one per scope.
+type Sql {
+    // Can runs use databases?
+    enabled: bool = true;
+
+    // Max number of rows returned by a query.
+    #[schema((value: int): bool => value > 0)]
+    max_rows: int = 1000;
+
+    // Max size of each database (bytes).
+    #[schema((value: int): bool => value > 0)]
+    max_size: int = 104857600;
+}
+
+// Message bus between runs ("bus" library), delivering messages to subscribed registry packages.
+type Bus {
+    // Can runs publish messages?
+    enabled: bool = true;
+
+    // Max number of times a message can be passed on by handlers publishing in response to it.
+    #[schema((value: int): bool => value >= 0)]
+    max_hops: int = 8;
+
+    // Max number of deliveries for a message, including every message passed on from it.
+    #[schema((value: int): bool => value > 0)]
+    max_deliveries: int = 100;
+
+    // Max size of a message (bytes).
+    #[schema((value: int): bool => value > 0)]
+    max_size: int = 1048576;
+}
+
+// Recorded failing runs, for replaying them ("POST /admin/runs/{id}/replay").
+type Runs {
+    // Record each failing run (inputs, package hashes, HTTP responses, and clock reads)?
+    record_failures: bool = true;
+
+    // Max number of recorded runs kept (oldest are removed first).
+    #[schema((value: int): bool => value >= 0)]
+    max_records: int = 100;
+
+    // Max size of a recorded run (bytes), larger runs are not recorded.
+    #[schema((value: int): bool => value > 0)]
+    max_size: int = 10485760;
+}
+
+// Isolated process sandbox for untrusted runs (Linux only).
+// Sandboxed runs execute in a child process of the runner, isolated with namespaces, rlimits, and a seccomp filter.
+// They cannot use the kv, sql, or bus libraries, and cannot be traced or recorded for replay.
+type Sandbox {
+    // Can runs opt in to the sandbox with "?sandbox=true"?
+    enabled: bool = false;
+
+    // Run every document sandboxed (runs, batches, pipelines, and schedules)?
+    // Runs that can't be sandboxed (sessions, websockets, endpoints, bus handlers, and replays) are refused.
+    required: bool = false;
+
+    // Isolate sandboxed runs in new user, network, IPC, UTS, and mount namespaces?
+    // The filesystem is not isolated (the mount namespace is not remounted), so runs only reach it through the sandboxed "fs" library.
+    namespaces: bool = true;
+
+    // Can sandboxed runs reach the network (HTTP library, still restricted by egress)?
+    network: bool = false;
+
+    // Block system calls that sandboxed runs should never need (exec, ptrace, mount, etc.) with seccomp?
+    seccomp: bool = true;
+
+    // Max memory (address space) of a sandboxed run (bytes).
+    #[schema((value: int): bool => value > 0)]
+    max_memory: int = 1073741824;
+
+    // Max CPU time of a sandboxed run.
+    max_cpu: s = 30s;
+
+    // Max number of open files in a sandboxed run.
+    #[schema((value: int): bool => value > 0)]
+    max_files: int = 64;
+
+    // Max size of a sandboxed run's output (exported result, bytes).
+    #[schema((value: int): bool => value > 0)]
+    max_output: int = 104857600;
+}
+
+// Libraries available to runs, by scope (Ex. 'fs', 'HTTP', 'Time', 'kv', 'sql', 'bus', 'secrets', 'Runner').
+// The core libraries (std, Object, Array, String, Number, etc.) are always available, since they only work on the run's own document.
+// Users and package scopes can be restricted further with allow lists in the users document.
+type Libraries {
+    // Libraries that runs can use ('*' for all).
+    allow: vec = ['*'];
+
+    // Libraries that unauthenticated runs can use ('*' for all).
+    // Empty by default, so unauthenticated runs only get the core libraries and have no side effects outside of the run.
+    unauth: vec = [];
+}
+
+// Plugins (cdylib shared libraries) that add Stof libraries and formats to runs (Unix only).
+// Plugins are loaded at startup, and must be built with the same Rust compiler and stof version as the runner (both are checked).
+// Plugins are trusted code: a plugin that panics takes down the runner.
+// Plugin libraries are available to runs like any other library (see "libraries").
+type Plugins {
+    // Load plugins at startup?
+    enabled: bool = false;
+
+    // Directory to load plugins (.so or .dylib files) from.
+    #[schema((value: str): bool => value.len() > 0)]
+    path: str = 'plugins';
+}
+
+// WebAssembly modules in registry packages, imported into runs as libraries.
+// A package lists a module in its imports ({ path: 'math.wasm', format: 'wasm' }), and runs call its exports with the module's file name as the library ("math.add(1, 2)").
+type Wasm {
+    // Can runs import WebAssembly modules?
+    enabled: bool = true;
+
+    // Max fuel (instructions) a module can use per run.
+    #[schema((value: int): bool => value > 0)]
+    max_fuel: int = 100000000;
+
+    // Max memory of a module instance (bytes).
+    #[schema((value: int): bool => value >= 0)]
+    max_memory: int = 16777216;
+
+    // Max size of a module file (bytes).
+    #[schema((value: int): bool => value > 0)]
+    max_module_size: int = 10485760;
+}
+
 type Runner {
     #[schema]
     server: Server = new Server {};
@@ -70,6 +328,45 @@ type Runner {
     #[schema]
     admin: Admin = new Admin {};
 
+    #[schema]
+    sessions: Sessions = new Sessions {};
+
+    #[schema]
+    egress: Egress = new Egress {};
+
+    #[schema]
+    secrets: Secrets = new Secrets {};
+
+    #[schema]
+    cache: Cache = new Cache {};
+
+    #[schema]
+    pipelines: Pipelines = new Pipelines {};
+
+    #[schema]
+    kv: Kv = new Kv {};
+
+    #[schema]
+    sql: Sql = new Sql {};
+
+    #[schema]
+    bus: Bus = new Bus {};
+
+    #[schema]
+    runs: Runs = new Runs {};
+
+    #[schema]
+    sandbox: Sandbox = new Sandbox {};
+
+    #[schema]
+    libraries: Libraries = new Libraries {};
+
+    #[schema]
+    plugins: Plugins = new Plugins {};
+
+    #[schema]
+    wasm: Wasm = new Wasm {};
+
     #[run]
     fn run() {
         self.valid = self.schemafy(self);
@@ -202,6 +499,42 @@ pub(crate) fn run_timeout(config: &mut SDoc) -> Duration {
 }
 
 
+/// Max size of the output captured from a run (bytes).
+pub(crate) fn run_max_log_size(config: &SDoc) -> usize {
+    if let Some(size_field) = SField::field(&config.graph, "root.server.max_log_size", '.', None) && let SVal::Number(num) = &size_field.value {
+        return num.int().max(0) as usize;
+    }
+    65536
+}
+
+
+/// Max size of the files in a run's scratch directory (bytes).
+pub(crate) fn run_max_scratch_size(config: &SDoc) -> u64 {
+    if let Some(size_field) = SField::field(&config.graph, "root.server.max_scratch_size", '.', None) && let SVal::Number(num) = &size_field.value {
+        return num.int().max(0) as u64;
+    }
+    10485760
+}
+
+
+/// Max number of documents in a batch run.
+pub(crate) fn run_max_batch_size(config: &SDoc) -> usize {
+    if let Some(size_field) = SField::field(&config.graph, "root.server.max_batch_size", '.', None) && let SVal::Number(num) = &size_field.value {
+        return num.int().max(1) as usize;
+    }
+    100
+}
+
+
+/// Max number of documents from a batch run that execute at the same time.
+pub(crate) fn run_max_batch_parallel(config: &SDoc) -> usize {
+    if let Some(parallel_field) = SField::field(&config.graph, "root.server.max_batch_parallel", '.', None) && let SVal::Number(num) = &parallel_field.value {
+        return num.int().max(1) as usize;
+    }
+    4
+}
+
+
 /// Registry enabled?
 pub(crate) fn registry_enabled(config: &SDoc) -> bool {
     if let Some(enabled_field) = SField::field(&config.graph, "root.registry.enabled", '.', None) {
@@ -226,11 +559,48 @@ pub(crate) fn registry_path(config: &SDoc) -> String {
 }
 
 
+/// Reserved name in the registry directory ("__" is added if the name doesn't start with it).
+pub(crate) fn reserved_name(name: &str) -> String {
+    if name.starts_with("__") {
+        return name.to_owned();
+    }
+    format!("__{}", name)
+}
+
+
+/// Move runner data configured with names that aren't reserved to their reserved names (Ex. "registry/users.json" -> "registry/__users.json").
+/// Older configs could use any name, which runs could read with "fs".
+pub(crate) fn migrate_reserved_names(config: &SDoc) -> Result<(), String> {
+    let registry_name = registry_path(config);
+    for path in ["root.registry.users", "root.registry.scopes", "root.sessions.path"] {
+        if let Some(field) = SField::field(&config.graph, path, '.', None) {
+            let name = field.to_string();
+            let old_path = format!("{}/{}", registry_name, name);
+            let new_path = format!("{}/{}", registry_name, reserved_name(&name));
+            if old_path != new_path && Path::new(&old_path).exists() && !Path::new(&new_path).exists() && let Err(error) = fs::rename(&old_path, &new_path) {
+                return Err(format!("could not move '{}' to '{}': {}", old_path, new_path, error));
+            }
+        }
+    }
+    Ok(())
+}
+
+
 /// Registry users file name.
 pub(crate) fn registry_users_filename(config: &SDoc) -> String {
     let mut name = String::from("__users__.json");
     if let Some(users_file) = SField::field(&config.graph, "root.registry.users", '.', None) {
-        name = users_file.to_string();
+        name = reserved_name(&users_file.to_string());
+    }
+    name
+}
+
+
+/// Registry scopes file name.
+pub(crate) fn registry_scopes_filename(config: &SDoc) -> String {
+    let mut name = String::from("__scopes__.json");
+    if let Some(scopes_file) = SField::field(&config.graph, "root.registry.scopes", '.', None) {
+        name = reserved_name(&scopes_file.to_string());
     }
     name
 }
@@ -286,3 +656,458 @@ pub(crate) fn unauth_exec(config: &mut SDoc) -> bool {
     }
     return false;
 }
+
+
+/// Sessions enabled?
+pub(crate) fn sessions_enabled(config: &SDoc) -> bool {
+    if let Some(enabled_field) = SField::field(&config.graph, "root.sessions.enabled", '.', None) && let SVal::Bool(val) = &enabled_field.value {
+        return *val;
+    }
+    false
+}
+
+
+/// Session idle timeout duration.
+/// Read from the field directly, so that it works for sessions objects given in a config file.
+pub(crate) fn session_idle_timeout(config: &SDoc) -> Duration {
+    if let Some(SVal::Number(num)) = SField::field(&config.graph, "root.sessions.idle_timeout", '.', None).map(|field| &field.value) {
+        return Duration::from_secs_f64(num.float_with_units(SUnits::Seconds).max(0.));
+    }
+    Duration::from_secs(600)
+}
+
+
+/// Max number of sessions in memory at once.
+pub(crate) fn sessions_max(config: &SDoc) -> usize {
+    if let Some(SVal::Number(num)) = SField::field(&config.graph, "root.sessions.max_sessions", '.', None).map(|field| &field.value) {
+        return num.int().max(1) as usize;
+    }
+    1000
+}
+
+
+/// Sessions persist path.
+/// Returns the directory to persist sessions within if persistence is enabled.
+pub(crate) fn sessions_persist_path(config: &SDoc) -> Option<String> {
+    let mut persist = false;
+    if let Some(persist_field) = SField::field(&config.graph, "root.sessions.persist", '.', None) && let SVal::Bool(val) = &persist_field.value {
+        persist = *val;
+    }
+    if !persist {
+        return None;
+    }
+
+    let mut path = String::from("__sessions__");
+    if let Some(path_field) = SField::field(&config.graph, "root.sessions.path", '.', None) {
+        path = reserved_name(&path_field.to_string());
+    }
+    Some(format!("{}/{}", registry_path(config), path))
+}
+
+
+/// Egress hosts and CIDRs.
+/// Field is "allow" or "deny".
+pub(crate) fn egress_hosts(config: &SDoc, field: &str) -> Vec<String> {
+    let mut hosts = Vec::new();
+    if let Some(hosts_field) = SField::field(&config.graph, &format!("root.egress.{}", field), '.', None) && let SVal::Array(vals) = &hosts_field.value {
+        for val in vals {
+            hosts.push(val.to_string());
+        }
+    }
+    hosts
+}
+
+
+/// Egress to private addresses allowed?
+pub(crate) fn egress_allow_private(config: &SDoc) -> bool {
+    if let Some(private_field) = SField::field(&config.graph, "root.egress.allow_private", '.', None) && let SVal::Bool(val) = &private_field.value {
+        return *val;
+    }
+    false
+}
+
+
+/// Max HTTP requests per run.
+pub(crate) fn egress_max_requests(config: &SDoc) -> usize {
+    if let Some(max_field) = SField::field(&config.graph, "root.egress.max_requests", '.', None) && let SVal::Number(num) = &max_field.value {
+        return num.int().max(0) as usize;
+    }
+    100
+}
+
+
+/// Max size of an HTTP response (bytes).
+pub(crate) fn egress_max_response_size(config: &SDoc) -> usize {
+    if let Some(size_field) = SField::field(&config.graph, "root.egress.max_response_size", '.', None) && let SVal::Number(num) = &size_field.value {
+        return num.int().max(0) as usize;
+    }
+    10485760
+}
+
+
+/// Max timeout for a single HTTP request.
+/// Read from the field directly, so that it works for egress objects given in a config file.
+pub(crate) fn egress_timeout(config: &SDoc) -> Duration {
+    if let Some(timeout_field) = SField::field(&config.graph, "root.egress.timeout", '.', None) && let SVal::Number(num) = &timeout_field.value {
+        return Duration::from_secs_f64(num.float_with_units(SUnits::Seconds).max(0.));
+    }
+    Duration::from_secs(10)
+}
+
+
+/// Secrets enabled?
+pub(crate) fn secrets_enabled(config: &SDoc) -> bool {
+    if let Some(enabled_field) = SField::field(&config.graph, "root.secrets.enabled", '.', None) && let SVal::Bool(val) = &enabled_field.value {
+        return *val;
+    }
+    false
+}
+
+
+/// Secrets directory (outside of the registry directory).
+pub(crate) fn secrets_path(config: &SDoc) -> String {
+    let mut path = String::from("secrets");
+    if let Some(path_field) = SField::field(&config.graph, "root.secrets.path", '.', None) {
+        path = path_field.to_string();
+    }
+    path
+}
+
+
+/// Secrets encryption key (empty if a generated key should be used).
+pub(crate) fn secrets_key(config: &SDoc) -> String {
+    let mut key = String::default();
+    if let Some(key_field) = SField::field(&config.graph, "root.secrets.key", '.', None) {
+        key = key_field.to_string();
+    }
+    key
+}
+
+
+/// Run result cache enabled?
+pub(crate) fn cache_enabled(config: &SDoc) -> bool {
+    if let Some(enabled_field) = SField::field(&config.graph, "root.cache.enabled", '.', None) && let SVal::Bool(val) = &enabled_field.value {
+        return *val;
+    }
+    false
+}
+
+
+/// Run result cache TTL.
+pub(crate) fn cache_ttl(config: &SDoc) -> Duration {
+    if let Some(ttl_field) = SField::field(&config.graph, "root.cache.ttl", '.', None) && let SVal::Number(num) = &ttl_field.value {
+        return Duration::from_secs_f64(num.float_with_units(SUnits::Seconds).max(0.));
+    }
+    Duration::from_secs(300)
+}
+
+
+/// Max total size of the run result cache (bytes).
+pub(crate) fn cache_max_size(config: &SDoc) -> usize {
+    if let Some(size_field) = SField::field(&config.graph, "root.cache.max_size", '.', None) && let SVal::Number(num) = &size_field.value {
+        return num.int().max(0) as usize;
+    }
+    67108864
+}
+
+
+/// Pipelines enabled?
+pub(crate) fn pipelines_enabled(config: &SDoc) -> bool {
+    if let Some(enabled_field) = SField::field(&config.graph, "root.pipelines.enabled", '.', None) && let SVal::Bool(val) = &enabled_field.value {
+        return *val;
+    }
+    false
+}
+
+
+/// Max number of steps in a pipeline.
+pub(crate) fn pipelines_max_steps(config: &SDoc) -> usize {
+    if let Some(steps_field) = SField::field(&config.graph, "root.pipelines.max_steps", '.', None) && let SVal::Number(num) = &steps_field.value {
+        return num.int().max(1) as usize;
+    }
+    50
+}
+
+
+/// Max number of pipeline steps that run at the same time.
+pub(crate) fn pipelines_max_parallel(config: &SDoc) -> usize {
+    if let Some(parallel_field) = SField::field(&config.graph, "root.pipelines.max_parallel", '.', None) && let SVal::Number(num) = &parallel_field.value {
+        return num.int().max(1) as usize;
+    }
+    4
+}
+
+
+/// Max number of retries for a single pipeline step.
+pub(crate) fn pipelines_max_retries(config: &SDoc) -> usize {
+    if let Some(retries_field) = SField::field(&config.graph, "root.pipelines.max_retries", '.', None) && let SVal::Number(num) = &retries_field.value {
+        return num.int().max(0) as usize;
+    }
+    3
+}
+
+
+/// Key-value store enabled?
+pub(crate) fn kv_enabled(config: &SDoc) -> bool {
+    if let Some(enabled_field) = SField::field(&config.graph, "root.kv.enabled", '.', None) && let SVal::Bool(val) = &enabled_field.value {
+        return *val;
+    }
+    false
+}
+
+
+/// Max number of keys in each key-value namespace.
+pub(crate) fn kv_max_keys(config: &SDoc) -> usize {
+    if let Some(keys_field) = SField::field(&config.graph, "root.kv.max_keys", '.', None) && let SVal::Number(num) = &keys_field.value {
+        return num.int().max(0) as usize;
+    }
+    1000
+}
+
+
+/// Max total size of each key-value namespace (bytes).
+pub(crate) fn kv_max_size(config: &SDoc) -> usize {
+    if let Some(size_field) = SField::field(&config.graph, "root.kv.max_size", '.', None) && let SVal::Number(num) = &size_field.value {
+        return num.int().max(0) as usize;
+    }
+    1048576
+}
+
+
+/// Databases enabled?
+pub(crate) fn sql_enabled(config: &SDoc) -> bool {
+    if let Some(enabled_field) = SField::field(&config.graph, "root.sql.enabled", '.', None) && let SVal::Bool(val) = &enabled_field.value {
+        return *val;
+    }
+    false
+}
+
+
+/// Max number of rows returned by a database query.
+pub(crate) fn sql_max_rows(config: &SDoc) -> usize {
+    if let Some(rows_field) = SField::field(&config.graph, "root.sql.max_rows", '.', None) && let SVal::Number(num) = &rows_field.value {
+        return num.int().max(1) as usize;
+    }
+    1000
+}
+
+
+/// Max size of each database (bytes).
+pub(crate) fn sql_max_size(config: &SDoc) -> u64 {
+    if let Some(size_field) = SField::field(&config.graph, "root.sql.max_size", '.', None) && let SVal::Number(num) = &size_field.value {
+        return num.int().max(1) as u64;
+    }
+    104857600
+}
+
+
+/// Can runs publish messages to the bus?
+pub(crate) fn bus_enabled(config: &SDoc) -> bool {
+    if let Some(enabled_field) = SField::field(&config.graph, "root.bus.enabled", '.', None) && let SVal::Bool(val) = &enabled_field.value {
+        return *val;
+    }
+    false
+}
+
+
+/// Max number of hops for a bus message (handlers publishing in response to a message).
+pub(crate) fn bus_max_hops(config: &SDoc) -> u32 {
+    if let Some(hops_field) = SField::field(&config.graph, "root.bus.max_hops", '.', None) && let SVal::Number(num) = &hops_field.value {
+        return num.int().max(0) as u32;
+    }
+    8
+}
+
+
+/// Max number of deliveries for a bus message, including every message passed on from it.
+pub(crate) fn bus_max_deliveries(config: &SDoc) -> u32 {
+    if let Some(deliveries_field) = SField::field(&config.graph, "root.bus.max_deliveries", '.', None) && let SVal::Number(num) = &deliveries_field.value {
+        return num.int().max(1) as u32;
+    }
+    100
+}
+
+
+/// Max size of a bus message (bytes).
+pub(crate) fn bus_max_size(config: &SDoc) -> usize {
+    if let Some(size_field) = SField::field(&config.graph, "root.bus.max_size", '.', None) && let SVal::Number(num) = &size_field.value {
+        return num.int().max(1) as usize;
+    }
+    1048576
+}
+
+
+/// Record failing runs for replay?
+pub(crate) fn runs_record_failures(config: &SDoc) -> bool {
+    if let Some(record_field) = SField::field(&config.graph, "root.runs.record_failures", '.', None) && let SVal::Bool(val) = &record_field.value {
+        return *val;
+    }
+    false
+}
+
+
+/// Max number of recorded runs kept.
+pub(crate) fn runs_max_records(config: &SDoc) -> usize {
+    if let Some(records_field) = SField::field(&config.graph, "root.runs.max_records", '.', None) && let SVal::Number(num) = &records_field.value {
+        return num.int().max(0) as usize;
+    }
+    100
+}
+
+
+/// Max size of a recorded run (bytes).
+pub(crate) fn runs_max_size(config: &SDoc) -> usize {
+    if let Some(size_field) = SField::field(&config.graph, "root.runs.max_size", '.', None) && let SVal::Number(num) = &size_field.value {
+        return num.int().max(1) as usize;
+    }
+    10485760
+}
+
+
+/// Can runs opt in to the sandbox?
+pub(crate) fn sandbox_enabled(config: &SDoc) -> bool {
+    if let Some(enabled_field) = SField::field(&config.graph, "root.sandbox.enabled", '.', None) && let SVal::Bool(val) = &enabled_field.value {
+        return *val;
+    }
+    false
+}
+
+
+/// Are all runs sandboxed?
+pub(crate) fn sandbox_required(config: &SDoc) -> bool {
+    if let Some(required_field) = SField::field(&config.graph, "root.sandbox.required", '.', None) && let SVal::Bool(val) = &required_field.value {
+        return *val;
+    }
+    false
+}
+
+
+/// Isolate sandboxed runs with namespaces?
+pub(crate) fn sandbox_namespaces(config: &SDoc) -> bool {
+    if let Some(namespaces_field) = SField::field(&config.graph, "root.sandbox.namespaces", '.', None) && let SVal::Bool(val) = &namespaces_field.value {
+        return *val;
+    }
+    true
+}
+
+
+/// Can sandboxed runs reach the network?
+pub(crate) fn sandbox_network(config: &SDoc) -> bool {
+    if let Some(network_field) = SField::field(&config.graph, "root.sandbox.network", '.', None) && let SVal::Bool(val) = &network_field.value {
+        return *val;
+    }
+    false
+}
+
+
+/// Filter the system calls of sandboxed runs with seccomp?
+pub(crate) fn sandbox_seccomp(config: &SDoc) -> bool {
+    if let Some(seccomp_field) = SField::field(&config.graph, "root.sandbox.seccomp", '.', None) && let SVal::Bool(val) = &seccomp_field.value {
+        return *val;
+    }
+    true
+}
+
+
+/// Max memory of a sandboxed run (bytes).
+pub(crate) fn sandbox_max_memory(config: &SDoc) -> u64 {
+    if let Some(memory_field) = SField::field(&config.graph, "root.sandbox.max_memory", '.', None) && let SVal::Number(num) = &memory_field.value {
+        return num.int().max(1) as u64;
+    }
+    1073741824
+}
+
+
+/// Max CPU time of a sandboxed run.
+pub(crate) fn sandbox_max_cpu(config: &SDoc) -> Duration {
+    if let Some(cpu_field) = SField::field(&config.graph, "root.sandbox.max_cpu", '.', None) && let SVal::Number(num) = &cpu_field.value {
+        return Duration::from_secs_f64(num.float_with_units(SUnits::Seconds).max(1.));
+    }
+    Duration::from_secs(30)
+}
+
+
+/// Max number of open files in a sandboxed run.
+pub(crate) fn sandbox_max_files(config: &SDoc) -> u64 {
+    if let Some(files_field) = SField::field(&config.graph, "root.sandbox.max_files", '.', None) && let SVal::Number(num) = &files_field.value {
+        return num.int().max(1) as u64;
+    }
+    64
+}
+
+
+/// Max size of a sandboxed run's output (bytes).
+pub(crate) fn sandbox_max_output(config: &SDoc) -> usize {
+    if let Some(output_field) = SField::field(&config.graph, "root.sandbox.max_output", '.', None) && let SVal::Number(num) = &output_field.value {
+        return num.int().max(1) as usize;
+    }
+    104857600
+}
+
+
+/// Libraries that runs can use, by scope ('*' for all).
+/// Field is "allow" or "unauth".
+pub(crate) fn libraries_allowed(config: &SDoc, field: &str) -> Vec<String> {
+    let mut libraries = Vec::new();
+    if let Some(libraries_field) = SField::field(&config.graph, &format!("root.libraries.{}", field), '.', None) && let SVal::Array(vals) = &libraries_field.value {
+        for val in vals {
+            libraries.push(val.to_string());
+        }
+    }
+    libraries
+}
+
+
+/// Load plugins at startup?
+pub(crate) fn plugins_enabled(config: &SDoc) -> bool {
+    if let Some(enabled_field) = SField::field(&config.graph, "root.plugins.enabled", '.', None) && let SVal::Bool(val) = &enabled_field.value {
+        return *val;
+    }
+    false
+}
+
+
+/// Plugins directory.
+pub(crate) fn plugins_path(config: &SDoc) -> String {
+    let mut path = String::from("plugins");
+    if let Some(path_field) = SField::field(&config.graph, "root.plugins.path", '.', None) {
+        path = path_field.to_string();
+    }
+    path
+}
+
+
+/// Can runs import WebAssembly modules?
+pub(crate) fn wasm_enabled(config: &SDoc) -> bool {
+    if let Some(enabled_field) = SField::field(&config.graph, "root.wasm.enabled", '.', None) && let SVal::Bool(val) = &enabled_field.value {
+        return *val;
+    }
+    true
+}
+
+
+/// Max fuel (instructions) a WebAssembly module can use per run.
+pub(crate) fn wasm_max_fuel(config: &SDoc) -> u64 {
+    if let Some(fuel_field) = SField::field(&config.graph, "root.wasm.max_fuel", '.', None) && let SVal::Number(num) = &fuel_field.value {
+        return num.int().max(1) as u64;
+    }
+    100000000
+}
+
+
+/// Max memory of a WebAssembly module instance (bytes).
+pub(crate) fn wasm_max_memory(config: &SDoc) -> usize {
+    if let Some(memory_field) = SField::field(&config.graph, "root.wasm.max_memory", '.', None) && let SVal::Number(num) = &memory_field.value {
+        return num.int().max(0) as usize;
+    }
+    16777216
+}
+
+
+/// Max size of a WebAssembly module file (bytes).
+pub(crate) fn wasm_max_module_size(config: &SDoc) -> usize {
+    if let Some(size_field) = SField::field(&config.graph, "root.wasm.max_module_size", '.', None) && let SVal::Number(num) = &size_field.value {
+        return num.int().max(1) as usize;
+    }
+    10485760
+}
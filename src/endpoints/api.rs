@@ -0,0 +1,205 @@
+//
+// Copyright 2024 Formata, Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+use std::{collections::BTreeMap, sync::Arc};
+use axum::{extract::{Path, Query, State}, http::{header::CONTENT_TYPE, HeaderMap, Method, StatusCode}, response::IntoResponse};
+use bytes::Bytes;
+use stof::{SDoc, SType, SVal};
+use tokio::{runtime::Handle, task::spawn_blocking, time::timeout};
+use crate::{bus::run_bus, config::{opaque_errors, registry_path, run_enabled, run_max_log_size, run_timeout, sandbox_required}, kv::run_kv, libraries::run_libraries, metrics::increment_server_run_count, response::StofResponse, run::{cancel::RunCancel, egress::EgressPolicy, import_package, initialize_document, logs::{RunLogs, RunStdLibrary}}, server::ServerState, sql::run_sql, users::auth::{auth_exec, auth_user}, wasm::WasmPolicy};
+use super::document_endpoints;
+
+
+/// Package endpoint handler.
+/// Path is "@scope/name/<endpoint path>", where "@scope/name" is a registry package.
+pub(crate) async fn endpoint_handler(State(state): State<ServerState>, Path(path): Path<String>, Query(query): Query<BTreeMap<String, String>>, method: Method, headers: HeaderMap, body: Bytes) -> impl IntoResponse {
+    if !auth_exec(&state, &headers).await {
+        return StofResponse::error(StatusCode::FORBIDDEN, "access denied");
+    }
+
+    let segments = path.split('/').collect::<Vec<&str>>();
+    if segments.len() < 2 {
+        return StofResponse::error(StatusCode::BAD_REQUEST, "package directory not found");
+    }
+    let package = segments[0..2].join("/").trim_start_matches('@').to_owned();
+    let endpoint_path = format!("/{}", segments[2..].join("/"));
+
+    let opaque_stof_errors;
+    let run_time;
+    let registry;
+    let egress;
+    let wasm;
+    let max_log_size;
+    {
+        let mut config = state.config.lock().await;
+        if !run_enabled(&config) {
+            return StofResponse::error(StatusCode::NOT_IMPLEMENTED, "runner is not available");
+        }
+        if sandbox_required(&config) {
+            return StofResponse::error(StatusCode::NOT_IMPLEMENTED, "endpoints cannot run in the sandbox, which is required");
+        }
+        opaque_stof_errors = opaque_errors(&config);
+        run_time = run_timeout(&mut config);
+        registry = registry_path(&config);
+        egress = EgressPolicy::from_config(&mut config);
+        wasm = WasmPolicy::from_config(&config);
+        max_log_size = run_max_log_size(&config);
+    }
+    // Access is restricted by the caller (the unauthenticated access if not authenticated), and the package's scope
+    let user = auth_user(&state, &headers).await;
+    let kv = run_kv(&state, &user, Some(&package)).await;
+    let sql = run_sql(&state, &user, Some(&package)).await;
+    let bus = run_bus(&state, &user, Some(&package)).await;
+    let libraries = run_libraries(&state, &user, Some(&package)).await;
+
+    let mut exists = false;
+    {
+        let registry = state.registry.lock().await;
+        if let Ok(exst) = registry.exists(&package) {
+            exists = exst;
+        }
+    }
+    if !exists {
+        return StofResponse::error(StatusCode::NOT_FOUND, "package not found");
+    }
+
+    let mut content_type = String::from("text/plain");
+    if let Some(ctype) = headers.get(CONTENT_TYPE) {
+        match ctype.to_str() {
+            Ok(ctype) => content_type = ctype.to_owned(),
+            Err(_) => return StofResponse::error(StatusCode::BAD_REQUEST, "invalid content type"),
+        }
+    }
+
+    let headers_map = SVal::Map(headers.iter().filter_map(|(k, v)| v.to_str().ok().map(|value| (SVal::String(k.to_string()), SVal::String(value.to_owned())))).collect());
+    let query_map = SVal::Map(query.iter().map(|(k, v)| (SVal::String(k.clone()), SVal::String(v.clone()))).collect());
+
+    // metrics
+    {
+        let mut metrics = state.metrics.lock().await;
+        increment_server_run_count(&mut metrics);
+    }
+
+    // Output printed by the endpoint is captured (not printed by the server)
+    // The endpoint is called on a blocking thread, so that the timeout can be enforced while it runs (see run_stof)
+    let logs = RunLogs::new(max_log_size, None);
+    let cancel = RunCancel::default();
+    let run_cancel = cancel.clone();
+    let handle = spawn_blocking(move || Handle::current().block_on(async move {
+        let mut doc = SDoc::default();
+        initialize_document(&mut doc, &registry, &egress, &wasm, &kv, &sql, &bus).await;
+        doc.load_lib(Arc::new(RunStdLibrary::new(logs)));
+        libraries.restrict(&mut doc);
+        if let Err(error) = import_package(&mut doc, &package) {
+            if !opaque_stof_errors {
+                return StofResponse::error(StatusCode::BAD_REQUEST, &error.to_string(&doc.graph));
+            }
+            return StofResponse::error(StatusCode::BAD_REQUEST, "error importing package");
+        }
+
+        let mut method_allowed = false;
+        let mut found = None;
+        for endpoint in document_endpoints(&doc) {
+            if let Some(params) = endpoint.match_path(&endpoint_path) {
+                if endpoint.method == method.as_str() {
+                    found = Some((endpoint, params));
+                    break;
+                }
+                method_allowed = true;
+            }
+        }
+        let (endpoint, path_params) = match found {
+            Some(found) => found,
+            None => {
+                if method_allowed {
+                    return StofResponse::error(StatusCode::METHOD_NOT_ALLOWED, "method not allowed");
+                }
+                return StofResponse::error(StatusCode::NOT_FOUND, "endpoint not found");
+            }
+        };
+
+        // Map the request onto the function parameters by name
+        // Path params by name, then "query", "headers", and "body"
+        let mut arguments = Vec::new();
+        let mut pending_nulls = 0;
+        for param in &endpoint.params {
+            let argument;
+            if let Some(value) = path_params.get(&param.name) {
+                argument = Some(SVal::String(value.clone()));
+            } else {
+                match param.name.as_str() {
+                    "query" => argument = Some(query_map.clone()),
+                    "headers" => argument = Some(headers_map.clone()),
+                    "body" => {
+                        match &param.ptype {
+                            SType::Object(_) => {
+                                let mut body = body.clone();
+                                if let Err(error) = doc.header_import("main", &content_type, &content_type, &mut body, "Body") {
+                                    if !opaque_stof_errors {
+                                        return StofResponse::error(StatusCode::BAD_REQUEST, &error.to_string(&doc.graph));
+                                    }
+                                    return StofResponse::error(StatusCode::BAD_REQUEST, "error parsing request body");
+                                }
+                                argument = doc.graph.root_by_name("Body").map(SVal::Object);
+                            },
+                            SType::String => argument = Some(SVal::String(String::from_utf8_lossy(&body).to_string())),
+                            _ => argument = Some(SVal::Blob(body.to_vec())),
+                        }
+                    },
+                    _ => argument = None,
+                }
+            }
+            if let Some(argument) = argument {
+                // Params without a value before this one get null
+                for _ in 0..pending_nulls {
+                    arguments.push(SVal::Null);
+                }
+                pending_nulls = 0;
+                arguments.push(argument);
+            } else {
+                // Trailing params without a value fall back onto their defaults
+                pending_nulls += 1;
+            }
+        }
+
+        run_cancel.guard(&mut doc);
+        match doc.call_func(&endpoint.func_path, None, arguments) {
+            Ok(value) => {
+                StofResponse::val_response(&doc, value)
+            },
+            Err(error) => {
+                if !opaque_stof_errors {
+                    return StofResponse::error(StatusCode::BAD_REQUEST, &error.to_string(&doc.graph));
+                }
+                StofResponse::error(StatusCode::BAD_REQUEST, "error calling endpoint")
+            }
+        }
+    }));
+
+    match timeout(run_time, handle).await {
+        Ok(Ok(res)) => {
+            res
+        },
+        Ok(Err(_)) => {
+            StofResponse::error(StatusCode::INTERNAL_SERVER_ERROR, "error calling endpoint")
+        },
+        Err(_) => {
+            // The endpoint is cancelled and left to end on its own thread
+            cancel.cancel();
+            StofResponse::error(StatusCode::REQUEST_TIMEOUT, "timeout while calling endpoint")
+        }
+    }
+}
@@ -0,0 +1,136 @@
+//
+// Copyright 2024 Formata, Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+use std::collections::BTreeMap;
+use stof::{SData, SDoc, SFunc, SParam, SVal};
+pub(crate) mod api;
+
+
+/// Stof function exposed as an HTTP endpoint.
+///
+/// Declared in a package with an "endpoint" attribute:
+/// - `#[endpoint('/hello/{name}')]` (GET)
+/// - `#[endpoint('POST /hello/{name}')]`
+/// - `#[endpoint(('POST', '/hello/{name}'))]`
+/// - `#[endpoint(map(('method', 'POST'), ('path', '/hello/{name}')))]`
+pub(crate) struct Endpoint {
+    /// HTTP method (uppercase).
+    pub method: String,
+
+    /// Path template, relative to the package.
+    pub path: String,
+
+    /// Path to the function in the document.
+    pub func_path: String,
+
+    /// Function parameters.
+    pub params: Vec<SParam>,
+}
+impl Endpoint {
+    /// Create an endpoint from an "endpoint" attribute value.
+    fn from_attribute(value: &SVal, func_path: String, params: Vec<SParam>) -> Option<Self> {
+        let mut method = String::from("GET");
+        let path;
+        match value {
+            SVal::String(value) => {
+                let split = value.split_whitespace().collect::<Vec<&str>>();
+                if split.len() == 1 {
+                    path = split[0].to_owned();
+                } else if split.len() == 2 {
+                    method = split[0].to_owned();
+                    path = split[1].to_owned();
+                } else {
+                    return None;
+                }
+            },
+            SVal::Tuple(values) => {
+                if values.len() != 2 {
+                    return None;
+                }
+                method = values[0].to_string();
+                path = values[1].to_string();
+            },
+            SVal::Map(map) => {
+                if let Some(method_val) = map.get(&SVal::String("method".into())) {
+                    method = method_val.to_string();
+                }
+                if let Some(path_val) = map.get(&SVal::String("path".into())) {
+                    path = path_val.to_string();
+                } else {
+                    return None;
+                }
+            },
+            _ => {
+                return None;
+            }
+        }
+        Some(Self {
+            method: method.to_uppercase(),
+            path: format!("/{}", path.trim_matches('/')),
+            func_path,
+            params,
+        })
+    }
+
+    /// Match a request path against this endpoint's path template.
+    /// Returns the path parameters if matched.
+    ///
+    /// Templates support named segments (`{name}`) and a trailing wildcard (`{*rest}`).
+    pub fn match_path(&self, path: &str) -> Option<BTreeMap<String, String>> {
+        let mut params = BTreeMap::new();
+        let template = self.path.trim_matches('/').split('/').filter(|seg| !seg.is_empty()).collect::<Vec<&str>>();
+        let segments = path.trim_matches('/').split('/').filter(|seg| !seg.is_empty()).collect::<Vec<&str>>();
+
+        for (index, temp) in template.iter().enumerate() {
+            if temp.starts_with("{*") && temp.ends_with('}') {
+                let name = temp.trim_start_matches("{*").trim_end_matches('}');
+                params.insert(name.to_owned(), segments[index.min(segments.len())..].join("/"));
+                return Some(params);
+            }
+            if index >= segments.len() {
+                return None;
+            }
+            if temp.starts_with('{') && temp.ends_with('}') {
+                let name = temp.trim_start_matches('{').trim_end_matches('}');
+                params.insert(name.to_owned(), segments[index].to_owned());
+            } else if *temp != segments[index] {
+                return None;
+            }
+        }
+        if template.len() != segments.len() {
+            return None;
+        }
+        Some(params)
+    }
+}
+
+
+/// Get all of the endpoints declared in a document.
+pub(crate) fn document_endpoints(doc: &SDoc) -> Vec<Endpoint> {
+    let mut endpoints = Vec::new();
+    for func_ref in SFunc::all_funcs(&doc.graph) {
+        if let Some(func) = SData::get::<SFunc>(&doc.graph, &func_ref) && let Some(attr_val) = func.attributes.get("endpoint") {
+            let func_nodes = func_ref.nodes(&doc.graph);
+            if let Some(node) = func_nodes.first() {
+                let func_path = format!("{}.{}", node.path(&doc.graph).replace('/', "."), func.name);
+                if let Some(endpoint) = Endpoint::from_attribute(attr_val, func_path, func.params.clone()) {
+                    endpoints.push(endpoint);
+                }
+            }
+        }
+    }
+    endpoints
+}
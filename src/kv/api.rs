@@ -0,0 +1,147 @@
+//
+// Copyright 2024 Formata, Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+use std::collections::BTreeMap;
+use axum::{extract::{Path, Query, State}, http::{HeaderMap, StatusCode}, response::IntoResponse};
+use stof::{SDoc, SField, SUnits, SVal};
+use crate::{response::StofResponse, run::export_document, server::ServerState, users::auth::auth_admin};
+use super::namespace_size;
+
+
+/// Get all key-value namespaces, with the number of keys and total size (bytes) of each.
+/// Uses the "export" query to determine the format (default is "json").
+pub(crate) async fn admin_get_kv_handler(State(state): State<ServerState>, Query(query): Query<BTreeMap<String, String>>, headers: HeaderMap) -> impl IntoResponse {
+    if !auth_admin(&state, &headers, false).await {
+        return StofResponse::error(StatusCode::FORBIDDEN, "access denied");
+    }
+
+    let mut export_format = String::from("json");
+    if let Some(format) = query.get("export") {
+        export_format = format.clone();
+    }
+
+    let mut store = state.kv.lock().unwrap();
+    let names = store.namespaces.keys().cloned().collect::<Vec<_>>();
+    let mut doc = SDoc::default();
+    if let Some(main) = doc.graph.main_root() {
+        let mut namespaces = Vec::new();
+        for name in names {
+            store.remove_expired(&name);
+            if let Some(entries) = store.namespaces.get(&name) {
+                let nref = doc.graph.insert_node(&format!("namespace{}", namespaces.len()), Some(&main));
+                SField::new_string(&mut doc.graph, "namespace", &name, &nref);
+                SField::new_int(&mut doc.graph, "keys", entries.len() as i64, &nref);
+                SField::new_int(&mut doc.graph, "size", namespace_size(entries) as i64, &nref);
+                namespaces.push(SVal::Object(nref));
+            }
+        }
+        SField::new_array(&mut doc.graph, "namespaces", namespaces, &main);
+        SField::new_int(&mut doc.graph, "max_keys", store.max_keys as i64, &main);
+        SField::new_int(&mut doc.graph, "max_size", store.max_size as i64, &main);
+    }
+    export_document(&doc, &export_format)
+}
+
+
+/// Get the entries in a key-value namespace (keys, sizes, and times - use the key path to get a value).
+/// Use "?prefix=" to only get keys that start with a prefix.
+/// Uses the "export" query to determine the format (default is "json").
+pub(crate) async fn admin_get_kv_namespace_handler(State(state): State<ServerState>, Path(namespace): Path<String>, Query(query): Query<BTreeMap<String, String>>, headers: HeaderMap) -> impl IntoResponse {
+    if !auth_admin(&state, &headers, false).await {
+        return StofResponse::error(StatusCode::FORBIDDEN, "access denied");
+    }
+
+    let mut export_format = String::from("json");
+    if let Some(format) = query.get("export") {
+        export_format = format.clone();
+    }
+    let prefix = query.get("prefix").cloned().unwrap_or_default();
+
+    let mut store = state.kv.lock().unwrap();
+    store.remove_expired(&namespace);
+    
+    let entries = match store.namespaces.get(&namespace) {
+        Some(value) => value,
+        None => return StofResponse::error(StatusCode::NOT_FOUND, "namespace not found"),
+    };
+
+    let mut doc = SDoc::default();
+    if let Some(main) = doc.graph.main_root() {
+        let mut values = Vec::new();
+        for (key, entry) in entries.iter().filter(|(key, _)| key.starts_with(&prefix)) {
+            let nref = doc.graph.insert_node(&format!("entry{}", values.len()), Some(&main));
+            SField::new_string(&mut doc.graph, "key", key, &nref);
+            SField::new_int(&mut doc.graph, "size", entry.value.len() as i64, &nref);
+            SField::new_units(&mut doc.graph, "updated", entry.updated as f64, SUnits::Milliseconds, &nref);
+            if let Some(expires) = entry.expires {
+                SField::new_units(&mut doc.graph, "expires", expires as f64, SUnits::Milliseconds, &nref);
+            }
+            values.push(SVal::Object(nref));
+        }
+        SField::new_string(&mut doc.graph, "namespace", &namespace, &main);
+        SField::new_array(&mut doc.graph, "entries", values, &main);
+    }
+    export_document(&doc, &export_format)
+}
+
+
+/// Get a key-value entry (a document with the "value").
+/// Uses the "export" query to determine the format (default is "json").
+pub(crate) async fn admin_get_kv_entry_handler(State(state): State<ServerState>, Path((namespace, key)): Path<(String, String)>, Query(query): Query<BTreeMap<String, String>>, headers: HeaderMap) -> impl IntoResponse {
+    if !auth_admin(&state, &headers, false).await {
+        return StofResponse::error(StatusCode::FORBIDDEN, "access denied");
+    }
+
+    let mut export_format = String::from("json");
+    if let Some(format) = query.get("export") {
+        export_format = format.clone();
+    }
+
+    let value = state.kv.lock().unwrap().get(&namespace, &key);
+    if let Some(bytes) = value {
+        if let Ok(doc) = SDoc::bytes(bytes, "bstof") {
+            return export_document(&doc, &export_format);
+        }
+        return StofResponse::error(StatusCode::INTERNAL_SERVER_ERROR, "could not read the value");
+    }
+    StofResponse::error(StatusCode::NOT_FOUND, "key not found")
+}
+
+
+/// Delete a key-value namespace (all of its entries).
+pub(crate) async fn admin_delete_kv_namespace_handler(State(state): State<ServerState>, Path(namespace): Path<String>, headers: HeaderMap) -> impl IntoResponse {
+    if !auth_admin(&state, &headers, false).await {
+        return StofResponse::error(StatusCode::FORBIDDEN, "access denied");
+    }
+
+    if state.kv.lock().unwrap().clear(&namespace) {
+        return StofResponse::msg(StatusCode::OK, "deleted namespace");
+    }
+    StofResponse::error(StatusCode::NOT_FOUND, "namespace not found")
+}
+
+
+/// Delete a key-value entry.
+pub(crate) async fn admin_delete_kv_entry_handler(State(state): State<ServerState>, Path((namespace, key)): Path<(String, String)>, headers: HeaderMap) -> impl IntoResponse {
+    if !auth_admin(&state, &headers, false).await {
+        return StofResponse::error(StatusCode::FORBIDDEN, "access denied");
+    }
+
+    if state.kv.lock().unwrap().delete(&namespace, &key) {
+        return StofResponse::msg(StatusCode::OK, "deleted key");
+    }
+    StofResponse::error(StatusCode::NOT_FOUND, "key not found")
+}
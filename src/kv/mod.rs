@@ -0,0 +1,380 @@
+//
+// Copyright 2024 Formata, Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+use std::{collections::BTreeMap, fs, sync::{Arc, Mutex}, time::Duration};
+use bytes::Bytes;
+use chrono::Utc;
+use stof::{SData, SDoc, SField, SVal};
+use crate::{config::{kv_enabled, kv_max_keys, kv_max_size, registry_path}, server::ServerState};
+pub(crate) mod api;
+
+
+/// Stored value.
+#[derive(Clone)]
+pub struct KvEntry {
+    /// Value document (bstof with a "value" field on its main root).
+    pub value: Bytes,
+
+    /// When this entry expires (ms since the epoch), if it has a TTL.
+    pub expires: Option<i64>,
+
+    /// When this entry was last set (ms since the epoch).
+    pub updated: i64,
+}
+impl KvEntry {
+    /// Has this entry expired?
+    pub fn expired(&self, now: i64) -> bool {
+        self.expires.is_some_and(|expires| expires <= now)
+    }
+}
+
+
+/// Key-value store for runs.
+/// Entries are kept in memory by namespace (a user or package scope), and saved to the registry directory in the background (see start_kv_saver).
+pub struct KvStore {
+    pub namespaces: BTreeMap<String, BTreeMap<String, KvEntry>>,
+
+    /// Store file path.
+    pub path: String,
+
+    /// Changed since it was last saved?
+    pub dirty: bool,
+
+    /// Max number of keys in each namespace.
+    pub max_keys: usize,
+
+    /// Max total size of the keys and values in each namespace (bytes).
+    pub max_size: usize,
+}
+impl KvStore {
+    /// Get a value.
+    pub fn get(&mut self, namespace: &str, key: &str) -> Option<Bytes> {
+        self.remove_expired(namespace);
+        if let Some(entries) = self.namespaces.get(namespace) && let Some(entry) = entries.get(key) {
+            return Some(entry.value.clone());
+        }
+        None
+    }
+
+    /// Set a value, with an optional TTL.
+    /// Errors if the namespace would go over its quota.
+    pub fn set(&mut self, namespace: &str, key: &str, value: Bytes, ttl: Option<Duration>) -> Result<(), String> {
+        self.remove_expired(namespace);
+        let now = Utc::now().timestamp_millis();
+        let entries = self.namespaces.entry(namespace.to_owned()).or_default();
+
+        let mut keys = entries.len();
+        let mut size = namespace_size(entries);
+        if let Some(existing) = entries.get(key) {
+            keys -= 1;
+            size -= key.len() + existing.value.len();
+        }
+        if keys + 1 > self.max_keys {
+            return Err(format!("too many keys (max is {})", self.max_keys));
+        }
+        if size + key.len() + value.len() > self.max_size {
+            return Err(format!("namespace is too large (max is {} bytes)", self.max_size));
+        }
+
+        entries.insert(key.to_owned(), KvEntry {
+            value,
+            expires: ttl.map(|ttl| now + ttl.as_millis() as i64),
+            updated: now,
+        });
+        self.dirty = true;
+        Ok(())
+    }
+
+    /// Delete a value.
+    pub fn delete(&mut self, namespace: &str, key: &str) -> bool {
+        let mut removed = false;
+        if let Some(entries) = self.namespaces.get_mut(namespace) {
+            removed = entries.remove(key).is_some();
+            if entries.is_empty() {
+                self.namespaces.remove(namespace);
+            }
+        }
+        if removed {
+            self.dirty = true;
+        }
+        removed
+    }
+
+    /// Keys in a namespace that start with a prefix (sorted).
+    pub fn list(&mut self, namespace: &str, prefix: &str) -> Vec<String> {
+        self.remove_expired(namespace);
+        if let Some(entries) = self.namespaces.get(namespace) {
+            return entries.keys().filter(|key| key.starts_with(prefix)).cloned().collect();
+        }
+        Vec::new()
+    }
+
+    /// Remove a namespace and all of its entries.
+    pub fn clear(&mut self, namespace: &str) -> bool {
+        if self.namespaces.remove(namespace).is_some() {
+            self.dirty = true;
+            return true;
+        }
+        false
+    }
+
+    /// Remove expired entries from a namespace.
+    pub fn remove_expired(&mut self, namespace: &str) {
+        let now = Utc::now().timestamp_millis();
+        if let Some(entries) = self.namespaces.get_mut(namespace) {
+            entries.retain(|_, entry| !entry.expired(now));
+            if entries.is_empty() {
+                self.namespaces.remove(namespace);
+            }
+        }
+    }
+
+    /// Entries to save, if the store changed since it was last saved.
+    /// Values are shared, so this is cheap enough to take under the lock, leaving the save itself off of it.
+    pub fn changes(&mut self) -> Option<BTreeMap<String, BTreeMap<String, KvEntry>>> {
+        if !self.dirty {
+            return None;
+        }
+        self.dirty = false;
+        Some(self.namespaces.clone())
+    }
+}
+
+
+/// Save key-value entries to a store file.
+/// Saved as a bstof document with an "entries" array on its main root.
+/// Written to a temporary file first and renamed over the store file, so a failed write never leaves a partial store.
+pub(crate) fn save_kv(path: &str, namespaces: &BTreeMap<String, BTreeMap<String, KvEntry>>) -> Result<(), String> {
+    let now = Utc::now().timestamp_millis();
+    let mut doc = SDoc::default();
+    if let Some(main) = doc.graph.main_root() {
+        let mut values = Vec::new();
+        for (namespace, entries) in namespaces {
+            for (key, entry) in entries.iter().filter(|(_, entry)| !entry.expired(now)) {
+                let nref = doc.graph.insert_node(&format!("entry{}", values.len()), Some(&main));
+                SField::new_string(&mut doc.graph, "namespace", namespace, &nref);
+                SField::new_string(&mut doc.graph, "key", key, &nref);
+                SField::new_int(&mut doc.graph, "updated", entry.updated, &nref);
+                SField::new_int(&mut doc.graph, "expires", entry.expires.unwrap_or(0), &nref);
+                SData::insert_new(&mut doc.graph, &nref, Box::new(SField::new("value", SVal::Blob(entry.value.to_vec()))));
+                values.push(SVal::Object(nref));
+            }
+        }
+        SField::new_array(&mut doc.graph, "entries", values, &main);
+    }
+    let bytes = doc.export_bytes("main", "bstof", None).map_err(|error| error.message)?;
+    let temp_path = format!("{}.tmp", path);
+    fs::write(&temp_path, bytes).map_err(|error| error.to_string())?;
+    fs::rename(&temp_path, path).map_err(|error| error.to_string())
+}
+
+
+/// Start saving the key-value store.
+/// Changes are batched and saved at most once a second, off of the store lock (changes from the last second before the runner stops can be lost).
+pub(crate) fn start_kv_saver(state: ServerState) {
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            let (path, changes) = {
+                let mut store = state.kv.lock().unwrap();
+                (store.path.clone(), store.changes())
+            };
+            if let Some(namespaces) = changes {
+                let saved = tokio::task::spawn_blocking(move || save_kv(&path, &namespaces)).await;
+                if !matches!(saved, Ok(Ok(()))) {
+                    state.kv.lock().unwrap().dirty = true;
+                }
+            }
+        }
+    });
+}
+
+
+/// Total size of the keys and values in a namespace (bytes).
+pub(crate) fn namespace_size(entries: &BTreeMap<String, KvEntry>) -> usize {
+    entries.iter().map(|(key, entry)| key.len() + entry.value.len()).sum()
+}
+
+
+/// Load the key-value store.
+/// Saved as a reserved entry in the registry directory ("__kv__.bstof"), which runs cannot read with "fs".
+pub(crate) fn load_kv(config: &SDoc) -> KvStore {
+    let registry_name = registry_path(config);
+    let kv_file_path = format!("{}/__kv__.bstof", registry_name);
+
+    let mut store = KvStore {
+        namespaces: BTreeMap::new(),
+        path: kv_file_path.clone(),
+        dirty: false,
+        max_keys: kv_max_keys(config),
+        max_size: kv_max_size(config),
+    };
+    if let Ok(doc) = SDoc::file(&kv_file_path, "bstof") && let Some(field) = doc.field("root.entries", None) && let SVal::Array(values) = &field.value {
+        for value in values {
+            if let SVal::Object(nref) = value {
+                let field = |name: &str| SField::field(&doc.graph, name, '.', Some(nref)).map(|field| field.value.clone());
+                if let (Some(SVal::String(namespace)), Some(SVal::String(key)), Some(SVal::Blob(value))) = (field("namespace"), field("key"), field("value")) {
+                    let mut entry = KvEntry {
+                        value: Bytes::from(value),
+                        expires: None,
+                        updated: 0,
+                    };
+                    if let Some(SVal::Number(updated)) = field("updated") {
+                        entry.updated = updated.int();
+                    }
+                    if let Some(SVal::Number(expires)) = field("expires") && expires.int() > 0 {
+                        entry.expires = Some(expires.int());
+                    }
+                    store.namespaces.entry(namespace).or_default().insert(key, entry);
+                }
+            }
+        }
+    }
+    store
+}
+
+
+/// Key-value access for a run.
+/// Runs without a store or namespace cannot use the "kv" library.
+#[derive(Clone, Default)]
+pub(crate) struct KvAccess {
+    pub store: Option<Arc<Mutex<KvStore>>>,
+    pub namespace: Option<String>,
+}
+
+
+/// Key-value namespace for a run.
+/// Runs of a registry package use the package scope ("scope:acme" for "@acme/..."), otherwise the user ("user:name").
+pub(crate) fn kv_namespace(user: &Option<(String, bool)>, package: Option<&str>) -> Option<String> {
+    if let Some(package) = package {
+        let scope = package.trim_start_matches('@').split('/').next().unwrap_or_default();
+        if !scope.is_empty() {
+            return Some(format!("scope:{}", scope));
+        }
+    }
+    if let Some((username, _)) = user && !username.is_empty() {
+        return Some(format!("user:{}", username));
+    }
+    None
+}
+
+
+/// Key-value access for a run by a user (if authenticated), of a registry package (if any).
+pub(crate) async fn run_kv(state: &ServerState, user: &Option<(String, bool)>, package: Option<&str>) -> KvAccess {
+    {
+        let config = state.config.lock().await;
+        if !kv_enabled(&config) {
+            return KvAccess::default();
+        }
+    }
+    KvAccess {
+        store: Some(state.kv.clone()),
+        namespace: kv_namespace(user, package),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, fs, thread::sleep, time::Duration};
+    use bytes::Bytes;
+    use nanoid::nanoid;
+    use stof::SDoc;
+    use super::{kv_namespace, load_kv, save_kv, KvStore};
+
+    fn kv_store(max_keys: usize, max_size: usize) -> KvStore {
+        KvStore {
+            namespaces: BTreeMap::new(),
+            path: String::default(),
+            dirty: false,
+            max_keys,
+            max_size,
+        }
+    }
+
+    #[test]
+    fn namespaces() {
+        assert_eq!(kv_namespace(&Some(("alice".into(), false)), Some("@acme/hello")), Some("scope:acme".into()));
+        assert_eq!(kv_namespace(&Some(("alice".into(), false)), None), Some("user:alice".into()));
+        assert_eq!(kv_namespace(&Some((String::default(), false)), None), None);
+        assert_eq!(kv_namespace(&None, None), None);
+
+        // Namespaces are isolated, each with its own quota
+        let mut store = kv_store(1, 1024);
+        store.set("user:alice", "key", Bytes::from("a"), None).unwrap();
+        store.set("user:bob", "key", Bytes::from("b"), None).unwrap();
+        assert!(store.set("user:alice", "other", Bytes::from("a"), None).is_err());
+        assert_eq!(store.get("user:alice", "key"), Some(Bytes::from("a")));
+        assert_eq!(store.get("user:bob", "key"), Some(Bytes::from("b")));
+
+        assert!(store.clear("user:alice"));
+        assert_eq!(store.get("user:alice", "key"), None);
+        assert_eq!(store.list("user:bob", ""), vec![String::from("key")]);
+    }
+
+    #[test]
+    fn quotas() {
+        let mut store = kv_store(10, 8);
+        store.set("ns", "key", Bytes::from("12345"), None).unwrap();
+        assert!(store.set("ns", "other", Bytes::from("1"), None).is_err());
+
+        // Replacing a value only counts the new value
+        store.set("ns", "key", Bytes::from("abcde"), None).unwrap();
+        assert!(store.set("ns", "key", Bytes::from("abcdef"), None).is_err());
+    }
+
+    #[test]
+    fn ttl() {
+        let mut store = kv_store(10, 1024);
+        store.set("ns", "short", Bytes::from("a"), Some(Duration::from_millis(10))).unwrap();
+        store.set("ns", "long", Bytes::from("b"), Some(Duration::from_secs(60))).unwrap();
+        store.set("ns", "forever", Bytes::from("c"), None).unwrap();
+        assert_eq!(store.list("ns", ""), vec![String::from("forever"), String::from("long"), String::from("short")]);
+
+        sleep(Duration::from_millis(20));
+        assert_eq!(store.get("ns", "short"), None);
+        assert_eq!(store.list("ns", ""), vec![String::from("forever"), String::from("long")]);
+
+        // Expired entries don't count against the quota
+        let mut store = kv_store(1, 1024);
+        store.set("ns", "short", Bytes::from("a"), Some(Duration::from_millis(10))).unwrap();
+        sleep(Duration::from_millis(20));
+        store.set("ns", "key", Bytes::from("b"), None).unwrap();
+    }
+
+    #[test]
+    fn save_and_load() {
+        let dir = std::env::temp_dir().join("stof-runner").join(nanoid!());
+        fs::create_dir_all(&dir).unwrap();
+        let config = SDoc::src(&format!("registry: {{ path: '{}' }}", dir.to_string_lossy()), "stof").unwrap();
+
+        let mut store = load_kv(&config);
+        assert!(store.changes().is_none());
+        store.set("user:alice", "key", Bytes::from("a"), None).unwrap();
+        store.set("user:alice", "expires", Bytes::from("b"), Some(Duration::from_secs(60))).unwrap();
+
+        // Changes are taken once
+        let changes = store.changes().unwrap();
+        assert!(store.changes().is_none());
+        save_kv(&store.path, &changes).unwrap();
+        assert!(!dir.join("__kv__.bstof.tmp").exists());
+
+        let mut loaded = load_kv(&config);
+        assert_eq!(loaded.get("user:alice", "key"), Some(Bytes::from("a")));
+        assert!(loaded.namespaces["user:alice"]["expires"].expires.is_some());
+        let _ = fs::remove_dir_all(dir);
+    }
+}
@@ -0,0 +1,144 @@
+//
+// Copyright 2024 Formata, Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+
+use std::sync::Arc;
+use stof::{lang::SError, Library, SDoc, SVal};
+use crate::{config::{get_admin, libraries_allowed}, server::ServerState, users::{scope_libraries, user_libraries}};
+
+
+/// Libraries that are always available to runs.
+/// These only work on the run's own document, so they have no side effects outside of the run.
+pub(crate) const CORE_LIBRARIES: [&str; 12] = ["std", "Object", "Array", "Map", "Set", "Function", "Number", "String", "Tuple", "Bool", "Blob", "Data"];
+
+/// Library scopes that the runner provides itself, which plugins and WebAssembly modules cannot replace.
+pub(crate) const RUNNER_LIBRARIES: [&str; 8] = ["fs", "HTTP", "kv", "sql", "bus", "secrets", "Runner", "trace"];
+
+
+/// Libraries a run can use.
+/// A library (by scope) is available if it's a core library, or if every allow list contains it (or '*').
+#[derive(Clone, Default)]
+pub(crate) struct RunLibraries {
+    /// Allow lists (config, user or unauthenticated, and package scope).
+    pub allow: Vec<Vec<String>>,
+}
+impl RunLibraries {
+    /// Run libraries from the config allow list.
+    pub fn from_config(config: &SDoc) -> Self {
+        Self {
+            allow: vec![libraries_allowed(config, "allow")],
+        }
+    }
+
+    /// Can the run use this library?
+    pub fn allowed(&self, scope: &str) -> bool {
+        if CORE_LIBRARIES.contains(&scope) {
+            return true;
+        }
+        self.allow.iter().all(|list| list.iter().any(|name| name == "*" || name == scope))
+    }
+
+    /// Remove the libraries the run can't use from a document.
+    /// Stof imports read files with the "fs" library, so without "fs" the document keeps its reads (see ImportFileSystemLibrary).
+    pub fn restrict(&self, doc: &mut SDoc) {
+        let fs = doc.libraries.get("fs");
+        doc.libraries.libraries.retain(|scope, _| self.allowed(scope));
+        if let Some(fs) = fs && !self.allowed("fs") {
+            doc.load_lib(Arc::new(ImportFileSystemLibrary { fs }));
+        }
+    }
+
+    /// Allow lists as a value (array of arrays).
+    pub fn to_val(&self) -> SVal {
+        SVal::Array(self.allow.iter().map(|list| SVal::Array(list.iter().map(|name| SVal::String(name.clone())).collect())).collect())
+    }
+
+    /// Allow lists as text (one list per line, with names separated by commas).
+    pub fn to_text(&self) -> String {
+        self.allow.iter().map(|list| format!("{}\n", list.join(","))).collect()
+    }
+
+    /// Run libraries from allow lists as text (see to_text).
+    pub fn from_text(text: &str) -> Self {
+        Self {
+            allow: text.lines().map(|line| line.split(',').filter(|name| !name.is_empty()).map(|name| name.to_owned()).collect()).collect(),
+        }
+    }
+
+    /// Run libraries from allow lists as a value (see to_val).
+    pub fn from_val(value: &SVal) -> Self {
+        let mut allow = Vec::new();
+        if let SVal::Array(lists) = value {
+            for list in lists {
+                if let SVal::Array(names) = list {
+                    allow.push(names.iter().map(|name| name.to_string()).collect());
+                }
+            }
+        }
+        Self {
+            allow,
+        }
+    }
+}
+
+
+/// File system library for runs that can't use "fs".
+/// Only reads files (what Stof imports use), through the run's own "fs" library, so that packages can still be imported.
+/// Imports can read these files anyway, so this gives the document nothing more.
+struct ImportFileSystemLibrary {
+    fs: Arc<dyn Library>,
+}
+impl Library for ImportFileSystemLibrary {
+    fn scope(&self) -> String {
+        "fs".to_string()
+    }
+
+    fn call(&self, pid: &str, doc: &mut SDoc, name: &str, parameters: &mut Vec<SVal>) -> Result<SVal, SError> {
+        match name {
+            "read" | "read_blob" => self.fs.call(pid, doc, name, parameters),
+            _ => Err(SError::filesys(pid, doc, name, "access denied (the fs library is not available to this run)")),
+        }
+    }
+}
+
+
+/// Libraries for a run by a user (if authenticated), of a registry package (if any).
+///
+/// Authenticated users are restricted to their allow list (the admin and the runner itself have none).
+/// Unauthenticated runs are restricted to the config "unauth" list, when the runner has an admin (otherwise all requests are valid).
+/// Package runs are also restricted to the allow list of the package's scope, if it has one.
+pub(crate) async fn run_libraries(state: &ServerState, user: &Option<(String, bool)>, package: Option<&str>) -> RunLibraries {
+    let mut libraries;
+    {
+        let config = state.config.lock().await;
+        libraries = RunLibraries::from_config(&config);
+        if user.is_none() && get_admin(&config).is_some() {
+            libraries.allow.push(libraries_allowed(&config, "unauth"));
+        }
+    }
+
+    let users = state.users.lock().await;
+    if let Some((username, admin)) = user && !admin && let Some(list) = user_libraries(&users, username) {
+        libraries.allow.push(list);
+    }
+    if let Some(package) = package {
+        let scope = package.trim_start_matches('@').split('/').next().unwrap_or_default();
+        if let Some(list) = scope_libraries(&users, scope) {
+            libraries.allow.push(list);
+        }
+    }
+    libraries
+}
@@ -19,6 +19,21 @@ mod response;
 mod users;
 mod registry;
 mod metrics;
+mod endpoints;
+mod sessions;
+mod schedules;
+mod secrets;
+mod pipelines;
+mod kv;
+mod sql;
+mod bus;
+mod runs;
+mod libraries;
+mod plugins;
+use plugins::load_plugins;
+mod wasm;
+mod sandbox;
+use sandbox::sandbox_main;
 
 mod config;
 use config::load_config;
@@ -35,6 +50,10 @@ use colored::Colorize;
 struct Cli {
     #[arg(short, long, value_name = "STOF_FILE")]
     config: Option<String>,
+
+    /// Run as a sandbox process for the server (reads a run from stdin).
+    #[arg(long, hide = true)]
+    sandbox: bool,
 }
 
 
@@ -44,6 +63,11 @@ async fn main() {
     let config = load_config(cli.config);
     match config {
         Ok(config) => {
+            // Sandbox processes load the same plugins, without reporting them again for every run
+            load_plugins(&config, !cli.sandbox);
+            if cli.sandbox {
+                std::process::exit(sandbox_main(config).await);
+            }
             serve(config).await;
         },
         Err(error) => {
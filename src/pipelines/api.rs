@@ -0,0 +1,94 @@
+//
+// Copyright 2024 Formata, Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+use std::{collections::BTreeMap, time::Instant};
+use axum::{extract::{Query, State}, http::{header::CONTENT_TYPE, HeaderMap, StatusCode}, response::IntoResponse};
+use bytes::Bytes;
+use crate::{config::{pipelines_enabled, pipelines_max_parallel, pipelines_max_retries, pipelines_max_steps, run_enabled}, response::StofResponse, run::{export_document, RunOptions}, server::ServerState, users::auth::{auth_exec, auth_user}};
+use super::{parse_pipeline, pipeline_document, run_pipeline};
+
+
+/// Run a pipeline handler.
+/// The body is a pipeline definition (Stof by default), with a "steps" object of named steps:
+///
+/// ```stof
+/// steps: {
+///     fetch: { package: '@acme/fetch', inputs: { url: 'https://example.com' }, retries: 2 }
+///     summarize: { package: '@acme/summarize', after: ['fetch'], timeout: 5s }
+/// }
+/// ```
+///
+/// Each step runs a registry package, with the output of every step it runs after given as an input of the same name.
+/// Independent steps run in parallel. The status is 207 (Multi-Status) if any step failed or was skipped.
+/// Uses the "export" query to determine the result format (default is "json").
+pub(crate) async fn run_pipeline_handler(State(state): State<ServerState>, Query(query): Query<BTreeMap<String, String>>, headers: HeaderMap, body: Bytes) -> impl IntoResponse {
+    if !auth_exec(&state, &headers).await {
+        return StofResponse::error(StatusCode::FORBIDDEN, "access denied");
+    }
+
+    let options;
+    let max_steps;
+    let max_parallel;
+    let max_retries;
+    {
+        let mut config = state.config.lock().await;
+        if !run_enabled(&config) || !pipelines_enabled(&config) {
+            return StofResponse::error(StatusCode::NOT_IMPLEMENTED, "pipelines are not available");
+        }
+        options = RunOptions::from_config(&mut config);
+        max_steps = pipelines_max_steps(&config);
+        max_parallel = pipelines_max_parallel(&config);
+        max_retries = pipelines_max_retries(&config);
+    }
+
+    let mut content_type = String::from("stof");
+    if let Some(ctype) = headers.get(CONTENT_TYPE) {
+        match ctype.to_str() {
+            Ok(ctype) => content_type = ctype.to_owned(),
+            Err(_) => return StofResponse::error(StatusCode::BAD_REQUEST, "invalid content type"),
+        }
+    }
+    let mut export_format = String::from("json");
+    if let Some(format) = query.get("export") {
+        export_format = format.clone();
+    }
+
+    
+    let steps = match parse_pipeline(&content_type, body) {
+        Ok(value) => value,
+        Err(message) => {
+            if !options.opaque_errors {
+                return StofResponse::error(StatusCode::BAD_REQUEST, &message);
+            }
+            return StofResponse::error(StatusCode::BAD_REQUEST, "invalid pipeline");
+        }
+    };
+    if steps.len() > max_steps {
+        return StofResponse::error(StatusCode::PAYLOAD_TOO_LARGE, &format!("too many steps in pipeline (max is {})", max_steps));
+    }
+
+    let user = auth_user(&state, &headers).await;
+    let start = Instant::now();
+    let results = run_pipeline(&state, steps, options, user, max_parallel, max_retries).await;
+    let succeeded = results.values().all(|result| result.succeeded());
+    let doc = pipeline_document(results, start.elapsed());
+
+    let mut response = export_document(&doc, &export_format);
+    if response.status == StatusCode::OK && !succeeded {
+        response.status = StatusCode::MULTI_STATUS;
+    }
+    response
+}
@@ -0,0 +1,459 @@
+//
+// Copyright 2024 Formata, Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+use std::{collections::{BTreeMap, HashMap, HashSet}, sync::Arc, time::{Duration, Instant}};
+use axum::http::StatusCode;
+use bytes::Bytes;
+use stof::{SData, SDoc, SField, SType, SUnits, SVal};
+use tokio::{sync::Semaphore, task::JoinSet};
+use crate::{bus::run_bus, kv::run_kv, libraries::run_libraries, metrics::increment_server_run_count, response::StofResponse, run::{run_stof, RunOptions}, secrets::run_secrets, server::ServerState, sql::run_sql};
+pub(crate) mod api;
+
+
+const PIPELINES_INTERFACE: &str = r#"
+// pipeline steps, run in parallel when they don't depend on each other
+type Step {
+    // registry package to run (Ex. "@scope/name")
+    package: str;
+
+    // steps that must finish successfully before this one
+    // the output of each is given to this step as an input of the same name
+    after: vec = [];
+
+    // inputs given to this step
+    inputs: obj = new {};
+
+    // path to export as this step's output (Ex. "root.result"), default is the whole document
+    output: str = '';
+
+    // timeout for each attempt (0s is the server's run timeout)
+    timeout: s = 0s;
+
+    // number of times to retry a failed run
+    retries: int = 0;
+}
+"#;
+
+
+/// Pipeline step (from a "Step" in the pipeline definition).
+pub(crate) struct PipelineStep {
+    pub name: String,
+
+    /// Registry package to run.
+    pub package: String,
+
+    /// Steps that must finish successfully before this one.
+    pub after: Vec<String>,
+
+    /// JSON object of inputs given to this step.
+    pub inputs: Option<Bytes>,
+
+    /// Path to export as this step's output.
+    pub output: Option<String>,
+
+    /// Timeout for each attempt.
+    pub timeout: Option<Duration>,
+
+    /// Number of times to retry a failed run.
+    pub retries: usize,
+}
+
+
+/// Result of a pipeline step.
+pub(crate) struct StepResult {
+    /// Run status (none if the step was skipped).
+    pub status: Option<StatusCode>,
+
+    /// Output of the step (bstof).
+    pub output: Option<Bytes>,
+
+    pub error: Option<String>,
+
+    /// Number of runs (including retries).
+    pub attempts: usize,
+
+    /// Time from the start of the pipeline until this step started.
+    pub started: Duration,
+
+    pub duration: Duration,
+}
+impl StepResult {
+    /// Step was skipped because a step it runs after did not succeed.
+    fn skipped(dependency: &str) -> Self {
+        Self {
+            status: None,
+            output: None,
+            error: Some(format!("skipped because '{}' did not succeed", dependency)),
+            attempts: 0,
+            started: Duration::ZERO,
+            duration: Duration::ZERO,
+        }
+    }
+
+    /// Did this step succeed?
+    pub fn succeeded(&self) -> bool {
+        self.status == Some(StatusCode::OK)
+    }
+}
+
+
+/// Parse a pipeline definition document.
+/// The document has a "steps" object, where each field is a named "Step" (validated and given defaults by casting).
+pub(crate) fn parse_pipeline(content_type: &str, mut body: Bytes) -> Result<Vec<PipelineStep>, String> {
+    let mut doc = SDoc::default();
+    let _ = doc.string_import("main", "stof", PIPELINES_INTERFACE, "");
+    if let Err(error) = doc.header_import("main", content_type, content_type, &mut body, "") {
+        return Err(error.to_string(&doc.graph));
+    }
+
+    let steps_ref;
+    match doc.field("root.steps", None) {
+        Some(field) => {
+            match &field.value {
+                SVal::Object(nref) => steps_ref = nref.clone(),
+                _ => return Err("pipeline steps must be an object".into()),
+            }
+        },
+        None => return Err("pipeline requires steps".into()),
+    }
+
+    let mut step_refs = Vec::new();
+    for field in SField::fields(&doc.graph, &steps_ref) {
+        match &field.value {
+            SVal::Object(nref) => step_refs.push((field.name.clone(), nref.clone())),
+            _ => return Err(format!("step '{}' must be an object", field.name)),
+        }
+    }
+
+    let mut steps = Vec::new();
+    for (name, nref) in step_refs {
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return Err(format!("invalid step name '{}'", name));
+        }
+        if let Err(error) = SVal::Object(nref.clone()).cast(SType::Object("Step".into()), "main", &mut doc) {
+            return Err(format!("invalid step '{}': {}", name, error.to_string(&doc.graph)));
+        }
+
+        let mut step = PipelineStep {
+            name: name.clone(),
+            package: String::default(),
+            after: Vec::new(),
+            inputs: None,
+            output: None,
+            timeout: None,
+            retries: 0,
+        };
+        for field in SField::fields(&doc.graph, &nref) {
+            match field.name.as_str() {
+                "package" => {
+                    step.package = field.to_string();
+                },
+                "after" => {
+                    if let SVal::Array(values) = &field.value {
+                        for value in values {
+                            step.after.push(value.to_string());
+                        }
+                    }
+                },
+                "inputs" => {
+                    if let SVal::Object(inputs_ref) = &field.value {
+                        match doc.export_string("main", "json", Some(inputs_ref)) {
+                            Ok(json) => step.inputs = Some(Bytes::from(json)),
+                            Err(_) => return Err(format!("error reading inputs for step '{}'", name)),
+                        }
+                    }
+                },
+                "output" => {
+                    let output = field.to_string();
+                    if !output.is_empty() {
+                        step.output = Some(output);
+                    }
+                },
+                "timeout" => {
+                    if let SVal::Number(num) = &field.value {
+                        let seconds = num.float_with_units(SUnits::Seconds);
+                        if seconds > 0. {
+                            step.timeout = Some(Duration::from_secs_f64(seconds));
+                        }
+                    }
+                },
+                "retries" => {
+                    if let SVal::Number(num) = &field.value {
+                        step.retries = num.int().max(0) as usize;
+                    }
+                },
+                _ => {}
+            }
+        }
+        if step.package.is_empty() {
+            return Err(format!("step '{}' requires a package", name));
+        }
+        steps.push(step);
+    }
+    if steps.is_empty() {
+        return Err("pipeline requires steps".into());
+    }
+    validate_pipeline(&steps)?;
+    Ok(steps)
+}
+
+
+/// Make sure every dependency is a step and that the steps do not form a cycle.
+fn validate_pipeline(steps: &[PipelineStep]) -> Result<(), String> {
+    let names: HashSet<&str> = steps.iter().map(|step| step.name.as_str()).collect();
+    for step in steps {
+        for dependency in &step.after {
+            if !names.contains(dependency.as_str()) {
+                return Err(format!("step '{}' runs after an unknown step '{}'", step.name, dependency));
+            }
+        }
+    }
+
+    // Resolve steps in dependency order, anything left over is part of a cycle
+    let mut resolved: HashSet<&str> = HashSet::new();
+    loop {
+        let mut changed = false;
+        for step in steps {
+            if !resolved.contains(step.name.as_str()) && step.after.iter().all(|dependency| resolved.contains(dependency.as_str())) {
+                resolved.insert(&step.name);
+                changed = true;
+            }
+        }
+        if !changed {
+            break;
+        }
+    }
+    if let Some(step) = steps.iter().find(|step| !resolved.contains(step.name.as_str())) {
+        return Err(format!("step '{}' is part of a dependency cycle", step.name));
+    }
+    Ok(())
+}
+
+
+/// Run a pipeline.
+///
+/// Steps run as soon as every step they run after has succeeded (up to max_parallel at a time).
+/// Steps that run after a failed (or skipped) step are skipped.
+///
+/// options: base run options for every step (timeout is the max for each attempt).
+/// user: user running the pipeline (for secrets).
+pub(crate) async fn run_pipeline(state: &ServerState, steps: Vec<PipelineStep>, options: RunOptions, user: Option<(String, bool)>, max_parallel: usize, max_retries: usize) -> BTreeMap<String, StepResult> {
+    let start = Instant::now();
+    let permits = Arc::new(Semaphore::new(max_parallel));
+    let mut results: BTreeMap<String, StepResult> = BTreeMap::new();
+    let mut pending = steps;
+    let mut running = JoinSet::new();
+    let mut tasks = HashMap::new();
+    loop {
+        // Skip or start every step whose dependencies are finished (skips can cascade, so repeat until nothing changes)
+        loop {
+            let mut changed = false;
+            let mut index = 0;
+            while index < pending.len() {
+                let step = &pending[index];
+                if let Some(failed) = step.after.iter().find(|dependency| results.get(*dependency).is_some_and(|result| !result.succeeded())) {
+                    results.insert(step.name.clone(), StepResult::skipped(failed));
+                    pending.remove(index);
+                    changed = true;
+                    continue;
+                }
+                if step.after.iter().all(|dependency| results.contains_key(dependency)) {
+                    let step = pending.remove(index);
+                    let mut run_options = options.clone();
+                    run_options.packages = Default::default();
+                    run_options.export_format = String::from("bstof");
+                    run_options.export_path = step.output.clone();
+                    run_options.package = Some(step.package.clone());
+                    run_options.inputs.json = step_inputs(&step, &results);
+                    if let Some(timeout) = step.timeout {
+                        run_options.timeout = timeout.min(options.timeout);
+                    }
+                    run_options.kv = run_kv(state, &user, Some(&step.package)).await;
+                    run_options.sql = run_sql(state, &user, Some(&step.package)).await;
+                    run_options.bus = run_bus(state, &user, Some(&step.package)).await;
+                    run_options.secrets = run_secrets(state, user.clone(), Some(&step.package)).await;
+                    run_options.libraries = run_libraries(state, &user, Some(&step.package)).await;
+
+                    let state = state.clone();
+                    let permits = permits.clone();
+                    let retries = step.retries.min(max_retries);
+                    let name = step.name.clone();
+                    let task = running.spawn(async move {
+                        let _permit = permits.acquire_owned().await;
+                        let started = start.elapsed();
+                        let mut attempts = 0;
+                        let mut response;
+                        loop {
+                            attempts += 1;
+                            {
+                                let mut metrics = state.metrics.lock().await;
+                                increment_server_run_count(&mut metrics);
+                            }
+                            response = run_stof(run_options.clone(), Bytes::default(), None).await;
+                            if response.status == StatusCode::OK || attempts > retries {
+                                break;
+                            }
+                        }
+                        step_result(response, attempts, started, start.elapsed() - started)
+                    });
+                    tasks.insert(task.id(), name);
+                    changed = true;
+                    continue;
+                }
+                index += 1;
+            }
+            if !changed {
+                break;
+            }
+        }
+
+        match running.join_next_with_id().await {
+            Some(Ok((id, result))) => {
+                if let Some(name) = tasks.remove(&id) {
+                    results.insert(name, result);
+                }
+            },
+            Some(Err(error)) => {
+                // The step task panicked, so it fails (and its dependents are skipped) while other steps keep running
+                if let Some(name) = tasks.remove(&error.id()) {
+                    results.insert(name, StepResult {
+                        status: Some(StatusCode::INTERNAL_SERVER_ERROR),
+                        output: None,
+                        error: Some(String::from("step panicked")),
+                        attempts: 0,
+                        started: Duration::ZERO,
+                        duration: Duration::ZERO,
+                    });
+                }
+            },
+            None => {
+                break;
+            }
+        }
+    }
+
+    // Anything still pending never got to run
+    for step in pending {
+        results.insert(step.name, StepResult {
+            status: None,
+            output: None,
+            error: Some(String::from("step did not run")),
+            attempts: 0,
+            started: Duration::ZERO,
+            duration: Duration::ZERO,
+        });
+    }
+    results
+}
+
+
+/// Result of a step from its last run.
+fn step_result(response: StofResponse, attempts: usize, started: Duration, duration: Duration) -> StepResult {
+    let mut result = StepResult {
+        status: Some(response.status),
+        output: None,
+        error: None,
+        attempts,
+        started,
+        duration,
+    };
+    if response.status == StatusCode::OK {
+        result.output = Some(response.bytes_body.unwrap_or_default());
+    } else {
+        result.error = Some(response.str_body);
+    }
+    result
+}
+
+
+/// JSON inputs for a step: its own inputs, along with the output of each step it runs after (by step name).
+fn step_inputs(step: &PipelineStep, results: &BTreeMap<String, StepResult>) -> Option<Bytes> {
+    if step.after.is_empty() {
+        return step.inputs.clone();
+    }
+
+    let mut doc = SDoc::default();
+    if let Some(inputs) = &step.inputs {
+        let mut bytes = inputs.clone();
+        let _ = doc.header_import("main", "json", "json", &mut bytes, "");
+    }
+    for dependency in &step.after {
+        if let Some(output) = results.get(dependency).and_then(|result| result.output.clone()) {
+            let mut bytes = output;
+            let _ = doc.header_import("main", "bstof", "bstof", &mut bytes, &format!("root.{}", dependency));
+        }
+    }
+    match doc.export_string("main", "json", None) {
+        Ok(json) => Some(Bytes::from(json)),
+        Err(_) => step.inputs.clone(),
+    }
+}
+
+
+/// Pipeline result document.
+/// Has a "steps" object with the "status", "success", "attempts", "started", "duration", and "error" or "output" of each step,
+/// along with the total "duration" and "succeeded", "failed", and "skipped" counts.
+pub(crate) fn pipeline_document(results: BTreeMap<String, StepResult>, duration: Duration) -> SDoc {
+    let mut doc = SDoc::default();
+    let mut succeeded = 0;
+    let mut failed = 0;
+    let mut skipped = 0;
+    if let Some(main) = doc.graph.main_root() {
+        let steps_ref = SField::new_object(&mut doc.graph, "steps", &main);
+        for (name, result) in results {
+            let nref = SField::new_object(&mut doc.graph, &name, &steps_ref);
+            SField::new_bool(&mut doc.graph, "success", result.succeeded(), &nref);
+            match result.status {
+                Some(status) => {
+                    SField::new_int(&mut doc.graph, "status", status.as_u16() as i64, &nref);
+                    if result.succeeded() {
+                        succeeded += 1;
+                    } else {
+                        failed += 1;
+                    }
+                },
+                None => {
+                    SField::new_bool(&mut doc.graph, "skipped", true, &nref);
+                    skipped += 1;
+                }
+            }
+            SField::new_int(&mut doc.graph, "attempts", result.attempts as i64, &nref);
+            SField::new_units(&mut doc.graph, "started", result.started.as_secs_f64() * 1000., SUnits::Milliseconds, &nref);
+            SField::new_units(&mut doc.graph, "duration", result.duration.as_secs_f64() * 1000., SUnits::Milliseconds, &nref);
+            if let Some(error) = &result.error {
+                SField::new_string(&mut doc.graph, "error", error, &nref);
+            }
+            if let Some(mut output) = result.output {
+                // Imported onto the root, then moved to the step (importing into the nested path would duplicate the fields along it)
+                let path = format!("root.output_{}", name);
+                if doc.header_import("main", "bstof", "bstof", &mut output, &path).is_err() {
+                    SField::new_string(&mut doc.graph, "error", "error exporting step output", &nref);
+                } else if let Some(dref) = SField::field_ref(&doc.graph, &path, '.', None) {
+                    if let Some(field) = SData::get_mut::<SField>(&mut doc.graph, &dref) {
+                        field.name = String::from("output");
+                    }
+                    doc.graph.put_data_ref(&nref, dref.clone());
+                    doc.graph.remove_data(dref, Some(&main));
+                }
+            }
+        }
+        SField::new_units(&mut doc.graph, "duration", duration.as_secs_f64() * 1000., SUnits::Milliseconds, &main);
+        SField::new_int(&mut doc.graph, "succeeded", succeeded, &main);
+        SField::new_int(&mut doc.graph, "failed", failed, &main);
+        SField::new_int(&mut doc.graph, "skipped", skipped, &main);
+    }
+    doc
+}
@@ -0,0 +1,115 @@
+//
+// Copyright 2024 Formata, Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+
+use std::{ffi::{c_char, c_void, CStr, CString}, sync::Arc};
+use stof::{Format, Library};
+use super::{Plugin, PLUGIN_ABI_VERSION, PLUGIN_RUSTC_VERSION, PLUGIN_STOF_VERSION};
+
+
+/// "stof_plugin_abi_version" export.
+type AbiVersionFn = extern "C" fn() -> u32;
+
+/// "stof_plugin_stof_version" and "stof_plugin_rustc_version" exports.
+type VersionFn = extern "C" fn() -> *const c_char;
+
+/// "stof_plugin_register" export.
+type RegisterFn = fn(&mut Vec<Arc<dyn Library>>, &mut Vec<Arc<dyn Format>>);
+
+
+/// Open a plugin (shared library), checking its versions and registering its libraries and formats.
+/// The library stays open for the life of the process once registered.
+pub(crate) fn open_plugin(path: &str) -> Result<Plugin, String> {
+    
+    let c_path = match CString::new(path) {
+        Ok(value) => value,
+        Err(_) => return Err(String::from("invalid plugin path")),
+    };
+    // Plugins are trusted code, installed by whoever runs the runner (their initializers run here)
+    let handle = unsafe { libc::dlopen(c_path.as_ptr(), libc::RTLD_NOW | libc::RTLD_LOCAL) };
+    if handle.is_null() {
+        return Err(dl_error());
+    }
+    let res = register_plugin(handle, path);
+    if res.is_err() {
+        unsafe { libc::dlclose(handle) };
+    }
+    res
+}
+
+
+/// Check the versions of an opened plugin and register its libraries and formats.
+fn register_plugin(handle: *mut c_void, path: &str) -> Result<Plugin, String> {
+    let abi_version = unsafe { std::mem::transmute::<*mut c_void, AbiVersionFn>(symbol(handle, "stof_plugin_abi_version")?) };
+    let version = abi_version();
+    if version != PLUGIN_ABI_VERSION {
+        return Err(format!("plugin ABI version {} is not supported (expected {})", version, PLUGIN_ABI_VERSION));
+    }
+
+    let version = version_export(handle, "stof_plugin_stof_version")?;
+    if version != PLUGIN_STOF_VERSION {
+        return Err(format!("plugin was built with stof {} (expected {})", version, PLUGIN_STOF_VERSION));
+    }
+
+    // Checked before calling anything that passes Rust types across
+    let version = version_export(handle, "stof_plugin_rustc_version")?;
+    if version != PLUGIN_RUSTC_VERSION {
+        return Err(format!("plugin was built with {} (expected {})", version, PLUGIN_RUSTC_VERSION));
+    }
+
+    // A panic here can't be caught (the plugin has its own copy of std), so it takes down the process
+    let register = unsafe { std::mem::transmute::<*mut c_void, RegisterFn>(symbol(handle, "stof_plugin_register")?) };
+    let mut libraries = Vec::new();
+    let mut formats = Vec::new();
+    register(&mut libraries, &mut formats);
+    Ok(Plugin {
+        path: path.to_owned(),
+        libraries,
+        formats,
+    })
+}
+
+
+/// Version string returned by a version export of an opened plugin.
+fn version_export(handle: *mut c_void, name: &str) -> Result<String, String> {
+    let version_fn = unsafe { std::mem::transmute::<*mut c_void, VersionFn>(symbol(handle, name)?) };
+    let version_ptr = version_fn();
+    if version_ptr.is_null() {
+        return Err(format!("plugin did not give a version from '{}'", name));
+    }
+    Ok(unsafe { CStr::from_ptr(version_ptr) }.to_string_lossy().to_string())
+}
+
+
+/// Exported symbol of an opened plugin.
+fn symbol(handle: *mut c_void, name: &str) -> Result<*mut c_void, String> {
+    let c_name = CString::new(name).unwrap();
+    let ptr = unsafe { libc::dlsym(handle, c_name.as_ptr()) };
+    if ptr.is_null() {
+        return Err(format!("plugin does not export '{}'", name));
+    }
+    Ok(ptr)
+}
+
+
+/// Last dynamic loader error.
+fn dl_error() -> String {
+    let error = unsafe { libc::dlerror() };
+    if error.is_null() {
+        return String::from("could not open plugin");
+    }
+    unsafe { CStr::from_ptr(error) }.to_string_lossy().to_string()
+}
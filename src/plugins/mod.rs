@@ -0,0 +1,150 @@
+//
+// Copyright 2024 Formata, Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+
+use std::{fs, sync::{Arc, OnceLock}};
+use colored::Colorize;
+use stof::{Format, Library, SDoc};
+use crate::{config::{plugins_enabled, plugins_path}, libraries::RUNNER_LIBRARIES};
+
+#[cfg(unix)]
+mod dynamic;
+#[cfg(unix)]
+use dynamic::open_plugin;
+
+#[cfg(not(unix))]
+mod unsupported;
+#[cfg(not(unix))]
+use unsupported::open_plugin;
+
+
+/// Plugin ABI version (returned by a plugin's "stof_plugin_abi_version").
+pub(crate) const PLUGIN_ABI_VERSION: u32 = 2;
+
+/// Compiler that plugins must be built with ("rustc --version" output, from the build script).
+/// The Rust ABI (and the layout of the trait objects passed to the runner) is only stable for the same compiler.
+pub(crate) const PLUGIN_RUSTC_VERSION: &str = env!("STOF_RUNNER_RUSTC_VERSION");
+
+/// Stof version that plugins must be built with (same as the stof dependency).
+/// Libraries and formats are passed to the runner as stof trait objects, so the versions have to match exactly.
+pub(crate) const PLUGIN_STOF_VERSION: &str = "0.3.21";
+
+
+/// Plugins loaded at startup.
+static PLUGINS: OnceLock<Vec<Plugin>> = OnceLock::new();
+
+
+/// Loaded plugin.
+pub(crate) struct Plugin {
+    /// Plugin file path.
+    pub path: String,
+
+    /// Libraries registered by the plugin.
+    pub libraries: Vec<Arc<dyn Library>>,
+
+    /// Formats registered by the plugin.
+    pub formats: Vec<Arc<dyn Format>>,
+}
+
+
+/// Load the plugins in the configured directory (if enabled).
+/// Called once at startup (by the server and by sandbox processes), before any runs.
+///
+/// A plugin is a cdylib that exports:
+/// - `extern "C" fn stof_plugin_abi_version() -> u32`: the plugin ABI version (PLUGIN_ABI_VERSION).
+/// - `extern "C" fn stof_plugin_stof_version() -> *const c_char`: the stof version it was built with (PLUGIN_STOF_VERSION).
+/// - `extern "C" fn stof_plugin_rustc_version() -> *const c_char`: the "rustc --version" of the compiler it was built with (PLUGIN_RUSTC_VERSION).
+/// - `fn stof_plugin_register(libraries: &mut Vec<Arc<dyn Library>>, formats: &mut Vec<Arc<dyn Format>>)`: registers its libraries and formats.
+///
+/// Plugins that can't be loaded are skipped (with an error if reporting). Plugins are never unloaded.
+/// Plugins are trusted code: a plugin that panics (while registering or in a library call) can't be caught by the runner,
+/// since it has its own copy of std, so it takes down the process.
+pub(crate) fn load_plugins(config: &SDoc, report: bool) {
+    let mut plugins = Vec::new();
+    if plugins_enabled(config) {
+        let dir = plugins_path(config);
+        let mut files = Vec::new();
+        if let Ok(entries) = fs::read_dir(&dir) {
+            for entry in entries.flatten() {
+                let path = entry.path();
+                if path.extension().is_some_and(|ext| ext == std::env::consts::DLL_EXTENSION) {
+                    files.push(path.to_string_lossy().to_string());
+                }
+            }
+        }
+        files.sort();
+
+        let defaults = SDoc::default();
+        for path in files {
+            match open_plugin(&path).and_then(|plugin| check_plugin(&defaults, &plugins, plugin)) {
+                Ok(plugin) => {
+                    if report {
+                        let libraries = plugin.libraries.iter().map(|library| library.scope()).collect::<Vec<_>>();
+                        let formats = plugin.formats.iter().map(|format| format.format()).collect::<Vec<_>>();
+                        eprintln!("loaded plugin {} (libraries: [{}], formats: [{}])", path, libraries.join(", "), formats.join(", "));
+                    }
+                    plugins.push(plugin);
+                },
+                Err(message) => {
+                    if report {
+                        eprintln!("{}: {}: {}", "PluginError".red(), path, message.dimmed());
+                    }
+                }
+            }
+        }
+    }
+    let _ = PLUGINS.set(plugins);
+}
+
+
+/// Make sure a plugin doesn't replace the libraries and formats of the runner (or of a plugin loaded before it).
+fn check_plugin(defaults: &SDoc, loaded: &[Plugin], plugin: Plugin) -> Result<Plugin, String> {
+    for library in &plugin.libraries {
+        let scope = library.scope();
+        if RUNNER_LIBRARIES.contains(&scope.as_str()) || defaults.libraries.libraries.contains_key(&scope) {
+            return Err(format!("library '{}' is provided by the runner", scope));
+        }
+        if let Some(other) = loaded.iter().find(|other| other.libraries.iter().any(|library| library.scope() == scope)) {
+            return Err(format!("library '{}' is provided by {}", scope, other.path));
+        }
+    }
+    for format in &plugin.formats {
+        let id = format.format();
+        if defaults.formats.formats.contains_key(&id) {
+            return Err(format!("format '{}' is provided by the runner", id));
+        }
+        if let Some(other) = loaded.iter().find(|other| other.formats.iter().any(|format| format.format() == id)) {
+            return Err(format!("format '{}' is provided by {}", id, other.path));
+        }
+    }
+    Ok(plugin)
+}
+
+
+/// Load the plugin libraries and formats into a document.
+/// Called before the runner loads its own, so plugins can never shadow them.
+pub(crate) fn load_plugin_libraries(doc: &mut SDoc) {
+    if let Some(plugins) = PLUGINS.get() {
+        for plugin in plugins {
+            for library in &plugin.libraries {
+                doc.load_lib(library.clone());
+            }
+            for format in &plugin.formats {
+                doc.load_format(format.clone());
+            }
+        }
+    }
+}
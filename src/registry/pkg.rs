@@ -14,6 +14,7 @@
 // limitations under the License.
 //
 
+use std::{collections::BTreeSet, sync::{Arc, Mutex}};
 use stof::{pkg::PKG, Format};
 
 
@@ -21,14 +22,25 @@ use stof::{pkg::PKG, Format};
 pub struct RPKG {
     pub pkg: PKG,
     pub base_path: String,
+
+    /// Packages imported with this format ("scope/name"), if tracked.
+    pub resolved: Option<Arc<Mutex<BTreeSet<String>>>>,
 }
 impl RPKG {
     pub fn new(registry_path: &str) -> Self {
         Self {
             pkg: Default::default(),
             base_path: registry_path.to_owned(),
+            resolved: None,
         }
     }
+
+    /// Registry PKG format that records the packages it imports.
+    pub fn tracked(registry_path: &str, resolved: Arc<Mutex<BTreeSet<String>>>) -> Self {
+        let mut format = Self::new(registry_path);
+        format.resolved = Some(resolved);
+        format
+    }
 }
 impl Format for RPKG {
     fn format(&self) -> String {
@@ -44,7 +56,11 @@ impl Format for RPKG {
     }
 
     fn file_import(&self, pid: &str, doc: &mut stof::SDoc, format: &str, full_path: &str, extension: &str, as_name: &str) -> Result<(), stof::lang::SError> {
-        let full_path = format!("{}/{}", self.base_path, full_path.trim_start_matches("__stof__/"));
+        let package = full_path.trim_start_matches("__stof__/");
+        if let Some(resolved) = &self.resolved {
+            resolved.lock().unwrap().insert(package.trim_end_matches(".stof").trim_end_matches(".pkg").to_owned());
+        }
+        let full_path = format!("{}/{}", self.base_path, package);
         self.pkg.file_import(pid, doc, format, &full_path, extension, as_name)
     }
 }
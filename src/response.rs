@@ -49,7 +49,6 @@ impl IntoResponse for StofResponse {
 }
 impl StofResponse {
     /// Creates a response from this value with a success status code.
-    #[allow(unused)]
     pub fn val_response(doc: &SDoc, value: SVal) -> Self {
         let mut status = StatusCode::OK;
         let mut headers = HeaderMap::new();
@@ -150,7 +149,18 @@ impl StofResponse {
                     }
                 }
             },
-            _ => {}
+            SVal::Object(nref) => {
+                if let Ok(result) = doc.export_string("main", "json", Some(&nref)) {
+                    headers.insert(CONTENT_TYPE, "application/json".parse().unwrap());
+                    str_body = result;
+                }
+            },
+            SVal::Void |
+            SVal::Null => {},
+            value => {
+                headers.insert(CONTENT_TYPE, "text/plain".parse().unwrap());
+                str_body = value.to_string();
+            }
         }
         Self {
             status,
@@ -0,0 +1,241 @@
+//
+// Copyright 2024 Formata, Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+use std::{collections::BTreeMap, io::{Cursor, Read}, sync::Arc};
+use axum::{extract::{Query, State}, http::{header::{ACCEPT, CONTENT_TYPE}, HeaderMap, StatusCode}, response::{IntoResponse, Response}};
+use bytes::Bytes;
+use stof::{SDoc, SField, SVal};
+use tokio::{sync::Semaphore, task::JoinSet};
+use zip::ZipArchive;
+use crate::{bus::run_bus, config::{run_enabled, run_max_batch_parallel, run_max_batch_size}, kv::run_kv, libraries::run_libraries, sql::run_sql, metrics::increment_server_run_count, response::StofResponse, secrets::run_secrets, server::ServerState, users::auth::{auth_exec, auth_user}};
+use super::{export_document, multipart::parse_multipart, negotiate::RunFormats, run_stof, scratch::{file_name, RunFile}, RunInputs, RunOptions};
+
+
+/// Batch run API endpoint handler.
+/// Runs many documents at once, given as a zip archive ("application/zip") or a "multipart/form-data" body.
+/// Each file is its own run, imported with the format of its file extension (default is "stof").
+/// A multipart part named "inputs" is a JSON object of inputs given to every document.
+///
+/// Documents execute in parallel on worker threads (up to the configured max at a time), each with its own timeout.
+/// A document that times out is cancelled and frees its slot, even though its thread may take until its next library call to end.
+/// The response contains a "results" array with the "name", "status", and "error" or exported "result" of each document,
+/// along with "total", "succeeded", and "failed" counts. The status is 207 (Multi-Status) if any document failed.
+///
+/// The export format is given with "?export=", otherwise it is negotiated with the Accept header (default is "json").
+/// Use "?path=root.result" to export only part of each resulting document.
+pub(crate) async fn run_batch_handler(State(state): State<ServerState>, Query(query): Query<BTreeMap<String, String>>, headers: HeaderMap, body: Bytes) -> Response {
+    if !auth_exec(&state, &headers).await {
+        return StofResponse::error(StatusCode::FORBIDDEN, "access denied").into_response();
+    }
+
+    let mut options;
+    let max_batch_size;
+    let max_parallel;
+    {
+        let mut config = state.config.lock().await;
+        if !run_enabled(&config) {
+            return StofResponse::error(StatusCode::NOT_IMPLEMENTED, "runner is not available").into_response();
+        }
+        options = RunOptions::from_config(&mut config);
+        max_batch_size = run_max_batch_size(&config);
+        max_parallel = run_max_batch_parallel(&config);
+    }
+
+    let export_format = match state.formats.export_format(query.get("export"), headers.get(ACCEPT), "json") {
+        Ok(format) => format,
+        Err(error) => return StofResponse::error(StatusCode::NOT_ACCEPTABLE, &error).into_response(),
+    };
+    if let Some(path) = query.get("path") {
+        options.export_path = Some(path.clone());
+    }
+    options.inputs = RunInputs::from_query(&query);
+
+    let mut content_type = String::default();
+    if let Some(ctype) = headers.get(CONTENT_TYPE) {
+        content_type = ctype.to_str().unwrap_or_default().to_owned();
+    }
+    let documents;
+    if content_type.starts_with("multipart/form-data") {
+        match parse_multipart(&content_type, body).await {
+            Ok(run) => {
+                documents = run.files;
+                options.inputs.json = run.inputs;
+            },
+            Err(message) => {
+                return StofResponse::error(StatusCode::BAD_REQUEST, &format!("error parsing multipart body: {}", message)).into_response();
+            }
+        }
+    } else if content_type.starts_with("application/zip") {
+        match unzip_documents(body, max_batch_size, options.max_scratch_size) {
+            Ok(files) => {
+                documents = files;
+            },
+            Err(message) => {
+                return StofResponse::error(StatusCode::BAD_REQUEST, &format!("error reading zip archive: {}", message)).into_response();
+            }
+        }
+    } else {
+        return StofResponse::error(StatusCode::UNSUPPORTED_MEDIA_TYPE, "batch runs require a zip archive or a multipart body").into_response();
+    }
+    if documents.is_empty() {
+        return StofResponse::error(StatusCode::BAD_REQUEST, "batch runs require at least one document").into_response();
+    }
+    if documents.len() > max_batch_size {
+        return StofResponse::error(StatusCode::PAYLOAD_TOO_LARGE, &format!("too many documents in batch (max is {})", max_batch_size)).into_response();
+    }
+
+    let user = auth_user(&state, &headers).await;
+    options.kv = run_kv(&state, &user, options.package.as_deref()).await;
+    options.sql = run_sql(&state, &user, options.package.as_deref()).await;
+    options.bus = run_bus(&state, &user, options.package.as_deref()).await;
+    options.libraries = run_libraries(&state, &user, options.package.as_deref()).await;
+    options.secrets = run_secrets(&state, user, options.package.as_deref()).await;
+
+    // metrics
+    {
+        let mut metrics = state.metrics.lock().await;
+        for _ in 0..documents.len() {
+            increment_server_run_count(&mut metrics);
+        }
+    }
+
+    // Each document is exported as bstof, so that it can be imported into the batch result
+    options.export_format = String::from("bstof");
+    let permits = Arc::new(Semaphore::new(max_parallel));
+    let mut runs = JoinSet::new();
+    let mut names = Vec::new();
+    for (index, document) in documents.into_iter().enumerate() {
+        let mut run_options = options.clone();
+        run_options.packages = Default::default();
+        run_options.content_type = document_format(&state.formats, &document.name);
+        names.push(document.name);
+
+        let permits = permits.clone();
+        runs.spawn(async move {
+            let _permit = permits.acquire_owned().await;
+            (index, run_stof(run_options, document.bytes, None).await)
+        });
+    }
+    let mut responses: Vec<Option<StofResponse>> = names.iter().map(|_| None).collect();
+    while let Some(res) = runs.join_next().await {
+        if let Ok((index, response)) = res {
+            responses[index] = Some(response);
+        }
+    }
+
+    let mut batch = SDoc::default();
+    let mut succeeded = 0;
+    let mut failed = 0;
+    if let Some(main) = batch.graph.main_root() {
+        let mut results = Vec::new();
+        for (index, (name, response)) in names.iter().zip(responses).enumerate() {
+            let response = response.unwrap_or(StofResponse::error(StatusCode::INTERNAL_SERVER_ERROR, "error running document"));
+            let nref = batch.graph.insert_node(&format!("result{}", index), Some(&main));
+            SField::new_string(&mut batch.graph, "name", name, &nref);
+            SField::new_int(&mut batch.graph, "status", response.status.as_u16() as i64, &nref);
+
+            let mut error = None;
+            if response.status == StatusCode::OK {
+                let mut bytes = response.bytes_body.unwrap_or_default();
+                if batch.header_import("main", "bstof", "bstof", &mut bytes, &format!("root.result{}.result", index)).is_err() {
+                    error = Some(String::from("error exporting document"));
+                }
+
+                // Importing also adds a field for the result node to the root, but results are only in the array
+                if let Some(dref) = SField::field_ref(&batch.graph, &format!("root.result{}", index), '.', None) {
+                    batch.graph.remove_data(dref, Some(&main));
+                }
+            } else {
+                error = Some(response.str_body);
+            }
+            if let Some(error) = error {
+                SField::new_bool(&mut batch.graph, "success", false, &nref);
+                SField::new_string(&mut batch.graph, "error", &error, &nref);
+                failed += 1;
+            } else {
+                SField::new_bool(&mut batch.graph, "success", true, &nref);
+                succeeded += 1;
+            }
+            results.push(SVal::Object(nref));
+        }
+        SField::new_array(&mut batch.graph, "results", results, &main);
+        SField::new_int(&mut batch.graph, "total", (succeeded + failed) as i64, &main);
+        SField::new_int(&mut batch.graph, "succeeded", succeeded as i64, &main);
+        SField::new_int(&mut batch.graph, "failed", failed as i64, &main);
+    }
+
+    let mut response = export_document(&batch, &export_format);
+    if response.status == StatusCode::OK && failed > 0 {
+        response.status = StatusCode::MULTI_STATUS;
+    }
+    response.into_response()
+}
+
+
+/// Read the documents out of a zip archive (directories are skipped).
+/// Each document is named by its file name, and all of the documents together can be at most max_size bytes.
+/// Stops after max_documents + 1 documents, so that an oversized batch is rejected without decompressing the rest.
+fn unzip_documents(body: Bytes, max_documents: usize, max_size: u64) -> Result<Vec<RunFile>, String> {
+    let mut archive;
+    match ZipArchive::new(Cursor::new(body)) {
+        Ok(value) => archive = value,
+        Err(error) => return Err(error.to_string()),
+    }
+
+    let mut documents = Vec::new();
+    let mut total_size = 0;
+    for index in 0..archive.len() {
+        if documents.len() > max_documents {
+            break;
+        }
+        
+        let file = match archive.by_index(index) {
+            Ok(value) => value,
+            Err(error) => return Err(error.to_string()),
+        };
+        if file.is_dir() {
+            continue;
+        }
+        let name = file_name(file.name());
+        if name.is_empty() {
+            continue;
+        }
+
+        // Don't trust the size in the archive, only read up to what's left of the max
+        let remaining = max_size - total_size;
+        let mut bytes = Vec::new();
+        if let Err(error) = file.take(remaining + 1).read_to_end(&mut bytes) {
+            return Err(error.to_string());
+        }
+        if bytes.len() as u64 > remaining {
+            return Err(format!("documents are too large at {} (max is {} bytes in total)", name, max_size));
+        }
+        total_size += bytes.len() as u64;
+        documents.push(RunFile { name, bytes: Bytes::from(bytes) });
+    }
+    Ok(documents)
+}
+
+
+/// Import format of a batch document.
+/// The file extension if it's a loaded format, otherwise stof.
+fn document_format(formats: &RunFormats, name: &str) -> String {
+    let format = name.split('.').next_back().unwrap_or_default();
+    if name.contains('.') && formats.imports.contains(format) {
+        return format.to_owned();
+    }
+    String::from("stof")
+}
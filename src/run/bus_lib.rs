@@ -0,0 +1,66 @@
+//
+// Copyright 2024 Formata, Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+use stof::{lang::SError, Library, SDoc, SVal};
+use crate::bus::BusAccess;
+use super::kv_lib::encode_value;
+
+
+/// Bus library.
+/// Lets documents publish messages to topics, delivered to the registry packages subscribed to them.
+///
+/// - `bus.publish(topic, value)`: publish a message, returning its ID (delivery happens after the call returns).
+///
+/// Values are objects, or primitives and collections of primitives (same as the kv library).
+pub struct BusLibrary {
+    pub bus: BusAccess,
+}
+impl BusLibrary {
+    pub fn new(bus: BusAccess) -> Self {
+        Self {
+            bus,
+        }
+    }
+}
+impl Library for BusLibrary {
+    fn scope(&self) -> String {
+        "bus".to_string()
+    }
+
+    fn call(&self, pid: &str, doc: &mut SDoc, name: &str, parameters: &mut Vec<SVal>) -> Result<SVal, SError> {
+        match name {
+            "publish" => {
+                if parameters.len() != 2 {
+                    return Err(SError::custom(pid, doc, "BusPublish", "publish requires a topic and a value"));
+                }
+                let value = parameters.pop().unwrap().unbox();
+                let topic = parameters.pop().unwrap().unbox().to_string();
+                
+                let bytes = match encode_value(doc, value) {
+                    Ok(value) => value,
+                    Err(message) => return Err(SError::custom(pid, doc, "BusPublish", &message)),
+                };
+                match self.bus.publish(&topic, bytes) {
+                    Ok(id) => Ok(SVal::String(id)),
+                    Err(message) => Err(SError::custom(pid, doc, "BusPublish", &message)),
+                }
+            },
+            _ => {
+                Err(SError::custom(pid, doc, "BusNotFound", &format!("{} is not a function in the bus library", name)))
+            }
+        }
+    }
+}
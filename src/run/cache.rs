@@ -0,0 +1,239 @@
+//
+// Copyright 2024 Formata, Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+use std::{collections::HashMap, fs, time::{Duration, Instant}};
+use axum::http::{HeaderMap, StatusCode};
+use bytes::Bytes;
+use ring::digest::{digest, Context, SHA256};
+use stof::SDoc;
+use crate::{config::{cache_max_size, cache_ttl}, response::StofResponse};
+use super::RunOptions;
+
+
+/// Cached run result.
+struct CacheEntry {
+    headers: HeaderMap,
+    status: StatusCode,
+    str_body: String,
+    bytes_body: Option<Bytes>,
+
+    /// Registry packages the run imported, with their hashes at the time it ran.
+    packages: Vec<(String, String)>,
+
+    /// When this entry was cached.
+    created: Instant,
+
+    /// Size of the cached body (bytes).
+    size: usize,
+}
+
+
+/// Run result cache.
+/// Results are kept until their TTL expires, until a registry package they imported changes, or until evicted
+/// (oldest first) to stay within the max size.
+pub(crate) struct RunCache {
+    entries: HashMap<String, CacheEntry>,
+
+    /// Total size of the cached bodies (bytes).
+    size: usize,
+
+    /// How long a result is kept.
+    pub ttl: Duration,
+
+    /// Max total size of the cached bodies (bytes).
+    pub max_size: usize,
+}
+impl RunCache {
+    /// Create a run cache from the server config.
+    pub fn new(config: &SDoc) -> Self {
+        Self {
+            entries: HashMap::new(),
+            size: 0,
+            ttl: cache_ttl(config),
+            max_size: cache_max_size(config),
+        }
+    }
+
+    /// Get a cached result.
+    /// Expired results, and results whose packages have changed in the registry, are removed instead.
+    pub fn get(&mut self, key: &str, registry_path: &str) -> Option<StofResponse> {
+        if let Some(entry) = self.entries.get(key) {
+            if entry.created.elapsed() < self.ttl && entry.packages.iter().all(|(package, hash)| *hash == package_hash(registry_path, package)) {
+                return Some(StofResponse {
+                    headers: entry.headers.clone(),
+                    status: entry.status,
+                    str_body: entry.str_body.clone(),
+                    bytes_body: entry.bytes_body.clone(),
+                });
+            }
+            self.remove(key);
+        }
+        None
+    }
+
+    /// Cache a result.
+    /// packages: registry packages the run imported ("scope/name").
+    pub fn insert(&mut self, key: String, response: &StofResponse, registry_path: &str, packages: Vec<String>) {
+        let size = response.bytes_body.as_ref().map(|bytes| bytes.len()).unwrap_or(response.str_body.len());
+        if size > self.max_size {
+            return;
+        }
+        self.remove(&key);
+
+        // Make room, removing expired results first, then the oldest
+        let ttl = self.ttl;
+        let expired = self.entries.iter()
+            .filter(|(_, entry)| entry.created.elapsed() >= ttl)
+            .map(|(key, _)| key.clone())
+            .collect::<Vec<_>>();
+        for key in expired {
+            self.remove(&key);
+        }
+        while self.size + size > self.max_size {
+            let oldest = self.entries.iter().min_by_key(|(_, entry)| entry.created).map(|(key, _)| key.clone());
+            match oldest {
+                Some(oldest) => self.remove(&oldest),
+                None => break,
+            }
+        }
+
+        let packages = packages.into_iter()
+            .map(|package| {
+                let hash = package_hash(registry_path, &package);
+                (package, hash)
+            })
+            .collect();
+        self.size += size;
+        self.entries.insert(key, CacheEntry {
+            headers: response.headers.clone(),
+            status: response.status,
+            str_body: response.str_body.clone(),
+            bytes_body: response.bytes_body.clone(),
+            packages,
+            created: Instant::now(),
+            size,
+        });
+    }
+
+    /// Remove a cached result.
+    fn remove(&mut self, key: &str) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.size -= entry.size;
+        }
+    }
+}
+
+
+/// Cache key for a run.
+/// Hash of everything that determines the result: the body, content type, export format and path, inputs, files,
+/// package, the user running it, and what the run can access (secrets, kv namespace, sql and bus access, and libraries),
+/// so that a result is never served to a caller that could not have produced it.
+pub(crate) fn cache_key(options: &RunOptions, user: &Option<(String, bool)>, body: &[u8]) -> String {
+    let mut context = Context::new(&SHA256);
+    let mut update = |bytes: &[u8]| {
+        context.update(&(bytes.len() as u64).to_le_bytes());
+        context.update(bytes);
+    };
+    update(body);
+    update(options.content_type.as_bytes());
+    update(options.export_format.as_bytes());
+    update(options.export_path.as_deref().unwrap_or_default().as_bytes());
+    update(&[options.logs as u8, options.logs_header as u8, options.scratch_zip as u8]);
+    update(options.package.as_deref().unwrap_or_default().as_bytes());
+    update(options.inputs.json.as_deref().unwrap_or_default());
+    for (name, value) in &options.inputs.values {
+        update(name.as_bytes());
+        update(value.as_bytes());
+    }
+    update(options.entry.as_deref().unwrap_or_default().as_bytes());
+    for file in &options.files {
+        update(file.name.as_bytes());
+        update(&file.bytes);
+    }
+    for (name, value) in &options.secrets {
+        update(name.as_bytes());
+        update(value.as_bytes());
+    }
+    match user {
+        Some((username, admin)) => {
+            update(&[1, *admin as u8]);
+            update(username.as_bytes());
+        },
+        None => update(&[0]),
+    }
+    update(&[options.kv.store.is_some() as u8]);
+    update(options.kv.namespace.as_deref().unwrap_or_default().as_bytes());
+    update(&[options.sql.path.is_some() as u8, options.sql.admin as u8]);
+    update(options.sql.package_scope.as_deref().unwrap_or_default().as_bytes());
+    if let Some((perms, scope)) = &options.sql.user {
+        update(&perms.to_le_bytes());
+        update(scope.as_bytes());
+    }
+    update(&[options.bus.sender.is_some() as u8]);
+    update(options.bus.source.as_bytes());
+    update(options.libraries.to_text().as_bytes());
+    hex(context.finish().as_ref())
+}
+
+
+/// Hash of a registry package ("scope/name"), or an empty string if it isn't in the registry.
+pub(crate) fn package_hash(registry_path: &str, package: &str) -> String {
+    match fs::read(format!("{}/{}/__pkg__.pkg", registry_path, package)) {
+        Ok(bytes) => hex(digest(&SHA256, &bytes).as_ref()),
+        Err(_) => String::default(),
+    }
+}
+
+
+/// Lowercase hex string.
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+    use crate::{config::load_config, response::StofResponse, run::RunOptions};
+    use super::{cache_key, RunCache};
+
+    #[test]
+    fn user_isolation() {
+        let mut config = load_config(None).unwrap();
+        let options = RunOptions::from_config(&mut config);
+        let body = b"#[run] fn r() { self.x = 1; }";
+        let alice = Some((String::from("alice"), false));
+        let bob = Some((String::from("bob"), false));
+
+        let key = cache_key(&options, &alice, body);
+        assert_eq!(key, cache_key(&options, &alice, body));
+        assert_ne!(key, cache_key(&options, &bob, body));
+        assert_ne!(key, cache_key(&options, &None, body));
+        assert_ne!(key, cache_key(&options, &Some((String::from("alice"), true)), body));
+
+        // Same user, but with access to different secrets
+        let mut secret_options = options.clone();
+        secret_options.secrets.insert(String::from("token"), String::from("s3cretkey"));
+        assert_ne!(key, cache_key(&secret_options, &alice, body));
+
+        // A result cached for one user is not served to another
+        let mut cache = RunCache::new(&config);
+        let registry = options.registry_path.clone();
+        cache.insert(key.clone(), &StofResponse::error(StatusCode::OK, "alice"), &registry, Vec::new());
+        assert_eq!(cache.get(&key, &registry).unwrap().str_body, "alice");
+        assert!(cache.get(&cache_key(&options, &bob, body), &registry).is_none());
+    }
+}
@@ -0,0 +1,76 @@
+//
+// Copyright 2024 Formata, Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+
+use std::sync::{atomic::{AtomicBool, Ordering}, Arc};
+use stof::{lang::SError, Library, SDoc, SVal};
+
+
+/// Cancellation of a run, shared between the run's document and whatever is waiting on it.
+///
+/// Documents execute on blocking threads that can't be stopped from outside, so a run that times out keeps going on its own thread.
+/// Once cancelled, every library call the document makes fails (kv, sql, HTTP, bus, fs, etc.), so it can't do anything
+/// more outside of itself, and most documents stop at their next call. Pure computation that makes no library calls runs until it finishes.
+#[derive(Clone, Default)]
+pub(crate) struct RunCancel {
+    cancelled: Arc<AtomicBool>,
+}
+impl RunCancel {
+    /// Cancel the run.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    /// Reset the cancellation, for documents that are called again after a cancelled call (sessions).
+    pub fn reset(&self) {
+        self.cancelled.store(false, Ordering::SeqCst);
+    }
+
+    /// Has the run been cancelled?
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Guard every library loaded into a document, so that calls fail once the run is cancelled.
+    /// Call after the document is imported, so that libraries loaded by imports (WebAssembly modules) are guarded too.
+    pub fn guard(&self, doc: &mut SDoc) {
+        for library in doc.libraries.libraries.values_mut() {
+            *library = Arc::new(CancelLibrary {
+                library: library.clone(),
+                cancel: self.clone(),
+            });
+        }
+    }
+}
+
+
+/// Library that fails every call once its run is cancelled (otherwise calls the library it wraps).
+struct CancelLibrary {
+    library: Arc<dyn Library>,
+    cancel: RunCancel,
+}
+impl Library for CancelLibrary {
+    fn scope(&self) -> String {
+        self.library.scope()
+    }
+
+    fn call(&self, pid: &str, doc: &mut SDoc, name: &str, parameters: &mut Vec<SVal>) -> Result<SVal, SError> {
+        if self.cancel.is_cancelled() {
+            return Err(SError::custom(pid, doc, "RunCancelled", "run was cancelled (timeout)"));
+        }
+        self.library.call(pid, doc, name, parameters)
+    }
+}
@@ -0,0 +1,590 @@
+//
+// Copyright 2024 Formata, Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+use std::{io::{self, Read}, net::{IpAddr, SocketAddr, ToSocketAddrs}, sync::{atomic::{AtomicUsize, Ordering}, Arc, Mutex}, time::Duration};
+use bytes::Bytes;
+use stof::{lang::SError, Library, SDoc, SNodeRef, SUnits, SVal};
+use ureq::Agent;
+use crate::config::{egress_allow_private, egress_hosts, egress_max_requests, egress_max_response_size, egress_timeout};
+use super::record::RunRecording;
+
+
+/// Default timeout for a request that does not give one (same as the HTTP library).
+const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Min timeout for a request (unless the max timeout is lower).
+const MIN_REQUEST_TIMEOUT: Duration = Duration::from_secs(1);
+
+
+/// Egress allow/deny rule.
+#[derive(Debug, Clone)]
+enum EgressRule {
+    /// Host name ("api.example.com"), or all subdomains of a host ("*.example.com").
+    Host(String),
+
+    /// Address range ("10.0.0.0/8"), or a single address ("10.0.0.1").
+    Cidr(IpAddr, u8),
+}
+impl EgressRule {
+    /// Parse a rule from the config.
+    fn parse(rule: &str) -> Option<Self> {
+        let rule = rule.trim().to_lowercase();
+        if rule.is_empty() {
+            return None;
+        }
+        let (addr, prefix) = match rule.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (rule.as_str(), None),
+        };
+        if let Ok(ip) = addr.trim_matches(['[', ']']).parse::<IpAddr>() {
+            let max = if ip.is_ipv4() { 32 } else { 128 };
+            let prefix = match prefix {
+                Some(prefix) => prefix.parse::<u8>().ok().filter(|prefix| *prefix <= max)?,
+                None => max,
+            };
+            // IPv4-mapped ranges are IPv4 ranges, since addresses are compared as IPv4 (see canonical_ip)
+            let mapped = match ip {
+                IpAddr::V6(v6) => v6.to_ipv4_mapped().filter(|_| prefix >= 96),
+                IpAddr::V4(_) => None,
+            };
+            if let Some(v4) = mapped {
+                return Some(Self::Cidr(IpAddr::V4(v4), prefix - 96));
+            }
+            return Some(Self::Cidr(ip, prefix));
+        }
+        if prefix.is_some() {
+            return None;
+        }
+        Some(Self::Host(rule.trim_end_matches('.').to_owned()))
+    }
+
+    /// Does this rule match a host name?
+    fn matches_host(&self, host: &str) -> bool {
+        match self {
+            Self::Host(pattern) => {
+                if let Some(domain) = pattern.strip_prefix("*.") {
+                    host.ends_with(&format!(".{}", domain))
+                } else {
+                    host == pattern
+                }
+            },
+            Self::Cidr(..) => false,
+        }
+    }
+
+    /// Does this rule match an address?
+    fn matches_ip(&self, ip: &IpAddr) -> bool {
+        match self {
+            Self::Cidr(range, prefix) => {
+                match (range, ip) {
+                    (IpAddr::V4(range), IpAddr::V4(ip)) => {
+                        let mask = u32::MAX.checked_shl(32 - *prefix as u32).unwrap_or(0);
+                        u32::from(*range) & mask == u32::from(*ip) & mask
+                    },
+                    (IpAddr::V6(range), IpAddr::V6(ip)) => {
+                        let mask = u128::MAX.checked_shl(128 - *prefix as u32).unwrap_or(0);
+                        u128::from(*range) & mask == u128::from(*ip) & mask
+                    },
+                    _ => false,
+                }
+            },
+            Self::Host(..) => false,
+        }
+    }
+}
+
+
+/// IPv4-mapped IPv6 addresses are treated as IPv4 addresses.
+fn canonical_ip(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => {
+            if let Some(v4) = v6.to_ipv4_mapped() {
+                return IpAddr::V4(v4);
+            }
+            ip
+        },
+        _ => ip,
+    }
+}
+
+
+/// Is this a private, loopback, link-local, or otherwise non-public address?
+fn is_private(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let octets = ip.octets();
+            ip.is_private() ||
+            ip.is_loopback() ||
+            ip.is_link_local() ||
+            ip.is_unspecified() ||
+            ip.is_broadcast() ||
+            ip.is_multicast() ||
+            octets[0] == 0 ||
+            (octets[0] == 100 && (octets[1] & 0b1100_0000) == 64) // 100.64.0.0/10 (shared address space)
+        },
+        IpAddr::V6(ip) => {
+            let segments = ip.segments();
+            ip.is_loopback() ||
+            ip.is_unspecified() ||
+            ip.is_multicast() ||
+            (segments[0] & 0xfe00) == 0xfc00 || // fc00::/7 (unique local)
+            (segments[0] & 0xffc0) == 0xfe80    // fe80::/10 (link-local)
+        },
+    }
+}
+
+
+/// Egress policy for HTTP requests made by runs.
+#[derive(Debug, Clone)]
+pub(crate) struct EgressPolicy {
+    /// Hosts and CIDRs that can be reached (any public address if empty).
+    allow: Vec<EgressRule>,
+
+    /// Hosts and CIDRs that can never be reached.
+    deny: Vec<EgressRule>,
+
+    /// Can private addresses be reached?
+    pub allow_private: bool,
+
+    /// Max requests per document.
+    pub max_requests: usize,
+
+    /// Max response size (bytes).
+    pub max_response_size: usize,
+
+    /// Max timeout for a single request.
+    pub timeout: Duration,
+}
+impl Default for EgressPolicy {
+    fn default() -> Self {
+        Self {
+            allow: Vec::new(),
+            deny: Vec::new(),
+            allow_private: false,
+            max_requests: 100,
+            max_response_size: 10485760,
+            timeout: Duration::from_secs(10),
+        }
+    }
+}
+impl EgressPolicy {
+    /// Egress policy from the server config.
+    pub fn from_config(config: &mut SDoc) -> Self {
+        Self {
+            allow: egress_hosts(config, "allow").iter().filter_map(|rule| EgressRule::parse(rule)).collect(),
+            deny: egress_hosts(config, "deny").iter().filter_map(|rule| EgressRule::parse(rule)).collect(),
+            allow_private: egress_allow_private(config),
+            max_requests: egress_max_requests(config),
+            max_response_size: egress_max_response_size(config),
+            timeout: egress_timeout(config),
+        }
+    }
+
+    /// Resolve a "host:port" network location, keeping only the addresses that this policy allows.
+    /// Used as the HTTP agent's resolver, so redirects are checked too.
+    fn resolve(&self, netloc: &str) -> io::Result<Vec<SocketAddr>> {
+        let host = match netloc.rsplit_once(':') {
+            Some((host, _port)) => host,
+            None => netloc,
+        };
+        let host = host.trim_matches(['[', ']']).trim_end_matches('.').to_lowercase();
+        let denied = || io::Error::new(io::ErrorKind::PermissionDenied, format!("egress to '{}' is not allowed", host));
+
+        if self.deny.iter().any(|rule| rule.matches_host(&host)) {
+            return Err(denied());
+        }
+        let host_allowed = self.allow.iter().any(|rule| rule.matches_host(&host));
+
+        let addrs = netloc.to_socket_addrs()?
+            .filter(|addr| {
+                let ip = canonical_ip(addr.ip());
+                if self.deny.iter().any(|rule| rule.matches_ip(&ip)) {
+                    return false;
+                }
+                let ip_allowed = self.allow.iter().any(|rule| rule.matches_ip(&ip));
+                if is_private(&ip) && !self.allow_private && !ip_allowed {
+                    return false;
+                }
+                self.allow.is_empty() || host_allowed || ip_allowed
+            })
+            .collect::<Vec<_>>();
+        if addrs.is_empty() {
+            return Err(denied());
+        }
+        Ok(addrs)
+    }
+}
+
+
+/// HTTP library with an egress policy.
+///
+/// Same functions and parameters as the stof HTTP library ("HTTP.get", "HTTP.post", etc.), only allowing requests to the hosts and addresses
+/// permitted by the runner's egress config, with a max number of requests, max response size (enforced while reading), and max timeout.
+///
+/// Responses are recorded if the run is being recorded, and replayed instead of making requests if it's a replay.
+pub struct EgressHTTPLibrary {
+    pub agent: Agent,
+    pub policy: Arc<EgressPolicy>,
+
+    /// Requests made so far.
+    pub requests: AtomicUsize,
+
+    /// Recording for the run, if any.
+    pub recording: Option<Arc<Mutex<RunRecording>>>,
+}
+impl EgressHTTPLibrary {
+    pub fn new(policy: EgressPolicy) -> Self {
+        let policy = Arc::new(policy);
+        let resolver = policy.clone();
+        Self {
+            agent: ureq::AgentBuilder::new()
+                .timeout(policy.timeout)
+                .resolver(move |netloc: &str| resolver.resolve(netloc))
+                .build(),
+            policy,
+            requests: AtomicUsize::new(0),
+            recording: None,
+        }
+    }
+
+    /// HTTP library that records (or replays) the responses of the requests it makes.
+    pub fn recorded(policy: EgressPolicy, recording: Arc<Mutex<RunRecording>>) -> Self {
+        let mut library = Self::new(policy);
+        library.recording = Some(recording);
+        library
+    }
+
+    /// Response object parameter, if any.
+    fn response_object(value: &SVal) -> Option<SNodeRef> {
+        match value {
+            SVal::Object(nref) => Some(nref.clone()),
+            SVal::Boxed(val) => Self::response_object(&val.lock().unwrap()),
+            _ => None,
+        }
+    }
+
+    /// Request timeout, limited by the policy (Ex. 0.5s is kept at 1s, and 60s is limited to the max timeout).
+    fn request_timeout(&self, seconds: f64) -> Duration {
+        let max_timeout = self.policy.timeout.as_secs_f64();
+        let min_timeout = MIN_REQUEST_TIMEOUT.as_secs_f64().min(max_timeout);
+        Duration::from_secs_f64(seconds.min(max_timeout).max(min_timeout))
+    }
+
+    /// Send a request, returning the response content type, headers, and body.
+    /// Parameters are the URL, then optional headers (vec of tuples or map), a body (str or blob), and a timeout.
+    /// The body is read up to the max response size, so larger responses are never fully read.
+    fn request(&self, name: &str, parameters: Vec<SVal>) -> Result<SVal, String> {
+        let mut parameters = parameters.into_iter().map(SVal::unbox);
+        let url = match parameters.next() {
+            Some(SVal::String(url)) => url,
+            Some(_) => return Err("url must be a string".into()),
+            None => return Err("must provide a URL as the first parameter when calling into the HTTP library".into()),
+        };
+        let mut request = match name {
+            "get" => self.agent.get(&url),
+            "head" => self.agent.head(&url),
+            "patch" => self.agent.patch(&url),
+            "post" => self.agent.post(&url),
+            "put" => self.agent.put(&url),
+            "delete" => self.agent.delete(&url),
+            _ => return Err(format!("unrecognized HTTP library function: {}", name)),
+        };
+
+        let mut str_body = None;
+        let mut blob_body = None;
+        let mut timeout = self.request_timeout(DEFAULT_REQUEST_TIMEOUT.as_secs_f64());
+        for param in parameters {
+            match param {
+                SVal::Array(vals) => {
+                    for val in vals {
+                        if let SVal::Tuple(header) = val && header.len() == 2 {
+                            request = request.set(&header[0].to_string(), &header[1].to_string());
+                        }
+                    }
+                },
+                SVal::Map(map) => {
+                    for (key, value) in map {
+                        request = request.set(&key.to_string(), &value.to_string());
+                    }
+                },
+                SVal::String(body) => str_body = Some(body),
+                SVal::Blob(body) => blob_body = Some(body),
+                SVal::Number(num) => timeout = self.request_timeout(num.float_with_units(SUnits::Seconds)),
+                _ => return Err("HTTP request parameters must be headers (vec | map), a body (str | blob), a timeout (float | units), or a response object (obj)".into()),
+            }
+        }
+        request = request.timeout(timeout);
+
+        let response_res;
+        if let Some(body) = str_body {
+            response_res = request.send_string(&body);
+        } else if let Some(body) = blob_body {
+            response_res = request.send_bytes(&body);
+        } else {
+            response_res = request.call();
+        }
+        let response = response_res.map_err(|error| format!("error sending request: {}", error))?;
+
+        let content_type = response.content_type().to_owned();
+        let headers = SVal::Map(response.headers_names().into_iter().filter_map(|name| {
+            let value = response.header(&name)?.to_owned();
+            Some((SVal::String(name), SVal::String(value)))
+        }).collect());
+
+        let max_size = self.policy.max_response_size;
+        let mut body = Vec::new();
+        if let Err(error) = response.into_reader().take(max_size as u64 + 1).read_to_end(&mut body) {
+            return Err(format!("error reading response: {}", error));
+        }
+        if body.len() > max_size {
+            return Err(format!("response is too large (max is {} bytes)", max_size));
+        }
+        Ok(SVal::Tuple(vec![SVal::String(content_type), headers, SVal::Blob(body)]))
+    }
+}
+impl Library for EgressHTTPLibrary {
+    fn scope(&self) -> String {
+        "HTTP".to_string()
+    }
+
+    fn call(&self, pid: &str, doc: &mut SDoc, name: &str, parameters: &mut Vec<SVal>) -> Result<SVal, SError> {
+        if self.requests.fetch_add(1, Ordering::SeqCst) >= self.policy.max_requests {
+            return Err(SError::custom(pid, doc, "HTTPError", &format!("too many HTTP requests (max is {})", self.policy.max_requests)));
+        }
+
+        // Response objects are imported here, after the response size is checked (replayed responses are checked too)
+        let mut response_obj = None;
+        if parameters.len() > 1 && let Some(nref) = Self::response_object(parameters.last().unwrap()) {
+            response_obj = Some(nref);
+            parameters.pop();
+        }
+
+        let res;
+        match &self.recording {
+            Some(recording) => {
+                let url = parameters.first().map(|url| url.to_string()).unwrap_or_default();
+                let replay = recording.lock().unwrap().replay;
+                if replay {
+                    let recorded = recording.lock().unwrap().replay_http(name, &url);
+                    match recorded {
+                        Ok(Ok(value)) => res = value,
+                        Ok(Err(message)) => return Err(SError::custom(pid, doc, "HTTPError", &message)),
+                        Err(message) => return Err(SError::custom(pid, doc, "ReplayError", &message)),
+                    }
+                } else {
+                    let call_res = self.request(name, std::mem::take(parameters));
+                    recording.lock().unwrap().record_http(name, &url, &call_res);
+                    res = call_res.map_err(|message| SError::custom(pid, doc, "HTTPError", &message))?;
+                }
+            },
+            None => {
+                res = self.request(name, std::mem::take(parameters)).map_err(|message| SError::custom(pid, doc, "HTTPError", &message))?;
+            }
+        }
+        if let SVal::Tuple(values) = &res && let (Some(SVal::String(content_type)), Some(SVal::Blob(body))) = (values.first(), values.get(2)) {
+            if body.len() > self.policy.max_response_size {
+                return Err(SError::custom(pid, doc, "HTTPError", &format!("response is too large (max is {} bytes)", self.policy.max_response_size)));
+            }
+            if let Some(response_obj) = response_obj {
+                let mut bytes = Bytes::from(body.clone());
+                let as_name = response_obj.path(&doc.graph);
+                doc.header_import(pid, content_type, content_type, &mut bytes, &as_name)?;
+            }
+        }
+        Ok(res)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(rule: &str) -> EgressRule {
+        EgressRule::parse(rule).unwrap()
+    }
+
+    fn ip(addr: &str) -> IpAddr {
+        canonical_ip(addr.parse().unwrap())
+    }
+
+    fn policy(allow: &[&str], deny: &[&str]) -> EgressPolicy {
+        EgressPolicy {
+            allow: allow.iter().map(|value| rule(value)).collect(),
+            deny: deny.iter().map(|value| rule(value)).collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn parse_rules() {
+        assert!(matches!(rule("10.0.0.0/8"), EgressRule::Cidr(_, 8)));
+        assert!(matches!(rule("10.0.0.1"), EgressRule::Cidr(_, 32)));
+        assert!(matches!(rule("[::1]"), EgressRule::Cidr(_, 128)));
+        assert!(matches!(rule("::ffff:10.0.0.0/104"), EgressRule::Cidr(IpAddr::V4(_), 8)));
+        assert!(matches!(rule("::ffff:0.0.0.0/80"), EgressRule::Cidr(IpAddr::V6(_), 80)));
+        assert!(matches!(rule("::ffff:10.0.0.1"), EgressRule::Cidr(IpAddr::V4(_), 32)));
+        assert!(matches!(rule(" API.Example.com. "), EgressRule::Host(host) if host == "api.example.com"));
+        assert!(EgressRule::parse("").is_none());
+        assert!(EgressRule::parse("10.0.0.0/33").is_none());
+        assert!(EgressRule::parse("::/129").is_none());
+        assert!(EgressRule::parse("10.0.0.0/x").is_none());
+        assert!(EgressRule::parse("example.com/8").is_none());
+    }
+
+    #[test]
+    fn ipv4_cidr_edges() {
+        let range = rule("192.168.4.0/22");
+        assert!(range.matches_ip(&ip("192.168.4.0")));
+        assert!(range.matches_ip(&ip("192.168.7.255")));
+        assert!(!range.matches_ip(&ip("192.168.3.255")));
+        assert!(!range.matches_ip(&ip("192.168.8.0")));
+
+        assert!(rule("0.0.0.0/0").matches_ip(&ip("8.8.8.8")));
+        assert!(rule("8.8.8.8/32").matches_ip(&ip("8.8.8.8")));
+        assert!(!rule("8.8.8.8/32").matches_ip(&ip("8.8.8.9")));
+    }
+
+    #[test]
+    fn ipv6_cidr_edges() {
+        let range = rule("2001:db8::/32");
+        assert!(range.matches_ip(&ip("2001:db8::")));
+        assert!(range.matches_ip(&ip("2001:db8:ffff:ffff:ffff:ffff:ffff:ffff")));
+        assert!(!range.matches_ip(&ip("2001:db9::")));
+        assert!(!range.matches_ip(&ip("2001:db7:ffff:ffff:ffff:ffff:ffff:ffff")));
+
+        assert!(rule("::/0").matches_ip(&ip("2001:db8::1")));
+        assert!(rule("::1/128").matches_ip(&ip("::1")));
+        assert!(!rule("::1/128").matches_ip(&ip("::2")));
+
+        // Address families never match each other
+        assert!(!rule("::/0").matches_ip(&ip("8.8.8.8")));
+        assert!(!rule("0.0.0.0/0").matches_ip(&ip("2001:db8::1")));
+    }
+
+    #[test]
+    fn ipv4_mapped_ipv6() {
+        assert_eq!(ip("::ffff:10.1.2.3"), ip("10.1.2.3"));
+        assert!(rule("10.0.0.0/8").matches_ip(&ip("::ffff:10.1.2.3")));
+        assert!(rule("::ffff:10.0.0.1").matches_ip(&ip("10.0.0.1")));
+        assert!(rule("::ffff:10.0.0.0/104").matches_ip(&ip("10.255.0.1")));
+        assert!(!rule("::ffff:10.0.0.0/104").matches_ip(&ip("11.0.0.1")));
+        assert!(is_private(&ip("::ffff:127.0.0.1")));
+        assert!(is_private(&ip("::ffff:192.168.1.1")));
+        assert!(!is_private(&ip("::ffff:8.8.8.8")));
+    }
+
+    #[test]
+    fn private_addresses() {
+        for addr in ["10.0.0.1", "172.16.0.1", "192.168.0.1", "127.0.0.1", "169.254.169.254", "0.0.0.0", "0.1.2.3", "255.255.255.255", "224.0.0.1", "100.64.0.1", "100.127.255.255", "::1", "::", "fc00::1", "fdff::1", "fe80::1", "febf::1", "ff02::1"] {
+            assert!(is_private(&ip(addr)), "{} should be private", addr);
+        }
+        for addr in ["8.8.8.8", "100.63.255.255", "100.128.0.0", "172.32.0.1", "2001:db8::1", "2606:4700::1111", "fec0::1"] {
+            assert!(!is_private(&ip(addr)), "{} should be public", addr);
+        }
+    }
+
+    #[test]
+    fn resolve_rejects_private_addresses() {
+        let open = policy(&[], &[]);
+        assert!(open.resolve("8.8.8.8:443").is_ok());
+        for netloc in ["127.0.0.1:80", "10.0.0.1:80", "169.254.169.254:80", "[::1]:80", "[fd00::1]:80", "[::ffff:127.0.0.1]:80", "[::ffff:10.0.0.1]:80"] {
+            let error = open.resolve(netloc).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::PermissionDenied, "{} should be denied", netloc);
+        }
+
+        // Host names are checked by the addresses they resolve to
+        assert!(open.resolve("localhost:80").is_err());
+
+        let private = EgressPolicy {
+            allow_private: true,
+            ..Default::default()
+        };
+        assert!(private.resolve("127.0.0.1:80").is_ok());
+        assert!(private.resolve("[::ffff:10.0.0.1]:80").is_ok());
+    }
+
+    #[test]
+    fn resolve_allow_and_deny() {
+        // Allowing a private range explicitly reaches it, but nothing else
+        let internal = policy(&["10.1.0.0/16"], &[]);
+        assert!(internal.resolve("10.1.2.3:80").is_ok());
+        assert!(internal.resolve("[::ffff:10.1.2.3]:80").is_ok());
+        assert!(internal.resolve("10.2.0.1:80").is_err());
+        assert!(internal.resolve("8.8.8.8:80").is_err());
+
+        // Deny takes precedence over allow
+        let denied = policy(&["8.8.0.0/16"], &["8.8.8.8"]);
+        assert!(denied.resolve("8.8.4.4:53").is_ok());
+        assert!(denied.resolve("8.8.8.8:53").is_err());
+
+        let hosts = policy(&[], &["localhost", "*.internal"]);
+        assert!(hosts.resolve("localhost:80").is_err());
+        assert!(hosts.resolve("db.internal:5432").is_err());
+    }
+
+    #[test]
+    fn host_rules() {
+        let wildcard = rule("*.example.com");
+        assert!(wildcard.matches_host("api.example.com"));
+        assert!(wildcard.matches_host("a.b.example.com"));
+        assert!(!wildcard.matches_host("example.com"));
+        assert!(!wildcard.matches_host("badexample.com"));
+        assert!(rule("example.com").matches_host("example.com"));
+        assert!(!rule("example.com").matches_host("api.example.com"));
+        assert!(!rule("10.0.0.1").matches_host("10.0.0.1"));
+    }
+
+    #[test]
+    fn request_timeouts() {
+        let library = EgressHTTPLibrary::new(EgressPolicy { timeout: Duration::from_secs(10), ..Default::default() });
+        assert_eq!(library.request_timeout(0.5), Duration::from_secs(1));
+        assert_eq!(library.request_timeout(-1.), Duration::from_secs(1));
+        assert_eq!(library.request_timeout(2.5), Duration::from_millis(2500));
+        assert_eq!(library.request_timeout(60.), Duration::from_secs(10));
+
+        // A max timeout below the min is kept
+        let library = EgressHTTPLibrary::new(EgressPolicy { timeout: Duration::from_millis(500), ..Default::default() });
+        assert_eq!(library.request_timeout(0.1), Duration::from_millis(500));
+    }
+
+    #[test]
+    fn response_size_limit() {
+        use std::{io::Write, net::TcpListener, thread};
+
+        // Serves a response that never ends, so only a limited read can finish
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for stream in listener.incoming().take(2) {
+                let mut stream = stream.unwrap();
+                let mut request = [0u8; 1024];
+                let _ = stream.read(&mut request);
+                let _ = stream.write_all(b"HTTP/1.1 200 OK\r\ncontent-type: text/plain\r\n\r\n");
+                let chunk = [b'a'; 64];
+                while stream.write_all(&chunk).is_ok() {}
+            }
+        });
+
+        let library = EgressHTTPLibrary::new(EgressPolicy { max_response_size: 100, ..policy(&["127.0.0.1"], &[]) });
+        let url = format!("http://{}/", address);
+        let res = library.request("get", vec![url.clone().into()]);
+        assert_eq!(res, Err(String::from("response is too large (max is 100 bytes)")));
+
+        let mut doc = SDoc::default();
+        assert!(library.call("main", &mut doc, "get", &mut vec![url.into(), 1.into()]).is_err());
+    }
+}
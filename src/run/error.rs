@@ -0,0 +1,256 @@
+//
+// Copyright 2024 Formata, Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+
+use std::collections::BTreeMap;
+use axum::http::{header::ACCEPT, HeaderMap, StatusCode};
+use stof::{lang::SError, SData, SDataRef, SDoc, SField, SFunc, SGraph, SVal};
+use super::negotiate::negotiate_format;
+
+
+/// Stable error codes for the ways a run can fail.
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) enum RunErrorCode {
+    /// Mounting the run's files into its scratch directory failed.
+    Files,
+
+    /// Creating the run's scratch directory failed.
+    Scratch,
+
+    /// Parsing the body or entry document failed.
+    Parse,
+
+    /// Importing the run's package failed.
+    Package,
+
+    /// Injecting the inputs failed.
+    Inputs,
+
+    /// Executing the document failed (errors thrown while running).
+    Execute,
+
+    /// The export path was not found in the resulting document.
+    ExportPath,
+
+    /// Exporting the resulting document failed.
+    Export,
+
+    /// Zipping the scratch directory failed.
+    ScratchZip,
+
+    /// The run did not finish within the timeout.
+    Timeout,
+
+    /// The run's thread failed unexpectedly.
+    Internal,
+
+    /// The sandbox process could not be started, or exited without a result.
+    Sandbox,
+}
+impl RunErrorCode {
+    /// Error code string.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Files => "RUN_FILES",
+            Self::Scratch => "RUN_SCRATCH",
+            Self::Parse => "RUN_PARSE",
+            Self::Package => "RUN_PACKAGE",
+            Self::Inputs => "RUN_INPUTS",
+            Self::Execute => "RUN_EXECUTE",
+            Self::ExportPath => "RUN_EXPORT_PATH",
+            Self::Export => "RUN_EXPORT",
+            Self::ScratchZip => "RUN_SCRATCH_ZIP",
+            Self::Timeout => "RUN_TIMEOUT",
+            Self::Internal => "RUN_INTERNAL",
+            Self::Sandbox => "RUN_SANDBOX",
+        }
+    }
+}
+
+
+/// Run error.
+/// Plain text errors are the same as they have always been, and structured errors break them into their parts.
+pub(crate) struct RunError {
+    pub code: RunErrorCode,
+    pub status: StatusCode,
+
+    /// Plain text error (the response body if structured errors are not returned).
+    pub text: String,
+
+    /// Stof error type (Ex. "ParseError" or a thrown type), or "RunnerError" for errors from the runner itself.
+    pub kind: String,
+    pub message: String,
+
+    /// Functions being called when the error occurred, outermost first.
+    pub stack: Vec<String>,
+
+    /// Document being parsed (entry file or package), if not the body.
+    pub file: Option<String>,
+
+    /// Line and column of a parse error.
+    pub line: Option<usize>,
+    pub column: Option<usize>,
+}
+impl RunError {
+    /// Error from the runner.
+    pub fn new(code: RunErrorCode, status: StatusCode, message: &str) -> Self {
+        Self {
+            code,
+            status,
+            text: message.to_owned(),
+            kind: String::from("RunnerError"),
+            message: message.to_owned(),
+            stack: Vec::new(),
+            file: None,
+            line: None,
+            column: None,
+        }
+    }
+
+    /// Error from Stof.
+    /// Parse errors are located with the line and column from the parser's message.
+    pub fn stof(code: RunErrorCode, status: StatusCode, doc: &SDoc, error: &SError) -> Self {
+        let mut res = Self::new(code, status, &error.to_string(&doc.graph));
+        res.kind = error.error_type.to_string();
+        res.message = error.message.clone();
+        res.stack = error.call_stack.iter().filter_map(|dref| function_path(&doc.graph, dref)).collect();
+        if let Some((line, column)) = parse_location(&error.message) {
+            res.line = Some(line);
+            res.column = Some(column);
+        }
+        res
+    }
+
+    /// Set the document being parsed.
+    pub fn in_file(mut self, file: Option<&str>) -> Self {
+        self.file = file.map(|file| file.to_owned());
+        self
+    }
+
+    /// Insert this error into a document as the "error" object on the main root.
+    pub fn insert_into(&self, doc: &mut SDoc) {
+        if let Some(main) = doc.graph.main_root() {
+            let nref = SField::new_object(&mut doc.graph, "error", &main);
+            SField::new_string(&mut doc.graph, "code", self.code.code(), &nref);
+            SField::new_int(&mut doc.graph, "status", self.status.as_u16() as i64, &nref);
+            SField::new_string(&mut doc.graph, "kind", &self.kind, &nref);
+            SField::new_string(&mut doc.graph, "message", &self.message, &nref);
+            SField::new_array(&mut doc.graph, "stack", self.stack.iter().map(|func| SVal::String(func.clone())).collect(), &nref);
+            if let Some(function) = self.stack.last() {
+                SField::new_string(&mut doc.graph, "function", function, &nref);
+            }
+            if let Some(file) = &self.file {
+                SField::new_string(&mut doc.graph, "file", file, &nref);
+            }
+            if let Some(line) = self.line {
+                SField::new_int(&mut doc.graph, "line", line as i64, &nref);
+            }
+            if let Some(column) = self.column {
+                SField::new_int(&mut doc.graph, "column", column as i64, &nref);
+            }
+        }
+    }
+}
+
+
+/// Format for structured errors: JSON, unless the Accept header asks for Stof (exported as BSTOF).
+pub(crate) fn error_format(headers: &HeaderMap) -> String {
+    let formats = BTreeMap::from([
+        (String::from("json"), String::from("application/json")),
+        (String::from("stof"), String::from("application/stof")),
+        (String::from("bstof"), String::from("application/bstof")),
+    ]);
+    if let Some(accept) = headers.get(ACCEPT) && let Some(format) = negotiate_format(&formats, accept.to_str().unwrap_or_default(), "json") && format != "json" {
+        return String::from("bstof");
+    }
+    String::from("json")
+}
+
+
+/// Path of a function ("root.main" or "Point.len" for type functions).
+pub(crate) fn function_path(graph: &SGraph, dref: &SDataRef) -> Option<String> {
+    let func = SData::get::<SFunc>(graph, dref)?;
+    let mut path = String::from("<unknown>");
+    if let Some(node) = dref.nodes(graph).first() {
+        // Type functions are named by their type instead of the prototype node
+        match SField::field(graph, "typename", '.', Some(node)) {
+            Some(typename) => path = typename.to_string(),
+            None => path = node.path(graph).replace('/', "."),
+        }
+    }
+    Some(format!("{}.{}", path, func.name))
+}
+
+
+/// Line and column from a parser error message (" --> 3:14").
+fn parse_location(message: &str) -> Option<(usize, usize)> {
+    let (_, location) = message.split_once("--> ")?;
+    let location = location.split_whitespace().next()?;
+    let (line, column) = location.split_once(':')?;
+    Some((line.parse().ok()?, column.parse().ok()?))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+    use axum::http::{header::ACCEPT, HeaderMap, StatusCode};
+    use stof::SDoc;
+    use super::{error_format, parse_location, RunError, RunErrorCode};
+
+    #[test]
+    fn error_codes() {
+        let codes = [
+            RunErrorCode::Files, RunErrorCode::Scratch, RunErrorCode::Parse, RunErrorCode::Package, RunErrorCode::Inputs, RunErrorCode::Execute,
+            RunErrorCode::ExportPath, RunErrorCode::Export, RunErrorCode::ScratchZip, RunErrorCode::Timeout, RunErrorCode::Internal, RunErrorCode::Sandbox,
+        ];
+        assert_eq!(codes.iter().map(|code| code.code()).collect::<BTreeSet<_>>().len(), codes.len());
+        assert_eq!(RunErrorCode::Parse.code(), "RUN_PARSE");
+        assert_eq!(RunErrorCode::Execute.code(), "RUN_EXECUTE");
+        assert_eq!(RunErrorCode::Timeout.code(), "RUN_TIMEOUT");
+    }
+
+    #[test]
+    fn stof_errors() {
+        let mut doc = SDoc::src("fn outer() { self.inner(); } fn inner() { throw('Invalid', 'bad value'); }", "stof").unwrap();
+        let error = doc.call_func("root.outer", None, vec![]).unwrap_err();
+        let error = RunError::stof(RunErrorCode::Execute, StatusCode::BAD_REQUEST, &doc, &error);
+        assert_eq!(error.code.code(), "RUN_EXECUTE");
+        assert_eq!(error.kind, "Invalid");
+        assert_eq!(error.message, "bad value");
+        assert_eq!(error.stack.last().map(|func| func.as_str()), Some("root.inner"));
+
+        let error = RunError::new(RunErrorCode::Files, StatusCode::BAD_REQUEST, "invalid file name");
+        assert_eq!(error.kind, "RunnerError");
+        assert_eq!(error.text, "invalid file name");
+        assert!(error.line.is_none());
+        assert_eq!(parse_location("unexpected token\n --> 3:14\n"), Some((3, 14)));
+    }
+
+    #[test]
+    fn error_formats() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(error_format(&headers), "json");
+        headers.insert(ACCEPT, "application/json".parse().unwrap());
+        assert_eq!(error_format(&headers), "json");
+        headers.insert(ACCEPT, "application/bstof".parse().unwrap());
+        assert_eq!(error_format(&headers), "bstof");
+        headers.insert(ACCEPT, "stof".parse().unwrap());
+        assert_eq!(error_format(&headers), "bstof");
+        headers.insert(ACCEPT, "image/png".parse().unwrap());
+        assert_eq!(error_format(&headers), "json");
+    }
+}
@@ -0,0 +1,136 @@
+//
+// Copyright 2024 Formata, Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+use std::collections::BTreeMap;
+use bytes::Bytes;
+use stof::{lang::SError, SData, SDoc, SField, SType, SVal};
+
+
+/// Name of the root that inputs are injected into.
+pub(crate) const INPUT_ROOT: &str = "Input";
+
+
+/// Run inputs, given separately from the document.
+#[derive(Clone, Default)]
+pub(crate) struct RunInputs {
+    /// JSON object of inputs (from an envelope body).
+    pub json: Option<Bytes>,
+
+    /// Named string inputs (from "input.<name>" query parameters).
+    /// These take precedence over JSON inputs with the same name.
+    pub values: BTreeMap<String, String>,
+}
+impl RunInputs {
+    /// Inputs from the query parameters ("?input.name=value").
+    pub fn from_query(query: &BTreeMap<String, String>) -> Self {
+        let mut inputs = Self::default();
+        for (key, value) in query {
+            if let Some(name) = key.strip_prefix("input.") && !name.is_empty() {
+                inputs.values.insert(name.to_owned(), value.clone());
+            }
+        }
+        inputs
+    }
+
+    /// Any inputs given?
+    pub fn is_empty(&self) -> bool {
+        self.json.is_none() && self.values.is_empty()
+    }
+}
+
+
+/// Run envelope body: a JSON object with a "document" string, optional "format" for the document (default "stof"), and optional "inputs" object.
+/// Returns the document format, document bytes, and JSON inputs.
+pub(crate) fn parse_envelope(body: Bytes) -> Result<(String, Bytes, Option<Bytes>), String> {
+    
+    let envelope = match SDoc::bytes(body, "json") {
+        Ok(doc) => doc,
+        Err(_) => return Err("error parsing envelope".into()),
+    };
+
+    let document;
+    match envelope.field("root.document", None) {
+        Some(field) => {
+            match &field.value {
+                SVal::String(src) => document = Bytes::from(src.clone()),
+                _ => return Err("envelope document must be a string".into()),
+            }
+        },
+        None => return Err("envelope requires a document".into()),
+    }
+
+    let mut format = String::from("stof");
+    if let Some(field) = envelope.field("root.format", None) {
+        format = field.to_string();
+    }
+
+    let mut inputs = None;
+    if let Some(field) = envelope.field("root.inputs", None) {
+        match &field.value {
+            SVal::Object(nref) => {
+                match envelope.export_string("main", "json", Some(nref)) {
+                    Ok(json) => inputs = Some(Bytes::from(json)),
+                    Err(_) => return Err("error reading envelope inputs".into()),
+                }
+            },
+            _ => return Err("envelope inputs must be an object".into()),
+        }
+    }
+    Ok((format, document, inputs))
+}
+
+
+/// Inject inputs into a document under the "Input" root.
+///
+/// If the document declares an "Input" type, the inputs are cast to it, validating and converting each field.
+/// Missing fields get the type's defaults, and missing required fields are an error.
+pub(crate) fn inject_inputs(doc: &mut SDoc, inputs: &RunInputs) -> Result<(), SError> {
+    let declared;
+    if let Some(main) = doc.graph.main_root() {
+        declared = doc.types.find(&doc.graph, INPUT_ROOT, &main).is_some();
+    } else {
+        declared = false;
+    }
+    if !declared && inputs.is_empty() {
+        return Ok(());
+    }
+
+    let input_root;
+    if let Some(json) = &inputs.json {
+        let mut bytes = json.clone();
+        doc.header_import("main", "json", "json", &mut bytes, INPUT_ROOT)?;
+        input_root = doc.graph.root_by_name(INPUT_ROOT).unwrap_or_else(|| doc.graph.insert_root(INPUT_ROOT));
+    } else if let Some(root) = doc.graph.root_by_name(INPUT_ROOT) {
+        input_root = root;
+    } else {
+        input_root = doc.graph.insert_root(INPUT_ROOT);
+    }
+
+    for (name, value) in &inputs.values {
+        if let Some(field_ref) = SField::field_ref(&doc.graph, name, '.', Some(&input_root)) {
+            if let Some(field) = SData::get_mut::<SField>(&mut doc.graph, &field_ref) {
+                field.value = value.as_str().into();
+            }
+        } else {
+            SField::new_string(&mut doc.graph, name, value, &input_root);
+        }
+    }
+
+    if declared {
+        SVal::Object(input_root).cast(SType::Object(INPUT_ROOT.into()), "main", doc)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,198 @@
+//
+// Copyright 2024 Formata, Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+use std::time::Duration;
+use bytes::Bytes;
+use nanoid::nanoid;
+use stof::{lang::SError, Library, SData, SDoc, SField, SUnits, SVal};
+use crate::kv::KvAccess;
+
+
+/// Key-value library.
+/// Gives documents a persistent key-value store, namespaced by the package scope or user running them.
+///
+/// - `kv.get(key)`: get a value (null if it doesn't exist or has expired).
+/// - `kv.set(key, value, ttl?)`: set a value, with an optional TTL (Ex. `5min`, default units are seconds).
+/// - `kv.delete(key)`: delete a value, returning true if it existed.
+/// - `kv.list(prefix?)`: keys that start with a prefix (sorted).
+///
+/// Values are objects, or primitives and collections of primitives.
+pub struct KvLibrary {
+    pub kv: KvAccess,
+}
+impl KvLibrary {
+    pub fn new(kv: KvAccess) -> Self {
+        Self {
+            kv,
+        }
+    }
+}
+impl Library for KvLibrary {
+    fn scope(&self) -> String {
+        "kv".to_string()
+    }
+
+    fn call(&self, pid: &str, doc: &mut SDoc, name: &str, parameters: &mut Vec<SVal>) -> Result<SVal, SError> {
+        let store;
+        let namespace;
+        match (&self.kv.store, &self.kv.namespace) {
+            (Some(kv_store), Some(kv_namespace)) => {
+                store = kv_store;
+                namespace = kv_namespace;
+            },
+            (Some(_), None) => {
+                return Err(SError::custom(pid, doc, "KvUnavailable", "kv requires a user or package scope"));
+            },
+            _ => {
+                return Err(SError::custom(pid, doc, "KvUnavailable", "kv is not available"));
+            }
+        }
+
+        match name {
+            "get" => {
+                if parameters.len() != 1 {
+                    return Err(SError::custom(pid, doc, "KvGet", "get requires a key"));
+                }
+                let key = parameters.pop().unwrap().unbox().to_string();
+                let value = store.lock().unwrap().get(namespace, &key);
+                if let Some(bytes) = value {
+                    return match decode_value(doc, bytes, "KvValue") {
+                        Some(value) => Ok(value),
+                        None => Err(SError::custom(pid, doc, "KvGet", &format!("could not read the value for '{}'", key))),
+                    };
+                }
+                Ok(SVal::Null)
+            },
+            "set" => {
+                if parameters.len() < 2 || parameters.len() > 3 {
+                    return Err(SError::custom(pid, doc, "KvSet", "set requires a key, a value, and an optional TTL"));
+                }
+                let mut ttl = None;
+                if parameters.len() == 3 {
+                    match parameters.pop().unwrap().unbox() {
+                        SVal::Number(num) => {
+                            let seconds = num.float_with_units(SUnits::Seconds);
+                            if seconds <= 0. {
+                                return Err(SError::custom(pid, doc, "KvSet", "TTL must be greater than zero"));
+                            }
+                            ttl = Some(Duration::from_secs_f64(seconds));
+                        },
+                        SVal::Null => {},
+                        _ => {
+                            return Err(SError::custom(pid, doc, "KvSet", "TTL must be a number"));
+                        }
+                    }
+                }
+                let value = parameters.pop().unwrap().unbox();
+                let key = parameters.pop().unwrap().unbox().to_string();
+                if key.is_empty() {
+                    return Err(SError::custom(pid, doc, "KvSet", "key cannot be empty"));
+                }
+                
+                let bytes = match encode_value(doc, value) {
+                    Ok(value) => value,
+                    Err(message) => return Err(SError::custom(pid, doc, "KvSet", &message)),
+                };
+                let res = store.lock().unwrap().set(namespace, &key, bytes, ttl);
+                if let Err(message) = res {
+                    return Err(SError::custom(pid, doc, "KvSet", &message));
+                }
+                Ok(SVal::Void)
+            },
+            "delete" => {
+                if parameters.len() != 1 {
+                    return Err(SError::custom(pid, doc, "KvDelete", "delete requires a key"));
+                }
+                let key = parameters.pop().unwrap().unbox().to_string();
+                let removed = store.lock().unwrap().delete(namespace, &key);
+                Ok(SVal::Bool(removed))
+            },
+            "list" => {
+                let mut prefix = String::default();
+                if let Some(value) = parameters.pop() {
+                    prefix = value.unbox().to_string();
+                }
+                let keys = store.lock().unwrap().list(namespace, &prefix);
+                Ok(SVal::Array(keys.into_iter().map(SVal::String).collect()))
+            },
+            _ => {
+                Err(SError::custom(pid, doc, "KvNotFound", &format!("{} is not a function in the kv library", name)))
+            }
+        }
+    }
+}
+
+
+/// Encode a value for the store (bstof document with a "value" field).
+pub(crate) fn encode_value(doc: &SDoc, value: SVal) -> Result<Bytes, String> {
+    let mut value_doc = SDoc::default();
+    match value {
+        SVal::Object(nref) => {
+            let mut bytes;
+            match doc.export_bytes("main", "bstof", Some(&nref)) {
+                Ok(value) => bytes = value,
+                Err(_) => return Err("could not export the value".into()),
+            }
+            if value_doc.header_import("main", "bstof", "bstof", &mut bytes, "root.value").is_err() {
+                return Err("could not export the value".into());
+            }
+        },
+        value => {
+            let value = storable(value)?;
+            if let Some(main) = value_doc.graph.main_root() {
+                SData::insert_new(&mut value_doc.graph, &main, Box::new(SField::new("value", value)));
+            }
+        }
+    }
+    match value_doc.export_bytes("main", "bstof", None) {
+        Ok(bytes) => Ok(bytes),
+        Err(_) => Err("could not export the value".into()),
+    }
+}
+
+
+/// Primitive value (or collection of them) that can be stored without its document.
+fn storable(value: SVal) -> Result<SVal, String> {
+    match value {
+        SVal::Null |
+        SVal::Bool(_) |
+        SVal::Number(_) |
+        SVal::String(_) |
+        SVal::Blob(_) => Ok(value),
+        SVal::Array(values) => Ok(SVal::Array(values.into_iter().map(|value| storable(value.unbox())).collect::<Result<_, _>>()?)),
+        SVal::Tuple(values) => Ok(SVal::Tuple(values.into_iter().map(|value| storable(value.unbox())).collect::<Result<_, _>>()?)),
+        SVal::Set(values) => Ok(SVal::Set(values.into_iter().map(|value| storable(value.unbox())).collect::<Result<_, _>>()?)),
+        SVal::Map(values) => Ok(SVal::Map(values.into_iter().map(|(key, value)| Ok((storable(key.unbox())?, storable(value.unbox())?))).collect::<Result<_, String>>()?)),
+        SVal::Boxed(_) => storable(value.unbox()),
+        SVal::Object(_) => Err("objects can only be stored as the value itself, not within a collection".into()),
+        _ => Err("value cannot be stored".into()),
+    }
+}
+
+
+/// Decode a stored value into a document.
+/// Objects are imported into the document under a new root (named with the given prefix).
+pub(crate) fn decode_value(doc: &mut SDoc, bytes: Bytes, root_prefix: &str) -> Option<SVal> {
+    let value_doc = SDoc::bytes(bytes, "bstof").ok()?;
+    let value = value_doc.field("root.value", None)?.value.clone();
+    if let SVal::Object(nref) = &value {
+        let mut bytes = value_doc.export_bytes("main", "bstof", Some(nref)).ok()?;
+        let root = format!("{}{}", root_prefix, nanoid!(10, &nanoid::alphabet::SAFE[2..]));
+        doc.header_import("main", "bstof", "bstof", &mut bytes, &root).ok()?;
+        return Some(SVal::Object(doc.graph.root_by_name(&root)?));
+    }
+    Some(value)
+}
@@ -0,0 +1,272 @@
+//
+// Copyright 2024 Formata, Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+use std::sync::{Arc, Mutex};
+use axum::http::{HeaderMap, HeaderValue};
+use stof::{lang::SError, Library, SDoc, SField, SType, SVal, StdLibrary};
+use tokio::sync::mpsc::UnboundedSender;
+use crate::secrets::MIN_SECRET_LEN;
+
+
+/// Replaces secret values in run output.
+pub const REDACTED: &str = "[REDACTED]";
+
+
+/// Captured log line.
+#[derive(Clone)]
+pub struct RunLog {
+    /// Output stream ("out", "err", or "progress").
+    pub stream: &'static str,
+
+    /// Printed message.
+    pub message: String,
+}
+
+
+/// Captured output for a single run.
+#[derive(Default)]
+pub struct RunLogs {
+    /// Captured lines, in the order they were printed (lines past the max size are not kept).
+    pub lines: Vec<RunLog>,
+
+    /// Total size of the captured messages (bytes).
+    pub size: usize,
+
+    /// Maximum size of the captured messages (bytes).
+    pub max_size: usize,
+
+    /// Were lines dropped because of the max size?
+    pub truncated: bool,
+
+    /// Lines are also sent here as they are captured (streaming).
+    pub events: Option<UnboundedSender<RunLog>>,
+
+    /// Secret values read by the run, redacted from everything it outputs.
+    /// Values shorter than MIN_SECRET_LEN are never redacted (runs never get them), since they would redact unrelated text.
+    pub secrets: Vec<String>,
+}
+impl RunLogs {
+    /// Create new shared run logs.
+    pub fn new(max_size: usize, events: Option<UnboundedSender<RunLog>>) -> Arc<Mutex<Self>> {
+        Arc::new(Mutex::new(Self {
+            max_size,
+            events,
+            ..Default::default()
+        }))
+    }
+
+    /// Push a log line.
+    /// Every line is streamed to the events (if any), but lines that would exceed the max size are not kept, and the logs are marked as truncated.
+    pub fn push(&mut self, stream: &'static str, message: String) {
+        let line = RunLog { stream, message: self.redact(&message) };
+        if let Some(events) = &self.events {
+            let _ = events.send(line.clone());
+        }
+        if self.truncated || self.size + line.message.len() > self.max_size {
+            self.truncated = true;
+            return;
+        }
+        self.size += line.message.len();
+        self.lines.push(line);
+    }
+
+    /// Redact secret values from text.
+    pub fn redact(&self, text: &str) -> String {
+        let mut text = text.to_owned();
+        for secret in self.secrets.iter().filter(|secret| secret.len() >= MIN_SECRET_LEN) {
+            text = text.replace(secret.as_str(), REDACTED);
+        }
+        text
+    }
+
+    /// Redact secret values from bytes.
+    pub fn redact_bytes(&self, bytes: &[u8]) -> Vec<u8> {
+        let mut bytes = bytes.to_vec();
+        for secret in self.secrets.iter().filter(|secret| secret.len() >= MIN_SECRET_LEN) {
+            let secret = secret.as_bytes();
+            let mut redacted = Vec::with_capacity(bytes.len());
+            let mut index = 0;
+            while index < bytes.len() {
+                if bytes[index..].starts_with(secret) {
+                    redacted.extend_from_slice(REDACTED.as_bytes());
+                    index += secret.len();
+                } else {
+                    redacted.push(bytes[index]);
+                    index += 1;
+                }
+            }
+            bytes = redacted;
+        }
+        bytes
+    }
+
+    /// Insert these logs into a document as a "logs" array field on the main root.
+    /// Each log is an object with a "stream" and a "message".
+    pub fn insert_into(&self, doc: &mut SDoc) {
+        if let Some(main) = doc.graph.main_root() {
+            let mut logs = Vec::new();
+            for (index, line) in self.lines.iter().enumerate() {
+                let nref = doc.graph.insert_node(&format!("log{}", index), Some(&main));
+                SField::new_string(&mut doc.graph, "stream", line.stream, &nref);
+                SField::new_string(&mut doc.graph, "message", &line.message, &nref);
+                logs.push(SVal::Object(nref));
+            }
+            SField::new_array(&mut doc.graph, "logs", logs, &main);
+            SField::new_bool(&mut doc.graph, "truncated", self.truncated, &main);
+        }
+    }
+
+    /// Insert these logs into response headers as an "x-run-logs" header.
+    /// The header is the JSON of the logs envelope ({"logs": [...], "truncated": false}), with non-ASCII characters escaped.
+    /// Proxies often limit header sizes, so keep the max log size small when using this.
+    pub fn insert_header(&self, headers: &mut HeaderMap) {
+        let mut doc = SDoc::default();
+        self.insert_into(&mut doc);
+        if let Ok(json) = doc.export_string("main", "json", None) {
+            let mut escaped = String::with_capacity(json.len());
+            for c in json.chars() {
+                if c.is_ascii() {
+                    escaped.push(c);
+                } else {
+                    let mut units = [0u16; 2];
+                    for unit in c.encode_utf16(&mut units) {
+                        escaped.push_str(&format!("\\u{:04x}", unit));
+                    }
+                }
+            }
+            if let Ok(value) = HeaderValue::from_str(&escaped) {
+                headers.insert("x-run-logs", value);
+            }
+        }
+    }
+}
+
+
+/// Standard library for runs.
+/// Captures printed output ("pln", "dbg", and "err") into the run logs instead of the server's stdout.
+pub struct RunStdLibrary {
+    pub std: StdLibrary,
+    pub logs: Arc<Mutex<RunLogs>>,
+}
+impl RunStdLibrary {
+    pub fn new(logs: Arc<Mutex<RunLogs>>) -> Self {
+        Self {
+            std: StdLibrary,
+            logs,
+        }
+    }
+
+    /// Format parameters the same way the standard library prints them.
+    fn format(doc: &mut SDoc, parameters: &[SVal], debug: bool) -> String {
+        let mut res = String::default();
+        for (i, param) in parameters.iter().enumerate() {
+            let print = if debug { param.debug(doc) } else { param.print(doc) };
+            match param.stype(&doc.graph) {
+                SType::String => {
+                    // Don't do any gaps for strings!
+                    res.push_str(&print);
+                },
+                _ => {
+                    if i > 0 {
+                        res.push_str(&format!(", {}", print));
+                    } else {
+                        res.push_str(&print);
+                    }
+                }
+            }
+        }
+        res
+    }
+}
+impl Library for RunStdLibrary {
+    fn scope(&self) -> String {
+        "std".to_string()
+    }
+
+    fn call(&self, pid: &str, doc: &mut SDoc, name: &str, parameters: &mut Vec<SVal>) -> Result<SVal, SError> {
+        match name {
+            "pln" => {
+                let message = Self::format(doc, parameters, false);
+                self.logs.lock().unwrap().push("out", message);
+                Ok(SVal::Void)
+            },
+            "dbg" => {
+                let message = Self::format(doc, parameters, true);
+                self.logs.lock().unwrap().push("out", message);
+                Ok(SVal::Void)
+            },
+            "err" => {
+                let message = Self::format(doc, parameters, false);
+                self.logs.lock().unwrap().push("err", message);
+                Ok(SVal::Void)
+            },
+            _ => {
+                self.std.call(pid, doc, name, parameters)
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderMap;
+    use tokio::sync::mpsc::unbounded_channel;
+    use super::RunLogs;
+
+    #[test]
+    fn redacts_secrets() {
+        let logs = RunLogs::new(1024, None);
+        let mut logs = logs.lock().unwrap();
+        logs.secrets = vec![String::from("s3cretkey"), String::from("abc"), String::default()];
+        assert_eq!(logs.redact("token s3cretkey, abc"), "token [REDACTED], abc");
+        assert_eq!(logs.redact_bytes(b"\xFFs3cretkeys3cretkey abc"), b"\xFF[REDACTED][REDACTED] abc".to_vec());
+
+        logs.push("out", String::from("key: s3cretkey"));
+        assert_eq!(logs.lines[0].message, "key: [REDACTED]");
+    }
+
+    #[test]
+    fn streams_past_max_size() {
+        let (sender, mut receiver) = unbounded_channel();
+        let logs = RunLogs::new(8, Some(sender));
+        let mut logs = logs.lock().unwrap();
+        logs.push("out", String::from("12345"));
+        logs.push("out", String::from("67890"));
+        logs.push("err", String::from("1"));
+
+        // Every line is streamed, but only the lines within the max size are kept
+        assert_eq!(logs.lines.len(), 1);
+        assert!(logs.truncated);
+        let mut streamed = Vec::new();
+        while let Ok(line) = receiver.try_recv() {
+            streamed.push(line.message);
+        }
+        assert_eq!(streamed, vec!["12345", "67890", "1"]);
+    }
+
+    #[test]
+    fn logs_header() {
+        let logs = RunLogs::new(1024, None);
+        let mut logs = logs.lock().unwrap();
+        logs.push("out", String::from("héllo"));
+        let mut headers = HeaderMap::new();
+        logs.insert_header(&mut headers);
+        let header = headers.get("x-run-logs").unwrap().to_str().unwrap();
+        assert!(header.contains(r#""message":"h\u00e9llo""#), "{}", header);
+        assert!(header.contains(r#""truncated":false"#), "{}", header);
+    }
+}
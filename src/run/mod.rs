@@ -14,152 +14,707 @@
 // limitations under the License.
 //
 
-use std::{collections::BTreeMap, sync::Arc, time::Duration};
-use axum::{extract::{Query, State}, http::{header::CONTENT_TYPE, HeaderMap, StatusCode}, response::IntoResponse};
+use std::{collections::{BTreeMap, BTreeSet}, sync::{Arc, Mutex}, time::Duration};
+use axum::{extract::{Query, State}, http::{header::{ACCEPT, CONTENT_TYPE}, HeaderMap, StatusCode}, response::{IntoResponse, Response}};
 use bytes::Bytes;
-use stof::{SDoc, SVal};
-use stof_http::HTTPLibrary;
-use tokio::time::timeout;
-use crate::{config::{opaque_errors, registry_path, run_enabled, run_timeout}, metrics::increment_server_run_count, registry::pkg::RPKG, response::StofResponse, server::ServerState, users::auth::auth_exec};
+use stof::{lang::SError, SDoc, SField, SNodeRef, SVal};
+use tokio::{runtime::Handle, sync::mpsc::UnboundedSender, task::spawn_blocking, time::timeout};
+use crate::{bus::{run_bus, BusAccess}, config::{cache_enabled, runs_record_failures, opaque_errors, sandbox_enabled, sandbox_required, registry_path, run_enabled, run_max_log_size, run_max_scratch_size, run_timeout}, kv::{run_kv, KvAccess}, libraries::{run_libraries, RunLibraries}, sql::{run_sql, SqlAccess}, metrics::increment_server_run_count, plugins::load_plugin_libraries, registry::pkg::RPKG, runs::RunRecord, response::StofResponse, sandbox::{run_sandboxed, SandboxPolicy, SandboxedLibrary, SANDBOXED_LIBRARIES}, server::ServerState, secrets::run_secrets, users::auth::{auth_exec, auth_user}, wasm::{WasmFormat, WasmPolicy}};
 mod sandbox_fs;
 use sandbox_fs::PFileSystemLibrary;
+pub(crate) mod logs;
+use logs::{RunLog, RunLogs, RunStdLibrary};
+mod runner_lib;
+use runner_lib::RunnerLibrary;
+mod stream;
+use stream::run_stof_sse;
+mod inputs;
+use inputs::{inject_inputs, parse_envelope, RunInputs};
+pub(crate) mod negotiate;
+pub(crate) mod scratch;
+use scratch::{RunFile, ScratchDir};
+mod multipart;
+use multipart::parse_multipart;
+pub(crate) mod ws;
+pub(crate) mod egress;
+use egress::{EgressHTTPLibrary, EgressPolicy};
+mod secrets_lib;
+use secrets_lib::{redact_document, SecretsLibrary};
+pub(crate) mod cache;
+use cache::{cache_key, package_hash};
+pub(crate) mod batch;
+pub(crate) mod kv_lib;
+use kv_lib::KvLibrary;
+mod sql_lib;
+use sql_lib::SqlLibrary;
+mod bus_lib;
+use bus_lib::BusLibrary;
+mod trace;
+use trace::{trace_document, RunTrace, TraceFormat};
+pub(crate) mod record;
+use record::{RecordedTimeLibrary, RunRecording};
+pub(crate) mod error;
+use error::{error_format, RunError, RunErrorCode};
+pub(crate) mod cancel;
+use cancel::RunCancel;
+
+
+/// Run options.
+#[derive(Clone)]
+pub(crate) struct RunOptions {
+    /// Content type for the incoming message body.
+    pub content_type: String,
+
+    /// Timeout for running the document.
+    pub timeout: Duration,
+
+    /// Hide specific error information from responses?
+    pub opaque_errors: bool,
+
+    /// Format for structured error bodies ("json" or "bstof"), or None for plain text errors.
+    pub error_format: Option<String>,
+
+    /// Format to export the resulting document in (default is "bstof").
+    pub export_format: String,
+
+    /// Path of the node to export (default is the whole document).
+    pub export_path: Option<String>,
+
+    /// Registry path.
+    pub registry_path: String,
+
+    /// Return the captured output in a structured envelope with the result?
+    pub logs: bool,
+
+    /// Return the captured output in an "x-run-logs" header instead, leaving the result as is?
+    pub logs_header: bool,
+
+    /// Max size of the captured output (bytes).
+    pub max_log_size: usize,
+
+    /// Registry package to import and run after the body document (Ex. "@scope/name").
+    pub package: Option<String>,
+
+    /// Inputs, injected into the "Input" root before the document executes.
+    pub inputs: RunInputs,
+
+    /// Files to mount into the run's scratch directory.
+    pub files: Vec<RunFile>,
+
+    /// Entry document within the files (imported in place of the body).
+    pub entry: Option<String>,
+
+    /// Max size of the run's scratch directory (bytes).
+    pub max_scratch_size: u64,
+
+    /// Return the scratch directory as a zip, along with the result?
+    pub scratch_zip: bool,
+
+    /// Egress policy for HTTP requests made by the document.
+    pub egress: EgressPolicy,
+
+    /// WebAssembly policy for modules imported by the document.
+    pub wasm: WasmPolicy,
+
+    /// Secrets that the document can read (name -> value).
+    pub secrets: BTreeMap<String, String>,
+
+    /// Key-value store access for the document.
+    pub kv: KvAccess,
+
+    /// Database access for the document.
+    pub sql: SqlAccess,
+
+    /// Message bus access for the document.
+    pub bus: BusAccess,
+
+    /// Record function call spans and return them (in this format) instead of the result?
+    pub trace: Option<TraceFormat>,
+
+    /// Recording of the HTTP responses and clock values the run sees (or the values to replay).
+    pub recording: Option<Arc<Mutex<RunRecording>>>,
+
+    /// Registry packages imported by the run ("scope/name"), filled in as it runs.
+    pub packages: Arc<Mutex<BTreeSet<String>>>,
+
+    /// Run in a sandbox process with this policy, instead of within the server?
+    /// Set from the config for every run when the sandbox is required.
+    pub sandbox: Option<SandboxPolicy>,
+
+    /// Running within a sandbox process? (kv, sql, and bus calls are errors, see SandboxedLibrary)
+    pub sandboxed: bool,
+
+    /// Libraries the document can use (others are removed before it's imported).
+    pub libraries: RunLibraries,
+}
+impl RunOptions {
+    /// Run options from the server configuration.
+    pub fn from_config(config: &mut SDoc) -> Self {
+        Self {
+            content_type: String::from("stof"),
+            timeout: run_timeout(config),
+            opaque_errors: opaque_errors(config),
+            error_format: None,
+            export_format: String::from("bstof"),
+            export_path: None,
+            registry_path: registry_path(config),
+            logs: false,
+            logs_header: false,
+            max_log_size: run_max_log_size(config),
+            package: None,
+            inputs: RunInputs::default(),
+            files: Vec::new(),
+            entry: None,
+            max_scratch_size: run_max_scratch_size(config),
+            scratch_zip: false,
+            egress: EgressPolicy::from_config(config),
+            wasm: WasmPolicy::from_config(config),
+            secrets: BTreeMap::new(),
+            kv: KvAccess::default(),
+            sql: SqlAccess::default(),
+            bus: BusAccess::default(),
+            trace: None,
+            recording: None,
+            packages: Default::default(),
+            sandbox: if sandbox_required(config) { Some(SandboxPolicy::from_config(config)) } else { None },
+            sandboxed: false,
+            libraries: RunLibraries::from_config(config),
+        }
+    }
+}
 
 
 /// Run API endpoint handler.
-pub(crate) async fn run_handler(State(state): State<ServerState>, Query(query): Query<BTreeMap<String, String>>, headers: HeaderMap, body: Bytes) -> impl IntoResponse {
+/// Use "?stream=sse" to stream output, progress, and the result as server-sent events.
+///
+/// The export format is given with "?export=", otherwise it is negotiated with the Accept header (default is "bstof").
+/// Use "?path=root.result" to export only part of the resulting document.
+///
+/// Inputs are given with "?input.<name>=value" query parameters, or with a JSON envelope body ("?envelope=true"):
+/// `{"document": "...", "format": "stof", "inputs": {...}}`.
+///
+/// Each run gets a scratch directory that the document can write to with the "fs" library.
+/// Use "?scratch=zip" to get a zip of the scratch directory and the result back.
+///
+/// Use "?cache=true" to get a cached result for the same run (same body, content type, export, inputs, packages, and caller access).
+/// The "X-Cache" header is "HIT" for a cached result and "MISS" otherwise.
+///
+/// Failing runs are recorded (if enabled), with the record ID in the "X-Run-Id" header, so that they can be replayed by the admin.
+///
+/// Errors have a stable code in the "X-Error-Code" header (Ex. "RUN_PARSE"). If errors are shown (server "errors" config),
+/// the body is a structured "error" object (code, status, kind, message, stack, function, and file/line/column for parse errors),
+/// exported as JSON, or BSTOF if the Accept header asks for Stof.
+///
+/// Use "?sandbox=true" to run the document in an isolated sandbox process (if enabled, or always if the sandbox is required).
+/// Sandboxed runs cannot use the kv, sql, or bus libraries, and are not traced or recorded.
+///
+/// Use "?trace=true" to get the function calls made by the document (name, start, duration, and depth) instead of the result,
+/// as Chrome trace-event JSON ("?trace=chrome", same as "true") or folded stacks for flame graphs ("?trace=folded").
+///
+/// Multi-file runs use a "multipart/form-data" body. The parts are mounted into a scratch directory for the run,
+/// with the entry document being the part named "document" or the file given by "?entry=".
+pub(crate) async fn run_handler(State(state): State<ServerState>, Query(query): Query<BTreeMap<String, String>>, headers: HeaderMap, mut body: Bytes) -> Response {
     if !auth_exec(&state, &headers).await {
-        return StofResponse::error(StatusCode::FORBIDDEN, "access denied");
+        return StofResponse::error(StatusCode::FORBIDDEN, "access denied").into_response();
     }
 
-    let opaque_stof_errors;
-    let run_time;
-    let registry;
+    let mut options;
     {
         let mut config = state.config.lock().await;
         if !run_enabled(&config) {
-            return StofResponse::error(StatusCode::NOT_IMPLEMENTED, "runner is not available");
+            return StofResponse::error(StatusCode::NOT_IMPLEMENTED, "runner is not available").into_response();
+        }
+        options = RunOptions::from_config(&mut config);
+
+        if query.get("sandbox").is_some_and(|sandbox| sandbox == "true") && !sandbox_enabled(&config) && !sandbox_required(&config) {
+            return StofResponse::error(StatusCode::NOT_IMPLEMENTED, "sandbox is not available").into_response();
+        }
+        // Sandboxed if requested (from_config already sandboxes every run when it's required)
+        if query.get("sandbox").is_some_and(|sandbox| sandbox == "true") {
+            options.sandbox = Some(SandboxPolicy::from_config(&config));
         }
-        opaque_stof_errors = opaque_errors(&config);
-        run_time = run_timeout(&mut config);
-        registry = registry_path(&config);
     }
 
-    let mut content_type = String::from("stof");
     if let Some(ctype) = headers.get(CONTENT_TYPE) {
-        content_type = ctype.to_str().unwrap().to_owned();
+        match ctype.to_str() {
+            Ok(ctype) => options.content_type = ctype.to_owned(),
+            Err(_) => return StofResponse::error(StatusCode::BAD_REQUEST, "invalid content type").into_response(),
+        }
     }
-
-    let mut export_format = String::from("bstof");
-    if let Some(format) = query.get("export") {
-        export_format = format.clone();
+    // The Accept header is for the stream itself when streaming
+    let accept = if query.contains_key("stream") { None } else { headers.get(ACCEPT) };
+    match state.formats.export_format(query.get("export"), accept, &options.export_format) {
+        Ok(format) => options.export_format = format,
+        Err(error) => return StofResponse::error(StatusCode::NOT_ACCEPTABLE, &error).into_response(),
+    }
+    if !options.opaque_errors {
+        options.error_format = Some(error_format(&headers));
+    }
+    if let Some(path) = query.get("path") {
+        options.export_path = Some(path.clone());
+    }
+    if let Some(logs) = query.get("logs") {
+        options.logs = logs == "true";
+        options.logs_header = logs == "header";
+    }
+    if let Some(scratch) = query.get("scratch") {
+        options.scratch_zip = scratch == "zip";
+    }
+    if let Some(trace) = query.get("trace") && trace != "false" {
+        match TraceFormat::from_query(trace) {
+            Some(format) => options.trace = Some(format),
+            None => return StofResponse::error(StatusCode::BAD_REQUEST, "unsupported trace format").into_response(),
+        }
+        if options.sandbox.is_some() {
+            return StofResponse::error(StatusCode::BAD_REQUEST, "sandboxed runs cannot be traced").into_response();
+        }
+    }
+    options.inputs = RunInputs::from_query(&query);
+    if options.content_type.starts_with("multipart/form-data") {
+        match parse_multipart(&options.content_type, body).await {
+            Ok(run) => {
+                options.entry = run.document;
+                if let Some(entry) = query.get("entry") {
+                    options.entry = Some(entry.clone());
+                }
+                if options.entry.is_none() {
+                    return StofResponse::error(StatusCode::BAD_REQUEST, "multipart runs require an entry document").into_response();
+                }
+                if !run.files.iter().any(|file| Some(&file.name) == options.entry.as_ref()) {
+                    return StofResponse::error(StatusCode::BAD_REQUEST, "entry document not found").into_response();
+                }
+                options.files = run.files;
+                options.inputs.json = run.inputs;
+                body = Bytes::default();
+            },
+            Err(message) => {
+                return StofResponse::error(StatusCode::BAD_REQUEST, &format!("error parsing multipart body: {}", message)).into_response();
+            }
+        }
+    }
+    if let Some(envelope) = query.get("envelope") && envelope == "true" {
+        match parse_envelope(body) {
+            Ok((format, document, inputs)) => {
+                options.content_type = format;
+                body = document;
+                options.inputs.json = inputs;
+            },
+            Err(message) => {
+                return StofResponse::error(StatusCode::BAD_REQUEST, &message).into_response();
+            }
+        }
     }
 
+    let user = auth_user(&state, &headers).await;
+    options.kv = run_kv(&state, &user, options.package.as_deref()).await;
+    options.sql = run_sql(&state, &user, options.package.as_deref()).await;
+    options.bus = run_bus(&state, &user, options.package.as_deref()).await;
+    options.secrets = run_secrets(&state, user.clone(), options.package.as_deref()).await;
+    options.libraries = run_libraries(&state, &user, options.package.as_deref()).await;
+
     // metrics
     {
         let mut metrics = state.metrics.lock().await;
         increment_server_run_count(&mut metrics);
     }
 
-    run_stof(&content_type, run_time, body, opaque_stof_errors, &export_format, &registry).await
+    if let Some(stream) = query.get("stream") {
+        if stream == "sse" {
+            // Result is sent as a text event, so default to JSON
+            if !query.contains_key("export") {
+                options.export_format = String::from("json");
+            }
+            return run_stof_sse(options, body).into_response();
+        }
+        return StofResponse::error(StatusCode::BAD_REQUEST, "unsupported stream type").into_response();
+    }
+
+    // Cached results (opt-in with "?cache=true")
+    let mut key = None;
+    if options.trace.is_none() && query.get("cache").is_some_and(|cache| cache == "true") {
+        let config = state.config.lock().await;
+        if cache_enabled(&config) {
+            key = Some(cache_key(&options, &user, &body));
+        }
+    }
+    if let Some(key) = &key {
+        let mut cache = state.cache.lock().await;
+        if let Some(mut response) = cache.get(key, &options.registry_path) {
+            response.headers.insert("x-cache", "HIT".parse().unwrap());
+            return response.into_response();
+        }
+    }
+
+    // Record the run, saved if it fails
+    let mut record = None;
+    if options.trace.is_none() && options.sandbox.is_none() {
+        let config = state.config.lock().await;
+        if runs_record_failures(&config) {
+            options.recording = Some(RunRecording::new(options.secrets.values().cloned().collect()));
+            record = Some(RunRecord::new(&options, &body, &user));
+        }
+    }
+
+    let packages = options.packages.clone();
+    let registry = options.registry_path.clone();
+    let recording = options.recording.clone();
+    let mut response = run_stof(options, body, None).await;
+    if let (Some(mut record), Some(recording)) = (record, recording) && !response.status.is_success() {
+        record.status = response.status.as_u16();
+        record.error = response.str_body.clone();
+        for package in packages.lock().unwrap().iter() {
+            record.packages.insert(package.clone(), package_hash(&registry, package));
+        }
+        {
+            let recording = recording.lock().unwrap();
+            record.http = recording.http.clone();
+            record.clock = recording.clock.clone();
+        }
+        let mut runs = state.runs.lock().await;
+        if runs.insert(&record) {
+            response.headers.insert("x-run-id", record.id.parse().unwrap());
+        }
+    }
+    if let Some(key) = key {
+        if response.status == StatusCode::OK {
+            let packages = packages.lock().unwrap().iter().cloned().collect();
+            let mut cache = state.cache.lock().await;
+            cache.insert(key, &response, &registry, packages);
+        }
+        response.headers.insert("x-cache", "MISS".parse().unwrap());
+    }
+    response.into_response()
 }
 
 
 /// Run some Stof.
 ///
-/// content_type: content type for the incoming message body.
-/// time: timeout for running this Stof.
-/// body: bytes of the incoming Stof document.
-/// opaque_errors: true if specific error information should be hidden from the response.
-/// export_format: default is "bstof", but the resulting document gets exported to this format for the response.
-async fn run_stof(content_type: &str, time: Duration, mut body: Bytes, opaque_errors: bool, export_format: &str, registry_path: &str) -> StofResponse {
-    let result = timeout(time, async move {
+/// options: content type, timeout, export format, etc. for this run.
+/// body: bytes of the incoming Stof document (imported before the package if one is given in the options).
+///
+/// events: optional channel to send output and progress to as it happens.
+///
+/// Output printed by the document is captured, and returned with the result if "logs" is requested ("?logs=true" for an envelope, "?logs=header" for an "x-run-logs" header).
+/// The document executes on a blocking thread, so that the timeout can be enforced while it runs.
+/// A run that times out is cancelled, but its thread can't be stopped, so it only ends at its next library call (see RunCancel).
+/// Runs with a sandbox policy execute in a sandbox process instead (see run_sandboxed).
+pub(crate) async fn run_stof(options: RunOptions, mut body: Bytes, events: Option<UnboundedSender<RunLog>>) -> StofResponse {
+    if let Some(policy) = options.sandbox.clone() {
+        return run_sandboxed(options, policy, body, events).await;
+    }
+
+    let logs = RunLogs::new(options.max_log_size, events);
+    let run_logs = logs.clone();
+    let run_options = options.clone();
+    let cancel = RunCancel::default();
+    let run_cancel = cancel.clone();
+    let handle = spawn_blocking(move || Handle::current().block_on(async move {
         let mut doc = SDoc::default();
-        initialize_document(&mut doc, registry_path).await;
-        let res = doc.header_import("main", &content_type, &content_type, &mut body, "");
+        initialize_document(&mut doc, &run_options.registry_path, &run_options.egress, &run_options.wasm, &run_options.kv, &run_options.sql, &run_options.bus).await;
+        doc.load_format(Arc::new(RPKG::tracked(&run_options.registry_path, run_options.packages.clone())));
+        doc.load_lib(Arc::new(RunStdLibrary::new(run_logs.clone())));
+        if run_options.sandboxed {
+            for (scope, error) in SANDBOXED_LIBRARIES {
+                doc.load_lib(Arc::new(SandboxedLibrary { scope, error }));
+            }
+        }
+        if let Some(recording) = &run_options.recording {
+            doc.load_lib(Arc::new(EgressHTTPLibrary::recorded(run_options.egress.clone(), recording.clone())));
+            doc.load_lib(Arc::new(RecordedTimeLibrary::new(recording.clone())));
+        }
+
+        // Scratch directory for the run, with any files mounted into it (removed when the run finishes)
+        let scratch = match ScratchDir::new() {
+            Ok(dir) => dir,
+            Err(message) => {
+                return run_error(&run_options, &run_logs, RunError::new(RunErrorCode::Scratch, StatusCode::INTERNAL_SERVER_ERROR, &message));
+            }
+        };
+        if let Err(message) = scratch.write_files(&run_options.files, run_options.max_scratch_size) {
+            return run_error(&run_options, &run_logs, RunError::new(RunErrorCode::Files, StatusCode::BAD_REQUEST, &message));
+        }
+        doc.load_lib(Arc::new(PFileSystemLibrary::with_scratch(&run_options.registry_path, &scratch.path_str(), run_options.max_scratch_size)));
+        doc.load_lib(Arc::new(RunnerLibrary::new(run_logs.clone(), None, Some(scratch.path_str()))));
+        doc.load_lib(Arc::new(SecretsLibrary::new(run_options.secrets.clone(), run_logs.clone())));
+        run_options.libraries.restrict(&mut doc);
+
+        let mut res = Ok(());
+        let mut error_code = RunErrorCode::Parse;
+        let mut error_file = run_options.entry.clone();
+        if let Some(entry) = &run_options.entry {
+            // Entry format is the file extension if it's a loaded format, otherwise stof
+            let mut format = entry.split('.').next_back().unwrap_or_default().to_owned();
+            if !entry.contains('.') || doc.formats.get(&format).is_none() {
+                format = String::from("stof");
+            }
+            res = doc.file_import("main", &format, &scratch.file_path(entry), &format, "");
+        } else if !body.is_empty() {
+            res = doc.header_import("main", &run_options.content_type, &run_options.content_type, &mut body, "");
+        }
+        if res.is_ok() && let Some(package) = &run_options.package {
+            error_code = RunErrorCode::Package;
+            error_file = Some(package.clone());
+            res = import_package(&mut doc, package);
+        }
+        if res.is_ok() && let Err(error) = inject_inputs(&mut doc, &run_options.inputs) {
+            if !run_options.opaque_errors {
+                return run_error(&run_options, &run_logs, RunError::stof(RunErrorCode::Inputs, StatusCode::BAD_REQUEST, &doc, &error));
+            }
+            return run_error(&run_options, &run_logs, RunError::new(RunErrorCode::Inputs, StatusCode::BAD_REQUEST, "invalid inputs"));
+        }
+        let mut trace = None;
+        if res.is_ok() && run_options.trace.is_some() {
+            let run_trace = RunTrace::new();
+            trace_document(&mut doc, run_trace.clone());
+            trace = Some(run_trace);
+        }
         match res {
             Ok(_) => {
-                // Execute the main root as a task
-                if let Some(main) = doc.graph.main_root() {
-                    if let Some(lib) = doc.libraries.get("Object") {
-                        let res = lib.call("main", &mut doc, "exec", &mut vec![SVal::Object(main)]);
-                        match res {
-                            Ok(_) => {
-                                // Nothing to do here...
-                            },
-                            Err(res) => {
-                                if !opaque_errors {
-                                    return StofResponse::error(StatusCode::BAD_REQUEST, &res.to_string(&doc.graph));
-                                }
-                                return StofResponse::error(StatusCode::BAD_REQUEST, "error executing document");
-                            },
-                        }
-                    }
+                run_cancel.guard(&mut doc);
+                if let Err(error) = execute_document(&mut doc, run_options.opaque_errors) {
+                    return run_error(&run_options, &run_logs, *error);
                 }
-
-                // Run the remote functions in this document
-                let res = doc.run(None, Some("remote".into()));
-                match res {
-                    Ok(_) => {
-                        // Nothing to do here...
-                    },
-                    Err(res) => {
-                        if !opaque_errors {
-                            return StofResponse::error(StatusCode::BAD_REQUEST, &res);
-                        }
-                        return StofResponse::error(StatusCode::BAD_REQUEST, "error running document");
-                    },
+                if let (Some(trace), Some(format)) = (&trace, run_options.trace) {
+                    return trace.lock().unwrap().response(format);
                 }
             },
             Err(error) => {
-                if !opaque_errors {
-                    return StofResponse::error(StatusCode::BAD_REQUEST, &error.to_string(&doc.graph));
+                if !run_options.opaque_errors {
+                    return run_error(&run_options, &run_logs, RunError::stof(error_code, StatusCode::BAD_REQUEST, &doc, &error).in_file(error_file.as_deref()));
                 }
-                return StofResponse::error(StatusCode::BAD_REQUEST, "error parsing document");
+                return run_error(&run_options, &run_logs, RunError::new(error_code, StatusCode::BAD_REQUEST, "error parsing document"));
             },
         }
 
-        if export_format != "bstof" {
-            if let Ok(text) = doc.export_string("main", export_format, None) {
-                if let Some(format) = doc.formats.get(export_format) {
+        // Secrets that were read never leave the run
+        redact_document(&mut doc, &run_logs.lock().unwrap());
+
+        let mut export_node = None;
+        if let Some(path) = &run_options.export_path {
+            export_node = doc.graph.node_ref(&path.replace('.', "/"), None);
+            if export_node.is_none() {
+                return run_error(&run_options, &run_logs, RunError::new(RunErrorCode::ExportPath, StatusCode::NOT_FOUND, "export path not found"));
+            }
+        }
+
+        let response = if run_options.logs {
+            let mut envelope = SDoc::default();
+            if let Ok(mut bytes) = doc.export_bytes("main", "bstof", export_node.as_ref()) && envelope.header_import("main", "bstof", "bstof", &mut bytes, "root.result").is_err() {
+                return run_error(&run_options, &run_logs, RunError::new(RunErrorCode::Export, StatusCode::INTERNAL_SERVER_ERROR, "error exporting document"));
+            }
+            run_logs.lock().unwrap().insert_into(&mut envelope);
+            export_document(&envelope, &run_options.export_format)
+        } else {
+            export(&doc, &run_options.export_format, export_node.as_ref())
+        };
+        if response.status != StatusCode::OK {
+            return run_error(&run_options, &run_logs, RunError::new(RunErrorCode::Export, response.status, &response.str_body));
+        }
+
+        if run_options.scratch_zip {
+            let result_name = format!("result.{}", run_options.export_format);
+            let result = response.bytes_body.unwrap_or(Bytes::from(response.str_body));
+            let zip_res;
+            {
+                let logs = run_logs.lock().unwrap();
+                zip_res = scratch.zip(&result_name, &result, |contents| logs.redact_bytes(contents));
+            }
+            return match zip_res {
+                Ok(zip) => {
                     let mut headers = HeaderMap::new();
-                    headers.insert(CONTENT_TYPE, format.content_type().parse().unwrap());
-                    return StofResponse {
+                    headers.insert(CONTENT_TYPE, "application/zip".parse().unwrap());
+                    StofResponse {
                         headers,
                         status: StatusCode::OK,
-                        str_body: text,
-                        bytes_body: None,
-                    };
+                        str_body: String::default(),
+                        bytes_body: Some(Bytes::from(zip)),
+                    }
+                },
+                Err(message) => {
+                    run_error(&run_options, &run_logs, RunError::new(RunErrorCode::ScratchZip, StatusCode::INTERNAL_SERVER_ERROR, &message))
                 }
+            };
+        }
+        response
+    }));
+
+    match timeout(options.timeout, handle).await {
+        Ok(Ok(mut res)) => {
+            if options.logs_header {
+                logs.lock().unwrap().insert_header(&mut res.headers);
             }
+            res
+        },
+        Ok(Err(_)) => {
+            run_error(&options, &logs, RunError::new(RunErrorCode::Internal, StatusCode::INTERNAL_SERVER_ERROR, "error running document"))
+        },
+        Err(_) => {
+            // The document is cancelled and left to end on its own thread, and nothing more gets streamed from it
+            cancel.cancel();
+            logs.lock().unwrap().events = None;
+            run_error(&options, &logs, RunError::new(RunErrorCode::Timeout, StatusCode::REQUEST_TIMEOUT, "timeout while running document"))
         }
+    }
+}
 
-        if let Ok(bytes) = doc.export_bytes("main", "bstof", None) {
-            return StofResponse::bstof(StatusCode::OK, bytes);
+
+/// Execute a document that was just imported.
+/// Executes the main root as a task, then runs the remote functions in the document.
+/// Returns the error if something goes wrong.
+pub(crate) fn execute_document(doc: &mut SDoc, opaque_errors: bool) -> Result<(), Box<RunError>> {
+    // Execute the main root as a task
+    if let Some(main) = doc.graph.main_root() && let Some(lib) = doc.libraries.get("Object") {
+        let res = lib.call("main", doc, "exec", &mut vec![SVal::Object(main)]);
+        match res {
+            Ok(_) => {
+                // Nothing to do here...
+            },
+            Err(res) => {
+                if !opaque_errors {
+                    return Err(Box::new(RunError::stof(RunErrorCode::Execute, StatusCode::BAD_REQUEST, doc, &res)));
+                }
+                return Err(Box::new(RunError::new(RunErrorCode::Execute, StatusCode::BAD_REQUEST, "error executing document")));
+            },
         }
-        StofResponse::error(StatusCode::INTERNAL_SERVER_ERROR, "error exporting document")
-    }).await;
+    }
 
-    match result {
-        Ok(res) => {
-            res
+    // Run the remote functions in this document
+    // Stof only gives back the combined message for these, so they are not broken into their parts
+    let res = doc.run(None, Some("remote".into()));
+    match res {
+        Ok(_) => {
+            // Nothing to do here...
         },
-        Err(_) => {
-            StofResponse::error(StatusCode::REQUEST_TIMEOUT, "timeout while running document")
+        Err(res) => {
+            if !opaque_errors {
+                let mut error = RunError::new(RunErrorCode::Execute, StatusCode::BAD_REQUEST, &res);
+                error.kind = String::from("RemoteError");
+                return Err(Box::new(error));
+            }
+            return Err(Box::new(RunError::new(RunErrorCode::Execute, StatusCode::BAD_REQUEST, "error running document")));
+        },
+    }
+    Ok(())
+}
+
+
+/// Run error response.
+/// If logs were requested, the error is returned within an envelope containing the captured output.
+/// Structured errors are returned as an "error" object if the options have an error format, otherwise the body is the plain text error.
+pub(crate) fn run_error(options: &RunOptions, logs: &Arc<Mutex<RunLogs>>, mut error: RunError) -> StofResponse {
+    {
+        let logs = logs.lock().unwrap();
+        error.text = logs.redact(&error.text);
+        error.message = logs.redact(&error.message);
+    }
+    let mut response;
+    if options.logs {
+        let mut envelope = SDoc::default();
+        if options.error_format.is_some() {
+            error.insert_into(&mut envelope);
+        } else if let Some(main) = envelope.graph.main_root() {
+            SField::new_string(&mut envelope.graph, "error", &error.text, &main);
         }
+        logs.lock().unwrap().insert_into(&mut envelope);
+        response = export_document(&envelope, &options.export_format);
+        response.status = error.status;
+    } else if let Some(format) = &options.error_format {
+        let mut doc = SDoc::default();
+        error.insert_into(&mut doc);
+        response = export_document(&doc, format);
+        response.status = error.status;
+    } else {
+        response = StofResponse::error(error.status, &error.text);
+    }
+    if options.logs_header {
+        logs.lock().unwrap().insert_header(&mut response.headers);
     }
-} 
+    response.headers.insert("x-error-code", error.code.code().parse().unwrap());
+    response
+}
+
+
+/// Export a document into a response.
+pub(crate) fn export_document(doc: &SDoc, export_format: &str) -> StofResponse {
+    export(doc, export_format, None)
+}
+
+
+/// Export a node of a document into a response.
+pub(crate) fn export_node(doc: &SDoc, export_format: &str, node: &SNodeRef) -> StofResponse {
+    export(doc, export_format, Some(node))
+}
+
+
+/// Export a document (or node) into a response.
+/// Formats that are not loaded (or that cannot export) are not acceptable (406).
+fn export(doc: &SDoc, export_format: &str, node: Option<&SNodeRef>) -> StofResponse {
+    if export_format == "bstof" {
+        if let Ok(bytes) = doc.export_bytes("main", "bstof", node) {
+            return StofResponse::bstof(StatusCode::OK, bytes);
+        }
+        return StofResponse::error(StatusCode::INTERNAL_SERVER_ERROR, "error exporting document");
+    }
+    if let Some(format) = doc.formats.get(export_format) {
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_TYPE, format.content_type().parse().unwrap());
+        if let Ok(text) = doc.export_string("main", export_format, node) {
+            return StofResponse {
+                headers,
+                status: StatusCode::OK,
+                str_body: text,
+                bytes_body: None,
+            };
+        }
+        if let Ok(bytes) = doc.export_bytes("main", export_format, node) {
+            return StofResponse {
+                headers,
+                status: StatusCode::OK,
+                str_body: String::default(),
+                bytes_body: Some(bytes),
+            };
+        }
+        return StofResponse::error(StatusCode::NOT_ACCEPTABLE, &format!("cannot export as {}", export_format));
+    }
+    StofResponse::error(StatusCode::NOT_ACCEPTABLE, &format!("{} is not an available export format", export_format))
+}
 
 
 /// Initialize document.
 /// Load additional libraries, etc.
-async fn initialize_document(doc: &mut SDoc, registry_path: &str) {
+pub(crate) async fn initialize_document(doc: &mut SDoc, registry_path: &str, egress: &EgressPolicy, wasm: &WasmPolicy, kv: &KvAccess, sql: &SqlAccess, bus: &BusAccess) {
+    // Add plugin libraries and formats first, so that the runner's own always take precedence
+    load_plugin_libraries(doc);
+
     // Replace the fs library with one that only has read access to the registry
     doc.load_lib(Arc::new(PFileSystemLibrary::new(registry_path)));
 
-    // Add HTTP library
-    doc.load_lib(Arc::new(HTTPLibrary::default()));
+    // Add HTTP library, restricted by the egress policy
+    doc.load_lib(Arc::new(EgressHTTPLibrary::new(egress.clone())));
+
+    // Add key-value library (namespaced by the user or package scope of the run)
+    doc.load_lib(Arc::new(KvLibrary::new(kv.clone())));
+
+    // Add SQL library (databases per scope, checked against the user or package scope of the run)
+    doc.load_lib(Arc::new(SqlLibrary::new(sql.clone())));
+
+    // Add bus library (publishing messages to subscribed packages)
+    doc.load_lib(Arc::new(BusLibrary::new(bus.clone())));
 
     // Add the Registry PKG format in place of the normal PKG format
     // This enables users to load packages from this registry using the familiar "import pkg '@hello/hello'" format
     doc.load_format(Arc::new(RPKG::new(registry_path)));
+
+    // Add the WebAssembly format, so that packages can include modules that are imported as libraries
+    if wasm.enabled {
+        doc.load_format(Arc::new(WasmFormat::new(registry_path, wasm.clone())));
+    }
+}
+
+
+/// Import a registry package into a document.
+/// Same as "import pkg '@scope/name'" from within Stof (requires the RPKG format from initialize_document).
+pub(crate) fn import_package(doc: &mut SDoc, package: &str) -> Result<(), SError> {
+    let import_path = format!("__stof__/{}.stof", package.trim_start_matches('@').trim_end_matches(".pkg"));
+    doc.file_import("main", "pkg", &import_path, "stof", "")
 }
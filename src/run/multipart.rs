@@ -0,0 +1,88 @@
+//
+// Copyright 2024 Formata, Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+use std::convert::Infallible;
+use bytes::Bytes;
+use multer::{parse_boundary, Multipart};
+use super::scratch::{file_name, RunFile};
+
+
+/// Parsed multipart run body.
+pub(crate) struct MultipartRun {
+    /// Files to mount into the run's scratch directory.
+    pub files: Vec<RunFile>,
+
+    /// File name of the part named "document" (the entry document), if any.
+    pub document: Option<String>,
+
+    /// JSON inputs from a part named "inputs", if any.
+    pub inputs: Option<Bytes>,
+}
+
+
+/// Parse a "multipart/form-data" run body.
+///
+/// Each part is a file, named by its file name (or its field name if it doesn't have one).
+/// The part named "document" is the entry document, and a part named "inputs" is a JSON object of inputs (not mounted as a file).
+pub(crate) async fn parse_multipart(content_type: &str, body: Bytes) -> Result<MultipartRun, String> {
+    
+    let boundary = match parse_boundary(content_type) {
+        Ok(value) => value,
+        Err(error) => return Err(error.to_string()),
+    };
+
+    let mut run = MultipartRun {
+        files: Vec::new(),
+        document: None,
+        inputs: None,
+    };
+    let mut multipart = Multipart::new(tokio_stream::once(Ok::<Bytes, Infallible>(body)), boundary);
+    loop {
+        match multipart.next_field().await {
+            Ok(Some(field)) => {
+                let field_name = field.name().unwrap_or_default().to_owned();
+                let mut name = file_name(field.file_name().unwrap_or_default());
+                if name.is_empty() {
+                    name = file_name(&field_name);
+                }
+                
+                let bytes = match field.bytes().await {
+                    Ok(value) => value,
+                    Err(error) => return Err(error.to_string()),
+                };
+
+                if field_name == "inputs" {
+                    run.inputs = Some(bytes);
+                    continue;
+                }
+                if name.is_empty() {
+                    return Err("multipart parts require a name".into());
+                }
+                if field_name == "document" {
+                    run.document = Some(name.clone());
+                }
+                run.files.push(RunFile { name, bytes });
+            },
+            Ok(None) => {
+                break;
+            },
+            Err(error) => {
+                return Err(error.to_string());
+            }
+        }
+    }
+    Ok(run)
+}
@@ -0,0 +1,177 @@
+//
+// Copyright 2024 Formata, Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+use std::collections::{BTreeMap, BTreeSet};
+use axum::http::HeaderValue;
+use stof::SDoc;
+use super::{initialize_document, RunOptions};
+
+
+/// Media types that map to a format, in addition to each format's own content type.
+const MEDIA_ALIASES: [(&str, &str); 6] = [
+    ("application/toml", "toml"),
+    ("text/yaml", "yaml"),
+    ("application/x-yaml", "yaml"),
+    ("text/stof", "stof"),
+    ("application/octet-stream", "bstof"),
+    ("text/json", "json"),
+];
+
+
+/// Formats that runs can import and export.
+/// Found once at startup from an initialized (empty) document, so requests don't have to initialize one to check a format.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct RunFormats {
+    /// Formats that documents can be imported with.
+    pub imports: BTreeSet<String>,
+
+    /// Formats that documents can be exported to (format -> content type).
+    pub exports: BTreeMap<String, String>,
+}
+impl RunFormats {
+    /// Formats of a run with these options (registry, plugins, and WebAssembly formats included).
+    pub async fn new(options: &RunOptions) -> Self {
+        let mut doc = SDoc::default();
+        initialize_document(&mut doc, &options.registry_path, &options.egress, &options.wasm, &Default::default(), &Default::default(), &Default::default()).await;
+        let mut formats = Self::default();
+        for (name, format) in &doc.formats.formats {
+            formats.imports.insert(name.clone());
+            if name == "bstof" || doc.export_string("main", name, None).is_ok() || doc.export_bytes("main", name, None).is_ok() {
+                formats.exports.insert(name.clone(), format.content_type());
+            }
+        }
+        formats.exports.entry(String::from("bstof")).or_insert(String::from("application/bstof"));
+        formats
+    }
+
+    /// Export format for a request, from the "export" query or the Accept header (default if neither is given).
+    /// Errors if the format can't be exported (406).
+    pub fn export_format(&self, export: Option<&String>, accept: Option<&HeaderValue>, default: &str) -> Result<String, String> {
+        if let Some(format) = export {
+            if !self.exports.contains_key(format) {
+                return Err(format!("{} is not an available export format", format));
+            }
+            return Ok(format.clone());
+        }
+        if let Some(accept) = accept {
+            return match negotiate_format(&self.exports, accept.to_str().unwrap_or_default(), default) {
+                Some(format) => Ok(format),
+                None => Err(String::from("no export format matches the accept header")),
+            };
+        }
+        Ok(default.to_owned())
+    }
+}
+
+
+/// Negotiate an export format from an Accept header.
+///
+/// Media ranges are tried in order of quality. Each can be a content type ("application/json"), a format name ("json"), or a wildcard.
+/// Wildcards ("*/*" or "text/*") pick the default format if it matches, otherwise the first export format that does.
+/// Returns None if no export format matches (406).
+///
+/// formats: export formats (format -> content type).
+pub(crate) fn negotiate_format(formats: &BTreeMap<String, String>, accept: &str, default: &str) -> Option<String> {
+    let mut ranges = Vec::new();
+    for (index, range) in accept.split(',').enumerate() {
+        let mut parts = range.split(';');
+        let media = parts.next().unwrap_or_default().trim().to_lowercase();
+        if media.is_empty() {
+            continue;
+        }
+        let mut quality = 1.0;
+        for param in parts {
+            if let Some(q) = param.trim().strip_prefix("q=") {
+                quality = q.trim().parse::<f32>().unwrap_or(0.0);
+            }
+        }
+        if quality > 0.0 {
+            ranges.push((quality, index, media));
+        }
+    }
+    if ranges.is_empty() {
+        return Some(default.to_owned());
+    }
+    ranges.sort_by(|a, b| b.0.total_cmp(&a.0).then(a.1.cmp(&b.1)));
+
+    for (_, _, media) in ranges {
+        if let Some(prefix) = media.strip_suffix("/*") {
+            if prefix == "*" || formats.get(default).is_some_and(|ctype| ctype.starts_with(prefix)) {
+                return Some(default.to_owned());
+            }
+            for (name, ctype) in formats {
+                if ctype.starts_with(prefix) {
+                    return Some(name.clone());
+                }
+            }
+            continue;
+        }
+        if formats.contains_key(&media) {
+            return Some(media);
+        }
+        for (alias, name) in MEDIA_ALIASES {
+            if media == alias && formats.contains_key(name) {
+                return Some(name.to_owned());
+            }
+        }
+        for (name, ctype) in formats {
+            if *ctype == media {
+                return Some(name.clone());
+            }
+        }
+    }
+    None
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use axum::http::HeaderValue;
+    use super::{negotiate_format, RunFormats};
+
+    fn formats() -> RunFormats {
+        RunFormats {
+            imports: Default::default(),
+            exports: BTreeMap::from([
+                (String::from("json"), String::from("application/json")),
+                (String::from("toml"), String::from("text/toml")),
+                (String::from("bstof"), String::from("application/bstof")),
+            ]),
+        }
+    }
+
+    #[test]
+    fn accept_header() {
+        let formats = formats();
+        assert_eq!(negotiate_format(&formats.exports, "application/json", "json").as_deref(), Some("json"));
+        assert_eq!(negotiate_format(&formats.exports, "application/toml", "json").as_deref(), Some("toml"));
+        assert_eq!(negotiate_format(&formats.exports, "text/*", "json").as_deref(), Some("toml"));
+        assert_eq!(negotiate_format(&formats.exports, "*/*", "json").as_deref(), Some("json"));
+        assert_eq!(negotiate_format(&formats.exports, "text/toml;q=0.5, application/bstof", "json").as_deref(), Some("bstof"));
+        assert_eq!(negotiate_format(&formats.exports, "image/png", "json"), None);
+    }
+
+    #[test]
+    fn export_format() {
+        let formats = formats();
+        assert_eq!(formats.export_format(None, None, "json").unwrap(), "json");
+        assert_eq!(formats.export_format(Some(&String::from("toml")), Some(&HeaderValue::from_static("application/json")), "json").unwrap(), "toml");
+        assert!(formats.export_format(Some(&String::from("bogus")), None, "json").is_err());
+        assert_eq!(formats.export_format(None, Some(&HeaderValue::from_static("text/toml")), "json").unwrap(), "toml");
+        assert!(formats.export_format(None, Some(&HeaderValue::from_static("image/png")), "json").is_err());
+    }
+}
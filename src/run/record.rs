@@ -0,0 +1,232 @@
+//
+// Copyright 2024 Formata, Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+use std::sync::{Arc, Mutex};
+use stof::{lang::SError, Library, SDoc, SVal, TimeLibrary};
+use crate::secrets::MIN_SECRET_LEN;
+use super::logs::REDACTED;
+
+
+/// Nondeterministic values seen by a run (HTTP responses and clock reads).
+/// When recording, values are added as the run makes the calls. When replaying, the recorded values are returned
+/// in the same order instead of making the calls, so a run can be executed again exactly as it happened.
+///
+/// Secret values that the run can read are redacted from recorded URLs and responses, so records never hold them.
+/// A replay sees the redacted responses, and its URLs are redacted the same way before they're compared with the recorded ones.
+#[derive(Default)]
+pub struct RunRecording {
+    /// Replaying recorded values?
+    pub replay: bool,
+
+    /// Secret values to redact from recorded URLs and responses.
+    pub secrets: Vec<String>,
+
+    /// HTTP calls: (function, url, success, response tuple or error message).
+    pub http: Vec<SVal>,
+
+    /// Time calls: (function, result).
+    pub clock: Vec<SVal>,
+
+    http_index: usize,
+    clock_index: usize,
+}
+impl RunRecording {
+    /// Create a new shared recording, redacting secret values.
+    pub fn new(secrets: Vec<String>) -> Arc<Mutex<Self>> {
+        Arc::new(Mutex::new(Self {
+            secrets,
+            ..Default::default()
+        }))
+    }
+
+    /// Create a new shared recording that replays recorded values (redacting secret values from URLs to compare).
+    pub fn replay(http: Vec<SVal>, clock: Vec<SVal>, secrets: Vec<String>) -> Arc<Mutex<Self>> {
+        Arc::new(Mutex::new(Self {
+            replay: true,
+            secrets,
+            http,
+            clock,
+            ..Default::default()
+        }))
+    }
+
+    /// Redact secret values from text.
+    fn redact_str(&self, text: &str) -> String {
+        let mut text = text.to_owned();
+        for secret in self.secrets.iter().filter(|secret| secret.len() >= MIN_SECRET_LEN) {
+            text = text.replace(secret.as_str(), REDACTED);
+        }
+        text
+    }
+
+    /// Redact secret values from a value (strings and text blobs, within tuples, arrays, and maps).
+    fn redact(&self, value: &SVal) -> SVal {
+        match value {
+            SVal::String(text) => SVal::String(self.redact_str(text)),
+            SVal::Blob(bytes) => match std::str::from_utf8(bytes) {
+                Ok(text) => SVal::Blob(self.redact_str(text).into_bytes()),
+                Err(_) => value.clone(),
+            },
+            SVal::Tuple(values) => SVal::Tuple(values.iter().map(|value| self.redact(value)).collect()),
+            SVal::Array(values) => SVal::Array(values.iter().map(|value| self.redact(value)).collect()),
+            SVal::Map(values) => SVal::Map(values.iter().map(|(key, value)| (self.redact(key), self.redact(value))).collect()),
+            _ => value.clone(),
+        }
+    }
+
+    /// Record an HTTP call result (with secret values redacted).
+    pub fn record_http(&mut self, name: &str, url: &str, res: &Result<SVal, String>) {
+        let (success, value) = match res {
+            Ok(value) => (true, self.redact(value)),
+            Err(message) => (false, SVal::String(self.redact_str(message))),
+        };
+        self.http.push(SVal::Tuple(vec![name.into(), self.redact_str(url).into(), SVal::Bool(success), value]));
+    }
+
+    /// Next recorded HTTP call result, which must be for the same function and url (compared with secret values redacted).
+    pub fn replay_http(&mut self, name: &str, url: &str) -> Result<Result<SVal, String>, String> {
+        let url = self.redact_str(url);
+        let url = url.as_str();
+        let recorded = self.http.get(self.http_index).cloned();
+        self.http_index += 1;
+        if let Some(SVal::Tuple(values)) = recorded && let [SVal::String(rec_name), SVal::String(rec_url), SVal::Bool(success), value] = values.as_slice() {
+            if rec_name != name || rec_url != url {
+                return Err(format!("replay diverged: expected HTTP.{}('{}'), got HTTP.{}('{}')", rec_name, rec_url, name, url));
+            }
+            if *success {
+                return Ok(Ok(value.clone()));
+            }
+            return Ok(Err(value.to_string()));
+        }
+        Err(format!("replay diverged: HTTP.{}('{}') was not made by the recorded run", name, url))
+    }
+
+    /// Record a time call result.
+    pub fn record_clock(&mut self, name: &str, value: &SVal) {
+        self.clock.push(SVal::Tuple(vec![name.into(), value.clone()]));
+    }
+
+    /// Next recorded time call result, which must be for the same function.
+    pub fn replay_clock(&mut self, name: &str) -> Result<SVal, String> {
+        let recorded = self.clock.get(self.clock_index).cloned();
+        self.clock_index += 1;
+        if let Some(SVal::Tuple(values)) = recorded && let [SVal::String(rec_name), value] = values.as_slice() {
+            if rec_name != name {
+                return Err(format!("replay diverged: expected Time.{}, got Time.{}", rec_name, name));
+            }
+            return Ok(value.clone());
+        }
+        Err(format!("replay diverged: Time.{} was not called by the recorded run", name))
+    }
+}
+
+
+/// Time library that records (or replays) the values read from the clock.
+pub struct RecordedTimeLibrary {
+    pub time: TimeLibrary,
+    pub recording: Arc<Mutex<RunRecording>>,
+}
+impl RecordedTimeLibrary {
+    pub fn new(recording: Arc<Mutex<RunRecording>>) -> Self {
+        Self {
+            time: TimeLibrary,
+            recording,
+        }
+    }
+}
+impl Library for RecordedTimeLibrary {
+    fn scope(&self) -> String {
+        "Time".to_string()
+    }
+
+    fn call(&self, pid: &str, doc: &mut SDoc, name: &str, parameters: &mut Vec<SVal>) -> Result<SVal, SError> {
+        match name {
+            "now" |
+            "nowNano" |
+            "diff" |
+            "diffNano" => {
+                let replay = self.recording.lock().unwrap().replay;
+                if replay {
+                    let res = self.recording.lock().unwrap().replay_clock(name);
+                    return match res {
+                        Ok(value) => Ok(value),
+                        Err(message) => Err(SError::custom(pid, doc, "ReplayError", &message)),
+                    };
+                }
+                let value = self.time.call(pid, doc, name, parameters)?;
+                self.recording.lock().unwrap().record_clock(name, &value);
+                Ok(value)
+            },
+            _ => {
+                self.time.call(pid, doc, name, parameters)
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use stof::SVal;
+    use super::RunRecording;
+
+    #[test]
+    fn short_secrets_not_redacted() {
+        let recording = RunRecording::new(vec![String::from("abc")]);
+        let mut recording = recording.lock().unwrap();
+        recording.record_http("get", "https://abc.example.com", &Err(String::from("abc")));
+        assert_eq!(recording.http, vec![SVal::Tuple(vec!["get".into(), "https://abc.example.com".into(), SVal::Bool(false), SVal::String(String::from("abc"))])]);
+    }
+
+    #[test]
+    fn record_redacts_secrets() {
+        let recording = RunRecording::new(vec![String::from("s3cretkey"), String::default()]);
+        let mut recording = recording.lock().unwrap();
+        let response = SVal::Tuple(vec![SVal::String(String::from("token s3cretkey")), SVal::Blob(b"body s3cretkey".to_vec()), SVal::Blob(vec![0xFF, 0xFE])]);
+        recording.record_http("get", "https://api.example.com/?key=s3cretkey", &Ok(response));
+        recording.record_http("get", "https://s3cretkey.example.com", &Err(String::from("failed: s3cretkey")));
+
+        let expected = vec![
+            SVal::Tuple(vec!["get".into(), "https://api.example.com/?key=[REDACTED]".into(), SVal::Bool(true), SVal::Tuple(vec![
+                SVal::String(String::from("token [REDACTED]")),
+                SVal::Blob(b"body [REDACTED]".to_vec()),
+                SVal::Blob(vec![0xFF, 0xFE]),
+            ])]),
+            SVal::Tuple(vec!["get".into(), "https://[REDACTED].example.com".into(), SVal::Bool(false), SVal::String(String::from("failed: [REDACTED]"))]),
+        ];
+        assert_eq!(recording.http, expected);
+    }
+
+    #[test]
+    fn replay_compares_redacted_urls() {
+        let recorded = vec![SVal::Tuple(vec!["get".into(), "https://api.example.com/?key=[REDACTED]".into(), SVal::Bool(true), SVal::String(String::from("ok"))])];
+
+        // The replay's URL has the secret value in it, which matches once redacted
+        let replay = RunRecording::replay(recorded.clone(), vec![], vec![String::from("s3cretkey")]);
+        let res = replay.lock().unwrap().replay_http("get", "https://api.example.com/?key=s3cretkey");
+        assert_eq!(res, Ok(Ok(SVal::String(String::from("ok")))));
+
+        // A different secret value diverges
+        let replay = RunRecording::replay(recorded.clone(), vec![], vec![String::from("s3cretkey")]);
+        assert!(replay.lock().unwrap().replay_http("get", "https://api.example.com/?key=other").is_err());
+
+        // Calls past the recording diverge
+        let replay = RunRecording::replay(recorded, vec![], vec![]);
+        let mut replay = replay.lock().unwrap();
+        assert!(replay.replay_http("get", "https://api.example.com/?key=[REDACTED]").is_ok());
+        assert!(replay.replay_http("get", "https://api.example.com/?key=[REDACTED]").is_err());
+    }
+}
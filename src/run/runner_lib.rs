@@ -0,0 +1,128 @@
+//
+// Copyright 2024 Formata, Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+use std::sync::{Arc, Mutex};
+use stof::{lang::SError, Library, SDoc, SVal};
+use tokio::sync::mpsc::UnboundedSender;
+use super::logs::RunLogs;
+
+
+/// Message sent to or from a running document.
+pub enum RunnerMessage {
+    Text(String),
+    Binary(Vec<u8>),
+}
+impl RunnerMessage {
+    /// Create a message from a value.
+    /// Objects are sent as JSON, blobs as binary, and everything else as text.
+    pub fn from_val(pid: &str, doc: &SDoc, value: SVal) -> Result<Self, SError> {
+        match value.unbox() {
+            SVal::Object(nref) => {
+                Ok(Self::Text(doc.export_min_string(pid, "json", Some(&nref))?))
+            },
+            SVal::Blob(blob) => {
+                Ok(Self::Binary(blob))
+            },
+            SVal::String(text) => {
+                Ok(Self::Text(text))
+            },
+            value => {
+                Ok(Self::Text(value.to_string()))
+            }
+        }
+    }
+
+    /// Redact secret values from this message.
+    pub fn redact(self, logs: &RunLogs) -> Self {
+        match self {
+            Self::Text(text) => Self::Text(logs.redact(&text)),
+            Self::Binary(blob) => Self::Binary(logs.redact_bytes(&blob)),
+        }
+    }
+
+    /// Message as a value (text is a string, binary is a blob).
+    pub fn into_val(self) -> SVal {
+        match self {
+            Self::Text(text) => SVal::String(text),
+            Self::Binary(blob) => SVal::Blob(blob),
+        }
+    }
+}
+
+
+/// Runner library.
+/// Gives documents access to runner features while they execute.
+///
+/// - `Runner.progress(value)`: emit a progress event (streamed to the client when using "?stream=sse").
+/// - `Runner.send(value)`: send a message to the client (websocket runs only).
+/// - `Runner.dir()`: path to the run's scratch directory (null if the run doesn't have one).
+pub struct RunnerLibrary {
+    pub logs: Arc<Mutex<RunLogs>>,
+    pub messages: Option<UnboundedSender<RunnerMessage>>,
+    pub dir: Option<String>,
+}
+impl RunnerLibrary {
+    pub fn new(logs: Arc<Mutex<RunLogs>>, messages: Option<UnboundedSender<RunnerMessage>>, dir: Option<String>) -> Self {
+        Self {
+            logs,
+            messages,
+            dir,
+        }
+    }
+}
+impl Library for RunnerLibrary {
+    fn scope(&self) -> String {
+        "Runner".to_string()
+    }
+
+    fn call(&self, pid: &str, doc: &mut SDoc, name: &str, parameters: &mut Vec<SVal>) -> Result<SVal, SError> {
+        match name {
+            "progress" => {
+                if parameters.len() != 1 {
+                    return Err(SError::custom(pid, doc, "RunnerProgress", "progress requires a single value"));
+                }
+                let message = match RunnerMessage::from_val(pid, doc, parameters.pop().unwrap())? {
+                    RunnerMessage::Text(text) => text,
+                    RunnerMessage::Binary(blob) => format!("{:?}", blob),
+                };
+                self.logs.lock().unwrap().push("progress", message);
+                Ok(SVal::Void)
+            },
+            "send" => {
+                if parameters.len() != 1 {
+                    return Err(SError::custom(pid, doc, "RunnerSend", "send requires a single value"));
+                }
+                if let Some(messages) = &self.messages {
+                    let message = RunnerMessage::from_val(pid, doc, parameters.pop().unwrap())?.redact(&self.logs.lock().unwrap());
+                    if messages.send(message).is_err() {
+                        return Err(SError::custom(pid, doc, "RunnerSend", "connection is closed"));
+                    }
+                    return Ok(SVal::Void);
+                }
+                Err(SError::custom(pid, doc, "RunnerSend", "send is only available for websocket runs"))
+            },
+            "dir" => {
+                if let Some(dir) = &self.dir {
+                    return Ok(SVal::String(dir.clone()));
+                }
+                Ok(SVal::Null)
+            },
+            _ => {
+                Err(SError::custom(pid, doc, "RunnerNotFound", &format!("{} is not a function in the Runner library", name)))
+            }
+        }
+    }
+}
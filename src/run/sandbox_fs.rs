@@ -14,20 +14,122 @@
 // limitations under the License.
 //
 
-use std::fs;
+use std::{fs, path::{Path, PathBuf}};
 use stof::{lang::SError, pkg::PKG, Library, SDoc, SVal};
+use super::scratch::dir_size;
 
 
 /// Sandboxed file system library.
+///
+/// Reads are limited to the registry, the package temp directory, and the run's scratch directory.
+/// Reserved entries in the registry directory (names starting with "__") hold the runner's own data and cannot be read.
+/// Writes ("write", "write_blob", and "remove") are limited to the run's scratch directory, up to a max total size.
+/// Relative paths that are not within the registry resolve to the scratch directory.
 pub struct PFileSystemLibrary {
     pub prefix_path: String,
     pub pkg: PKG,
+
+    /// Per-run scratch directory.
+    pub scratch_dir: Option<String>,
+
+    /// Max total size of the scratch directory (bytes).
+    pub max_scratch_size: u64,
 }
 impl PFileSystemLibrary {
     pub fn new(prefix_path: &str) -> Self {
         Self {
             prefix_path: prefix_path.to_owned(),
             pkg: PKG::default(),
+            scratch_dir: None,
+            max_scratch_size: 0,
+        }
+    }
+
+    /// Sandboxed file system with access to a run's scratch directory.
+    pub fn with_scratch(prefix_path: &str, scratch_dir: &str, max_scratch_size: u64) -> Self {
+        let mut lib = Self::new(prefix_path);
+        lib.scratch_dir = Some(scratch_dir.to_owned());
+        lib.max_scratch_size = max_scratch_size;
+        lib
+    }
+
+    /// Path within the scratch directory, if this path can be written.
+    fn scratch_path(&self, path: &str) -> Option<PathBuf> {
+        if path.split(['/', '\\']).any(|component| component == "..") {
+            return None;
+        }
+        if let Some(scratch_dir) = &self.scratch_dir {
+            if Path::new(path).starts_with(Path::new(scratch_dir)) {
+                return Some(PathBuf::from(path));
+            }
+            if !Path::new(path).is_absolute() {
+                return Some(Path::new(scratch_dir).join(path.trim_start_matches("./")));
+            }
+        }
+        None
+    }
+
+    /// Path components within the registry directory, if this path is in it (empty for the registry directory itself).
+    fn registry_components<'a>(&self, path: &'a str) -> Option<Vec<&'a str>> {
+        let rest = path.strip_prefix(&self.prefix_path)?;
+        if !rest.is_empty() && !rest.starts_with(['/', '\\']) && !self.prefix_path.ends_with(['/', '\\']) {
+            return None;
+        }
+        Some(rest.split(['/', '\\']).filter(|component| !component.is_empty() && *component != ".").collect())
+    }
+
+    /// Is this a reserved name in the registry directory (Ex. "__users__.json" or "__runs__")?
+    /// Reserved entries hold the runner's own data (users, run records, key-value stores, databases, sessions, etc.).
+    fn reserved(name: &str) -> bool {
+        name.starts_with("__")
+    }
+
+    /// Path to read, if this path can be read.
+    /// Reserved entries in the registry directory can never be read.
+    fn read_path(&self, path: &str) -> Option<PathBuf> {
+        if path.split(['/', '\\']).any(|component| component == "..") {
+            return None;
+        }
+        if let Some(components) = self.registry_components(path) {
+            if components.first().is_some_and(|name| Self::reserved(name)) {
+                return None;
+            }
+            return Some(PathBuf::from(path));
+        }
+        if Path::new(path).starts_with(Path::new(&self.pkg.temp_dir)) {
+            return Some(PathBuf::from(path));
+        }
+        self.scratch_path(path)
+    }
+
+    /// Write to a file in the scratch directory, enforcing the max size.
+    fn write(&self, pid: &str, doc: &SDoc, name: &str, path: &str, contents: &[u8]) -> Result<SVal, SError> {
+        let scratch_dir;
+        let file_path;
+        match (&self.scratch_dir, self.scratch_path(path)) {
+            (Some(dir), Some(file)) => {
+                scratch_dir = dir;
+                file_path = file;
+            },
+            _ => {
+                return Err(SError::filesys(pid, doc, name, "access denied"));
+            }
+        }
+
+        let mut size = dir_size(scratch_dir);
+        if let Ok(metadata) = fs::metadata(&file_path) {
+            size = size.saturating_sub(metadata.len());
+        }
+        if size + contents.len() as u64 > self.max_scratch_size {
+            return Err(SError::filesys(pid, doc, name, "scratch directory size limit exceeded"));
+        }
+
+        if let Some(parent) = file_path.parent() {
+            let _ = fs::create_dir_all(parent);
+        }
+        match fs::write(&file_path, contents) {
+            Ok(_) => Ok(SVal::Void),
+            Err(error) => Err(SError::filesys(pid, doc, name, &error.to_string())),
         }
     }
 }
@@ -41,11 +143,12 @@ impl Library for PFileSystemLibrary {
             "read" => {
                 if parameters.len() == 1 {
                     let path = parameters.pop().unwrap().owned_to_string();
-                    if !path.starts_with(&self.prefix_path) && !path.starts_with(&self.pkg.temp_dir) {
+                    let read_path = self.read_path(&path);
+                    if read_path.is_none() {
                         return Err(SError::filesys(pid, &doc, "read", "access denied"));
                     }
 
-                    let res = fs::read_to_string(&path);
+                    let res = fs::read_to_string(read_path.unwrap());
                     return match res {
                         Ok(contents) => {
                             Ok(SVal::String(contents))
@@ -60,11 +163,12 @@ impl Library for PFileSystemLibrary {
             "read_blob" => {
                 if parameters.len() == 1 {
                     let path = parameters.pop().unwrap().owned_to_string();
-                    if !path.starts_with(&self.prefix_path) && !path.starts_with(&self.pkg.temp_dir) {
+                    let read_path = self.read_path(&path);
+                    if read_path.is_none() {
                         return Err(SError::filesys(pid, &doc, "read", "access denied"));
                     }
                     
-                    let res = fs::read(&path);
+                    let res = fs::read(read_path.unwrap());
                     return match res {
                         Ok(blob) => {
                             Ok(SVal::Blob(blob))
@@ -76,9 +180,129 @@ impl Library for PFileSystemLibrary {
                 }
                 Err(SError::filesys(pid, &doc, "read_blob", "invalid arguments - file path not found"))
             },
+            "write" => {
+                if parameters.len() == 2 {
+                    let contents = parameters.pop().unwrap().owned_to_string();
+                    let path = parameters.pop().unwrap().owned_to_string();
+                    return self.write(pid, doc, "write", &path, contents.as_bytes());
+                }
+                Err(SError::filesys(pid, doc, "write", "invalid arguments - requires a file path and contents"))
+            },
+            "write_blob" => {
+                if parameters.len() == 2 {
+                    let blob = parameters.pop().unwrap();
+                    let path = parameters.pop().unwrap().owned_to_string();
+                    match blob {
+                        SVal::Blob(blob) => {
+                            return self.write(pid, doc, "write_blob", &path, &blob);
+                        },
+                        _ => {
+                            return Err(SError::filesys(pid, doc, "write_blob", "contents must be a blob"));
+                        }
+                    }
+                }
+                Err(SError::filesys(pid, doc, "write_blob", "invalid arguments - requires a file path and blob"))
+            },
+            "exists" => {
+                if parameters.len() == 1 {
+                    let path = parameters.pop().unwrap().owned_to_string();
+                    if let Some(read_path) = self.read_path(&path) {
+                        return Ok(SVal::Bool(read_path.exists()));
+                    }
+                    return Ok(SVal::Bool(false));
+                }
+                Err(SError::filesys(pid, doc, "exists", "invalid arguments - file path not found"))
+            },
+            "list" => {
+                // List the names in a directory (the scratch directory by default)
+                let mut path = String::from(".");
+                if parameters.len() == 1 {
+                    path = parameters.pop().unwrap().owned_to_string();
+                }
+                let read_path = self.read_path(&path);
+                if read_path.is_none() {
+                    return Err(SError::filesys(pid, doc, "list", "access denied"));
+                }
+                // Reserved entries are hidden when listing the registry directory itself
+                let registry_root = self.registry_components(&path).is_some_and(|components| components.is_empty());
+                match fs::read_dir(read_path.unwrap()) {
+                    Ok(entries) => {
+                        let mut names = Vec::new();
+                        for entry in entries.flatten() {
+                            let name = entry.file_name().to_string_lossy().to_string();
+                            if registry_root && Self::reserved(&name) {
+                                continue;
+                            }
+                            names.push(name);
+                        }
+                        names.sort();
+                        Ok(SVal::Array(names.into_iter().map(SVal::String).collect()))
+                    },
+                    Err(error) => {
+                        Err(SError::filesys(pid, doc, "list", &error.to_string()))
+                    }
+                }
+            },
+            "remove" => {
+                if parameters.len() == 1 {
+                    let path = parameters.pop().unwrap().owned_to_string();
+                    let file_path = self.scratch_path(&path);
+                    if file_path.is_none() || Some(file_path.as_ref().unwrap().as_path()) == self.scratch_dir.as_ref().map(Path::new) {
+                        return Err(SError::filesys(pid, doc, "remove", "access denied"));
+                    }
+                    let file_path = file_path.unwrap();
+                    let res = if file_path.is_dir() {
+                        fs::remove_dir_all(&file_path)
+                    } else {
+                        fs::remove_file(&file_path)
+                    };
+                    return Ok(SVal::Bool(res.is_ok()));
+                }
+                Err(SError::filesys(pid, doc, "remove", "invalid arguments - file path not found"))
+            },
             _ => {
-                Err(SError::filesys(pid, &doc, "NotFound", &format!("{} is not a function in the FileSystem Library", name)))
+                Err(SError::filesys(pid, doc, "NotFound", &format!("{} is not a function in the FileSystem Library", name)))
             }
         }
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::PFileSystemLibrary;
+    use std::path::PathBuf;
+
+    #[test]
+    fn scratch_path_within_scratch_dir() {
+        let lib = PFileSystemLibrary::with_scratch("registry", "/tmp/stof-runner/abc", 1024);
+        assert_eq!(lib.scratch_path("/tmp/stof-runner/abc/out.txt"), Some(PathBuf::from("/tmp/stof-runner/abc/out.txt")));
+        assert_eq!(lib.scratch_path("out.txt"), Some(PathBuf::from("/tmp/stof-runner/abc/out.txt")));
+        assert_eq!(lib.scratch_path("./dir/out.txt"), Some(PathBuf::from("/tmp/stof-runner/abc/dir/out.txt")));
+    }
+
+    #[test]
+    fn scratch_path_sibling_prefix_denied() {
+        let lib = PFileSystemLibrary::with_scratch("registry", "/tmp/stof-runner/abc", 1024);
+        assert_eq!(lib.scratch_path("/tmp/stof-runner/abcx"), None);
+        assert_eq!(lib.scratch_path("/tmp/stof-runner/abcx/out.txt"), None);
+        assert_eq!(lib.scratch_path("/tmp/stof-runner/abc/../xyz/out.txt"), None);
+        assert_eq!(lib.read_path("/tmp/stof-runner/abcx/out.txt"), None);
+    }
+
+    #[test]
+    fn temp_dir_sibling_prefix_denied() {
+        let mut lib = PFileSystemLibrary::new("registry");
+        lib.pkg.temp_dir = "/tmp/stof-pkg".to_owned();
+        assert_eq!(lib.read_path("/tmp/stof-pkg/a/pkg.stof"), Some(PathBuf::from("/tmp/stof-pkg/a/pkg.stof")));
+        assert_eq!(lib.read_path("/tmp/stof-pkgx/a/pkg.stof"), None);
+    }
+
+    #[test]
+    fn reserved_registry_entries_denied() {
+        let lib = PFileSystemLibrary::new("registry");
+        assert_eq!(lib.read_path("registry/app/main.stof"), Some(PathBuf::from("registry/app/main.stof")));
+        assert_eq!(lib.read_path("registry/__users__.json"), None);
+        assert_eq!(lib.read_path("registry/__runs__/abc.json"), None);
+    }
+}
@@ -0,0 +1,179 @@
+//
+// Copyright 2024 Formata, Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+use std::{collections::BTreeSet, fs, io::{Cursor, Write}, path::{Path, PathBuf}};
+use bytes::Bytes;
+use nanoid::nanoid;
+use walkdir::WalkDir;
+use zip::{write::SimpleFileOptions, ZipWriter};
+
+
+/// File given to a run (Ex. a multipart upload).
+#[derive(Clone)]
+pub(crate) struct RunFile {
+    /// File name within the scratch directory.
+    pub name: String,
+
+    /// File contents.
+    pub bytes: Bytes,
+}
+
+
+/// Scratch directory for a single run.
+/// Created in the system temp directory and removed when dropped (when the run finishes).
+pub(crate) struct ScratchDir {
+    pub path: PathBuf,
+}
+impl ScratchDir {
+    /// Create a new, empty scratch directory.
+    pub fn new() -> Result<Self, String> {
+        let path = std::env::temp_dir().join("stof-runner").join(nanoid!());
+        if let Err(error) = fs::create_dir_all(&path) {
+            return Err(error.to_string());
+        }
+        Ok(Self {
+            path,
+        })
+    }
+
+    /// Path as a string (used as the sandbox prefix).
+    pub fn path_str(&self) -> String {
+        self.path.to_string_lossy().to_string()
+    }
+
+    /// Path to a file within this directory.
+    pub fn file_path(&self, name: &str) -> String {
+        self.path.join(name).to_string_lossy().to_string()
+    }
+
+    /// Write files into this directory.
+    /// Only the file name is used from each file, so files cannot be written outside of this directory.
+    /// Errors (before writing anything) if two files have the same name or the files are larger than max_size in total.
+    pub fn write_files(&self, files: &[RunFile], max_size: u64) -> Result<(), String> {
+        let mut names = BTreeSet::new();
+        let mut size = 0;
+        for file in files {
+            let name = file_name(&file.name);
+            if name.is_empty() {
+                return Err(format!("invalid file name: {}", file.name));
+            }
+            if !names.insert(name.clone()) {
+                return Err(format!("duplicate file name: {}", name));
+            }
+            size += file.bytes.len() as u64;
+        }
+        if size > max_size {
+            return Err(format!("files are too large (max scratch size is {} bytes)", max_size));
+        }
+
+        for file in files {
+            if let Err(error) = fs::write(self.path.join(file_name(&file.name)), &file.bytes) {
+                return Err(error.to_string());
+            }
+        }
+        Ok(())
+    }
+
+    /// Zip this directory (under "scratch/"), along with a result file.
+    /// The contents of each scratch file are passed through redact first.
+    pub fn zip(&self, result_name: &str, result: &[u8], redact: impl Fn(&[u8]) -> Vec<u8>) -> Result<Vec<u8>, String> {
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        let options = SimpleFileOptions::default();
+        if let Err(error) = zip.start_file(result_name, options) {
+            return Err(error.to_string());
+        }
+        if let Err(error) = zip.write_all(result) {
+            return Err(error.to_string());
+        }
+
+        for entry in WalkDir::new(&self.path).min_depth(1).into_iter().flatten() {
+            if !entry.file_type().is_file() {
+                continue;
+            }
+            if let Ok(relative) = entry.path().strip_prefix(&self.path) {
+                let name = format!("scratch/{}", relative.to_string_lossy().replace('\\', "/"));
+                match fs::read(entry.path()) {
+                    Ok(contents) => {
+                        if let Err(error) = zip.start_file(name, options) {
+                            return Err(error.to_string());
+                        }
+                        if let Err(error) = zip.write_all(&redact(&contents)) {
+                            return Err(error.to_string());
+                        }
+                    },
+                    Err(error) => {
+                        return Err(error.to_string());
+                    }
+                }
+            }
+        }
+
+        match zip.finish() {
+            Ok(cursor) => Ok(cursor.into_inner()),
+            Err(error) => Err(error.to_string()),
+        }
+    }
+}
+impl Drop for ScratchDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+
+/// Total size of the files in a directory (bytes).
+pub(crate) fn dir_size(path: impl AsRef<Path>) -> u64 {
+    let mut size = 0;
+    for entry in WalkDir::new(path).into_iter().flatten() {
+        if let Ok(metadata) = entry.metadata() && metadata.is_file() {
+            size += metadata.len();
+        }
+    }
+    size
+}
+
+
+/// File name (last component) of a path.
+pub(crate) fn file_name(path: &str) -> String {
+    if let Some(name) = Path::new(path).file_name() {
+        return name.to_string_lossy().to_string();
+    }
+    String::default()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use super::{dir_size, RunFile, ScratchDir};
+
+    fn file(name: &str, contents: &str) -> RunFile {
+        RunFile { name: name.to_owned(), bytes: Bytes::from(contents.to_owned()) }
+    }
+
+    #[test]
+    fn write_files() {
+        let scratch = ScratchDir::new().unwrap();
+        scratch.write_files(&[file("../a.txt", "hello"), file("b.txt", "world")], 10).unwrap();
+        assert_eq!(std::fs::read_to_string(scratch.file_path("a.txt")).unwrap(), "hello");
+        assert_eq!(dir_size(&scratch.path), 10);
+
+        let scratch = ScratchDir::new().unwrap();
+        assert!(scratch.write_files(&[file("a.txt", "hello"), file("b.txt", "world!")], 10).is_err());
+        assert!(scratch.write_files(&[file("a.txt", "hello"), file("dir/a.txt", "hi")], 10).is_err());
+        assert_eq!(dir_size(&scratch.path), 0);
+    }
+}
@@ -0,0 +1,122 @@
+//
+// Copyright 2024 Formata, Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+use std::{collections::BTreeMap, sync::{Arc, Mutex}};
+use stof::{lang::SError, Library, SData, SDoc, SField, SVal};
+use super::logs::RunLogs;
+
+
+/// Secrets library.
+/// Gives documents read access to the secrets that the run is allowed to read.
+///
+/// - `secrets.get(name)`: get a secret value (errors if the secret doesn't exist or cannot be read by this run).
+///
+/// Values that are read are recorded in the run logs, so that they are redacted from the run's output.
+/// Redaction is best-effort: it covers the response (result, logs, errors, and scratch zip) and websocket messages,
+/// but not what the document sends elsewhere (kv, sql, bus messages, or HTTP requests), and not values derived from a secret (encoded, split, etc.).
+pub struct SecretsLibrary {
+    pub secrets: BTreeMap<String, String>,
+    pub logs: Arc<Mutex<RunLogs>>,
+}
+impl SecretsLibrary {
+    pub fn new(secrets: BTreeMap<String, String>, logs: Arc<Mutex<RunLogs>>) -> Self {
+        Self {
+            secrets,
+            logs,
+        }
+    }
+}
+impl Library for SecretsLibrary {
+    fn scope(&self) -> String {
+        "secrets".to_string()
+    }
+
+    fn call(&self, pid: &str, doc: &mut SDoc, name: &str, parameters: &mut Vec<SVal>) -> Result<SVal, SError> {
+        match name {
+            "get" => {
+                if parameters.len() != 1 {
+                    return Err(SError::custom(pid, doc, "SecretsGet", "get requires a secret name"));
+                }
+                let name = parameters.pop().unwrap().unbox().to_string();
+                if let Some(value) = self.secrets.get(&name) {
+                    if !value.is_empty() {
+                        let mut logs = self.logs.lock().unwrap();
+                        if !logs.secrets.contains(value) {
+                            logs.secrets.push(value.clone());
+                        }
+                    }
+                    return Ok(SVal::String(value.clone()));
+                }
+                Err(SError::custom(pid, doc, "SecretsGet", &format!("secret '{}' not found", name)))
+            },
+            _ => {
+                Err(SError::custom(pid, doc, "SecretsNotFound", &format!("{} is not a function in the secrets library", name)))
+            }
+        }
+    }
+}
+
+
+/// Redact secret values from every field in a document (before it is exported).
+pub(crate) fn redact_document(doc: &mut SDoc, logs: &RunLogs) {
+    if logs.secrets.is_empty() {
+        return;
+    }
+    let mut field_refs = Vec::new();
+    for node in doc.graph.nodes.store.values() {
+        field_refs.append(&mut node.data_refs::<SField>(&doc.graph));
+    }
+    for field_ref in field_refs {
+        if let Some(field) = SData::get_mut::<SField>(&mut doc.graph, &field_ref) {
+            redact_value(&mut field.value, logs);
+        }
+    }
+}
+
+
+/// Redact secret values from a value (strings, blobs, and collections of them).
+fn redact_value(value: &mut SVal, logs: &RunLogs) {
+    match value {
+        SVal::String(text) => {
+            *text = logs.redact(text);
+        },
+        SVal::Blob(blob) => {
+            *blob = logs.redact_bytes(blob);
+        },
+        SVal::Array(vals) | SVal::Tuple(vals) => {
+            for val in vals {
+                redact_value(val, logs);
+            }
+        },
+        SVal::Map(map) => {
+            *map = std::mem::take(map).into_iter().map(|(mut key, mut val)| {
+                redact_value(&mut key, logs);
+                redact_value(&mut val, logs);
+                (key, val)
+            }).collect();
+        },
+        SVal::Set(set) => {
+            *set = std::mem::take(set).into_iter().map(|mut val| {
+                redact_value(&mut val, logs);
+                val
+            }).collect();
+        },
+        SVal::Boxed(val) => {
+            redact_value(&mut val.lock().unwrap(), logs);
+        },
+        _ => {}
+    }
+}
@@ -0,0 +1,268 @@
+//
+// Copyright 2024 Formata, Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+use nanoid::nanoid;
+use rusqlite::{params_from_iter, types::{Value, ValueRef}, Batch, Connection, Statement};
+use stof::{lang::SError, Library, SData, SDoc, SField, SNum, SVal};
+use crate::sql::{database_scope, SqlAccess};
+
+
+/// SQL library.
+/// Gives documents a SQLite database per scope, managed by the runner.
+///
+/// - `sql.query(scope, sql, params?, arrays?)`: run a read-only statement, returning its rows as objects (or arrays if `arrays` is true).
+/// - `sql.execute(scope, sql, params?)`: run a statement that modifies the database, returning the number of rows changed.
+///
+/// Parameters are positional (`?` or `?1`) and can be null, bools, numbers, strings, or blobs.
+pub struct SqlLibrary {
+    pub sql: SqlAccess,
+}
+impl SqlLibrary {
+    pub fn new(sql: SqlAccess) -> Self {
+        Self {
+            sql,
+        }
+    }
+}
+impl Library for SqlLibrary {
+    fn scope(&self) -> String {
+        "sql".to_string()
+    }
+
+    fn call(&self, pid: &str, doc: &mut SDoc, name: &str, parameters: &mut Vec<SVal>) -> Result<SVal, SError> {
+        if self.sql.path.is_none() {
+            return Err(SError::custom(pid, doc, "SqlUnavailable", "sql is not available"));
+        }
+        match name {
+            "query" => {
+                if parameters.len() < 2 || parameters.len() > 4 {
+                    return Err(SError::custom(pid, doc, "SqlQuery", "query requires a scope, a statement, optional parameters, and an optional arrays flag"));
+                }
+                let mut arrays = false;
+                if parameters.len() == 4 {
+                    arrays = parameters.pop().unwrap().unbox().truthy();
+                }
+                let params = query_params(pid, doc, parameters, 2)?;
+                let statement = parameters.pop().unwrap().unbox().to_string();
+                let scope = self.scope_param(pid, doc, parameters.pop().unwrap())?;
+                if !self.sql.can_read(&scope) {
+                    return Err(SError::custom(pid, doc, "SqlDenied", &format!("cannot query the '{}' database", scope)));
+                }
+
+                
+                let rows = match self.query(&scope, &statement, params) {
+                    Ok(value) => value,
+                    Err(message) => return Err(SError::custom(pid, doc, "SqlQuery", &message)),
+                };
+                Ok(rows_value(doc, rows, arrays))
+            },
+            "execute" => {
+                if parameters.len() < 2 || parameters.len() > 3 {
+                    return Err(SError::custom(pid, doc, "SqlExecute", "execute requires a scope, a statement, and optional parameters"));
+                }
+                let params = query_params(pid, doc, parameters, 2)?;
+                let statement = parameters.pop().unwrap().unbox().to_string();
+                let scope = self.scope_param(pid, doc, parameters.pop().unwrap())?;
+                if !self.sql.can_write(&scope) {
+                    return Err(SError::custom(pid, doc, "SqlDenied", &format!("cannot modify the '{}' database", scope)));
+                }
+
+                
+                let conn = match self.sql.open(&scope) {
+                    Ok(value) => value,
+                    Err(message) => return Err(SError::custom(pid, doc, "SqlExecute", &message)),
+                };
+                let mut stmt;
+                match prepare(&conn, &statement) {
+                    Ok(value) => stmt = value,
+                    Err(message) => return Err(SError::custom(pid, doc, "SqlExecute", &message)),
+                }
+                match stmt.execute(params_from_iter(params)) {
+                    Ok(changed) => Ok(SVal::Number(SNum::I64(changed as i64))),
+                    Err(error) => Err(SError::custom(pid, doc, "SqlExecute", &error.to_string())),
+                }
+            },
+            _ => {
+                Err(SError::custom(pid, doc, "SqlNotFound", &format!("{} is not a function in the sql library", name)))
+            }
+        }
+    }
+}
+impl SqlLibrary {
+    /// Database scope parameter.
+    fn scope_param(&self, pid: &str, doc: &SDoc, value: SVal) -> Result<String, SError> {
+        let scope = value.unbox().to_string();
+        match database_scope(&scope) {
+            Some(scope) => Ok(scope),
+            None => Err(SError::custom(pid, doc, "SqlScope", &format!("'{}' is not a valid database scope", scope))),
+        }
+    }
+
+    /// Run a read-only query, returning the column names and rows (limited by the max rows).
+    fn query(&self, scope: &str, statement: &str, params: Vec<Value>) -> Result<(Vec<String>, Vec<Vec<SVal>>), String> {
+        let conn = self.sql.open(scope)?;
+        let mut stmt = prepare(&conn, statement)?;
+        if !stmt.readonly() {
+            return Err("query statements must be read-only (use execute to modify the database)".into());
+        }
+        let columns = stmt.column_names().into_iter().map(String::from).collect::<Vec<_>>();
+
+        let mut rows = Vec::new();
+        let mut results = stmt.query(params_from_iter(params)).map_err(|error| error.to_string())?;
+        while let Some(row) = results.next().map_err(|error| error.to_string())? {
+            if rows.len() >= self.sql.max_rows {
+                return Err(format!("query returned too many rows (max is {})", self.sql.max_rows));
+            }
+            let mut values = Vec::new();
+            for i in 0..columns.len() {
+                let value = match row.get_ref(i).map_err(|error| error.to_string())? {
+                    ValueRef::Null => SVal::Null,
+                    ValueRef::Integer(value) => SVal::Number(SNum::I64(value)),
+                    ValueRef::Real(value) => SVal::Number(SNum::F64(value)),
+                    ValueRef::Text(value) => SVal::String(String::from_utf8_lossy(value).to_string()),
+                    ValueRef::Blob(value) => SVal::Blob(value.to_vec()),
+                };
+                values.push(value);
+            }
+            rows.push(values);
+        }
+        Ok((columns, rows))
+    }
+}
+
+
+/// Prepare a single statement (multiple statements are not allowed).
+fn prepare<'conn>(conn: &'conn Connection, statement: &str) -> Result<Statement<'conn>, String> {
+    let mut batch = Batch::new(conn, statement);
+    
+    let stmt = match batch.next() {
+        Ok(Some(value)) => value,
+        Ok(None) => return Err("statement cannot be empty".into()),
+        Err(error) => return Err(error.to_string()),
+    };
+    match batch.next() {
+        Ok(None) => Ok(stmt),
+        _ => Err("only one statement can be run at a time".into()),
+    }
+}
+
+
+/// Statement parameters (an optional array at the given index).
+fn query_params(pid: &str, doc: &SDoc, parameters: &mut Vec<SVal>, index: usize) -> Result<Vec<Value>, SError> {
+    let mut params = Vec::new();
+    if parameters.len() > index {
+        match parameters.pop().unwrap().unbox() {
+            SVal::Array(values) |
+            SVal::Tuple(values) => {
+                for value in values {
+                    match param_value(value.unbox()) {
+                        Some(value) => params.push(value),
+                        None => return Err(SError::custom(pid, doc, "SqlParams", "parameters must be null, bools, numbers, strings, or blobs")),
+                    }
+                }
+            },
+            SVal::Null => {},
+            _ => {
+                return Err(SError::custom(pid, doc, "SqlParams", "parameters must be an array"));
+            }
+        }
+    }
+    Ok(params)
+}
+
+
+/// SQLite value for a statement parameter.
+fn param_value(value: SVal) -> Option<Value> {
+    match value {
+        SVal::Null => Some(Value::Null),
+        SVal::Bool(value) => Some(Value::Integer(value as i64)),
+        SVal::Number(SNum::I64(value)) => Some(Value::Integer(value)),
+        SVal::Number(num) => Some(Value::Real(num.float())),
+        SVal::String(value) => Some(Value::Text(value)),
+        SVal::Blob(value) => Some(Value::Blob(value)),
+        _ => None,
+    }
+}
+
+
+/// Query rows as a Stof value.
+/// Objects are created under a field for the query (on the "SqlRows" root), with a field for each column.
+fn rows_value(doc: &mut SDoc, (columns, rows): (Vec<String>, Vec<Vec<SVal>>), arrays: bool) -> SVal {
+    if arrays {
+        return SVal::Array(rows.into_iter().map(SVal::Array).collect());
+    }
+    let root = match doc.graph.root_by_name("SqlRows") {
+        Some(root) => root,
+        None => doc.graph.insert_root("SqlRows"),
+    };
+    let query = SField::new_object(&mut doc.graph, &format!("query{}", nanoid!(10, &nanoid::alphabet::SAFE[2..])), &root);
+    let mut objects = Vec::new();
+    for (i, row) in rows.into_iter().enumerate() {
+        let nref = SField::new_object(&mut doc.graph, &format!("row{}", i), &query);
+        for (column, value) in columns.iter().zip(row) {
+            SData::insert_new(&mut doc.graph, &nref, Box::new(SField::new(column, value)));
+        }
+        objects.push(SVal::Object(nref));
+    }
+    SVal::Array(objects)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use nanoid::nanoid;
+    use stof::{Library, SDoc, SField, SVal};
+    use crate::sql::SqlAccess;
+    use super::SqlLibrary;
+
+    #[test]
+    fn query_rows() {
+        let path = std::env::temp_dir().join("stof-runner").join(nanoid!());
+        let library = SqlLibrary::new(SqlAccess {
+            path: Some(path.to_string_lossy().to_string()),
+            package_scope: Some("acme".into()),
+            max_rows: 10,
+            max_size: 1048576,
+            ..Default::default()
+        });
+        let mut doc = SDoc::default();
+        library.call("main", &mut doc, "execute", &mut vec!["acme".into(), "CREATE TABLE items (name TEXT, count INTEGER)".into()]).unwrap();
+        let changed = library.call("main", &mut doc, "execute", &mut vec!["@acme".into(), "INSERT INTO items VALUES (?, ?), (?, ?)".into(), SVal::Array(vec!["a".into(), 1.into(), "b".into(), 2.into()])]).unwrap();
+        assert_eq!(changed, SVal::from(2));
+
+        // Rows of every query are nested under a single root
+        let roots = doc.graph.roots.len();
+        for _ in 0..2 {
+            let rows = library.call("main", &mut doc, "query", &mut vec!["acme".into(), "SELECT * FROM items ORDER BY name".into()]).unwrap();
+            match rows {
+                SVal::Array(rows) => {
+                    assert_eq!(rows.len(), 2);
+                    let SVal::Object(row) = &rows[1] else { panic!("row is not an object") };
+                    assert_eq!(SField::field(&doc.graph, "name", '.', Some(row)).unwrap().value, SVal::from("b"));
+                },
+                _ => panic!("rows are not an array"),
+            }
+        }
+        assert_eq!(doc.graph.roots.len(), roots + 1);
+
+        let rows = library.call("main", &mut doc, "query", &mut vec!["acme".into(), "SELECT count FROM items ORDER BY name".into(), SVal::Array(vec![]), true.into()]).unwrap();
+        assert_eq!(rows, SVal::Array(vec![SVal::Array(vec![1.into()]), SVal::Array(vec![2.into()])]));
+
+        assert!(library.call("main", &mut doc, "query", &mut vec!["other".into(), "SELECT 1".into()]).is_err());
+        assert!(library.call("main", &mut doc, "query", &mut vec!["acme".into(), "DELETE FROM items".into()]).is_err());
+        let _ = std::fs::remove_dir_all(path);
+    }
+}
@@ -0,0 +1,57 @@
+//
+// Copyright 2024 Formata, Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+use std::convert::Infallible;
+use axum::{http::StatusCode, response::{sse::{Event, KeepAlive}, Sse}};
+use bytes::Bytes;
+use tokio::sync::mpsc::unbounded_channel;
+use tokio_stream::{wrappers::UnboundedReceiverStream, Stream, StreamExt};
+use super::{logs::RunLog, run_stof, RunOptions};
+
+
+/// Run some Stof, streaming server-sent events as it executes.
+///
+/// Events:
+/// - "out" and "err": captured output lines.
+/// - "progress": values emitted with `Runner.progress(value)`.
+/// - "result": the exported document (text export formats only, default is "json").
+/// - "error": run error (including timeouts), as a structured JSON error if errors are shown (never BSTOF, since events are text).
+pub(crate) fn run_stof_sse(mut options: RunOptions, body: Bytes) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    if options.error_format.is_some() {
+        options.error_format = Some(String::from("json"));
+    }
+    let (tx, rx) = unbounded_channel::<RunLog>();
+    let events = tx.clone();
+    tokio::spawn(async move {
+        let response = run_stof(options, body, Some(events)).await;
+        let line;
+        if response.status == StatusCode::OK {
+            if response.bytes_body.is_some() {
+                line = RunLog { stream: "error", message: "binary export formats cannot be streamed".into() };
+            } else {
+                line = RunLog { stream: "result", message: response.str_body };
+            }
+        } else {
+            line = RunLog { stream: "error", message: response.str_body };
+        }
+        let _ = tx.send(line);
+    });
+
+    let stream = UnboundedReceiverStream::new(rx).map(|line| {
+        Ok(Event::default().event(line.stream).data(line.message))
+    });
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
@@ -0,0 +1,330 @@
+//
+// Copyright 2024 Formata, Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+use std::{collections::BTreeMap, fmt::Write, sync::{Arc, Mutex}, time::Instant};
+use axum::http::{header::CONTENT_TYPE, HeaderMap, StatusCode};
+use bytes::Bytes;
+use stof::{lang::{SError, Statements}, Library, SData, SDataRef, SDoc, SFunc, SParam, SType, SVal};
+use crate::response::StofResponse;
+use super::error::function_path;
+
+
+/// Trace output format.
+#[derive(Clone, Copy, PartialEq)]
+pub enum TraceFormat {
+    /// Chrome trace-event JSON (chrome://tracing, Perfetto, speedscope).
+    Chrome,
+
+    /// Folded stacks with self time in microseconds (flamegraph.pl, inferno, speedscope).
+    Folded,
+}
+impl TraceFormat {
+    /// Trace format from the "trace" query ("true" or "chrome", and "folded").
+    pub fn from_query(value: &str) -> Option<Self> {
+        match value {
+            "true" | "chrome" => Some(Self::Chrome),
+            "folded" => Some(Self::Folded),
+            _ => None,
+        }
+    }
+}
+
+
+/// Function call span.
+pub struct TraceSpan {
+    pub name: String,
+
+    /// Start, relative to the start of the trace (microseconds).
+    pub start: u64,
+
+    /// Duration (microseconds).
+    pub duration: u64,
+
+    /// Call depth (0 for functions called by the runner).
+    pub depth: usize,
+
+    /// Names of the calling functions, outermost first.
+    pub stack: Vec<String>,
+}
+
+
+/// Function call spans recorded for a single run.
+pub struct RunTrace {
+    pub start: Instant,
+    pub spans: Vec<TraceSpan>,
+
+    /// Currently executing functions (name, start).
+    open: Vec<(String, u64)>,
+}
+impl RunTrace {
+    /// Create a new shared run trace.
+    pub fn new() -> Arc<Mutex<Self>> {
+        Arc::new(Mutex::new(Self {
+            start: Instant::now(),
+            spans: Vec::new(),
+            open: Vec::new(),
+        }))
+    }
+
+    fn now(&self) -> u64 {
+        self.start.elapsed().as_micros() as u64
+    }
+
+    /// Enter a function.
+    pub fn enter(&mut self, name: &str) {
+        let now = self.now();
+        self.open.push((name.to_owned(), now));
+    }
+
+    /// Exit the current function, recording its span.
+    pub fn exit(&mut self) {
+        let now = self.now();
+        if let Some((name, start)) = self.open.pop() {
+            self.spans.push(TraceSpan {
+                name,
+                start,
+                duration: now - start,
+                depth: self.open.len(),
+                stack: self.open.iter().map(|(name, _)| name.clone()).collect(),
+            });
+        }
+    }
+
+    /// Chrome trace-event JSON (complete events, ordered by start time).
+    pub fn chrome(&self) -> String {
+        let mut spans = self.spans.iter().collect::<Vec<_>>();
+        spans.sort_by_key(|span| (span.start, span.depth));
+        let events = spans.into_iter().map(|span| {
+            format!(r#"{{"name":{},"cat":"stof","ph":"X","ts":{},"dur":{},"pid":1,"tid":1,"args":{{"depth":{}}}}}"#, json_string(&span.name), span.start, span.duration, span.depth)
+        }).collect::<Vec<_>>();
+        format!(r#"{{"traceEvents":[{}],"displayTimeUnit":"ms"}}"#, events.join(","))
+    }
+
+    /// Folded stacks ("outer;inner <self time>"), one line per unique stack.
+    pub fn folded(&self) -> String {
+        // Self time is the span duration minus the time spent in the functions it called
+        let mut children = vec![0u64; self.spans.len()];
+        let mut open: Vec<usize> = Vec::new();
+        let mut order = (0..self.spans.len()).collect::<Vec<_>>();
+        order.sort_by_key(|i| (self.spans[*i].start, self.spans[*i].depth));
+        for i in order {
+            let span = &self.spans[i];
+            while let Some(parent) = open.last() {
+                if self.spans[*parent].depth < span.depth {
+                    break;
+                }
+                open.pop();
+            }
+            if let Some(parent) = open.last() {
+                children[*parent] += span.duration;
+            }
+            open.push(i);
+        }
+
+        let mut stacks = BTreeMap::new();
+        for (i, span) in self.spans.iter().enumerate() {
+            let mut stack = span.stack.clone();
+            stack.push(span.name.clone());
+            *stacks.entry(stack.join(";")).or_insert(0u64) += span.duration.saturating_sub(children[i]);
+        }
+        let mut folded = String::default();
+        for (stack, time) in stacks {
+            let _ = writeln!(folded, "{} {}", stack, time);
+        }
+        folded
+    }
+
+    /// Trace response in a format.
+    pub fn response(&self, format: TraceFormat) -> StofResponse {
+        let mut headers = HeaderMap::new();
+        let body = match format {
+            TraceFormat::Chrome => {
+                headers.insert(CONTENT_TYPE, "application/json".parse().unwrap());
+                self.chrome()
+            },
+            TraceFormat::Folded => {
+                headers.insert(CONTENT_TYPE, "text/plain".parse().unwrap());
+                self.folded()
+            }
+        };
+        StofResponse {
+            headers,
+            status: StatusCode::OK,
+            str_body: String::default(),
+            bytes_body: Some(Bytes::from(body)),
+        }
+    }
+}
+
+
+/// Traced function (the original function body, called by its wrapper).
+struct TracedFunc {
+    name: String,
+    dref: SDataRef,
+    params: Vec<SParam>,
+    statements: Statements,
+    rtype: SType,
+}
+
+
+/// Trace library.
+/// Functions in a traced document are wrapped to call "trace.call(id, ...params)", which records a span around the original body.
+pub struct TraceLibrary {
+    pub trace: Arc<Mutex<RunTrace>>,
+    funcs: Vec<TracedFunc>,
+}
+impl Library for TraceLibrary {
+    fn scope(&self) -> String {
+        "trace".to_string()
+    }
+
+    fn call(&self, pid: &str, doc: &mut SDoc, name: &str, parameters: &mut Vec<SVal>) -> Result<SVal, SError> {
+        match name {
+            "call" => {
+                if parameters.is_empty() {
+                    return Err(SError::custom(pid, doc, "TraceCall", "call requires a function id"));
+                }
+                let id = parameters.remove(0).unbox().to_string();
+                
+                let func = match id.parse::<usize>().ok().and_then(|index| self.funcs.get(index)) {
+                    Some(traced) => traced,
+                    None => return Err(SError::custom(pid, doc, "TraceCall", "traced function not found")),
+                };
+                self.trace.lock().unwrap().enter(&func.name);
+                let res = SFunc::call_internal(&func.dref, pid, doc, std::mem::take(parameters), false, &func.params, &func.statements, &func.rtype);
+                self.trace.lock().unwrap().exit();
+                res
+            },
+            _ => {
+                Err(SError::custom(pid, doc, "TraceNotFound", &format!("{} is not a function in the trace library", name)))
+            }
+        }
+    }
+}
+
+
+/// Instrument every function in a document for tracing.
+/// Each function body is replaced with a call into the trace library, which runs the original body within a span.
+pub fn trace_document(doc: &mut SDoc, trace: Arc<Mutex<RunTrace>>) {
+    let mut funcs = Vec::new();
+    let mut frefs = SFunc::all_funcs(&doc.graph).into_iter().collect::<Vec<_>>();
+    frefs.sort_by_key(|dref| dref.id.clone());
+    for dref in frefs {
+        
+        let name = match function_path(&doc.graph, &dref) {
+            Some(path) => path,
+            None => continue,
+        };
+        
+        let func = match SData::get::<SFunc>(&doc.graph, &dref) {
+            Some(data) => data,
+            None => continue,
+        };
+        let id = funcs.len();
+        let params = func.params.iter().map(|param| param.name.clone()).collect::<Vec<_>>();
+        let mut call = format!("trace.call('{}'", id);
+        for param in &params {
+            call.push_str(&format!(", {}", param));
+        }
+        call.push(')');
+        let wrapper_src = if func.rtype == SType::Void {
+            format!("fn wrapper() {{ {}; }}", call)
+        } else {
+            format!("fn wrapper() {{ return {}; }}", call)
+        };
+        
+        let wrapper = match wrapper_statements(&wrapper_src) {
+            Some(statements) => statements,
+            None => continue,
+        };
+        funcs.push(TracedFunc {
+            name,
+            dref: dref.clone(),
+            params: func.params.clone(),
+            statements: func.statements.clone(),
+            rtype: func.rtype.clone(),
+        });
+        if let Some(func) = SData::get_mut::<SFunc>(&mut doc.graph, &dref) {
+            func.statements = wrapper;
+        }
+    }
+    doc.load_lib(Arc::new(TraceLibrary {
+        trace,
+        funcs,
+    }));
+}
+
+
+/// Statements of a parsed wrapper function.
+fn wrapper_statements(src: &str) -> Option<Statements> {
+    let doc = SDoc::src(src, "stof").ok()?;
+    let dref = SFunc::func_ref(&doc.graph, "root.wrapper", '.', None)?;
+    SData::get::<SFunc>(&doc.graph, &dref).map(|func| func.statements.clone())
+}
+
+
+/// JSON string literal.
+fn json_string(value: &str) -> String {
+    let mut res = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' => res.push_str("\\\""),
+            '\\' => res.push_str("\\\\"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(res, "\\u{:04x}", c as u32);
+            },
+            c => res.push(c),
+        }
+    }
+    res.push('"');
+    res
+}
+
+
+#[cfg(test)]
+mod tests {
+    use stof::{SDoc, SVal};
+    use super::{trace_document, RunTrace, TraceSpan};
+
+    #[test]
+    fn trace_output() {
+        let trace = RunTrace::new();
+        {
+            let mut trace = trace.lock().unwrap();
+            trace.spans.push(TraceSpan { name: String::from("root.inner"), start: 10, duration: 30, depth: 1, stack: vec![String::from("root.outer")] });
+            trace.spans.push(TraceSpan { name: String::from("root.outer"), start: 0, duration: 100, depth: 0, stack: vec![] });
+        }
+        let trace = trace.lock().unwrap();
+        assert_eq!(trace.folded(), "root.outer 70\nroot.outer;root.inner 30\n");
+        assert_eq!(trace.chrome(), concat!(
+            r#"{"traceEvents":[{"name":"root.outer","cat":"stof","ph":"X","ts":0,"dur":100,"pid":1,"tid":1,"args":{"depth":0}},"#,
+            r#"{"name":"root.inner","cat":"stof","ph":"X","ts":10,"dur":30,"pid":1,"tid":1,"args":{"depth":1}}],"displayTimeUnit":"ms"}"#,
+        ));
+    }
+
+    #[test]
+    fn traced_document() {
+        let mut doc = SDoc::src("fn outer(x: int): int { return self.inner(x) + 1; } fn inner(x: int): int { return x * 2; }", "stof").unwrap();
+        let trace = RunTrace::new();
+        trace_document(&mut doc, trace.clone());
+        assert_eq!(doc.call_func("root.outer", None, vec![SVal::from(2)]).unwrap(), SVal::from(5));
+
+        let trace = trace.lock().unwrap();
+        let spans = trace.spans.iter().map(|span| (span.name.as_str(), span.depth, span.stack.clone())).collect::<Vec<_>>();
+        assert_eq!(spans, vec![("root.inner", 1, vec![String::from("root.outer")]), ("root.outer", 0, vec![])]);
+        assert!(trace.folded().contains("root.outer;root.inner "));
+    }
+}
@@ -0,0 +1,261 @@
+//
+// Copyright 2024 Formata, Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+use std::{collections::BTreeMap, sync::Arc};
+use axum::{extract::{ws::{close_code, CloseFrame, Message, WebSocket, WebSocketUpgrade}, Query, State}, http::{HeaderMap, StatusCode}, response::{IntoResponse, Response}};
+use bytes::Bytes;
+use stof::{SData, SDoc, SFunc};
+use tokio::{runtime::Handle, sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender}, task::spawn_blocking, time::{sleep_until, Instant}};
+use crate::{bus::run_bus, config::run_enabled, metrics::increment_server_run_count, response::StofResponse, kv::run_kv, libraries::run_libraries, sql::run_sql, secrets::run_secrets, server::ServerState, users::auth::{auth_exec, auth_user}};
+use super::{cancel::RunCancel, execute_document, initialize_document, logs::{RunLogs, RunStdLibrary}, runner_lib::{RunnerLibrary, RunnerMessage}, secrets_lib::SecretsLibrary, RunOptions};
+
+
+/// Websocket run handler.
+///
+/// The first message from the client is the document (content type given by "?format=", default is "stof").
+/// Once the document has been executed, every following message is delivered to each `#[on_message]` function in the document.
+/// Text messages are passed as strings and binary messages as blobs.
+///
+/// Documents send messages to the client with `Runner.send(value)`.
+/// Non-empty values returned from `#[on_message]` functions are sent to the client as well.
+///
+/// The run timeout applies to executing the document and to handling each message.
+/// Once the connection is done, the run is cancelled, so a document that is still running fails at its next library call.
+/// Errors close the connection, with the error as the close reason (truncated to 123 bytes).
+pub(crate) async fn ws_run_handler(ws: WebSocketUpgrade, State(state): State<ServerState>, Query(query): Query<BTreeMap<String, String>>, headers: HeaderMap) -> Response {
+    if !auth_exec(&state, &headers).await {
+        return StofResponse::error(StatusCode::FORBIDDEN, "access denied").into_response();
+    }
+
+    let mut options;
+    {
+        let mut config = state.config.lock().await;
+        if !run_enabled(&config) {
+            return StofResponse::error(StatusCode::NOT_IMPLEMENTED, "runner is not available").into_response();
+        }
+        options = RunOptions::from_config(&mut config);
+    }
+    if options.sandbox.is_some() {
+        return StofResponse::error(StatusCode::NOT_IMPLEMENTED, "websocket runs cannot run in the sandbox, which is required").into_response();
+    }
+    if let Some(format) = query.get("format") {
+        options.content_type = format.clone();
+    }
+    let user = auth_user(&state, &headers).await;
+    options.kv = run_kv(&state, &user, None).await;
+    options.sql = run_sql(&state, &user, None).await;
+    options.bus = run_bus(&state, &user, None).await;
+    options.libraries = run_libraries(&state, &user, None).await;
+    options.secrets = run_secrets(&state, user, None).await;
+
+    // metrics
+    {
+        let mut metrics = state.metrics.lock().await;
+        increment_server_run_count(&mut metrics);
+    }
+
+    ws.on_upgrade(move |socket| handle_socket(socket, options))
+}
+
+
+/// Handle a websocket run connection.
+async fn handle_socket(mut socket: WebSocket, options: RunOptions) {
+    // First message is the document
+    let body = match socket.recv().await {
+        Some(Ok(Message::Text(text))) => Bytes::copy_from_slice(text.as_str().as_bytes()),
+        Some(Ok(Message::Binary(bytes))) => bytes,
+        _ => {
+            return;
+        }
+    };
+
+    let (incoming, incoming_rx) = unbounded_channel::<RunnerMessage>();
+    let (outgoing, mut outgoing_rx) = unbounded_channel::<RunnerMessage>();
+    let (status, mut status_rx) = unbounded_channel::<Result<(), String>>();
+    let run_options = options.clone();
+    let cancel = RunCancel::default();
+    let run_cancel = cancel.clone();
+    spawn_blocking(move || run_document(run_options, body, run_cancel, incoming_rx, outgoing, status));
+
+    // Executing the document is the first pending step
+    let mut pending = 1;
+    let mut deadline = Instant::now() + options.timeout;
+    let mut close_reason = None;
+    loop {
+        tokio::select! {
+            message = socket.recv() => {
+                match message {
+                    Some(Ok(Message::Text(text))) => {
+                        if pending < 1 {
+                            deadline = Instant::now() + options.timeout;
+                        }
+                        pending += 1;
+                        let _ = incoming.send(RunnerMessage::Text(text.as_str().to_owned()));
+                    },
+                    Some(Ok(Message::Binary(bytes))) => {
+                        if pending < 1 {
+                            deadline = Instant::now() + options.timeout;
+                        }
+                        pending += 1;
+                        let _ = incoming.send(RunnerMessage::Binary(bytes.to_vec()));
+                    },
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => {
+                        break;
+                    },
+                    _ => {
+                        // Ping and pong are handled by axum
+                    }
+                }
+            },
+            Some(message) = outgoing_rx.recv() => {
+                let message = match message {
+                    RunnerMessage::Text(text) => Message::Text(text.into()),
+                    RunnerMessage::Binary(blob) => Message::Binary(blob.into()),
+                };
+                if socket.send(message).await.is_err() {
+                    break;
+                }
+            },
+            step = status_rx.recv() => {
+                match step {
+                    Some(Ok(_)) => {
+                        pending -= 1;
+                        deadline = Instant::now() + options.timeout;
+                    },
+                    Some(Err(message)) => {
+                        close_reason = Some((close_code::ERROR, message));
+                        break;
+                    },
+                    None => {
+                        break;
+                    }
+                }
+            },
+            _ = sleep_until(deadline), if pending > 0 => {
+                close_reason = Some((close_code::ERROR, String::from("timeout while running document")));
+                break;
+            },
+        }
+    }
+
+    // The connection is done (closed, failed, or timed out), so the document can't do anything more
+    cancel.cancel();
+
+    // Send anything the document already sent before closing
+    while let Ok(message) = outgoing_rx.try_recv() {
+        let message = match message {
+            RunnerMessage::Text(text) => Message::Text(text.into()),
+            RunnerMessage::Binary(blob) => Message::Binary(blob.into()),
+        };
+        let _ = socket.send(message).await;
+    }
+    let (code, reason) = close_reason.unwrap_or((close_code::NORMAL, String::default()));
+    let _ = socket.send(Message::Close(Some(CloseFrame { code, reason: close_frame_reason(&reason).into() }))).await;
+}
+
+
+/// Max size of a close frame reason (bytes), since control frame payloads are at most 125 bytes (including the 2 byte code).
+const MAX_CLOSE_REASON: usize = 123;
+
+
+/// Close frame reason for a message, truncated to fit in a close frame (at a char boundary).
+fn close_frame_reason(message: &str) -> &str {
+    let mut end = message.len().min(MAX_CLOSE_REASON);
+    while !message.is_char_boundary(end) {
+        end -= 1;
+    }
+    &message[..end]
+}
+
+
+/// Run a websocket document (on a blocking thread).
+/// Reports the result of executing the document and handling each message on the status channel.
+/// Library calls fail once the run is cancelled (on timeout).
+fn run_document(options: RunOptions, mut body: Bytes, cancel: RunCancel, mut incoming: UnboundedReceiver<RunnerMessage>, outgoing: UnboundedSender<RunnerMessage>, status: UnboundedSender<Result<(), String>>) {
+    let logs = RunLogs::new(options.max_log_size, None);
+    let mut doc = SDoc::default();
+    Handle::current().block_on(initialize_document(&mut doc, &options.registry_path, &options.egress, &options.wasm, &options.kv, &options.sql, &options.bus));
+    doc.load_lib(Arc::new(RunStdLibrary::new(logs.clone())));
+    doc.load_lib(Arc::new(RunnerLibrary::new(logs.clone(), Some(outgoing.clone()), None)));
+    doc.load_lib(Arc::new(SecretsLibrary::new(options.secrets.clone(), logs.clone())));
+    options.libraries.restrict(&mut doc);
+
+    // Errors are sent to the client as the close reason, so secrets are redacted from them
+    let fail = |message: String| {
+        let _ = status.send(Err(logs.lock().unwrap().redact(&message)));
+    };
+
+    if let Err(error) = doc.header_import("main", &options.content_type, &options.content_type, &mut body, "") {
+        if !options.opaque_errors {
+            fail(error.to_string(&doc.graph));
+        } else {
+            fail("error parsing document".into());
+        }
+        return;
+    }
+    cancel.guard(&mut doc);
+    if let Err(error) = execute_document(&mut doc, options.opaque_errors) {
+        fail(error.text);
+        return;
+    }
+    let handlers = message_handlers(&doc);
+    let _ = status.send(Ok(()));
+
+    // Deliver messages until the connection is closed
+    while let Some(message) = incoming.blocking_recv() {
+        let value = message.into_val();
+        for handler in &handlers {
+            match doc.call_func(handler, None, vec![value.clone()]) {
+                Ok(res) => {
+                    if !res.is_empty() {
+                        match RunnerMessage::from_val("main", &doc, res) {
+                            Ok(message) => {
+                                let _ = outgoing.send(message.redact(&logs.lock().unwrap()));
+                            },
+                            Err(error) => {
+                                fail(error.to_string(&doc.graph));
+                                return;
+                            }
+                        }
+                    }
+                },
+                Err(error) => {
+                    if !options.opaque_errors {
+                        fail(error.to_string(&doc.graph));
+                    } else {
+                        fail("error handling message".into());
+                    }
+                    return;
+                }
+            }
+        }
+        if status.send(Ok(())).is_err() {
+            return;
+        }
+    }
+}
+
+
+/// Paths to all of the "on_message" functions in a document.
+fn message_handlers(doc: &SDoc) -> Vec<String> {
+    let mut handlers = Vec::new();
+    for func_ref in SFunc::all_funcs(&doc.graph) {
+        if let Some(func) = SData::get::<SFunc>(&doc.graph, &func_ref) && func.attributes.contains_key("on_message") && let Some(node) = func_ref.nodes(&doc.graph).first() {
+            handlers.push(format!("{}.{}", node.path(&doc.graph).replace('/', "."), func.name));
+        }
+    }
+    handlers
+}
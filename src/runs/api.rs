@@ -0,0 +1,146 @@
+//
+// Copyright 2024 Formata, Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+use std::collections::BTreeMap;
+use axum::{extract::{Path, Query, State}, http::{HeaderMap, StatusCode}, response::IntoResponse};
+use stof::SDoc;
+use crate::{config::run_enabled, kv::run_kv, libraries::run_libraries, metrics::increment_server_run_count, response::StofResponse, run::{cache::package_hash, error::error_format, export_document, record::RunRecording, run_stof, RunOptions}, secrets::run_secrets, server::ServerState, sql::run_sql, users::auth::auth_admin};
+
+
+/// Get the recorded runs (summaries, by ID).
+/// Uses the "export" query to determine the format (default is "json").
+pub(crate) async fn admin_get_runs_handler(State(state): State<ServerState>, Query(query): Query<BTreeMap<String, String>>, headers: HeaderMap) -> impl IntoResponse {
+    if !auth_admin(&state, &headers, true).await {
+        return StofResponse::error(StatusCode::FORBIDDEN, "access denied");
+    }
+
+    let mut export_format = String::from("json");
+    if let Some(format) = query.get("export") {
+        export_format = format.clone();
+    }
+
+    let mut doc = SDoc::default();
+    let mut runs = state.runs.lock().await;
+    for id in runs.ids.clone() {
+        if let Some(summary) = runs.summary(&id) {
+            summary.insert_into(&mut doc, &id);
+        }
+    }
+    export_document(&doc, &export_format)
+}
+
+
+/// Get a recorded run.
+/// Uses the "export" query to determine the format (default is "json").
+pub(crate) async fn admin_get_run_handler(State(state): State<ServerState>, Path(id): Path<String>, Query(query): Query<BTreeMap<String, String>>, headers: HeaderMap) -> impl IntoResponse {
+    if !auth_admin(&state, &headers, true).await {
+        return StofResponse::error(StatusCode::FORBIDDEN, "access denied");
+    }
+
+    let mut export_format = String::from("json");
+    if let Some(format) = query.get("export") {
+        export_format = format.clone();
+    }
+
+    let runs = state.runs.lock().await;
+    if let Some(record) = runs.get(&id) {
+        return export_document(&record.to_doc(), &export_format);
+    }
+    StofResponse::error(StatusCode::NOT_FOUND, "run not found")
+}
+
+
+/// Delete a recorded run.
+pub(crate) async fn admin_delete_run_handler(State(state): State<ServerState>, Path(id): Path<String>, headers: HeaderMap) -> impl IntoResponse {
+    if !auth_admin(&state, &headers, false).await {
+        return StofResponse::error(StatusCode::FORBIDDEN, "access denied");
+    }
+
+    let mut runs = state.runs.lock().await;
+    if runs.delete(&id) {
+        return StofResponse::msg(StatusCode::OK, "deleted run");
+    }
+    StofResponse::error(StatusCode::NOT_FOUND, "run not found")
+}
+
+
+/// Replay a recorded run.
+/// Executes the run again with its recorded options, body, and user, returning HTTP responses and clock values
+/// from the recording instead of making the calls. The response is the same as the original run's would be.
+///
+/// Packages that changed since the run was recorded are a conflict (409), unless "?force=true" is given.
+///
+/// Replays cannot publish bus messages, and by default cannot use the key-value store, databases, or secrets either,
+/// so that replaying a run doesn't change (or read) live data. Use "?live=true" to replay with the recorded user's current
+/// key-value store, databases, and secrets. Secret values are redacted from recorded HTTP calls, so replays see them redacted.
+pub(crate) async fn admin_replay_run_handler(State(state): State<ServerState>, Path(id): Path<String>, Query(query): Query<BTreeMap<String, String>>, headers: HeaderMap) -> impl IntoResponse {
+    if !auth_admin(&state, &headers, false).await {
+        return StofResponse::error(StatusCode::FORBIDDEN, "access denied");
+    }
+
+    let record;
+    {
+        let runs = state.runs.lock().await;
+        match runs.get(&id) {
+            Some(value) => record = value,
+            None => return StofResponse::error(StatusCode::NOT_FOUND, "run not found"),
+        }
+    }
+
+    let mut options;
+    {
+        let mut config = state.config.lock().await;
+        if !run_enabled(&config) {
+            return StofResponse::error(StatusCode::NOT_IMPLEMENTED, "runner is not available");
+        }
+        options = RunOptions::from_config(&mut config);
+    }
+    if options.sandbox.is_some() {
+        // Replays use the recorded HTTP responses and clock, which only work in-process
+        return StofResponse::error(StatusCode::NOT_IMPLEMENTED, "runs cannot be replayed when the sandbox is required");
+    }
+    record.apply(&mut options);
+    if !options.opaque_errors {
+        options.error_format = Some(error_format(&headers));
+    }
+
+    let force = query.get("force").is_some_and(|force| force == "true");
+    if !force {
+        for (package, hash) in &record.packages {
+            if package_hash(&options.registry_path, package) != *hash {
+                return StofResponse::error(StatusCode::CONFLICT, &format!("package @{} changed since the run was recorded", package));
+            }
+        }
+    }
+
+    if query.get("live").is_some_and(|live| live == "true") {
+        options.kv = run_kv(&state, &record.user, options.package.as_deref()).await;
+        options.sql = run_sql(&state, &record.user, options.package.as_deref()).await;
+        options.secrets = run_secrets(&state, record.user.clone(), options.package.as_deref()).await;
+    }
+    options.libraries = run_libraries(&state, &record.user, options.package.as_deref()).await;
+    options.recording = Some(RunRecording::replay(record.http, record.clock, options.secrets.values().cloned().collect()));
+
+    // metrics
+    {
+        let mut metrics = state.metrics.lock().await;
+        increment_server_run_count(&mut metrics);
+    }
+
+    let mut response = run_stof(options, record.body, None).await;
+    response.headers.insert("x-replay-of", record.id.parse().unwrap());
+    response
+}
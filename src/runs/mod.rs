@@ -0,0 +1,383 @@
+//
+// Copyright 2024 Formata, Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+use std::{collections::{BTreeMap, HashMap, VecDeque}, fs};
+use bytes::Bytes;
+use chrono::Utc;
+use nanoid::nanoid;
+use stof::{SData, SDoc, SField, SNum, SUnits, SVal};
+use crate::{config::{registry_path, runs_max_records, runs_max_size}, run::{scratch::RunFile, RunOptions}};
+pub(crate) mod api;
+
+
+/// Alphabet for recorded run IDs (used as file names in the records directory).
+const ID_ALPHABET: [char; 36] = [
+    'a', 'b', 'c', 'd', 'e', 'f', 'g', 'h', 'i', 'j', 'k', 'l', 'm', 'n', 'o', 'p', 'q', 'r', 's', 't', 'u', 'v', 'w', 'x', 'y', 'z',
+    '0', '1', '2', '3', '4', '5', '6', '7', '8', '9',
+];
+
+
+/// Recorded run.
+/// Everything needed to execute a run again as it happened: its options and body, the hashes of the packages it imported,
+/// and the HTTP responses and clock values it saw.
+pub(crate) struct RunRecord {
+    pub id: String,
+    pub time: i64,
+    pub status: u16,
+    pub error: String,
+
+    /// User that made the run (name, admin), if authenticated.
+    pub user: Option<(String, bool)>,
+
+    pub content_type: String,
+    pub export_format: String,
+    pub export_path: Option<String>,
+    pub logs: bool,
+    pub logs_header: bool,
+    pub scratch_zip: bool,
+    pub package: Option<String>,
+    pub entry: Option<String>,
+    pub body: Bytes,
+    pub inputs_json: Option<Bytes>,
+    pub inputs: BTreeMap<String, String>,
+    pub files: Vec<RunFile>,
+
+    /// Packages imported by the run ("scope/name" -> hash).
+    pub packages: BTreeMap<String, String>,
+
+    /// Recorded HTTP calls and clock reads (see RunRecording).
+    pub http: Vec<SVal>,
+    pub clock: Vec<SVal>,
+}
+impl RunRecord {
+    /// New record of a run, with a new ID.
+    pub fn new(options: &RunOptions, body: &Bytes, user: &Option<(String, bool)>) -> Self {
+        Self {
+            id: nanoid!(16, &ID_ALPHABET),
+            time: Utc::now().timestamp_millis(),
+            status: 0,
+            error: String::default(),
+            user: user.clone(),
+            content_type: options.content_type.clone(),
+            export_format: options.export_format.clone(),
+            export_path: options.export_path.clone(),
+            logs: options.logs,
+            logs_header: options.logs_header,
+            scratch_zip: options.scratch_zip,
+            package: options.package.clone(),
+            entry: options.entry.clone(),
+            body: body.clone(),
+            inputs_json: options.inputs.json.clone(),
+            inputs: options.inputs.values.clone(),
+            files: options.files.clone(),
+            packages: BTreeMap::new(),
+            http: Vec::new(),
+            clock: Vec::new(),
+        }
+    }
+
+    /// Set the recorded options on run options (for a replay).
+    pub fn apply(&self, options: &mut RunOptions) {
+        options.content_type = self.content_type.clone();
+        options.export_format = self.export_format.clone();
+        options.export_path = self.export_path.clone();
+        options.logs = self.logs;
+        options.logs_header = self.logs_header;
+        options.scratch_zip = self.scratch_zip;
+        options.package = self.package.clone();
+        options.entry = self.entry.clone();
+        options.inputs.json = self.inputs_json.clone();
+        options.inputs.values = self.inputs.clone();
+        options.files = self.files.clone();
+    }
+
+    /// Record document.
+    pub fn to_doc(&self) -> SDoc {
+        let mut doc = SDoc::default();
+        if let Some(main) = doc.graph.main_root() {
+            let optional = |value: &Option<String>| value.clone().map(SVal::String).unwrap_or(SVal::Null);
+
+            // Text bodies are kept as strings so that records are readable
+            let body = match std::str::from_utf8(&self.body) {
+                Ok(text) => SVal::String(text.to_owned()),
+                Err(_) => SVal::Blob(self.body.to_vec()),
+            };
+            let mut fields = vec![
+                ("id", SVal::String(self.id.clone())),
+                ("time", SVal::Number(SNum::Units(self.time as f64, SUnits::Milliseconds))),
+                ("status", SVal::Number(SNum::I64(self.status as i64))),
+                ("error", SVal::String(self.error.clone())),
+                ("content_type", SVal::String(self.content_type.clone())),
+                ("export", SVal::String(self.export_format.clone())),
+                ("path", optional(&self.export_path)),
+                ("logs", SVal::Bool(self.logs)),
+                ("logs_header", SVal::Bool(self.logs_header)),
+                ("scratch_zip", SVal::Bool(self.scratch_zip)),
+                ("package", optional(&self.package)),
+                ("entry", optional(&self.entry)),
+                ("body", body),
+                ("inputs_json", self.inputs_json.as_ref().map(|json| SVal::Blob(json.to_vec())).unwrap_or(SVal::Null)),
+                ("inputs", SVal::Map(self.inputs.iter().map(|(name, value)| (SVal::String(name.clone()), SVal::String(value.clone()))).collect())),
+                ("files", SVal::Array(self.files.iter().map(|file| SVal::Tuple(vec![SVal::String(file.name.clone()), SVal::Blob(file.bytes.to_vec())])).collect())),
+                ("packages", SVal::Map(self.packages.iter().map(|(name, hash)| (SVal::String(name.clone()), SVal::String(hash.clone()))).collect())),
+                ("http", SVal::Array(self.http.clone())),
+                ("clock", SVal::Array(self.clock.clone())),
+            ];
+            if let Some((user, admin)) = &self.user {
+                fields.push(("user", SVal::String(user.clone())));
+                fields.push(("admin", SVal::Bool(*admin)));
+            }
+            for (name, value) in fields {
+                SData::insert_new(&mut doc.graph, &main, Box::new(SField::new(name, value)));
+            }
+        }
+        doc
+    }
+
+    /// Record from a record document.
+    pub fn from_doc(doc: &SDoc) -> Option<Self> {
+        let field = |name: &str| doc.field(&format!("root.{}", name), None).map(|field| field.value.clone()).unwrap_or(SVal::Null);
+        let string = |name: &str| match field(name) {
+            SVal::String(value) => Some(value),
+            _ => None,
+        };
+        let flag = |name: &str| matches!(field(name), SVal::Bool(true));
+
+        let mut record = Self {
+            id: string("id")?,
+            time: 0,
+            status: 0,
+            error: string("error").unwrap_or_default(),
+            user: None,
+            content_type: string("content_type")?,
+            export_format: string("export")?,
+            export_path: string("path"),
+            logs: flag("logs"),
+            logs_header: flag("logs_header"),
+            scratch_zip: flag("scratch_zip"),
+            package: string("package"),
+            entry: string("entry"),
+            body: Bytes::default(),
+            inputs_json: None,
+            inputs: BTreeMap::new(),
+            files: Vec::new(),
+            packages: BTreeMap::new(),
+            http: Vec::new(),
+            clock: Vec::new(),
+        };
+        if let SVal::Number(time) = field("time") {
+            record.time = time.float_with_units(SUnits::Milliseconds) as i64;
+        }
+        if let SVal::Number(status) = field("status") {
+            record.status = status.int() as u16;
+        }
+        if let Some(user) = string("user") {
+            record.user = Some((user, flag("admin")));
+        }
+        match field("body") {
+            SVal::String(body) => record.body = Bytes::from(body),
+            SVal::Blob(body) => record.body = Bytes::from(body),
+            _ => {}
+        }
+        if let SVal::Blob(json) = field("inputs_json") {
+            record.inputs_json = Some(Bytes::from(json));
+        }
+        if let SVal::Map(inputs) = field("inputs") {
+            for (name, value) in inputs {
+                record.inputs.insert(name.to_string(), value.to_string());
+            }
+        }
+        if let SVal::Array(files) = field("files") {
+            for file in files {
+                if let SVal::Tuple(values) = file && let [SVal::String(name), SVal::Blob(bytes)] = values.as_slice() {
+                    record.files.push(RunFile {
+                        name: name.clone(),
+                        bytes: Bytes::from(bytes.clone()),
+                    });
+                }
+            }
+        }
+        if let SVal::Map(packages) = field("packages") {
+            for (name, hash) in packages {
+                record.packages.insert(name.to_string(), hash.to_string());
+            }
+        }
+        if let SVal::Array(http) = field("http") {
+            record.http = http;
+        }
+        if let SVal::Array(clock) = field("clock") {
+            record.clock = clock;
+        }
+        Some(record)
+    }
+}
+
+
+/// Summary of a recorded run (without the body, files, and recorded values).
+#[derive(Clone)]
+pub(crate) struct RunSummary {
+    pub time: i64,
+    pub status: u16,
+    pub error: String,
+    pub package: Option<String>,
+    pub user: Option<String>,
+
+    /// Number of recorded HTTP calls and clock reads.
+    pub http: usize,
+    pub clock: usize,
+}
+impl RunSummary {
+    /// Summary of a record.
+    pub fn new(record: &RunRecord) -> Self {
+        Self {
+            time: record.time,
+            status: record.status,
+            error: record.error.clone(),
+            package: record.package.clone(),
+            user: record.user.as_ref().map(|(user, _)| user.clone()),
+            http: record.http.len(),
+            clock: record.clock.len(),
+        }
+    }
+
+    /// Insert this summary into a document, as an object on the main root.
+    pub fn insert_into(&self, doc: &mut SDoc, name: &str) {
+        if let Some(main) = doc.graph.main_root() {
+            let node = SField::new_object(&mut doc.graph, name, &main);
+            let mut fields = vec![
+                ("time", SVal::Number(SNum::Units(self.time as f64, SUnits::Milliseconds))),
+                ("status", SVal::Number(SNum::I64(self.status as i64))),
+                ("error", SVal::String(self.error.clone())),
+                ("package", self.package.clone().map(SVal::String).unwrap_or(SVal::Null)),
+                ("http", SVal::Number(SNum::I64(self.http as i64))),
+                ("clock", SVal::Number(SNum::I64(self.clock as i64))),
+            ];
+            if let Some(user) = &self.user {
+                fields.push(("user", SVal::String(user.clone())));
+            }
+            for (name, value) in fields {
+                SData::insert_new(&mut doc.graph, &node, Box::new(SField::new(name, value)));
+            }
+        }
+    }
+}
+
+
+/// Recorded runs.
+/// Each record is saved as a bstof document in the records directory ("<registry>/__runs__/<id>.bstof").
+/// The records directory is a reserved entry in the registry directory, so runs cannot read records with "fs".
+pub(crate) struct RunRecords {
+    pub path: String,
+    pub max_records: usize,
+    pub max_size: usize,
+
+    /// Record IDs, oldest first.
+    pub ids: VecDeque<String>,
+
+    /// Summaries of the records (by ID), so that listing records doesn't read them all from disk.
+    /// Filled in as records are saved, or read once for records from a previous start.
+    pub summaries: HashMap<String, RunSummary>,
+}
+impl RunRecords {
+    /// Is this a valid record ID?
+    fn valid_id(id: &str) -> bool {
+        !id.is_empty() && id.chars().all(|c| c.is_ascii_alphanumeric())
+    }
+
+    fn file_path(&self, id: &str) -> String {
+        format!("{}/{}.bstof", self.path, id)
+    }
+
+    /// Save a record, removing the oldest records past the max.
+    /// Records larger than the max size are not saved.
+    pub fn insert(&mut self, record: &RunRecord) -> bool {
+        if self.max_records < 1 {
+            return false;
+        }
+        
+        let bytes = match record.to_doc().export_bytes("main", "bstof", None) {
+            Ok(value) => value,
+            Err(_) => return false,
+        };
+        if bytes.len() > self.max_size || fs::create_dir_all(&self.path).is_err() || fs::write(self.file_path(&record.id), bytes).is_err() {
+            return false;
+        }
+        self.ids.push_back(record.id.clone());
+        self.summaries.insert(record.id.clone(), RunSummary::new(record));
+        while self.ids.len() > self.max_records {
+            if let Some(id) = self.ids.pop_front() {
+                self.summaries.remove(&id);
+                let _ = fs::remove_file(self.file_path(&id));
+            }
+        }
+        true
+    }
+
+    /// Get a record summary (read from the record the first time if it isn't known yet).
+    pub fn summary(&mut self, id: &str) -> Option<RunSummary> {
+        if let Some(summary) = self.summaries.get(id) {
+            return Some(summary.clone());
+        }
+        let summary = RunSummary::new(&self.get(id)?);
+        self.summaries.insert(id.to_owned(), summary.clone());
+        Some(summary)
+    }
+
+    /// Get a record.
+    pub fn get(&self, id: &str) -> Option<RunRecord> {
+        if !Self::valid_id(id) || !self.ids.contains(&id.to_owned()) {
+            return None;
+        }
+        let doc = SDoc::file(&self.file_path(id), "bstof").ok()?;
+        RunRecord::from_doc(&doc)
+    }
+
+    /// Delete a record.
+    pub fn delete(&mut self, id: &str) -> bool {
+        if let Some(index) = self.ids.iter().position(|record_id| record_id == id) {
+            self.ids.remove(index);
+            self.summaries.remove(id);
+            let _ = fs::remove_file(self.file_path(id));
+            return true;
+        }
+        false
+    }
+}
+
+
+/// Load the recorded runs (IDs from the records directory, oldest first).
+pub(crate) fn load_runs(config: &SDoc) -> RunRecords {
+    let mut records = RunRecords {
+        path: format!("{}/__runs__", registry_path(config)),
+        max_records: runs_max_records(config),
+        max_size: runs_max_size(config),
+        ids: VecDeque::new(),
+        summaries: HashMap::new(),
+    };
+    if let Ok(entries) = fs::read_dir(&records.path) {
+        let mut files = Vec::new();
+        for entry in entries.flatten() {
+            let name = entry.file_name().to_string_lossy().to_string();
+            if let Some(id) = name.strip_suffix(".bstof") && RunRecords::valid_id(id) {
+                let modified = entry.metadata().and_then(|meta| meta.modified()).ok();
+                files.push((modified, id.to_owned()));
+            }
+        }
+        files.sort();
+        records.ids = files.into_iter().map(|(_, id)| id).collect();
+    }
+    records
+}
@@ -0,0 +1,236 @@
+//
+// Copyright 2024 Formata, Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+
+use std::{fs::File, io, os::fd::FromRawFd};
+use tokio::process::Command;
+use super::SandboxPolicy;
+
+
+/// Audit architecture of this build (checked by the seccomp filter).
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: u32 = 0xC000_003E;
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: u32 = 0xC000_00B7;
+
+
+/// System calls that sandboxed runs can never make.
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+const DENIED_SYSCALLS: &[libc::c_long] = &[
+    libc::SYS_execve,
+    libc::SYS_execveat,
+    libc::SYS_ptrace,
+    libc::SYS_process_vm_readv,
+    libc::SYS_process_vm_writev,
+    libc::SYS_kill,
+    libc::SYS_tkill,
+    libc::SYS_mount,
+    libc::SYS_umount2,
+    libc::SYS_pivot_root,
+    libc::SYS_chroot,
+    libc::SYS_unshare,
+    libc::SYS_setns,
+    libc::SYS_reboot,
+    libc::SYS_kexec_load,
+    libc::SYS_kexec_file_load,
+    libc::SYS_init_module,
+    libc::SYS_finit_module,
+    libc::SYS_delete_module,
+    libc::SYS_bpf,
+    libc::SYS_perf_event_open,
+    libc::SYS_keyctl,
+    libc::SYS_add_key,
+    libc::SYS_request_key,
+    libc::SYS_userfaultfd,
+    libc::SYS_swapon,
+    libc::SYS_swapoff,
+    libc::SYS_acct,
+    libc::SYS_settimeofday,
+    libc::SYS_clock_settime,
+    libc::SYS_sethostname,
+    libc::SYS_setdomainname,
+    libc::SYS_quotactl,
+    libc::SYS_name_to_handle_at,
+    libc::SYS_open_by_handle_at,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_fork,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_vfork,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_iopl,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_ioperm,
+];
+
+
+/// Isolate the sandbox process before it starts: new namespaces and resource limits.
+/// The process is also killed if the server exits.
+///
+/// The mount namespace only keeps the sandbox's mounts to itself: the filesystem is not isolated (nothing is remounted or pivoted),
+/// so the sandbox process can see what the runner can. Runs only reach it through the sandboxed "fs" library,
+/// and seccomp blocks exec, mount, and pivot_root, but a run that escapes the Stof interpreter is only limited by the user namespace.
+pub(crate) fn isolate(command: &mut Command, policy: &SandboxPolicy) -> Result<(), String> {
+    let mut flags = 0;
+    if policy.namespaces {
+        flags = libc::CLONE_NEWUSER | libc::CLONE_NEWIPC | libc::CLONE_NEWUTS | libc::CLONE_NEWNS;
+        if !policy.network {
+            flags |= libc::CLONE_NEWNET;
+        }
+    }
+    let max_memory = policy.max_memory as libc::rlim_t;
+    let max_cpu = policy.max_cpu.as_secs().max(1) as libc::rlim_t;
+    let max_files = policy.max_files as libc::rlim_t;
+
+    // Runs in the forked child before exec, so only async-signal-safe calls
+    unsafe {
+        command.pre_exec(move || {
+            if libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            if flags != 0 && libc::unshare(flags) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            set_limit(libc::RLIMIT_AS, max_memory, max_memory)?;
+            set_limit(libc::RLIMIT_CPU, max_cpu, max_cpu + 1)?;
+            set_limit(libc::RLIMIT_NOFILE, max_files, max_files)?;
+            set_limit(libc::RLIMIT_CORE, 0, 0)?;
+            Ok(())
+        });
+    }
+    Ok(())
+}
+
+
+/// Set a resource limit.
+fn set_limit(resource: libc::__rlimit_resource_t, soft: libc::rlim_t, hard: libc::rlim_t) -> io::Result<()> {
+    let limit = libc::rlimit {
+        rlim_cur: soft,
+        rlim_max: hard,
+    };
+    if unsafe { libc::setrlimit(resource, &limit) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+
+/// Protocol output for the sandbox process: a copy of stdout, with stdout itself redirected to stderr.
+pub(crate) fn protocol_output() -> Result<File, String> {
+    unsafe {
+        let fd = libc::dup(libc::STDOUT_FILENO);
+        if fd < 0 {
+            return Err(io::Error::last_os_error().to_string());
+        }
+        if libc::dup2(libc::STDERR_FILENO, libc::STDOUT_FILENO) < 0 {
+            return Err(io::Error::last_os_error().to_string());
+        }
+        Ok(File::from_raw_fd(fd))
+    }
+}
+
+
+/// Install a seccomp filter on every thread of this process.
+/// Denied system calls fail with EPERM, new processes cannot be created (only threads), signals can only be sent to this process,
+/// and sockets cannot be opened unless the network is allowed.
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+pub(crate) fn restrict_syscalls(network: bool) -> Result<(), String> {
+    const SYSCALL_NR: u32 = 0;
+    const ARCH: u32 = 4;
+    const ARG0: u32 = 16;
+    let deny = libc::SECCOMP_RET_ERRNO | libc::EPERM as u32;
+    let allow = libc::SECCOMP_RET_ALLOW;
+
+    let pid = unsafe { libc::getpid() } as u32;
+    let mut filter = vec![
+        stmt(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, ARCH),
+        jump(libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K, AUDIT_ARCH, 1, 0),
+        stmt(libc::BPF_RET | libc::BPF_K, libc::SECCOMP_RET_KILL_PROCESS),
+        stmt(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, SYSCALL_NR),
+    ];
+    let mut denied = DENIED_SYSCALLS.to_vec();
+    if !network {
+        denied.push(libc::SYS_socket);
+    }
+    for nr in denied {
+        filter.push(jump(libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K, nr as u32, 0, 1));
+        filter.push(stmt(libc::BPF_RET | libc::BPF_K, deny));
+    }
+
+    // clone3 flags cannot be checked, so it is reported as unavailable (libc falls back to clone)
+    filter.push(jump(libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K, libc::SYS_clone3 as u32, 0, 1));
+    filter.push(stmt(libc::BPF_RET | libc::BPF_K, libc::SECCOMP_RET_ERRNO | libc::ENOSYS as u32));
+
+    // clone is only for threads
+    filter.push(jump(libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K, libc::SYS_clone as u32, 0, 4));
+    filter.push(stmt(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, ARG0));
+    filter.push(jump(libc::BPF_JMP | libc::BPF_JSET | libc::BPF_K, libc::CLONE_THREAD as u32, 0, 1));
+    filter.push(stmt(libc::BPF_RET | libc::BPF_K, allow));
+    filter.push(stmt(libc::BPF_RET | libc::BPF_K, deny));
+
+    // tgkill is only for threads of this process (abort uses it)
+    filter.push(jump(libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K, libc::SYS_tgkill as u32, 0, 4));
+    filter.push(stmt(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, ARG0));
+    filter.push(jump(libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K, pid, 0, 1));
+    filter.push(stmt(libc::BPF_RET | libc::BPF_K, allow));
+    filter.push(stmt(libc::BPF_RET | libc::BPF_K, deny));
+
+    filter.push(stmt(libc::BPF_RET | libc::BPF_K, allow));
+
+    let program = libc::sock_fprog {
+        len: filter.len() as u16,
+        filter: filter.as_mut_ptr(),
+    };
+    unsafe {
+        if libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) != 0 {
+            return Err(format!("error setting no_new_privs: {}", io::Error::last_os_error()));
+        }
+        if libc::syscall(libc::SYS_seccomp, libc::SECCOMP_SET_MODE_FILTER, libc::SECCOMP_FILTER_FLAG_TSYNC, &program as *const libc::sock_fprog) != 0 {
+            return Err(format!("error installing seccomp filter: {}", io::Error::last_os_error()));
+        }
+    }
+    Ok(())
+}
+
+
+/// Seccomp is only supported on x86_64 and aarch64.
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+pub(crate) fn restrict_syscalls(_network: bool) -> Result<(), String> {
+    Err(String::from("seccomp is not supported on this architecture"))
+}
+
+
+/// BPF statement.
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+fn stmt(code: u32, k: u32) -> libc::sock_filter {
+    libc::sock_filter {
+        code: code as u16,
+        jt: 0,
+        jf: 0,
+        k,
+    }
+}
+
+
+/// BPF jump.
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+fn jump(code: u32, k: u32, jt: u8, jf: u8) -> libc::sock_filter {
+    libc::sock_filter {
+        code: code as u16,
+        jt,
+        jf,
+        k,
+    }
+}
@@ -0,0 +1,450 @@
+//
+// Copyright 2024 Formata, Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+
+use std::{io::{self, Read, Write}, process::Stdio, time::Duration};
+use axum::http::{header::CONTENT_TYPE, HeaderMap, StatusCode};
+use bytes::Bytes;
+use stof::{lang::SError, Library, SData, SDoc, SField, SVal};
+use tokio::{io::{AsyncRead, AsyncReadExt, AsyncWriteExt}, process::{Child, Command}, sync::mpsc::{unbounded_channel, UnboundedSender}, time::timeout};
+use crate::{config::{sandbox_max_cpu, sandbox_max_files, sandbox_max_memory, sandbox_max_output, sandbox_namespaces, sandbox_network, sandbox_seccomp}, libraries::RunLibraries, response::StofResponse, run::{error::{RunError, RunErrorCode}, export_document, logs::{RunLog, RunLogs}, run_error, run_stof, RunOptions}, runs::RunRecord};
+
+#[cfg(target_os = "linux")]
+mod linux;
+#[cfg(target_os = "linux")]
+use linux::{isolate, protocol_output, restrict_syscalls};
+
+#[cfg(not(target_os = "linux"))]
+mod unsupported;
+#[cfg(not(target_os = "linux"))]
+use unsupported::{isolate, protocol_output, restrict_syscalls};
+
+
+/// Request frame (server -> sandbox): bstof run request.
+const FRAME_REQUEST: u8 = b'R';
+
+/// Log frame (sandbox -> server): "stream\nmessage".
+const FRAME_LOG: u8 = b'L';
+
+/// Result frame (sandbox -> server): bstof run result.
+const FRAME_RESULT: u8 = b'D';
+
+/// Extra time given to a sandboxed run past the run timeout, so that the sandbox can report its own timeout.
+const TIMEOUT_GRACE: Duration = Duration::from_secs(1);
+
+
+/// Libraries that need the runner's state, so they can't be used by sandboxed runs (scope, error type).
+pub(crate) const SANDBOXED_LIBRARIES: [(&str, &str); 3] = [
+    ("kv", "KvUnavailable"),
+    ("sql", "SqlUnavailable"),
+    ("bus", "BusUnavailable"),
+];
+
+
+/// Library that sandboxed runs can't use.
+/// Every call is an error saying so, instead of the library looking unconfigured.
+pub(crate) struct SandboxedLibrary {
+    pub scope: &'static str,
+    pub error: &'static str,
+}
+impl Library for SandboxedLibrary {
+    fn scope(&self) -> String {
+        self.scope.to_string()
+    }
+
+    fn call(&self, pid: &str, doc: &mut SDoc, _name: &str, _parameters: &mut Vec<SVal>) -> Result<SVal, SError> {
+        Err(SError::custom(pid, doc, self.error, &format!("{} is not available in sandboxed runs", self.scope)))
+    }
+}
+
+
+/// Sandbox policy.
+#[derive(Debug, Clone)]
+pub(crate) struct SandboxPolicy {
+    /// Isolate the run in new namespaces?
+    pub namespaces: bool,
+
+    /// Can the run reach the network?
+    pub network: bool,
+
+    /// Filter the run's system calls?
+    pub seccomp: bool,
+
+    /// Max memory (address space, bytes).
+    pub max_memory: u64,
+
+    /// Max CPU time.
+    pub max_cpu: Duration,
+
+    /// Max open files.
+    pub max_files: u64,
+
+    /// Max size of the run's output (bytes).
+    pub max_output: usize,
+}
+impl SandboxPolicy {
+    /// Sandbox policy from the server configuration.
+    pub fn from_config(config: &SDoc) -> Self {
+        Self {
+            namespaces: sandbox_namespaces(config),
+            network: sandbox_network(config),
+            seccomp: sandbox_seccomp(config),
+            max_memory: sandbox_max_memory(config),
+            max_cpu: sandbox_max_cpu(config),
+            max_files: sandbox_max_files(config),
+            max_output: sandbox_max_output(config),
+        }
+    }
+}
+
+
+/// Run some Stof in a sandbox process.
+///
+/// The runner executable is started again as the sandbox ("--sandbox", with the same configuration), isolated by the policy.
+/// The run is written to its stdin as a request frame (the same document as a recorded run, with the run's secrets and libraries),
+/// and it writes back log frames as the run prints, then a result frame with the response (bstof exports).
+///
+/// Results are converted to the run's export format here, so the sandbox never needs more than bstof.
+/// A sandbox that exits without a result (crash, seccomp, or rlimit) is a "RUN_SANDBOX" error, and one that doesn't finish in time is killed.
+pub(crate) async fn run_sandboxed(options: RunOptions, policy: SandboxPolicy, body: Bytes, events: Option<UnboundedSender<RunLog>>) -> StofResponse {
+    let logs = RunLogs::new(options.max_log_size, events);
+    let sandbox_error = |message: &str| RunError::new(RunErrorCode::Sandbox, StatusCode::INTERNAL_SERVER_ERROR, message);
+
+    let mut request = RunRecord::new(&options, &body, &None).to_doc();
+    if let Some(main) = request.graph.main_root() {
+        let secrets = SVal::Map(options.secrets.iter().map(|(name, value)| (SVal::String(name.clone()), SVal::String(value.clone()))).collect());
+        SData::insert_new(&mut request.graph, &main, Box::new(SField::new("secrets", secrets)));
+        SField::new_bool(&mut request.graph, "structured_errors", options.error_format.is_some(), &main);
+        SData::insert_new(&mut request.graph, &main, Box::new(SField::new("libraries", options.libraries.to_val())));
+    }
+    
+    let request_bytes = match request.export_bytes("main", "bstof", None) {
+        Ok(bytes) => bytes,
+        Err(_) => return run_error(&options, &logs, sandbox_error("error creating sandbox request")),
+    };
+
+    let mut child;
+    match spawn_sandbox(&policy) {
+        Ok(value) => child = value,
+        Err(message) => return run_error(&options, &logs, sandbox_error(&format!("error starting sandbox: {}", message))),
+    }
+    let (Some(mut stdin), Some(mut stdout)) = (child.stdin.take(), child.stdout.take()) else {
+        return run_error(&options, &logs, sandbox_error("error starting sandbox"));
+    };
+
+    let exchange = async {
+        let mut frame = Vec::with_capacity(request_bytes.len() + 5);
+        frame.push(FRAME_REQUEST);
+        frame.extend_from_slice(&(request_bytes.len() as u32).to_be_bytes());
+        frame.extend_from_slice(&request_bytes);
+        stdin.write_all(&frame).await?;
+        drop(stdin);
+
+        loop {
+            let (kind, payload) = read_frame(&mut stdout, policy.max_output).await?;
+            match kind {
+                FRAME_LOG => {
+                    let line = String::from_utf8_lossy(&payload);
+                    let (stream, message) = line.split_once('\n').unwrap_or(("out", &line));
+                    logs.lock().unwrap().push(log_stream(stream), message.to_owned());
+                },
+                FRAME_RESULT => {
+                    return Ok(payload);
+                },
+                _ => {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "unknown frame"));
+                }
+            }
+        }
+    };
+    let res = timeout(options.timeout + TIMEOUT_GRACE, exchange).await;
+    let _ = child.start_kill();
+    let status = child.wait().await;
+
+    match res {
+        Ok(Ok(result)) => {
+            match SDoc::bytes(Bytes::from(result), "bstof") {
+                Ok(result) => {
+                    let mut response = sandbox_response(&options, &result);
+                    if options.logs_header {
+                        logs.lock().unwrap().insert_header(&mut response.headers);
+                    }
+                    response
+                },
+                Err(_) => run_error(&options, &logs, sandbox_error("invalid sandbox result")),
+            }
+        },
+        Ok(Err(error)) => {
+            let mut message = format!("sandboxed run exited unexpectedly ({})", error);
+            if let Ok(status) = status && !status.success() {
+                message = format!("sandboxed run exited unexpectedly ({})", status);
+            }
+            run_error(&options, &logs, sandbox_error(&message))
+        },
+        Err(_) => {
+            run_error(&options, &logs, RunError::new(RunErrorCode::Timeout, StatusCode::REQUEST_TIMEOUT, "timeout while running document"))
+        }
+    }
+}
+
+
+/// Start the sandbox process, isolated by the policy.
+fn spawn_sandbox(policy: &SandboxPolicy) -> Result<Child, String> {
+    let exe = std::env::current_exe().map_err(|error| error.to_string())?;
+    let mut command = Command::new(exe);
+    command
+        .args(std::env::args().skip(1))
+        .arg("--sandbox")
+        .env("TOKIO_WORKER_THREADS", "1")
+        .env("MALLOC_ARENA_MAX", "1")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .kill_on_drop(true);
+    isolate(&mut command, policy)?;
+    command.spawn().map_err(|error| error.to_string())
+}
+
+
+/// Response from a sandbox result document.
+/// Bstof exports are converted into the format the run asked for (results and logs envelopes in the export format, errors in the error format).
+fn sandbox_response(options: &RunOptions, result: &SDoc) -> StofResponse {
+    let field = |name: &str| result.field(&format!("root.{}", name), None).map(|field| field.value.clone()).unwrap_or(SVal::Null);
+
+    let mut status = StatusCode::INTERNAL_SERVER_ERROR;
+    if let SVal::Number(num) = field("status") {
+        status = StatusCode::from_u16(num.int() as u16).unwrap_or(status);
+    }
+    let content_type = field("content_type").to_string();
+    let mut body = Bytes::default();
+    if let SVal::Blob(blob) = field("body") {
+        body = Bytes::from(blob);
+    }
+    if let SVal::Array(packages) = field("packages") {
+        let mut imported = options.packages.lock().unwrap();
+        for package in packages {
+            imported.insert(package.to_string());
+        }
+    }
+
+    let mut format = options.export_format.clone();
+    if !status.is_success() && !options.logs && let Some(error_format) = &options.error_format {
+        format = error_format.clone();
+    }
+    let mut response;
+    if content_type == "application/bstof" && format != "bstof" {
+        let mut doc = SDoc::default();
+        if doc.header_import("main", "bstof", "bstof", &mut body, "").is_err() {
+            return StofResponse::error(StatusCode::INTERNAL_SERVER_ERROR, "invalid sandbox result");
+        }
+        response = export_document(&doc, &format);
+        if response.status == StatusCode::OK {
+            response.status = status;
+        }
+    } else {
+        let mut headers = HeaderMap::new();
+        if let Ok(value) = content_type.parse() {
+            headers.insert(CONTENT_TYPE, value);
+        }
+        response = StofResponse {
+            headers,
+            status,
+            str_body: String::default(),
+            bytes_body: Some(body),
+        };
+    }
+    if let SVal::String(code) = field("error_code") && let Ok(value) = code.parse() {
+        response.headers.insert("x-error-code", value);
+    }
+    response
+}
+
+
+/// Sandbox process.
+/// Reads the run request from stdin, restricts its own system calls, then runs it the same as the server would,
+/// writing log frames and the result frame to stdout. Returns the process exit code.
+pub(crate) async fn sandbox_main(mut config: SDoc) -> i32 {
+    // Frames go to a copy of stdout, and anything else printed goes to stderr
+    let mut output;
+    match protocol_output() {
+        Ok(file) => output = file,
+        Err(message) => {
+            eprintln!("sandbox: {}", message);
+            return 1;
+        }
+    }
+
+    let request;
+    match read_frame_blocking(&mut io::stdin().lock()) {
+        Ok((FRAME_REQUEST, payload)) => {
+            match SDoc::bytes(Bytes::from(payload), "bstof") {
+                Ok(doc) => request = doc,
+                Err(_) => {
+                    eprintln!("sandbox: invalid request");
+                    return 1;
+                }
+            }
+        },
+        _ => {
+            eprintln!("sandbox: no request");
+            return 1;
+        }
+    }
+    
+    let record = match RunRecord::from_doc(&request) {
+        Some(value) => value,
+        None => {
+            eprintln!("sandbox: invalid request");
+            return 1;
+        }
+    };
+
+    let mut options = RunOptions::from_config(&mut config);
+    options.sandbox = None; // already in the sandbox
+    options.sandboxed = true;
+    record.apply(&mut options);
+    if let Some(SVal::Map(secrets)) = request.field("root.secrets", None).map(|field| field.value.clone()) {
+        options.secrets = secrets.into_iter().map(|(name, value)| (name.to_string(), value.to_string())).collect();
+    }
+    if let Some(libraries) = request.field("root.libraries", None) {
+        options.libraries = RunLibraries::from_val(&libraries.value);
+    }
+    if let Some(SVal::Bool(true)) = request.field("root.structured_errors", None).map(|field| field.value.clone()) {
+        options.error_format = Some(String::from("bstof"));
+    }
+    if !options.scratch_zip {
+        options.export_format = String::from("bstof");
+    }
+
+    let policy = SandboxPolicy::from_config(&config);
+    if policy.seccomp && let Err(message) = restrict_syscalls(policy.network) {
+        eprintln!("sandbox: {}", message);
+        return 1;
+    }
+
+    let (events, mut lines) = unbounded_channel::<RunLog>();
+    let packages = options.packages.clone();
+    let run = run_stof(options, record.body, Some(events));
+    tokio::pin!(run);
+    let response;
+    loop {
+        tokio::select! {
+            Some(line) = lines.recv() => {
+                let _ = write_frame(&mut output, FRAME_LOG, format!("{}\n{}", line.stream, line.message).as_bytes());
+            },
+            res = &mut run => {
+                while let Ok(line) = lines.try_recv() {
+                    let _ = write_frame(&mut output, FRAME_LOG, format!("{}\n{}", line.stream, line.message).as_bytes());
+                }
+                response = res;
+                break;
+            }
+        }
+    }
+
+    let mut result = SDoc::default();
+    if let Some(main) = result.graph.main_root() {
+        SField::new_int(&mut result.graph, "status", response.status.as_u16() as i64, &main);
+        let mut content_type = String::from("text/plain");
+        if let Some(value) = response.headers.get(CONTENT_TYPE) {
+            content_type = value.to_str().unwrap_or_default().to_owned();
+        }
+        SField::new_string(&mut result.graph, "content_type", &content_type, &main);
+        if let Some(code) = response.headers.get("x-error-code") {
+            SField::new_string(&mut result.graph, "error_code", code.to_str().unwrap_or_default(), &main);
+        }
+        let body = response.bytes_body.unwrap_or(Bytes::from(response.str_body));
+        SData::insert_new(&mut result.graph, &main, Box::new(SField::new("body", SVal::Blob(body.to_vec()))));
+        let packages = packages.lock().unwrap().iter().map(|package| SVal::String(package.clone())).collect();
+        SField::new_array(&mut result.graph, "packages", packages, &main);
+    }
+    match result.export_bytes("main", "bstof", None) {
+        Ok(bytes) => {
+            if write_frame(&mut output, FRAME_RESULT, &bytes).is_err() {
+                return 1;
+            }
+            0
+        },
+        Err(_) => {
+            eprintln!("sandbox: error exporting result");
+            1
+        }
+    }
+}
+
+
+/// Log stream name from a log frame.
+fn log_stream(stream: &str) -> &'static str {
+    match stream {
+        "err" => "err",
+        "progress" => "progress",
+        _ => "out",
+    }
+}
+
+
+/// Write a frame: kind byte, payload length (u32, big-endian), then the payload.
+fn write_frame(output: &mut impl Write, kind: u8, payload: &[u8]) -> io::Result<()> {
+    output.write_all(&[kind])?;
+    output.write_all(&(payload.len() as u32).to_be_bytes())?;
+    output.write_all(payload)?;
+    output.flush()
+}
+
+
+/// Read a frame from the sandbox, rejecting payloads larger than the max size.
+async fn read_frame(input: &mut (impl AsyncRead + Unpin), max_size: usize) -> io::Result<(u8, Vec<u8>)> {
+    let mut header = [0u8; 5];
+    input.read_exact(&mut header).await?;
+    let len = u32::from_be_bytes([header[1], header[2], header[3], header[4]]) as usize;
+    if len > max_size {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "sandbox output is too large"));
+    }
+    let mut payload = vec![0u8; len];
+    input.read_exact(&mut payload).await?;
+    Ok((header[0], payload))
+}
+
+
+/// Read a frame from the server.
+fn read_frame_blocking(input: &mut impl Read) -> io::Result<(u8, Vec<u8>)> {
+    let mut header = [0u8; 5];
+    input.read_exact(&mut header)?;
+    let len = u32::from_be_bytes([header[1], header[2], header[3], header[4]]) as usize;
+    let mut payload = vec![0u8; len];
+    input.read_exact(&mut payload)?;
+    Ok((header[0], payload))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use stof::SDoc;
+    use super::{SandboxedLibrary, SANDBOXED_LIBRARIES};
+
+    #[test]
+    fn sandboxed_libraries() {
+        let mut doc = SDoc::src("fn get(): unknown { return kv.get('key'); } fn query(): unknown { return sql.query('acme', 'SELECT 1'); }", "stof").unwrap();
+        for (scope, error) in SANDBOXED_LIBRARIES {
+            doc.load_lib(Arc::new(SandboxedLibrary { scope, error }));
+        }
+        let error = doc.call_func("get", None, vec![]).unwrap_err();
+        assert_eq!(error.message, "kv is not available in sandboxed runs");
+        let error = doc.call_func("query", None, vec![]).unwrap_err();
+        assert_eq!(error.message, "sql is not available in sandboxed runs");
+    }
+}
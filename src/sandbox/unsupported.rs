@@ -0,0 +1,38 @@
+//
+// Copyright 2024 Formata, Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+
+use std::fs::File;
+use tokio::process::Command;
+use super::SandboxPolicy;
+
+
+/// Sandboxed runs are only available on Linux.
+pub(crate) fn isolate(_command: &mut Command, _policy: &SandboxPolicy) -> Result<(), String> {
+    Err(String::from("the sandbox is only available on Linux"))
+}
+
+
+/// Sandboxed runs are only available on Linux.
+pub(crate) fn protocol_output() -> Result<File, String> {
+    Err(String::from("the sandbox is only available on Linux"))
+}
+
+
+/// Sandboxed runs are only available on Linux.
+pub(crate) fn restrict_syscalls(_network: bool) -> Result<(), String> {
+    Err(String::from("the sandbox is only available on Linux"))
+}
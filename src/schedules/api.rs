@@ -0,0 +1,143 @@
+//
+// Copyright 2024 Formata, Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+use std::collections::BTreeMap;
+use axum::{extract::{Path, Query, State}, http::{header::CONTENT_TYPE, HeaderMap, StatusCode}, response::IntoResponse};
+use bytes::Bytes;
+use nanoid::nanoid;
+use stof::{SDoc, SVal};
+use crate::{response::StofResponse, run::export_node, server::ServerState, users::auth::auth_admin};
+use super::{admin_delete_schedule, admin_set_schedule, parse_cron};
+
+
+/// Alphabet for schedule IDs (used as field names in the schedules document).
+const ID_ALPHABET: [char; 36] = [
+    'a', 'b', 'c', 'd', 'e', 'f', 'g', 'h', 'i', 'j', 'k', 'l', 'm', 'n', 'o', 'p', 'q', 'r', 's', 't', 'u', 'v', 'w', 'x', 'y', 'z',
+    '0', '1', '2', '3', '4', '5', '6', '7', '8', '9',
+];
+
+
+/// Create/update a schedule.
+/// Body fields: "cron", "package", and optionally "id" (update), "input" (document string or object), "format" (input format), and "export" (result format).
+pub(crate) async fn admin_set_schedule_handler(State(state): State<ServerState>, headers: HeaderMap, body: Bytes) -> impl IntoResponse {
+    if !auth_admin(&state, &headers, false).await {
+        return StofResponse::error(StatusCode::FORBIDDEN, "access denied");
+    }
+
+    let mut content_type = String::from("stof");
+    if let Some(ctype) = headers.get(CONTENT_TYPE) {
+        match ctype.to_str() {
+            Ok(ctype) => content_type = ctype.to_owned(),
+            Err(_) => return StofResponse::error(StatusCode::BAD_REQUEST, "invalid content type"),
+        }
+    }
+    if let Ok(doc) = SDoc::bytes(body, &content_type) && let Some(cron) = doc.field("root.cron", None) && let Some(package) = doc.field("root.package", None) {
+        let cron = cron.to_string();
+        if parse_cron(&cron).is_none() {
+            return StofResponse::error(StatusCode::BAD_REQUEST, "invalid cron expression");
+        }
+
+        let mut id = nanoid!(12, &ID_ALPHABET);
+        if let Some(id_field) = doc.field("root.id", None) {
+            id = id_field.to_string();
+            if id.is_empty() || !id.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+                return StofResponse::error(StatusCode::BAD_REQUEST, "invalid schedule id");
+            }
+        }
+
+        let mut input = String::default();
+        let mut format = String::from("json");
+        if let Some(input_field) = doc.field("root.input", None) {
+            match &input_field.value {
+                SVal::Object(nref) => {
+                    if let Ok(json) = doc.export_string("main", "json", Some(nref)) {
+                        input = json;
+                    }
+                },
+                value => {
+                    input = value.to_string();
+                }
+            }
+        }
+        if let Some(format_field) = doc.field("root.format", None) {
+            format = format_field.to_string();
+        }
+        let mut export = String::from("json");
+        if let Some(export_field) = doc.field("root.export", None) {
+            export = export_field.to_string();
+        }
+
+        let mut schedules = state.schedules.lock().await;
+        if admin_set_schedule(&mut schedules, &id, &cron, &package.to_string(), &input, &format, &export) {
+            return StofResponse::stof(StatusCode::OK, &format!("str id: '{}'", id));
+        }
+    }
+    StofResponse::error(StatusCode::BAD_REQUEST, "not a valid schedule body")
+}
+
+
+/// Get all schedules (with history).
+/// Uses the "export" query to determine the format (default is "json").
+pub(crate) async fn admin_get_schedules_handler(State(state): State<ServerState>, Query(query): Query<BTreeMap<String, String>>, headers: HeaderMap) -> impl IntoResponse {
+    if !auth_admin(&state, &headers, true).await {
+        return StofResponse::error(StatusCode::FORBIDDEN, "access denied");
+    }
+
+    let mut export_format = String::from("json");
+    if let Some(format) = query.get("export") {
+        export_format = format.clone();
+    }
+
+    let schedules = state.schedules.lock().await;
+    if let Some(root) = schedules.graph.root_by_name("Schedules") {
+        return export_node(&schedules, &export_format, &root);
+    }
+    StofResponse::error(StatusCode::INTERNAL_SERVER_ERROR, "schedules not found")
+}
+
+
+/// Get a schedule (with history).
+/// Uses the "export" query to determine the format (default is "json").
+pub(crate) async fn admin_get_schedule_handler(State(state): State<ServerState>, Path(id): Path<String>, Query(query): Query<BTreeMap<String, String>>, headers: HeaderMap) -> impl IntoResponse {
+    if !auth_admin(&state, &headers, true).await {
+        return StofResponse::error(StatusCode::FORBIDDEN, "access denied");
+    }
+
+    let mut export_format = String::from("json");
+    if let Some(format) = query.get("export") {
+        export_format = format.clone();
+    }
+
+    let schedules = state.schedules.lock().await;
+    if !id.contains('.') && let Some(field) = schedules.field(&format!("Schedules.{}", id), None) && let SVal::Object(nref) = &field.value {
+        return export_node(&schedules, &export_format, nref);
+    }
+    StofResponse::error(StatusCode::NOT_FOUND, "schedule not found")
+}
+
+
+/// Delete a schedule.
+pub(crate) async fn admin_delete_schedule_handler(State(state): State<ServerState>, Path(id): Path<String>, headers: HeaderMap) -> impl IntoResponse {
+    if !auth_admin(&state, &headers, false).await {
+        return StofResponse::error(StatusCode::FORBIDDEN, "access denied");
+    }
+
+    let mut schedules = state.schedules.lock().await;
+    if !id.contains('.') && admin_delete_schedule(&mut schedules, &id) {
+        return StofResponse::msg(StatusCode::OK, "deleted schedule");
+    }
+    StofResponse::error(StatusCode::NOT_FOUND, "schedule not found")
+}
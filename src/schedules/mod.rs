@@ -0,0 +1,379 @@
+//
+// Copyright 2024 Formata, Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+use std::{collections::HashSet, fs, str::FromStr, sync::Arc, time::{Duration, Instant}};
+use chrono::{DateTime, Utc};
+use cron::Schedule;
+use stof::{SData, SDoc, SField, SVal};
+use tokio::sync::Mutex;
+use crate::{bus::run_bus, config::{registry_path, run_enabled}, kv::run_kv, libraries::run_libraries, metrics::increment_server_run_count, run::{run_stof, RunOptions}, secrets::run_secrets, server::ServerState, sql::run_sql};
+pub(crate) mod api;
+
+
+const SCHEDULES_INTERFACE: &str = r#"
+// make sure the Schedules root exists
+root Schedules: {}
+
+type Schedule {
+    id: str;
+
+    // cron expression (with or without seconds)
+    cron: str;
+
+    // registry package to run (Ex. "@scope/name")
+    package: str;
+
+    // input document, imported before the package on each run
+    input: str = '';
+    format: str = 'json';
+
+    // export format for the run result
+    export: str = 'json';
+
+    created: ms = Time.now();
+    runs: int = 0;
+
+    // last run
+    last_run: ms = 0ms;
+    last_duration: ms = 0ms;
+    last_status: int = 0;
+    last_result: str = '';
+    last_error: str = '';
+
+    // recent runs
+    history: obj = new {};
+
+    fn record(time: ms, duration: ms, status: int, result: str, error: str) {
+        self.runs += 1;
+        self.last_run = time;
+        self.last_duration = duration;
+        self.last_status = status;
+        self.last_result = result;
+        self.last_error = error;
+
+        let history = self.history;
+        history.set(`run${self.runs}`, new {
+            time: time,
+            duration: duration,
+            status: status,
+            error: error,
+        });
+        history.removeField(`run${self.runs - root.Config.max_history}`, true);
+    }
+}
+
+Config: {
+    // saved by the runner after each change
+    save_path: 'registry/__schedules__.bstof'
+    max_history: 10
+}
+
+// set a schedule
+fn set_schedule(id: str, cron: str, package: str, input: str = '', format: str = 'json', export: str = 'json'): bool {
+    Schedules.removeField(id, true);
+    let res = Schedules.set(id, new Schedule {
+        id: id,
+        cron: cron,
+        package: package,
+        input: input,
+        format: format,
+        export: export,
+        history: new {},
+    });
+    return res;
+}
+
+// delete a schedule by id
+fn delete_schedule(id: str): bool {
+    let res = Schedules.removeField(id, true);
+    return res;
+}
+
+// record a run for a schedule
+fn record(id: str, time: ms, duration: ms, status: int, result: str, error: str): bool {
+    let schedule: Schedule = Schedules.at(id);
+    if (schedule) {
+        schedule.record(time, duration, status, result, error);
+        return true;
+    }
+    return false;
+}
+"#;
+
+
+/// Load schedules file.
+pub(crate) fn load_schedules(config: &SDoc) -> SDoc {
+    let registry_name = registry_path(config);
+    let schedules_file_path = format!("{}/__schedules__.bstof", registry_name);
+
+    if let Ok(exists) = fs::exists(&schedules_file_path) && exists && let Ok(doc) = SDoc::file(&schedules_file_path, "bstof") {
+        return doc;
+    }
+
+    let mut doc = SDoc::default();
+    let _ = doc.string_import("main", "stof", SCHEDULES_INTERFACE, "");
+
+    if let Some(field_ref) = SField::field_ref(&doc.graph, "root.Config.save_path", '.', None) && let Some(field) = SData::get_mut::<SField>(&mut doc.graph, &field_ref) {
+        field.value = schedules_file_path.into();
+    }
+    doc
+}
+
+
+/// Save the schedules document.
+pub(crate) fn save_schedules(schedules: &SDoc) {
+    if let Some(save_path) = schedules.field("root.Config.save_path", None) && let Ok(bytes) = schedules.export_bytes("main", "bstof", None) {
+        let _ = fs::write(save_path.to_string(), bytes);
+    }
+}
+
+
+/// Parse a cron expression.
+/// Standard 5 field expressions (no seconds) are accepted, running at the start of the minute.
+pub(crate) fn parse_cron(expr: &str) -> Option<Schedule> {
+    let mut expr = expr.trim().to_owned();
+    if expr.split_whitespace().count() == 5 {
+        expr = format!("0 {}", expr);
+    }
+    Schedule::from_str(&expr).ok()
+}
+
+
+/// Is a schedule due, given the last time the schedules were checked?
+/// Due if its next run after the last check has come, so runs missed between checks only run once.
+fn job_due(schedule: &Schedule, last_check: &DateTime<Utc>, now: &DateTime<Utc>) -> bool {
+    schedule.after(last_check).next().is_some_and(|next| next <= *now)
+}
+
+
+/// Scheduled job.
+pub(crate) struct ScheduledJob {
+    pub id: String,
+    pub schedule: Schedule,
+    pub package: String,
+    pub input: String,
+    pub format: String,
+    pub export: String,
+}
+
+
+/// Get all of the scheduled jobs.
+/// Schedules with an invalid cron expression are skipped.
+pub(crate) fn scheduled_jobs(schedules: &SDoc) -> Vec<ScheduledJob> {
+    let mut jobs = Vec::new();
+    if let Some(root) = schedules.graph.root_by_name("Schedules") {
+        for field in SField::fields(&schedules.graph, &root) {
+            if let SVal::Object(nref) = &field.value {
+                let get = |name: &str| {
+                    if let Some(field) = SField::field(&schedules.graph, name, '.', Some(nref)) {
+                        return field.to_string();
+                    }
+                    String::default()
+                };
+                if let Some(schedule) = parse_cron(&get("cron")) {
+                    jobs.push(ScheduledJob {
+                        id: get("id"),
+                        schedule,
+                        package: get("package"),
+                        input: get("input"),
+                        format: get("format"),
+                        export: get("export"),
+                    });
+                }
+            }
+        }
+    }
+    jobs
+}
+
+
+/// ADMIN set a schedule.
+pub(crate) fn admin_set_schedule(schedules: &mut SDoc, id: &str, cron: &str, package: &str, input: &str, format: &str, export: &str) -> bool {
+    if let Ok(res) = schedules.call_func("root.set_schedule", None, vec![id.into(), cron.into(), package.into(), input.into(), format.into(), export.into()]) {
+        save_schedules(schedules);
+        return res.truthy();
+    }
+    false
+}
+
+
+/// ADMIN delete a schedule.
+pub(crate) fn admin_delete_schedule(schedules: &mut SDoc, id: &str) -> bool {
+    if let Ok(res) = schedules.call_func("root.delete_schedule", None, vec![id.into()]) {
+        save_schedules(schedules);
+        return res.truthy();
+    }
+    false
+}
+
+
+/// Record a scheduled run.
+pub(crate) fn record_schedule_run(schedules: &mut SDoc, id: &str, time: i64, duration: i64, status: i64, result: &str, error: &str) -> bool {
+    if let Ok(res) = schedules.call_func("root.record", None, vec![id.into(), time.into(), duration.into(), status.into(), result.into(), error.into()]) {
+        save_schedules(schedules);
+        return res.truthy();
+    }
+    false
+}
+
+
+/// Start the scheduler.
+/// Checks the schedules every second, running due jobs through the run pipeline.
+/// A job will not start again while a previous run of it is still going.
+pub(crate) fn start_scheduler(state: ServerState) {
+    let running = Arc::new(Mutex::new(HashSet::<String>::new()));
+    tokio::spawn(async move {
+        let mut last_check = Utc::now();
+        loop {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            let now = Utc::now();
+            let jobs;
+            {
+                let schedules = state.schedules.lock().await;
+                jobs = scheduled_jobs(&schedules);
+            }
+            for job in jobs {
+                if job_due(&job.schedule, &last_check, &now) {
+                    let mut running_jobs = running.lock().await;
+                    if running_jobs.insert(job.id.clone()) {
+                        tokio::spawn(run_job(state.clone(), running.clone(), job));
+                    }
+                }
+            }
+            last_check = now;
+        }
+    });
+}
+
+
+/// Run a scheduled job and record the result in its history.
+async fn run_job(state: ServerState, running: Arc<Mutex<HashSet<String>>>, job: ScheduledJob) {
+    let mut options;
+    {
+        let mut config = state.config.lock().await;
+        if !run_enabled(&config) {
+            running.lock().await.remove(&job.id);
+            return;
+        }
+        options = RunOptions::from_config(&mut config);
+    }
+    options.content_type = job.format;
+    options.export_format = job.export;
+    options.package = Some(job.package);
+
+    // Schedules are managed by the admin, so scheduled runs read secrets and get libraries as the admin
+    options.secrets = run_secrets(&state, Some((String::default(), true)), options.package.as_deref()).await;
+    options.libraries = run_libraries(&state, &Some((String::default(), true)), options.package.as_deref()).await;
+    options.kv = run_kv(&state, &None, options.package.as_deref()).await;
+    options.sql = run_sql(&state, &None, options.package.as_deref()).await;
+    options.bus = run_bus(&state, &None, options.package.as_deref()).await;
+
+    // metrics
+    {
+        let mut metrics = state.metrics.lock().await;
+        increment_server_run_count(&mut metrics);
+    }
+
+    let time = Utc::now().timestamp_millis();
+    let start = Instant::now();
+    let response = run_stof(options, job.input.into(), None).await;
+    let duration = start.elapsed().as_millis() as i64;
+
+    let mut result = String::default();
+    let mut error = String::default();
+    if response.status.is_success() {
+        if response.bytes_body.is_none() {
+            result = response.str_body;
+        }
+    } else {
+        error = response.str_body;
+    }
+    {
+        let mut schedules = state.schedules.lock().await;
+        record_schedule_run(&mut schedules, &job.id, time, duration, response.status.as_u16() as i64, &result, &error);
+    }
+    running.lock().await.remove(&job.id);
+}
+
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+    use super::*;
+
+    fn time(hour: u32, min: u32, sec: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2026, 3, 14, hour, min, sec).unwrap()
+    }
+
+    fn next_run(expr: &str, after: DateTime<Utc>) -> DateTime<Utc> {
+        parse_cron(expr).unwrap().after(&after).next().unwrap()
+    }
+
+    #[test]
+    fn invalid_expressions() {
+        for expr in ["", "   ", "* * * *", "not a cron", "61 * * * *", "* 25 * * *", "* * 32 * *", "* * * 13 *", "* * * * * * * *", "*/0 * * * *"] {
+            assert!(parse_cron(expr).is_none(), "'{}' should be invalid", expr);
+        }
+    }
+
+    #[test]
+    fn valid_expressions() {
+        for expr in ["* * * * *", "*/5 * * * *", "0 9 * * Mon-Fri", "30 * * * * *", " 0 0 * * * * ", "@hourly"] {
+            assert!(parse_cron(expr).is_some(), "'{}' should be valid", expr);
+        }
+    }
+
+    #[test]
+    fn five_fields_run_at_the_start_of_the_minute() {
+        assert_eq!(next_run("* * * * *", time(10, 0, 0)), time(10, 1, 0));
+        assert_eq!(next_run("* * * * *", time(10, 0, 30)), time(10, 1, 0));
+        assert_eq!(next_run("*/15 * * * *", time(10, 7, 0)), time(10, 15, 0));
+        assert_eq!(next_run("0 12 * * *", time(10, 0, 0)), time(12, 0, 0));
+        assert_eq!(next_run("0 9 * * *", time(10, 0, 0)), Utc.with_ymd_and_hms(2026, 3, 15, 9, 0, 0).unwrap());
+    }
+
+    #[test]
+    fn six_fields_have_seconds() {
+        assert_eq!(next_run("30 * * * * *", time(10, 0, 0)), time(10, 0, 30));
+        assert_eq!(next_run("*/10 * * * * *", time(10, 0, 25)), time(10, 0, 30));
+    }
+
+    #[test]
+    fn due_jobs() {
+        let schedule = parse_cron("* * * * *").unwrap();
+        assert!(!job_due(&schedule, &time(10, 0, 58), &time(10, 0, 59)));
+        assert!(job_due(&schedule, &time(10, 0, 59), &time(10, 1, 0)));
+        assert!(!job_due(&schedule, &time(10, 1, 0), &time(10, 1, 1)));
+
+        // Missed runs (checks that were late) are only due once
+        assert!(job_due(&schedule, &time(10, 0, 30), &time(10, 5, 0)));
+        assert!(!job_due(&schedule, &time(10, 5, 0), &time(10, 5, 1)));
+    }
+
+    #[test]
+    fn jobs_skip_invalid_expressions() {
+        let mut schedules = SDoc::default();
+        schedules.string_import("main", "stof", SCHEDULES_INTERFACE, "").unwrap();
+        for (id, cron) in [("good", "*/5 * * * *"), ("bad", "every day"), ("seconds", "0 0 * * * *")] {
+            let res = schedules.call_func("root.set_schedule", None, vec![id.into(), cron.into(), "@acme/hello".into()]).unwrap();
+            assert!(res.truthy());
+        }
+
+        let mut ids = scheduled_jobs(&schedules).into_iter().map(|job| job.id).collect::<Vec<_>>();
+        ids.sort();
+        assert_eq!(ids, vec!["good", "seconds"]);
+    }
+}
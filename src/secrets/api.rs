@@ -0,0 +1,121 @@
+//
+// Copyright 2024 Formata, Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+use std::collections::BTreeMap;
+use axum::{extract::{Path, Query, State}, http::{header::CONTENT_TYPE, HeaderMap, StatusCode}, response::IntoResponse};
+use bytes::Bytes;
+use stof::{SData, SDoc, SField, SVal};
+use crate::{response::StofResponse, run::export_document, server::ServerState, users::auth::auth_admin};
+use super::{admin_delete_secret, admin_set_secret, secret_names, MIN_SECRET_LEN};
+
+
+/// Create/update a secret.
+/// Body fields: "name", "value" (at least 8 characters), and optionally "scope" (package scope that can read it), "users" (usernames that can read it),
+/// and "public" (can unauthenticated runs read it, when "users" is empty).
+pub(crate) async fn admin_set_secret_handler(State(state): State<ServerState>, headers: HeaderMap, body: Bytes) -> impl IntoResponse {
+    if !auth_admin(&state, &headers, false).await {
+        return StofResponse::error(StatusCode::FORBIDDEN, "access denied");
+    }
+
+    let mut content_type = String::from("stof");
+    if let Some(ctype) = headers.get(CONTENT_TYPE) {
+        match ctype.to_str() {
+            Ok(ctype) => content_type = ctype.to_owned(),
+            Err(_) => return StofResponse::error(StatusCode::BAD_REQUEST, "invalid content type"),
+        }
+    }
+    if let Ok(doc) = SDoc::bytes(body, &content_type) && let Some(name) = doc.field("root.name", None) && let Some(value) = doc.field("root.value", None) {
+        let name = name.to_string();
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return StofResponse::error(StatusCode::BAD_REQUEST, "invalid secret name");
+        }
+        let value = value.to_string();
+        if value.len() < MIN_SECRET_LEN {
+            return StofResponse::error(StatusCode::BAD_REQUEST, &format!("secret value must be at least {} characters", MIN_SECRET_LEN));
+        }
+
+        let mut scope = String::default();
+        if let Some(scope_field) = doc.field("root.scope", None) {
+            scope = scope_field.to_string().trim_start_matches('@').to_owned();
+        }
+        let mut users = Vec::new();
+        if let Some(users_field) = doc.field("root.users", None) {
+            match &users_field.value {
+                SVal::Array(vals) => {
+                    for val in vals {
+                        users.push(val.to_string());
+                    }
+                },
+                _ => {
+                    return StofResponse::error(StatusCode::BAD_REQUEST, "users must be an array");
+                }
+            }
+        }
+        let mut public = false;
+        if let Some(public_field) = doc.field("root.public", None) {
+            public = public_field.value.truthy();
+        }
+
+        let mut secrets = state.secrets.lock().await;
+        if admin_set_secret(&mut secrets, &name, &value, &scope, users, public) {
+            return StofResponse::msg(StatusCode::OK, "set secret");
+        }
+    }
+    StofResponse::error(StatusCode::BAD_REQUEST, "not a valid secret body")
+}
+
+
+/// Get all secrets (names and access rules only - values are never returned).
+/// Uses the "export" query to determine the format (default is "json").
+pub(crate) async fn admin_get_secrets_handler(State(state): State<ServerState>, Query(query): Query<BTreeMap<String, String>>, headers: HeaderMap) -> impl IntoResponse {
+    if !auth_admin(&state, &headers, false).await {
+        return StofResponse::error(StatusCode::FORBIDDEN, "access denied");
+    }
+
+    let mut export_format = String::from("json");
+    if let Some(format) = query.get("export") {
+        export_format = format.clone();
+    }
+
+    let secrets = state.secrets.lock().await;
+    let mut doc = SDoc::default();
+    if let Some(main) = doc.graph.main_root() {
+        for name in secret_names(&secrets) {
+            let nref = SField::new_object(&mut doc.graph, &name, &main);
+            for field in ["name", "scope", "users", "public", "created"] {
+                if let Some(value) = secrets.doc.field(&format!("Secrets.{}.{}", name, field), None) {
+                    let value = value.value.clone();
+                    SData::insert_new(&mut doc.graph, &nref, Box::new(SField::new(field, value)));
+                }
+            }
+        }
+    }
+    export_document(&doc, &export_format)
+}
+
+
+/// Delete a secret.
+pub(crate) async fn admin_delete_secret_handler(State(state): State<ServerState>, Path(name): Path<String>, headers: HeaderMap) -> impl IntoResponse {
+    if !auth_admin(&state, &headers, false).await {
+        return StofResponse::error(StatusCode::FORBIDDEN, "access denied");
+    }
+
+    let mut secrets = state.secrets.lock().await;
+    if !name.contains('.') && admin_delete_secret(&mut secrets, &name) {
+        return StofResponse::msg(StatusCode::OK, "deleted secret");
+    }
+    StofResponse::error(StatusCode::NOT_FOUND, "secret not found")
+}
@@ -0,0 +1,318 @@
+//
+// Copyright 2024 Formata, Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+use std::{collections::BTreeMap, fs, path::Path};
+use bytes::Bytes;
+use ring::{aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN}, digest::{digest, SHA256}, rand::{SecureRandom, SystemRandom}};
+use stof::{SDoc, SField, SVal};
+use crate::{config::{registry_path, secrets_enabled, secrets_key, secrets_path}, server::ServerState};
+pub(crate) mod api;
+
+
+/// Min length of a secret value.
+/// Secret values are redacted from run output, so short values would redact unrelated text.
+pub(crate) const MIN_SECRET_LEN: usize = 8;
+
+
+const SECRETS_INTERFACE: &str = r#"
+// make sure the Secrets root exists
+root Secrets: {}
+
+type Secret {
+    name: str;
+
+    #[private]
+    value: str;
+
+    // package scope that can read this secret (Ex. "acme" for "@acme/..." packages)
+    // if set, only runs of packages within this scope can read it
+    scope: str = '';
+
+    // users that can read this secret
+    // if empty, any authenticated user that can exec can read it (the admin can always read it)
+    users: vec = [];
+
+    // can unauthenticated runs read this secret too? (only when users is empty)
+    public: bool = false;
+
+    created: ms = Time.now();
+
+    fn can_read(username: str, admin: bool, scope: str): bool {
+        if (self.scope.len() > 0 && self.scope != scope) return false;
+        if (admin) return true;
+        if (username.len() < 1) return self.public && self.users.len() < 1;
+        if (self.users.len() < 1) return true;
+        return self.users.contains(username);
+    }
+}
+
+// set a secret
+fn set_secret(name: str, value: str, scope: str = '', users: vec = [], public: bool = false): bool {
+    Secrets.removeField(name, true);
+    return Secrets.set(name, new Secret {
+        name: name,
+        value: value,
+        scope: scope,
+        users: users,
+        public: public,
+    });
+}
+
+// delete a secret by name
+fn delete_secret(name: str): bool {
+    return Secrets.removeField(name, true);
+}
+
+// can a run read this secret?
+fn can_read(name: str, username: str, admin: bool, scope: str): bool {
+    let secret: Secret = Secrets.at(name);
+    return secret && secret.can_read(username, admin, scope);
+}
+"#;
+
+
+/// Secrets store.
+/// The secrets document is kept in memory, and saved encrypted (AES-256-GCM) in the secrets directory.
+pub struct SecretStore {
+    /// Secrets document.
+    pub doc: SDoc,
+
+    /// Encrypted secrets file path.
+    pub path: String,
+
+    /// Encryption key.
+    key: LessSafeKey,
+}
+impl SecretStore {
+    /// Encrypt bytes (nonce + ciphertext + tag).
+    fn encrypt(&self, bytes: &[u8]) -> Result<Vec<u8>, String> {
+        let mut nonce = [0u8; NONCE_LEN];
+        if SystemRandom::new().fill(&mut nonce).is_err() {
+            return Err("could not generate a nonce".into());
+        }
+        let mut in_out = bytes.to_vec();
+        if self.key.seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), Aad::empty(), &mut in_out).is_err() {
+            return Err("could not encrypt secrets".into());
+        }
+        let mut encrypted = nonce.to_vec();
+        encrypted.append(&mut in_out);
+        Ok(encrypted)
+    }
+
+    /// Decrypt bytes created with encrypt.
+    fn decrypt(&self, bytes: &[u8]) -> Result<Vec<u8>, String> {
+        if bytes.len() < NONCE_LEN {
+            return Err("secrets file is not valid".into());
+        }
+        let (nonce, ciphertext) = bytes.split_at(NONCE_LEN);
+        let nonce = Nonce::try_assume_unique_for_key(nonce).map_err(|_| String::from("secrets file is not valid"))?;
+        let mut in_out = ciphertext.to_vec();
+        match self.key.open_in_place(nonce, Aad::empty(), &mut in_out) {
+            Ok(plain) => Ok(plain.to_vec()),
+            Err(_) => Err("could not decrypt secrets (wrong key?)".into()),
+        }
+    }
+
+    /// Save the secrets document (encrypted).
+    pub fn save(&self) {
+        if let Ok(bytes) = self.doc.export_bytes("main", "bstof", None) && let Ok(encrypted) = self.encrypt(&bytes) {
+            let _ = fs::write(&self.path, encrypted);
+        }
+    }
+}
+
+
+/// Load the secrets store.
+/// The key comes from the config, or from a generated key file in the secrets directory.
+/// Runs can read the registry directory, so the secrets file and key are never kept there
+/// (files from older versions that saved them in the registry are moved to the secrets directory).
+pub(crate) fn load_secrets(config: &SDoc) -> Result<SecretStore, String> {
+    let registry_name = registry_path(config);
+    let secrets_dir = secrets_path(config);
+    if let Err(error) = fs::create_dir_all(&secrets_dir) {
+        return Err(format!("could not create the secrets directory: {}", error));
+    }
+    for name in ["__secrets__.enc", "__secrets__.key"] {
+        let old_path = format!("{}/{}", registry_name, name);
+        let new_path = format!("{}/{}", secrets_dir, name);
+        if Path::new(&old_path).exists() && !Path::new(&new_path).exists() && let Err(error) = fs::rename(&old_path, &new_path) {
+            return Err(format!("could not move '{}' to the secrets directory: {}", old_path, error));
+        }
+    }
+    let secrets_file_path = format!("{}/__secrets__.enc", secrets_dir);
+
+    let key_bytes;
+    let key = secrets_key(config);
+    if !key.is_empty() {
+        key_bytes = digest(&SHA256, key.as_bytes()).as_ref().to_vec();
+    } else {
+        let key_file_path = format!("{}/__secrets__.key", secrets_dir);
+        match fs::read(&key_file_path) {
+            Ok(bytes) => {
+                key_bytes = bytes;
+            },
+            Err(_) => {
+                let mut bytes = vec![0u8; 32];
+                if SystemRandom::new().fill(&mut bytes).is_err() {
+                    return Err("could not generate a secrets key".into());
+                }
+                if let Err(error) = fs::write(&key_file_path, &bytes) {
+                    return Err(format!("could not write the secrets key file: {}", error));
+                }
+                key_bytes = bytes;
+            }
+        }
+    }
+    let key = match UnboundKey::new(&AES_256_GCM, &key_bytes) {
+        Ok(key) => LessSafeKey::new(key),
+        Err(_) => return Err("secrets key file is not valid".into()),
+    };
+
+    let mut store = SecretStore {
+        doc: SDoc::default(),
+        path: secrets_file_path,
+        key,
+    };
+    if let Ok(encrypted) = fs::read(&store.path) {
+        let bytes = store.decrypt(&encrypted)?;
+        match SDoc::bytes(Bytes::from(bytes), "bstof") {
+            Ok(doc) => {
+                store.doc = doc;
+            },
+            Err(_) => {
+                return Err("secrets file is not valid".into());
+            }
+        }
+    } else {
+        let _ = store.doc.string_import("main", "stof", SECRETS_INTERFACE, "");
+    }
+    Ok(store)
+}
+
+
+/// ADMIN set a secret.
+pub(crate) fn admin_set_secret(store: &mut SecretStore, name: &str, value: &str, scope: &str, users: Vec<String>, public: bool) -> bool {
+    let users = users.into_iter().map(SVal::String).collect::<Vec<_>>();
+    if let Ok(res) = store.doc.call_func("root.set_secret", None, vec![name.into(), value.into(), scope.into(), SVal::Array(users), public.into()]) {
+        store.save();
+        return res.truthy();
+    }
+    false
+}
+
+
+/// ADMIN delete a secret.
+pub(crate) fn admin_delete_secret(store: &mut SecretStore, name: &str) -> bool {
+    if let Ok(res) = store.doc.call_func("root.delete_secret", None, vec![name.into()]) {
+        store.save();
+        return res.truthy();
+    }
+    false
+}
+
+
+/// Names of all secrets.
+pub(crate) fn secret_names(store: &SecretStore) -> Vec<String> {
+    let mut names = Vec::new();
+    if let Some(root) = store.doc.graph.root_by_name("Secrets") {
+        for field in SField::fields(&store.doc.graph, &root) {
+            names.push(field.name.clone());
+        }
+    }
+    names
+}
+
+
+/// Secrets that a run can read.
+///
+/// user: the authenticated user for the run (username and whether they are the admin).
+/// package: the registry package being run, if any (Ex. "@acme/hello").
+pub(crate) async fn run_secrets(state: &ServerState, user: Option<(String, bool)>, package: Option<&str>) -> BTreeMap<String, String> {
+    let mut secrets = BTreeMap::new();
+    {
+        let config = state.config.lock().await;
+        if !secrets_enabled(&config) {
+            return secrets;
+        }
+    }
+
+    let (username, admin) = user.unwrap_or_default();
+    let mut scope = String::default();
+    if let Some(package) = package {
+        scope = package.trim_start_matches('@').split('/').next().unwrap_or_default().to_owned();
+    }
+
+    let mut store = state.secrets.lock().await;
+    for name in secret_names(&store) {
+        // secrets files from older versions keep their own "can_read", which let unauthenticated runs read any secret without users
+        if username.is_empty() && !admin && !store.doc.field(&format!("Secrets.{}.public", name), None).is_some_and(|public| public.value.truthy()) {
+            continue;
+        }
+        if let Ok(res) = store.doc.call_func("root.can_read", None, vec![name.clone().into(), username.clone().into(), admin.into(), scope.clone().into()]) && res.truthy() && let Some(value) = store.doc.field(&format!("Secrets.{}.value", name), None) {
+            let value = value.to_string();
+            if value.len() >= MIN_SECRET_LEN {
+                secrets.insert(name, value);
+            }
+        }
+    }
+    secrets
+}
+
+
+#[cfg(test)]
+mod tests {
+    use stof::{SDoc, SVal};
+    use super::SECRETS_INTERFACE;
+
+    fn can_read(doc: &mut SDoc, name: &str, username: &str, admin: bool, scope: &str) -> bool {
+        doc.call_func("root.can_read", None, vec![name.into(), username.into(), admin.into(), scope.into()]).unwrap().truthy()
+    }
+
+    #[test]
+    fn secret_access() {
+        let mut doc = SDoc::default();
+        doc.string_import("main", "stof", SECRETS_INTERFACE, "").unwrap();
+        let secrets = [
+            ("any", "", vec![], false),
+            ("public", "", vec![], true),
+            ("listed", "", vec![SVal::from("bob")], true),
+            ("scoped", "acme", vec![], true),
+        ];
+        for (name, scope, users, public) in secrets {
+            doc.call_func("root.set_secret", None, vec![name.into(), "value1234".into(), scope.into(), SVal::Array(users), public.into()]).unwrap();
+        }
+
+        // Authenticated users can read secrets without a users list
+        assert!(can_read(&mut doc, "any", "alice", false, ""));
+        assert!(!can_read(&mut doc, "any", "", false, ""));
+
+        // Unauthenticated runs only read public secrets
+        assert!(can_read(&mut doc, "public", "", false, ""));
+
+        // Users lists apply to everyone but the admin (public doesn't apply)
+        assert!(can_read(&mut doc, "listed", "bob", false, ""));
+        assert!(!can_read(&mut doc, "listed", "alice", false, ""));
+        assert!(!can_read(&mut doc, "listed", "", false, ""));
+        assert!(can_read(&mut doc, "listed", "admin", true, ""));
+
+        // Scopes apply to everyone
+        assert!(can_read(&mut doc, "scoped", "", false, "acme"));
+        assert!(!can_read(&mut doc, "scoped", "alice", false, "other"));
+        assert!(!can_read(&mut doc, "scoped", "admin", true, ""));
+
+        assert!(!can_read(&mut doc, "missing", "admin", true, ""));
+    }
+}
@@ -15,13 +15,13 @@
 //
 
 use std::{net::SocketAddr, sync::Arc, time::Duration};
-use axum::{routing::{get, post}, Router};
+use axum::{routing::{any, delete, get, post}, Router};
 use colored::Colorize;
 use stof::SDoc;
-use tokio::sync::Mutex;
+use tokio::sync::{mpsc::{unbounded_channel, UnboundedSender}, Mutex};
 use tower_governor::{governor::GovernorConfig, GovernorLayer};
 use tower_http::cors::CorsLayer;
-use crate::{config::{server_address, server_port}, metrics::{api::{get_downloads_count_handler, get_packages_count_handler, get_server_run_count_handler, get_total_downloads_count_handler}, load_metrics}, registry::{api::{delete_registry_handler, get_registry_handler, publish_registry_handler}, system::SystemRegistry, Registry}, run::run_handler, users::{api::{admin_delete_user_handler, admin_set_user_handler}, load_users}};
+use crate::{bus::{api::{admin_delete_subscription_handler, admin_get_subscription_handler, admin_get_subscriptions_handler, admin_set_subscription_handler}, load_subscriptions, start_bus, BusMessage}, config::{migrate_reserved_names, server_address, server_port, session_idle_timeout}, endpoints::api::endpoint_handler, kv::{api::{admin_delete_kv_entry_handler, admin_delete_kv_namespace_handler, admin_get_kv_entry_handler, admin_get_kv_handler, admin_get_kv_namespace_handler}, load_kv, start_kv_saver, KvStore}, metrics::{api::{get_downloads_count_handler, get_packages_count_handler, get_server_run_count_handler, get_total_downloads_count_handler}, load_metrics}, pipelines::api::run_pipeline_handler, registry::{api::{delete_registry_handler, get_registry_handler, publish_registry_handler}, system::SystemRegistry, Registry}, run::{batch::run_batch_handler, cache::RunCache, negotiate::RunFormats, run_handler, ws::ws_run_handler, RunOptions}, runs::{api::{admin_delete_run_handler, admin_get_run_handler, admin_get_runs_handler, admin_replay_run_handler}, load_runs, RunRecords}, schedules::{api::{admin_delete_schedule_handler, admin_get_schedule_handler, admin_get_schedules_handler, admin_set_schedule_handler}, load_schedules, start_scheduler}, secrets::{api::{admin_delete_secret_handler, admin_get_secrets_handler, admin_set_secret_handler}, load_secrets, SecretStore}, sessions::{api::{call_session_handler, create_session_handler, delete_session_handler, get_session_handler}, Sessions}, users::{api::{admin_delete_scope_handler, admin_delete_user_handler, admin_set_scope_handler, admin_set_user_handler}, load_users}};
 
 
 /// Server state.
@@ -38,11 +38,38 @@ pub struct ServerState {
 
     /// Registry.
     pub registry: Arc<Mutex<dyn Registry>>,
+
+    /// Document sessions.
+    pub sessions: Arc<Mutex<Sessions>>,
+
+    /// Scheduled jobs.
+    pub schedules: Arc<Mutex<SDoc>>,
+
+    /// Secrets store.
+    pub secrets: Arc<Mutex<SecretStore>>,
+
+    /// Run result cache.
+    pub cache: Arc<Mutex<RunCache>>,
+
+    /// Key-value store for runs (locked from within runs, so not an async mutex).
+    pub kv: Arc<std::sync::Mutex<KvStore>>,
+
+    /// Message bus subscriptions.
+    pub subscriptions: Arc<Mutex<SDoc>>,
+
+    /// Message bus (messages published by runs).
+    pub bus: UnboundedSender<BusMessage>,
+
+    /// Recorded failing runs.
+    pub runs: Arc<Mutex<RunRecords>>,
+
+    /// Formats that runs can import and export (found at startup).
+    pub formats: Arc<RunFormats>,
 }
 
 
 /// Start the runner server.
-pub async fn serve(config: SDoc) {
+pub async fn serve(mut config: SDoc) {
     // Setup governor configuration - see https://crates.io/crates/tower_governor
     let governor_conf = Arc::new(GovernorConfig::default());
     let governor_limiter = governor_conf.limiter().clone();
@@ -56,16 +83,68 @@ pub async fn serve(config: SDoc) {
 
     let cors = CorsLayer::permissive();
     let address = SocketAddr::from((server_address(&config), server_port(&config)));
+    if let Err(error) = migrate_reserved_names(&config) {
+        println!("{}: {}", "RegistryError".red(), error.dimmed());
+        return;
+    }
     let users = load_users(&config);
     let metrics = load_metrics(&config);
     let registry = SystemRegistry::new(&config);
+    let sessions = Sessions::new(&mut config);
+    let cache = RunCache::new(&config);
+    let kv = load_kv(&config);
+    let schedules = load_schedules(&config);
+    let subscriptions = load_subscriptions(&config);
+    let runs = load_runs(&config);
+    let (bus, bus_receiver) = unbounded_channel();
+    let secrets = match load_secrets(&config) {
+        Ok(secrets) => secrets,
+        Err(error) => {
+            println!("{}: {}", "SecretsError".red(), error.dimmed());
+            return;
+        }
+    };
+    let formats = RunFormats::new(&RunOptions::from_config(&mut config)).await;
     let state = ServerState {
         config: Arc::new(Mutex::new(config)),
         users: Arc::new(Mutex::new(users)),
         registry: Arc::new(Mutex::new(registry)),
         metrics: Arc::new(Mutex::new(metrics)),
+        sessions: Arc::new(Mutex::new(sessions)),
+        schedules: Arc::new(Mutex::new(schedules)),
+        secrets: Arc::new(Mutex::new(secrets)),
+        cache: Arc::new(Mutex::new(cache)),
+        kv: Arc::new(std::sync::Mutex::new(kv)),
+        subscriptions: Arc::new(Mutex::new(subscriptions)),
+        bus,
+        runs: Arc::new(Mutex::new(runs)),
+        formats: Arc::new(formats),
     };
 
+    // Run scheduled jobs
+    start_scheduler(state.clone());
+
+    // Deliver bus messages to subscriptions
+    start_bus(state.clone(), bus_receiver);
+
+    // Save key-value changes
+    start_kv_saver(state.clone());
+
+    // Evict idle sessions
+    let session_state = state.clone();
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(Duration::from_secs(30)).await;
+            let idle_timeout;
+            {
+                let config = session_state.config.lock().await;
+                idle_timeout = session_idle_timeout(&config);
+            }
+            let mut sessions = session_state.sessions.lock().await;
+            sessions.evict_idle(idle_timeout);
+        }
+    });
+
     let app = Router::new()
         // Registry API
         .route("/registry/{*path}", get(get_registry_handler)
@@ -74,10 +153,56 @@ pub async fn serve(config: SDoc) {
 
         // Run API
         .route("/run", post(run_handler))
+        .route("/run/batch", post(run_batch_handler))
+        .route("/ws/run", get(ws_run_handler))
+
+        // Package Endpoints API
+        .route("/api/{*path}", any(endpoint_handler))
+
+        // Sessions API
+        .route("/sessions", post(create_session_handler))
+        .route("/sessions/{id}", get(get_session_handler)
+            .delete(delete_session_handler))
+        .route("/sessions/{id}/call/{*path}", post(call_session_handler))
+
+        // Pipelines API
+        .route("/pipelines/run", post(run_pipeline_handler))
 
         // Admin Users API
         .route("/admin/users", post(admin_set_user_handler)
             .delete(admin_delete_user_handler))
+        .route("/admin/scopes", post(admin_set_scope_handler)
+            .delete(admin_delete_scope_handler))
+
+        // Admin Schedules API
+        .route("/admin/schedules", get(admin_get_schedules_handler)
+            .post(admin_set_schedule_handler))
+        .route("/admin/schedules/{id}", get(admin_get_schedule_handler)
+            .delete(admin_delete_schedule_handler))
+
+        // Admin Secrets API
+        .route("/admin/secrets", get(admin_get_secrets_handler)
+            .post(admin_set_secret_handler))
+        .route("/admin/secrets/{name}", delete(admin_delete_secret_handler))
+
+        // Admin Key-Value API
+        .route("/admin/kv", get(admin_get_kv_handler))
+        .route("/admin/kv/{namespace}", get(admin_get_kv_namespace_handler)
+            .delete(admin_delete_kv_namespace_handler))
+        .route("/admin/kv/{namespace}/{*key}", get(admin_get_kv_entry_handler)
+            .delete(admin_delete_kv_entry_handler))
+
+        // Admin Bus API
+        .route("/admin/bus", get(admin_get_subscriptions_handler)
+            .post(admin_set_subscription_handler))
+        .route("/admin/bus/{id}", get(admin_get_subscription_handler)
+            .delete(admin_delete_subscription_handler))
+
+        // Admin Runs API (recorded failing runs)
+        .route("/admin/runs", get(admin_get_runs_handler))
+        .route("/admin/runs/{id}", get(admin_get_run_handler)
+            .delete(admin_delete_run_handler))
+        .route("/admin/runs/{id}/replay", post(admin_replay_run_handler))
 
         // Admin Metrics API
         .route("/admin/metrics/run", get(get_server_run_count_handler))
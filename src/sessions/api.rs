@@ -0,0 +1,295 @@
+//
+// Copyright 2024 Formata, Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+use std::{collections::BTreeMap, time::Instant};
+use axum::{extract::{Path, Query, State}, http::{header::CONTENT_TYPE, HeaderMap, StatusCode}, response::IntoResponse};
+use bytes::Bytes;
+use stof::{SDoc, SVal};
+use tokio::{runtime::Handle, task::spawn_blocking, time::timeout};
+use crate::{bus::BusAccess, config::{opaque_errors, registry_path, run_enabled, run_timeout, sandbox_required, sessions_enabled}, kv::KvAccess, libraries::run_libraries, sql::SqlAccess, response::StofResponse, run::{cancel::RunCancel, egress::EgressPolicy, export_document, import_package, initialize_document}, server::ServerState, users::auth::{auth_exec, auth_user}, wasm::WasmPolicy};
+use super::{get_session, Session, SessionHandle};
+
+
+/// Sessions available?
+/// Returns an error response if not.
+async fn sessions_available(state: &ServerState, headers: &HeaderMap) -> Option<StofResponse> {
+    if !auth_exec(state, headers).await {
+        return Some(StofResponse::error(StatusCode::FORBIDDEN, "access denied"));
+    }
+    let config = state.config.lock().await;
+    if !run_enabled(&config) || !sessions_enabled(&config) {
+        return Some(StofResponse::error(StatusCode::NOT_IMPLEMENTED, "sessions are not available"));
+    }
+    if sandbox_required(&config) {
+        return Some(StofResponse::error(StatusCode::NOT_IMPLEMENTED, "sessions cannot run in the sandbox, which is required"));
+    }
+    None
+}
+
+
+/// Session that the caller can use.
+/// Returns an error response if the session doesn't exist or the caller isn't its owner.
+async fn caller_session(state: &ServerState, headers: &HeaderMap, id: &str) -> Result<SessionHandle, StofResponse> {
+    let Some(handle) = get_session(state, id).await else {
+        return Err(StofResponse::error(StatusCode::NOT_FOUND, "session not found"));
+    };
+    let user = auth_user(state, headers).await;
+    if !handle.accessible(&user) {
+        return Err(StofResponse::error(StatusCode::FORBIDDEN, "access denied"));
+    }
+    Ok(handle)
+}
+
+
+/// Create a session handler.
+/// The session document is created from the request body, or from a registry package with "?package=@scope/name".
+pub(crate) async fn create_session_handler(State(state): State<ServerState>, Query(query): Query<BTreeMap<String, String>>, headers: HeaderMap, mut body: Bytes) -> impl IntoResponse {
+    if let Some(response) = sessions_available(&state, &headers).await {
+        return response;
+    }
+
+    let opaque_stof_errors;
+    let run_time;
+    let registry;
+    let egress;
+    let wasm;
+    {
+        let mut config = state.config.lock().await;
+        opaque_stof_errors = opaque_errors(&config);
+        run_time = run_timeout(&mut config);
+        registry = registry_path(&config);
+        egress = EgressPolicy::from_config(&mut config);
+        wasm = WasmPolicy::from_config(&config);
+    }
+
+    let mut content_type = String::from("stof");
+    if let Some(ctype) = headers.get(CONTENT_TYPE) {
+        match ctype.to_str() {
+            Ok(ctype) => content_type = ctype.to_owned(),
+            Err(_) => return StofResponse::error(StatusCode::BAD_REQUEST, "invalid content type"),
+        }
+    }
+    let package = query.get("package").cloned();
+    let user = auth_user(&state, &headers).await;
+    let libraries = run_libraries(&state, &user, package.as_deref()).await;
+    let doc_libraries = libraries.clone();
+    {
+        let sessions = state.sessions.lock().await;
+        if !sessions.available() {
+            return StofResponse::error(StatusCode::TOO_MANY_REQUESTS, "too many sessions");
+        }
+    }
+
+    // Imported on a blocking thread, so that the timeout can be enforced while it runs
+    let handle = spawn_blocking(move || Handle::current().block_on(async move {
+        let mut doc = SDoc::default();
+        initialize_document(&mut doc, &registry, &egress, &wasm, &KvAccess::default(), &SqlAccess::default(), &BusAccess::default()).await;
+        doc_libraries.restrict(&mut doc);
+
+        let res;
+        if let Some(package) = package {
+            res = import_package(&mut doc, &package);
+        } else {
+            res = doc.header_import("main", &content_type, &content_type, &mut body, "");
+        }
+        match res {
+            Ok(_) => Ok(doc),
+            Err(error) => {
+                if !opaque_stof_errors {
+                    return Err(error.to_string(&doc.graph));
+                }
+                Err(String::from("error parsing document"))
+            }
+        }
+    }));
+
+    match timeout(run_time, handle).await {
+        Ok(Ok(Ok(doc))) => {
+            let inserted;
+            {
+                let mut sessions = state.sessions.lock().await;
+                inserted = sessions.insert(Session::new(doc, libraries), user.map(|(name, _)| name));
+            }
+            match inserted {
+                Ok((id, handle)) => {
+                    handle.session.lock().await.persist();
+                    StofResponse::stof(StatusCode::CREATED, &format!("str id: '{}'", id))
+                },
+                Err(message) => {
+                    StofResponse::error(StatusCode::TOO_MANY_REQUESTS, &message)
+                }
+            }
+        },
+        Ok(Ok(Err(message))) => {
+            StofResponse::error(StatusCode::BAD_REQUEST, &message)
+        },
+        Ok(Err(_)) => {
+            StofResponse::error(StatusCode::INTERNAL_SERVER_ERROR, "error creating session")
+        },
+        Err(_) => {
+            StofResponse::error(StatusCode::REQUEST_TIMEOUT, "timeout while creating session")
+        }
+    }
+}
+
+
+/// Call a function in a session handler.
+/// Path is "{id}/call/{function path}", where the function path is relative to the session document ("root/increment" or "root.increment").
+/// Arguments are given by value with an optional body document containing an "args" array.
+pub(crate) async fn call_session_handler(State(state): State<ServerState>, Path((id, path)): Path<(String, String)>, headers: HeaderMap, body: Bytes) -> impl IntoResponse {
+    if let Some(response) = sessions_available(&state, &headers).await {
+        return response;
+    }
+
+    let opaque_stof_errors;
+    let run_time;
+    {
+        let mut config = state.config.lock().await;
+        opaque_stof_errors = opaque_errors(&config);
+        run_time = run_timeout(&mut config);
+    }
+
+    let mut arguments = Vec::new();
+    if !body.is_empty() {
+        let mut content_type = String::from("stof");
+        if let Some(ctype) = headers.get(CONTENT_TYPE) {
+            match ctype.to_str() {
+                Ok(ctype) => content_type = ctype.to_owned(),
+                Err(_) => return StofResponse::error(StatusCode::BAD_REQUEST, "invalid content type"),
+            }
+        }
+        match SDoc::bytes(body, &content_type) {
+            Ok(args_doc) => {
+                if let Some(args_field) = args_doc.field("root.args", None) {
+                    match &args_field.value {
+                        SVal::Array(values) => {
+                            for value in values {
+                                if value.is_object() {
+                                    return StofResponse::error(StatusCode::BAD_REQUEST, "object arguments are not supported");
+                                }
+                                arguments.push(value.clone());
+                            }
+                        },
+                        _ => {
+                            return StofResponse::error(StatusCode::BAD_REQUEST, "args must be an array");
+                        }
+                    }
+                }
+            },
+            Err(_) => {
+                return StofResponse::error(StatusCode::BAD_REQUEST, "error parsing arguments");
+            }
+        }
+    }
+
+    let handle = match caller_session(&state, &headers, &id).await {
+        Ok(handle) => handle,
+        Err(response) => return response,
+    };
+
+    // Called on a blocking thread, so that the timeout can be enforced while it runs (see run_stof)
+    // A call that times out while waiting for the session (in use by another call) is not made
+    let timed_out = RunCancel::default();
+    let call_timed_out = timed_out.clone();
+    let session = handle.session.clone();
+    let cancel = handle.cancel.clone();
+    let call = spawn_blocking(move || {
+        let mut session = session.blocking_lock();
+        if call_timed_out.is_cancelled() {
+            return StofResponse::error(StatusCode::REQUEST_TIMEOUT, "timeout while calling session function");
+        }
+
+        let mut func_path = path.trim_matches('/').replace('/', ".");
+        if session.doc.func(&func_path, None).is_none() {
+            func_path = format!("root.{}", func_path);
+            if session.doc.func(&func_path, None).is_none() {
+                return StofResponse::error(StatusCode::NOT_FOUND, "function not found");
+            }
+        }
+
+        cancel.reset();
+        let response = match session.doc.call_func(&func_path, None, arguments) {
+            Ok(value) => {
+                StofResponse::val_response(&session.doc, value)
+            },
+            Err(error) => {
+                if !opaque_stof_errors {
+                    StofResponse::error(StatusCode::BAD_REQUEST, &error.to_string(&session.doc.graph))
+                } else {
+                    StofResponse::error(StatusCode::BAD_REQUEST, "error calling session function")
+                }
+            }
+        };
+        session.last_used = Instant::now();
+        session.persist();
+        response
+    });
+
+    match timeout(run_time, call).await {
+        Ok(Ok(res)) => {
+            res
+        },
+        Ok(Err(_)) => {
+            StofResponse::error(StatusCode::INTERNAL_SERVER_ERROR, "error calling session function")
+        },
+        Err(_) => {
+            // The call is cancelled and left to end on its own thread (the session stays locked until it does)
+            timed_out.cancel();
+            handle.cancel.cancel();
+            StofResponse::error(StatusCode::REQUEST_TIMEOUT, "timeout while calling session function")
+        }
+    }
+}
+
+
+/// Export a session document handler.
+/// Uses the "export" query to determine the format (default is "bstof").
+pub(crate) async fn get_session_handler(State(state): State<ServerState>, Path(id): Path<String>, Query(query): Query<BTreeMap<String, String>>, headers: HeaderMap) -> impl IntoResponse {
+    if let Some(response) = sessions_available(&state, &headers).await {
+        return response;
+    }
+
+    let mut export_format = String::from("bstof");
+    if let Some(format) = query.get("export") {
+        export_format = format.clone();
+    }
+
+    let handle = match caller_session(&state, &headers, &id).await {
+        Ok(handle) => handle,
+        Err(response) => return response,
+    };
+
+    let mut session = handle.session.lock().await;
+    session.last_used = Instant::now();
+    export_document(&session.doc, &export_format)
+}
+
+
+/// Delete a session handler.
+pub(crate) async fn delete_session_handler(State(state): State<ServerState>, Path(id): Path<String>, headers: HeaderMap) -> impl IntoResponse {
+    if let Some(response) = sessions_available(&state, &headers).await {
+        return response;
+    }
+
+    if let Err(response) = caller_session(&state, &headers, &id).await {
+        return response;
+    }
+    let mut sessions = state.sessions.lock().await;
+    if sessions.remove(&id) {
+        return StofResponse::msg(StatusCode::OK, "session removed");
+    }
+    StofResponse::error(StatusCode::NOT_FOUND, "session not found")
+}
@@ -0,0 +1,264 @@
+//
+// Copyright 2024 Formata, Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+use std::{collections::HashMap, fs, sync::Arc, time::{Duration, Instant}};
+use nanoid::nanoid;
+use stof::SDoc;
+use tokio::sync::Mutex;
+use crate::{bus::BusAccess, config::{registry_path, sessions_max, sessions_persist_path}, kv::KvAccess, libraries::RunLibraries, sql::SqlAccess, run::{cancel::RunCancel, egress::EgressPolicy, initialize_document}, server::ServerState, wasm::WasmPolicy};
+pub(crate) mod api;
+
+
+/// Stateful document session.
+pub struct Session {
+    /// Session document.
+    pub doc: SDoc,
+
+    /// Last time this session was used.
+    pub last_used: Instant,
+
+    /// Libraries the session document can use.
+    pub libraries: RunLibraries,
+
+    /// File to persist the session document to (if persistence is enabled).
+    pub file: Option<String>,
+}
+impl Session {
+    /// Create a new session from a document (restricted to the libraries).
+    pub fn new(mut doc: SDoc, libraries: RunLibraries) -> Self {
+        libraries.restrict(&mut doc);
+        Self {
+            doc,
+            last_used: Instant::now(),
+            libraries,
+            file: None,
+        }
+    }
+
+    /// Persist the session document to disk (if enabled).
+    pub fn persist(&self) {
+        if let Some(file) = &self.file && let Ok(bytes) = self.doc.export_bytes("main", "bstof", None) {
+            let _ = fs::write(file, bytes);
+        }
+    }
+}
+
+
+/// Session in memory, along with what's needed to use it without locking it.
+#[derive(Clone)]
+pub struct SessionHandle {
+    /// The session (locked while in use).
+    pub session: Arc<Mutex<Session>>,
+
+    /// User that created the session (None if created without authentication).
+    pub owner: Option<String>,
+
+    /// Cancellation of the session's calls (reset at the start of each call, see RunCancel).
+    pub cancel: RunCancel,
+}
+impl SessionHandle {
+    /// Handle for a session, guarding its document so that a call can be cancelled.
+    fn new(mut session: Session, owner: Option<String>) -> Self {
+        let cancel = RunCancel::default();
+        cancel.guard(&mut session.doc);
+        Self {
+            session: Arc::new(Mutex::new(session)),
+            owner,
+            cancel,
+        }
+    }
+
+    /// Can this user use the session?
+    /// Sessions created by an authenticated user can only be used by that user (or the admin).
+    /// Sessions created without authentication can be used by anyone with the session ID.
+    pub fn accessible(&self, user: &Option<(String, bool)>) -> bool {
+        match &self.owner {
+            Some(owner) => user.as_ref().is_some_and(|(name, admin)| *admin || name == owner),
+            None => true,
+        }
+    }
+}
+
+
+/// Document sessions.
+/// Each session has its own lock, so that calls into different sessions can happen at the same time.
+pub struct Sessions {
+    /// Sessions in memory.
+    pub sessions: HashMap<String, SessionHandle>,
+
+    /// Max number of sessions in memory at once.
+    pub max_sessions: usize,
+
+    /// Directory to persist sessions within (if enabled).
+    pub persist_path: Option<String>,
+
+    /// Registry path (for initializing persisted documents).
+    pub registry_path: String,
+
+    /// Egress policy (for initializing persisted documents).
+    pub egress: EgressPolicy,
+
+    /// WebAssembly policy (for initializing persisted documents).
+    pub wasm: WasmPolicy,
+
+    /// Config libraries (for persisted documents without a libraries file).
+    pub libraries: RunLibraries,
+}
+impl Sessions {
+    /// Create sessions from the server config.
+    pub fn new(config: &mut SDoc) -> Self {
+        let persist_path = sessions_persist_path(config);
+        if let Some(path) = &persist_path {
+            let _ = fs::create_dir_all(path);
+        }
+        Self {
+            sessions: Default::default(),
+            max_sessions: sessions_max(config),
+            persist_path,
+            registry_path: registry_path(config),
+            egress: EgressPolicy::from_config(config),
+            wasm: WasmPolicy::from_config(config),
+            libraries: RunLibraries::from_config(config),
+        }
+    }
+
+    /// Session file path with an extension (if persistence is enabled).
+    fn session_file(&self, id: &str, extension: &str) -> Option<String> {
+        if let Some(path) = &self.persist_path {
+            return Some(format!("{}/{}.{}", path, id, extension));
+        }
+        None
+    }
+
+    /// Can another session be created?
+    pub fn available(&self) -> bool {
+        self.sessions.len() < self.max_sessions
+    }
+
+    /// Insert a new session created by an owner, returning its ID and handle.
+    /// The session document is not persisted here (call persist on the session without holding the sessions lock).
+    pub fn insert(&mut self, mut session: Session, owner: Option<String>) -> Result<(String, SessionHandle), String> {
+        if !self.available() {
+            return Err(String::from("too many sessions"));
+        }
+        let id = nanoid!();
+        if let Some(file) = self.session_file(&id, "libraries") {
+            let _ = fs::write(file, session.libraries.to_text());
+        }
+        if let Some(owner) = &owner && let Some(file) = self.session_file(&id, "owner") {
+            let _ = fs::write(file, owner);
+        }
+        session.file = self.session_file(&id, "bstof");
+        let handle = SessionHandle::new(session, owner);
+        self.sessions.insert(id.clone(), handle.clone());
+        Ok((id, handle))
+    }
+
+    /// Loader for a persisted session (if persistence is enabled).
+    fn loader(&self, id: &str) -> Option<SessionLoader> {
+        if id.contains('/') || id.contains('.') {
+            return None;
+        }
+        Some(SessionLoader {
+            file: self.session_file(id, "bstof")?,
+            libraries_file: self.session_file(id, "libraries")?,
+            owner_file: self.session_file(id, "owner")?,
+            registry_path: self.registry_path.clone(),
+            egress: self.egress.clone(),
+            wasm: self.wasm.clone(),
+            libraries: self.libraries.clone(),
+        })
+    }
+
+    /// Remove a session (from memory and disk).
+    pub fn remove(&mut self, id: &str) -> bool {
+        let mut removed = self.sessions.remove(id).is_some();
+        if !id.contains('/') && !id.contains('.') {
+            if let Some(file) = self.session_file(id, "bstof") && fs::remove_file(file).is_ok() {
+                removed = true;
+            }
+            for extension in ["libraries", "owner"] {
+                if let Some(file) = self.session_file(id, extension) {
+                    let _ = fs::remove_file(file);
+                }
+            }
+        }
+        removed
+    }
+
+    /// Evict sessions that have not been used within the idle timeout.
+    /// Sessions that are currently in use are skipped.
+    pub fn evict_idle(&mut self, idle_timeout: Duration) {
+        let mut evict = Vec::new();
+        for (id, handle) in &self.sessions {
+            if let Ok(session) = handle.session.try_lock() && session.last_used.elapsed() > idle_timeout {
+                evict.push(id.clone());
+            }
+        }
+        for id in evict {
+            // Persisted sessions are already on disk, so just drop them from memory
+            self.sessions.remove(&id);
+        }
+    }
+}
+
+
+/// Loads a persisted session from disk.
+struct SessionLoader {
+    file: String,
+    libraries_file: String,
+    owner_file: String,
+    registry_path: String,
+    egress: EgressPolicy,
+    wasm: WasmPolicy,
+    libraries: RunLibraries,
+}
+impl SessionLoader {
+    /// Load the session and its owner (None if not persisted).
+    async fn load(self) -> Option<(Session, Option<String>)> {
+        let mut doc = SDoc::file(&self.file, "bstof").ok()?;
+        initialize_document(&mut doc, &self.registry_path, &self.egress, &self.wasm, &KvAccess::default(), &SqlAccess::default(), &BusAccess::default()).await;
+        let mut libraries = self.libraries;
+        if let Ok(text) = fs::read_to_string(&self.libraries_file) {
+            libraries = RunLibraries::from_text(&text);
+        }
+        let owner = fs::read_to_string(&self.owner_file).ok();
+
+        let mut session = Session::new(doc, libraries);
+        session.file = Some(self.file);
+        Some((session, owner))
+    }
+}
+
+
+/// Get a session by ID.
+/// If not in memory and persistence is enabled, the session is loaded from disk (without holding the sessions lock).
+pub(crate) async fn get_session(state: &ServerState, id: &str) -> Option<SessionHandle> {
+    let loader;
+    {
+        let sessions = state.sessions.lock().await;
+        if let Some(handle) = sessions.sessions.get(id) {
+            return Some(handle.clone());
+        }
+        loader = sessions.loader(id)?;
+    }
+
+    let (session, owner) = loader.load().await?;
+    let mut sessions = state.sessions.lock().await;
+    // Loaded by another request in the meantime?
+    let handle = sessions.sessions.entry(id.to_owned()).or_insert_with(|| SessionHandle::new(session, owner));
+    Some(handle.clone())
+}
@@ -0,0 +1,151 @@
+//
+// Copyright 2024 Formata, Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+use std::{fs, time::Duration};
+use rusqlite::{limits::Limit, Connection};
+use crate::{config::{registry_path, sql_enabled, sql_max_rows, sql_max_size}, server::ServerState, users::user_perms};
+
+
+/// Database access for a run.
+/// Each scope has its own SQLite database file in the registry directory.
+///
+/// A run can use the database of a scope if it's run by the admin, if it's a run of a package within that scope,
+/// or if the user running it can read (query) or write (execute) and has that scope as their modify scope in the users document.
+/// Users without a modify scope can't use any databases outside of the packages they run.
+#[derive(Clone, Default)]
+pub(crate) struct SqlAccess {
+    /// Database directory (none if databases are not available).
+    pub path: Option<String>,
+
+    /// Run by the admin?
+    pub admin: bool,
+
+    /// Scope of the package being run.
+    pub package_scope: Option<String>,
+
+    /// Permissions and modify scope of the user running it (from the users document).
+    pub user: Option<(i64, String)>,
+
+    /// Max number of rows returned by a query.
+    pub max_rows: usize,
+
+    /// Max size of each database (bytes).
+    pub max_size: u64,
+}
+impl SqlAccess {
+    /// Can this run query the database of a scope?
+    pub fn can_read(&self, scope: &str) -> bool {
+        self.allowed(scope, 0b0001)
+    }
+
+    /// Can this run modify the database of a scope?
+    pub fn can_write(&self, scope: &str) -> bool {
+        self.allowed(scope, 0b0010)
+    }
+
+    fn allowed(&self, scope: &str, perm: i64) -> bool {
+        if self.admin || self.package_scope.as_deref() == Some(scope) {
+            return true;
+        }
+        if let Some((perms, user_scope)) = &self.user {
+            return perms & perm > 0 && user_scope == scope;
+        }
+        false
+    }
+
+    /// Open the database of a scope (created if it doesn't exist).
+    /// Attaching other databases is disabled, and the database cannot grow past the max size.
+    pub fn open(&self, scope: &str) -> Result<Connection, String> {
+        
+        let path = match &self.path {
+            Some(value) => value,
+            None => return Err("databases are not available".into()),
+        };
+        if let Err(error) = fs::create_dir_all(path) {
+            return Err(error.to_string());
+        }
+
+        let conn = Connection::open(format!("{}/{}.sqlite", path, scope)).map_err(|error| error.to_string())?;
+        conn.set_limit(Limit::SQLITE_LIMIT_ATTACHED, 0);
+        conn.busy_timeout(Duration::from_secs(5)).map_err(|error| error.to_string())?;
+        let page_size: i64 = conn.pragma_query_value(None, "page_size", |row| row.get(0)).map_err(|error| error.to_string())?;
+        let max_pages = (self.max_size as i64 / page_size.max(1)).max(1);
+        conn.pragma_update(None, "max_page_count", max_pages).map_err(|error| error.to_string())?;
+        Ok(conn)
+    }
+}
+
+
+/// Database scope from a scope or package name ("@acme", "acme", or "@acme/name" -> "acme").
+/// Scopes can only contain alphanumeric characters, '-', and '_'.
+pub(crate) fn database_scope(name: &str) -> Option<String> {
+    let scope = name.trim_start_matches('@').split('/').next().unwrap_or_default();
+    if scope.is_empty() || !scope.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        return None;
+    }
+    Some(scope.to_owned())
+}
+
+
+/// Database access for a run by a user (if authenticated), of a registry package (if any).
+pub(crate) async fn run_sql(state: &ServerState, user: &Option<(String, bool)>, package: Option<&str>) -> SqlAccess {
+    let mut access = SqlAccess::default();
+    {
+        let config = state.config.lock().await;
+        if !sql_enabled(&config) {
+            return access;
+        }
+        // Reserved entry in the registry directory, so runs can only reach databases through the sql library
+        access.path = Some(format!("{}/__sql__", registry_path(&config)));
+        access.max_rows = sql_max_rows(&config);
+        access.max_size = sql_max_size(&config);
+    }
+    access.package_scope = package.and_then(database_scope);
+    if let Some((username, admin)) = user {
+        access.admin = *admin;
+        if !*admin {
+            let users = state.users.lock().await;
+            access.user = user_perms(&users, username);
+        }
+    }
+    access
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::SqlAccess;
+
+    #[test]
+    fn sql_access() {
+        let admin = SqlAccess { admin: true, ..Default::default() };
+        assert!(admin.can_read("acme") && admin.can_write("acme"));
+
+        let package = SqlAccess { package_scope: Some("acme".into()), ..Default::default() };
+        assert!(package.can_read("acme") && package.can_write("acme"));
+        assert!(!package.can_read("other") && !package.can_write("other"));
+
+        // Users need a matching modify scope
+        let reader = SqlAccess { user: Some((0b0001, "acme".into())), ..Default::default() };
+        assert!(reader.can_read("acme") && !reader.can_write("acme"));
+        assert!(!reader.can_read("other"));
+
+        let unscoped = SqlAccess { user: Some((0b1111, String::default())), ..Default::default() };
+        assert!(!unscoped.can_read("acme") && !unscoped.can_write("acme"));
+
+        assert!(!SqlAccess::default().can_read("acme"));
+    }
+}
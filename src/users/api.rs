@@ -18,10 +18,11 @@ use axum::{extract::State, http::{header::CONTENT_TYPE, HeaderMap, StatusCode},
 use bytes::Bytes;
 use stof::{SDoc, SVal};
 use crate::{response::StofResponse, server::ServerState};
-use super::{admin_delete_user, admin_set_user, auth::auth_admin};
+use super::{admin_delete_scope, admin_delete_user, admin_set_scope, admin_set_user, auth::auth_admin};
 
 
 /// Create/update a user.
+/// The "libraries" list is optional (default is all libraries that the config allows).
 pub(crate) async fn admin_set_user_handler(State(state): State<ServerState>, headers: HeaderMap, body: Bytes) -> impl IntoResponse {
     if !auth_admin(&state, &headers, false).await {
         return StofResponse::error(StatusCode::FORBIDDEN, "access denied");
@@ -39,15 +40,21 @@ pub(crate) async fn admin_set_user_handler(State(state): State<ServerState>, hea
                     if let Some(scope_field) = doc.field("root.scope", None) {
                         scope = scope_field.to_string();
                     }
-                    match &perms.value {
-                        SVal::Number(num) => {
-                            let perms = num.int();
-                            let mut users = state.users.lock().await;
-                            if admin_set_user(&mut users, &user.to_string(), &pass.to_string(), perms, &scope) {
-                                return StofResponse::msg(StatusCode::OK, "set user");
-                            }
-                        },
-                        _ => {}
+                    let mut libraries = vec![String::from("*")];
+                    if let Some(libraries_field) = doc.field("root.libraries", None) {
+                        match &libraries_field.value {
+                            SVal::Array(vals) => {
+                                libraries = vals.iter().map(|val| val.to_string()).collect();
+                            },
+                            _ => return StofResponse::error(StatusCode::BAD_REQUEST, "user libraries must be a list"),
+                        }
+                    }
+                    if let SVal::Number(num) = &perms.value {
+                        let perms = num.int();
+                        let mut users = state.users.lock().await;
+                        if admin_set_user(&mut users, &user.to_string(), &pass.to_string(), perms, &scope, libraries) {
+                            return StofResponse::msg(StatusCode::OK, "set user");
+                        }
                     }
                 }
             }
@@ -77,3 +84,46 @@ pub(crate) async fn admin_delete_user_handler(State(state): State<ServerState>,
     }
     StofResponse::error(StatusCode::BAD_REQUEST, "not a valid user body")
 }
+
+
+/// Set the library allow list for the runs of a registry scope's packages.
+pub(crate) async fn admin_set_scope_handler(State(state): State<ServerState>, headers: HeaderMap, body: Bytes) -> impl IntoResponse {
+    if !auth_admin(&state, &headers, false).await {
+        return StofResponse::error(StatusCode::FORBIDDEN, "access denied");
+    }
+
+    let mut content_type = String::from("stof");
+    if let Some(ctype) = headers.get(CONTENT_TYPE) {
+        content_type = ctype.to_str().unwrap().to_owned();
+    }
+    if let Ok(doc) = SDoc::bytes(body, &content_type) && let Some(scope) = doc.field("root.scope", None) && let Some(libraries) = doc.field("root.libraries", None) && let SVal::Array(vals) = &libraries.value {
+        let scope = scope.to_string().trim_start_matches('@').to_owned();
+        let libraries = vals.iter().map(|val| val.to_string()).collect();
+        let mut users = state.users.lock().await;
+        if admin_set_scope(&mut users, &scope, libraries) {
+            return StofResponse::msg(StatusCode::OK, "set scope");
+        }
+    }
+    StofResponse::error(StatusCode::BAD_REQUEST, "not a valid scope body")
+}
+
+
+/// Delete the library allow list of a registry scope.
+pub(crate) async fn admin_delete_scope_handler(State(state): State<ServerState>, headers: HeaderMap, body: Bytes) -> impl IntoResponse {
+    if !auth_admin(&state, &headers, false).await {
+        return StofResponse::error(StatusCode::FORBIDDEN, "access denied");
+    }
+
+    let mut content_type = String::from("stof");
+    if let Some(ctype) = headers.get(CONTENT_TYPE) {
+        content_type = ctype.to_str().unwrap().to_owned();
+    }
+    if let Ok(doc) = SDoc::bytes(body, &content_type) && let Some(scope) = doc.field("root.scope", None) {
+        let scope = scope.to_string().trim_start_matches('@').to_owned();
+        let mut users = state.users.lock().await;
+        if admin_delete_scope(&mut users, &scope) {
+            return StofResponse::msg(StatusCode::OK, "deleted scope");
+        }
+    }
+    StofResponse::error(StatusCode::BAD_REQUEST, "not a valid scope body")
+}
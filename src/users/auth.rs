@@ -17,7 +17,7 @@
 use axum::http::{header::AUTHORIZATION, HeaderMap};
 use http_auth_basic::Credentials;
 use crate::{config::{get_admin, unauth_delete, unauth_exec, unauth_read, unauth_write}, server::ServerState};
-use super::{can_delete, can_exec, can_read, can_write};
+use super::{authenticated, can_delete, can_exec, can_read, can_write};
 
 
 /// Authenticated as admin.
@@ -133,3 +133,22 @@ pub(crate) async fn auth_exec(state: &ServerState, headers: &HeaderMap) -> bool
         true
     }
 }
+
+
+/// Authenticated user.
+/// Returns the username and whether the user is the admin, if the request has valid credentials.
+pub(crate) async fn auth_user(state: &ServerState, headers: &HeaderMap) -> Option<(String, bool)> {
+    let config = state.config.lock().await;
+    if let Some(admin) = get_admin(&config) && let Some(authorization) = headers.get(AUTHORIZATION) && let Ok(credentials) = Credentials::from_header(authorization.to_str().unwrap().to_string()) {
+        let user = credentials.user_id;
+        let pass = credentials.password;
+        if user == admin.0 && pass == admin.1 {
+            return Some((user, true));
+        }
+        let mut users = state.users.lock().await;
+        if authenticated(&mut users, &user, &pass) {
+            return Some((user, false));
+        }
+    }
+    None
+}
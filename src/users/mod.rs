@@ -17,13 +17,14 @@
 pub(crate) mod auth;
 pub(crate) mod api;
 
-use stof::{SData, SDoc, SField};
-use crate::config::{registry_path, registry_users_filename};
+use stof::{SData, SDoc, SField, SVal};
+use crate::config::{registry_path, registry_scopes_filename, registry_users_filename};
 
 
 const USERS_INTERFACE: &str = r#"
-// make sure the Users root exists
+// make sure the Users and Scopes roots exist
 root Users: {}
+root Scopes: {}
 
 type User {
     username: str;
@@ -38,6 +39,9 @@ type User {
     // if set, this user can only modify this registry within this scope
     scope: str = '';
 
+    // libraries this user's runs can use ('*' for all that the config allows)
+    libraries: vec = ['*'];
+
     fn authenticated(password: str): bool {
         return self.password == password;
     }
@@ -63,21 +67,45 @@ type User {
     }
 }
 
+// library allow list for the runs of packages in a registry scope
+type Scope {
+    scope: str;
+
+    // libraries the runs of this scope's packages can use ('*' for all that the config allows)
+    libraries: vec = ['*'];
+}
+
 obj Admin: {
-    // default export JSON path
+    // default export JSON paths
     export_json_path: 'registry/__users__.json'
+    export_scopes_path: 'registry/__scopes__.json'
 
     // set a user
-    fn set_user(username: str, password: str, perms: int = 0b1111, scope: str = ''): bool {
+    fn set_user(username: str, password: str, perms: int = 0b1111, scope: str = '', libraries: vec = ['*']): bool {
         Users.removeField(username, true);
         return Users.set(username, new User {
             username: username,
             password: password,
             perms: perms,
             scope: scope,
+            libraries: libraries,
         });
     }
 
+    // set a scope's library allow list
+    fn set_scope(scope: str, libraries: vec): bool {
+        Scopes.removeField(scope, true);
+        return Scopes.set(scope, new Scope {
+            scope: scope,
+            libraries: libraries,
+        });
+    }
+
+    // delete a scope's library allow list
+    fn delete_scope(scope: str): bool {
+        return Scopes.removeField(scope, true);
+    }
+
     // delete a user by username
     fn delete_user(username: str): bool {
         return Users.removeField(username, true);
@@ -88,6 +116,12 @@ obj Admin: {
         let json = stringify(Users, 'json');
         fs.write(path, json);
     }
+
+    // export scopes to a json file
+    fn export_json_scopes(path: str = root.Admin.export_scopes_path) {
+        let json = stringify(Scopes, 'json');
+        fs.write(path, json);
+    }
 }
 
 // authenticate a user by username, returning the user if present
@@ -130,9 +164,11 @@ pub(crate) fn load_users(config: &SDoc) -> SDoc {
     let registry_name = registry_path(config);
     let users_name = registry_users_filename(config);
     let users_file_path = format!("{}/{}", registry_name, users_name);
+    let scopes_file_path = format!("{}/{}", registry_name, registry_scopes_filename(config));
 
     let mut doc = SDoc::default();
     let _ = doc.file_import("main", "json", &users_file_path, "json", "Users");
+    let _ = doc.file_import("main", "json", &scopes_file_path, "json", "Scopes");
     let _ = doc.string_import("main", "stof", USERS_INTERFACE, "");
 
     if let Some(field_ref) = SField::field_ref(&doc.graph, "root.Admin.export_json_path", '.', None) {
@@ -140,6 +176,9 @@ pub(crate) fn load_users(config: &SDoc) -> SDoc {
             field.value = users_file_path.into();
         }
     }
+    if let Some(field_ref) = SField::field_ref(&doc.graph, "root.Admin.export_scopes_path", '.', None) && let Some(field) = SData::get_mut::<SField>(&mut doc.graph, &field_ref) {
+        field.value = scopes_file_path.into();
+    }
     doc
 }
 
@@ -150,9 +189,16 @@ pub(crate) fn admin_export_users(users: &mut SDoc) {
 }
 
 
+/// ADMIN export scopes file.
+pub(crate) fn admin_export_scopes(users: &mut SDoc) {
+    let _ = users.call_func("root.Admin.export_json_scopes", None, vec![]);
+}
+
+
 /// ADMIN create a new user.
-pub(crate) fn admin_set_user(users: &mut SDoc, user: &str, pass: &str, perms: i64, scope: &str) -> bool {
-    if let Ok(res) = users.call_func("root.Admin.set_user", None, vec![user.into(), pass.into(), perms.into(), scope.into()]) {
+pub(crate) fn admin_set_user(users: &mut SDoc, user: &str, pass: &str, perms: i64, scope: &str, libraries: Vec<String>) -> bool {
+    let libraries = SVal::Array(libraries.into_iter().map(SVal::String).collect());
+    if let Ok(res) = users.call_func("root.Admin.set_user", None, vec![user.into(), pass.into(), perms.into(), scope.into(), libraries]) {
         admin_export_users(users);
         return res.truthy();
     }
@@ -170,6 +216,36 @@ pub(crate) fn admin_delete_user(users: &mut SDoc, user: &str) -> bool {
 }
 
 
+/// ADMIN set a scope's library allow list.
+pub(crate) fn admin_set_scope(users: &mut SDoc, scope: &str, libraries: Vec<String>) -> bool {
+    let libraries = SVal::Array(libraries.into_iter().map(SVal::String).collect());
+    if let Ok(res) = users.call_func("root.Admin.set_scope", None, vec![scope.into(), libraries]) {
+        admin_export_scopes(users);
+        return res.truthy();
+    }
+    false
+}
+
+
+/// ADMIN delete a scope's library allow list.
+pub(crate) fn admin_delete_scope(users: &mut SDoc, scope: &str) -> bool {
+    if let Ok(res) = users.call_func("root.Admin.delete_scope", None, vec![scope.into()]) {
+        admin_export_scopes(users);
+        return res.truthy();
+    }
+    false
+}
+
+
+/// Authenticated?
+pub(crate) fn authenticated(users: &mut SDoc, user: &str, pass: &str) -> bool {
+    if let Ok(res) = users.call_func("root.authenticate", None, vec![user.into(), pass.into()]) {
+        return res.truthy();
+    }
+    false
+}
+
+
 /// Can read?
 pub(crate) fn can_read(users: &mut SDoc, user: &str, pass: &str) -> bool {
     if let Ok(res) = users.call_func("root.can_read", None, vec![user.into(), pass.into()]) {
@@ -204,3 +280,43 @@ pub(crate) fn can_exec(users: &mut SDoc, user: &str, pass: &str) -> bool {
     }
     false
 }
+
+
+/// User permissions and modify scope (empty for any scope).
+pub(crate) fn user_perms(users: &SDoc, user: &str) -> Option<(i64, String)> {
+    if user.is_empty() || user.contains('.') {
+        return None;
+    }
+    let mut scope = String::default();
+    if let Some(scope_field) = users.field(&format!("Users.{}.scope", user), None) {
+        scope = scope_field.to_string().trim_start_matches('@').to_owned();
+    }
+    if let Some(perms_field) = users.field(&format!("Users.{}.perms", user), None) && let SVal::Number(num) = &perms_field.value {
+        return Some((num.int(), scope));
+    }
+    None
+}
+
+
+/// Libraries a user's runs can use (None if the user doesn't have an allow list).
+pub(crate) fn user_libraries(users: &SDoc, user: &str) -> Option<Vec<String>> {
+    if user.is_empty() || user.contains('.') {
+        return None;
+    }
+    if let Some(libraries_field) = users.field(&format!("Users.{}.libraries", user), None) && let SVal::Array(vals) = &libraries_field.value {
+        return Some(vals.iter().map(|val| val.to_string()).collect());
+    }
+    None
+}
+
+
+/// Libraries the runs of a scope's packages can use (None if the scope doesn't have an allow list).
+pub(crate) fn scope_libraries(users: &SDoc, scope: &str) -> Option<Vec<String>> {
+    if scope.is_empty() || scope.contains('.') {
+        return None;
+    }
+    if let Some(libraries_field) = users.field(&format!("Scopes.{}.libraries", scope), None) && let SVal::Array(vals) = &libraries_field.value {
+        return Some(vals.iter().map(|val| val.to_string()).collect());
+    }
+    None
+}
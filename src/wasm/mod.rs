@@ -0,0 +1,735 @@
+//
+// Copyright 2024 Formata, Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+
+use std::{fs, path::Path, sync::{Arc, Mutex}};
+use stof::{lang::SError, Format, Library, SDoc, SNum, SVal};
+use wasmi::{core::ValType, Caller, Config, Engine, Extern, ExternType, FuncType, Instance, Linker, Module, Store, StoreLimits, StoreLimitsBuilder, Val};
+use crate::{config::{wasm_enabled, wasm_max_fuel, wasm_max_memory, wasm_max_module_size}, libraries::{CORE_LIBRARIES, RUNNER_LIBRARIES}};
+
+
+/// WebAssembly policy for runs.
+#[derive(Debug, Clone)]
+pub(crate) struct WasmPolicy {
+    /// Can runs import WebAssembly modules?
+    pub enabled: bool,
+
+    /// Max fuel (instructions) per module per run.
+    pub max_fuel: u64,
+
+    /// Max memory of a module instance (bytes).
+    pub max_memory: usize,
+
+    /// Max size of a module file (bytes).
+    pub max_module_size: usize,
+}
+impl WasmPolicy {
+    /// WebAssembly policy from the server configuration.
+    pub fn from_config(config: &SDoc) -> Self {
+        Self {
+            enabled: wasm_enabled(config),
+            max_fuel: wasm_max_fuel(config),
+            max_memory: wasm_max_memory(config),
+            max_module_size: wasm_max_module_size(config),
+        }
+    }
+}
+
+
+/// WebAssembly format.
+/// Imports a module from a registry package as a library, named after the module's file ("math.wasm" is the "math" library).
+///
+/// Packages list modules in their imports: `imports: [{ path: 'math.wasm', format: 'wasm' }]`.
+/// Modules are only imported from the registry (packages), never from other paths on the server.
+///
+/// WebAssembly libraries can only reach the run through their arguments, results, and printing,
+/// so they are not checked against the library allow lists.
+pub struct WasmFormat {
+    pub registry_path: String,
+    pub policy: WasmPolicy,
+}
+impl WasmFormat {
+    pub fn new(registry_path: &str, policy: WasmPolicy) -> Self {
+        Self {
+            registry_path: registry_path.to_owned(),
+            policy,
+        }
+    }
+}
+impl Format for WasmFormat {
+    fn format(&self) -> String {
+        "wasm".to_string()
+    }
+
+    fn content_type(&self) -> String {
+        "application/wasm".to_string()
+    }
+
+    fn file_import(&self, pid: &str, doc: &mut SDoc, _format: &str, full_path: &str, _extension: &str, _as_name: &str) -> Result<(), SError> {
+        let registry = Path::new(&self.registry_path);
+        let path = Path::new(full_path);
+        if !path.starts_with(registry) || full_path.split(['/', '\\']).any(|component| component == "..") {
+            return Err(SError::fmt(pid, doc, "wasm", "WebAssembly modules can only be imported from registry packages"));
+        }
+        let scope = path.file_stem().map(|stem| stem.to_string_lossy().to_string()).unwrap_or_default();
+        if scope.is_empty() || CORE_LIBRARIES.contains(&scope.as_str()) || RUNNER_LIBRARIES.contains(&scope.as_str()) || doc.libraries.libraries.contains_key(&scope) {
+            return Err(SError::fmt(pid, doc, "wasm", &format!("'{}' cannot be used as a WebAssembly library name", scope)));
+        }
+
+        let bytes;
+        match fs::metadata(path) {
+            Ok(metadata) => {
+                if metadata.len() > self.policy.max_module_size as u64 {
+                    return Err(SError::fmt(pid, doc, "wasm", &format!("module '{}' is larger than the max module size", scope)));
+                }
+                match fs::read(path) {
+                    Ok(contents) => bytes = contents,
+                    Err(error) => return Err(SError::fmt(pid, doc, "wasm", &error.to_string())),
+                }
+            },
+            Err(_) => {
+                return Err(SError::fmt(pid, doc, "wasm", &format!("module '{}' not found", scope)));
+            }
+        }
+        match WasmLibrary::compile(&bytes) {
+            Ok(module) => {
+                doc.load_lib(Arc::new(WasmLibrary::new(&scope, module, self.policy.clone())));
+                Ok(())
+            },
+            Err(message) => {
+                Err(SError::fmt(pid, doc, "wasm", &format!("invalid module '{}': {}", scope, message)))
+            }
+        }
+    }
+}
+
+
+/// WebAssembly library.
+/// Calls the functions that a module exports, instantiating the module on the first call.
+/// The instance (memory and globals) persists between calls in a run, and is reset if a call traps.
+///
+/// Arguments are passed by the export's parameter types: numbers as ints or floats, and bools as 0 or 1.
+/// Strings and blobs are passed as their length in bytes, and the module copies them into its memory with `stof.read_arg(index, ptr)`.
+/// A single result is returned as a number, and multiple results as a tuple (or a string or blob with `stof.return_str` and `stof.return_blob`).
+///
+/// Host functions that take a pointer use the module's exported "memory" (exported by default by most toolchains).
+/// Printed text is sent to the run's output once the call returns.
+///
+/// Modules run in the wasmi interpreter. Every instruction uses fuel, and a run's calls share the max fuel, after which calls trap.
+/// Memory is limited to the policy's max memory.
+pub struct WasmLibrary {
+    pub scope: String,
+    pub module: Module,
+    pub policy: WasmPolicy,
+    instance: Mutex<Option<(Store<CallState>, Instance)>>,
+    fuel: Mutex<u64>,
+}
+impl WasmLibrary {
+    pub fn new(scope: &str, module: Module, policy: WasmPolicy) -> Self {
+        Self {
+            scope: scope.to_owned(),
+            module,
+            instance: Mutex::new(None),
+            fuel: Mutex::new(policy.max_fuel),
+            policy,
+        }
+    }
+
+    /// Compile and validate a module.
+    /// Modules can only import the runner's host functions (see host_func_type).
+    pub fn compile(bytes: &[u8]) -> Result<Module, String> {
+        let mut config = Config::default();
+        config.consume_fuel(true);
+        let engine = Engine::new(&config);
+        let module = Module::new(&engine, bytes).map_err(|error| error.to_string())?;
+        for import in module.imports() {
+            let (import_module, name) = (import.module(), import.name());
+            match import.ty() {
+                ExternType::Func(ty) => {
+                    if import_module != "stof" {
+                        return Err(format!("unsupported import '{}.{}'", import_module, name));
+                    }
+                    match host_func_type(name) {
+                        Some(host_ty) => {
+                            if *ty != host_ty {
+                                return Err(format!("import 'stof.{}' has the wrong type", name));
+                            }
+                        },
+                        None => return Err(format!("unsupported import '{}.{}'", import_module, name)),
+                    }
+                },
+                _ => return Err(format!("unsupported import '{}.{}' (only functions can be imported)", import_module, name)),
+            }
+        }
+        Ok(module)
+    }
+
+    /// Instantiate the module with some fuel, running its start function.
+    fn instantiate(&self, fuel: u64) -> Result<(Store<CallState>, Instance), wasmi::Error> {
+        let limits = StoreLimitsBuilder::new()
+            .memory_size(self.policy.max_memory)
+            .instances(1)
+            .memories(1)
+            .build();
+        let mut store = Store::new(self.module.engine(), CallState { limits, ..Default::default() });
+        store.limiter(|state| &mut state.limits);
+        store.set_fuel(fuel)?;
+
+        let mut linker = <Linker<CallState>>::new(self.module.engine());
+        linker.func_wrap("stof", "print", |mut caller: Caller<'_, CallState>, ptr: i32, len: i32| -> Result<(), wasmi::Error> {
+            let text = String::from_utf8_lossy(&call_bytes(&mut caller, ptr, len)?).to_string();
+            caller.data_mut().printed.push(text);
+            Ok(())
+        })?;
+        linker.func_wrap("stof", "read_arg", |mut caller: Caller<'_, CallState>, index: i32, ptr: i32| -> Result<i32, wasmi::Error> {
+            let memory = call_memory(&caller)?;
+            let (memory, state) = memory.data_and_store_mut(&mut caller);
+            match state.inputs.get(index as u32 as usize).and_then(|input| input.as_ref()) {
+                Some(input) => {
+                    let start = ptr as u32 as usize;
+                    let dest = memory.get_mut(start..start + input.len()).ok_or(wasmi::Error::new("out of bounds memory access"))?;
+                    dest.copy_from_slice(input);
+                    Ok(input.len() as i32)
+                },
+                None => Ok(-1),
+            }
+        })?;
+        linker.func_wrap("stof", "return_str", |mut caller: Caller<'_, CallState>, ptr: i32, len: i32| -> Result<(), wasmi::Error> {
+            match String::from_utf8(call_bytes(&mut caller, ptr, len)?) {
+                Ok(text) => caller.data_mut().output = Some(SVal::String(text)),
+                Err(_) => return Err(wasmi::Error::new("returned string is not valid UTF-8")),
+            }
+            Ok(())
+        })?;
+        linker.func_wrap("stof", "return_blob", |mut caller: Caller<'_, CallState>, ptr: i32, len: i32| -> Result<(), wasmi::Error> {
+            let bytes = call_bytes(&mut caller, ptr, len)?;
+            caller.data_mut().output = Some(SVal::Blob(bytes));
+            Ok(())
+        })?;
+
+        let instance = linker.instantiate(&mut store, &self.module)?.start(&mut store)?;
+        Ok((store, instance))
+    }
+
+    /// Module argument from a Stof value.
+    fn argument(value: &SVal, ty: ValType) -> Option<Val> {
+        let int;
+        let float;
+        match value {
+            SVal::Number(num) => {
+                int = num.int();
+                float = num.float();
+            },
+            SVal::Bool(val) => {
+                int = *val as i64;
+                float = int as f64;
+            },
+            SVal::String(val) => {
+                int = val.len() as i64;
+                float = int as f64;
+            },
+            SVal::Blob(val) => {
+                int = val.len() as i64;
+                float = int as f64;
+            },
+            _ => return None,
+        }
+        match ty {
+            ValType::I32 => Some(Val::I32(int as i32)),
+            ValType::I64 => Some(Val::I64(int)),
+            ValType::F32 => Some(Val::F32((float as f32).into())),
+            ValType::F64 => Some(Val::F64(float.into())),
+            ValType::FuncRef | ValType::ExternRef => None,
+        }
+    }
+
+    /// Stof value from a module result.
+    fn result(value: Val) -> SVal {
+        match value {
+            Val::I32(val) => SVal::Number(SNum::I64(val as i64)),
+            Val::I64(val) => SVal::Number(SNum::I64(val)),
+            Val::F32(val) => SVal::Number(SNum::F64(f32::from(val) as f64)),
+            Val::F64(val) => SVal::Number(SNum::F64(f64::from(val))),
+            Val::FuncRef(_) | Val::ExternRef(_) => SVal::Null,
+        }
+    }
+
+    /// Call an exported function with arguments (and the string and blob arguments by index), instantiating the module if needed.
+    /// Returns the results, with any output (return_str or return_blob) and printed text from the call.
+    fn invoke(&self, name: &str, args: Vec<Val>, inputs: Vec<Option<Vec<u8>>>, result_types: &[ValType]) -> Result<(Vec<Val>, CallState), String> {
+        let mut instance = self.instance.lock().unwrap();
+        let mut fuel = self.fuel.lock().unwrap();
+        if instance.is_none() {
+            match self.instantiate(*fuel) {
+                Ok(new_instance) => *instance = Some(new_instance),
+                Err(error) => {
+                    // Instantiating would fail the same way again (and a start function could use up the fuel each time)
+                    *fuel = 0;
+                    return Err(error.to_string());
+                }
+            }
+        }
+        let (store, module_instance) = instance.as_mut().unwrap();
+        let func = module_instance.get_func(&*store, name).ok_or(String::from("function not found"))?;
+        let mut results = result_types.iter().map(|ty| Val::default(*ty)).collect::<Vec<_>>();
+
+        store.data_mut().inputs = inputs;
+        let res = func.call(&mut *store, &args, &mut results);
+        *fuel = store.get_fuel().unwrap_or_default();
+        let state = CallState {
+            output: store.data_mut().output.take(),
+            printed: std::mem::take(&mut store.data_mut().printed),
+            ..Default::default()
+        };
+        match res {
+            Ok(_) => Ok((results, state)),
+            Err(error) => {
+                *instance = None;
+                Err(error.to_string())
+            }
+        }
+    }
+}
+impl Library for WasmLibrary {
+    fn scope(&self) -> String {
+        self.scope.clone()
+    }
+
+    fn call(&self, pid: &str, doc: &mut SDoc, name: &str, parameters: &mut Vec<SVal>) -> Result<SVal, SError> {
+        let ty = match self.module.get_export(name).and_then(|ty| ty.func().cloned()) {
+            Some(ty) => ty,
+            None => return Err(SError::custom(pid, doc, "WasmNotFound", &format!("{} is not a function in the {} library", name, self.scope))),
+        };
+        if parameters.len() != ty.params().len() {
+            return Err(SError::custom(pid, doc, "WasmArgs", &format!("{} requires {} arguments", name, ty.params().len())));
+        }
+        let mut args = Vec::new();
+        let mut inputs = Vec::new();
+        for (param, param_ty) in parameters.drain(..).zip(ty.params()) {
+            let param = param.unbox();
+            match Self::argument(&param, *param_ty) {
+                Some(arg) => args.push(arg),
+                None => return Err(SError::custom(pid, doc, "WasmArgs", &format!("invalid argument for {}: {}", name, param.to_string()))),
+            }
+            match param {
+                SVal::String(val) => inputs.push(Some(val.into_bytes())),
+                SVal::Blob(val) => inputs.push(Some(val)),
+                _ => inputs.push(None),
+            }
+        }
+
+        let res = self.invoke(name, args, inputs, ty.results());
+        let (mut results, state) = match res {
+            Ok(res) => res,
+            Err(message) => return Err(SError::custom(pid, doc, "WasmTrap", &format!("{}.{}: {}", self.scope, name, message))),
+        };
+
+        // Printed text goes to the run's output
+        if let Some(std) = doc.libraries.get("std") {
+            for text in state.printed {
+                std.call(pid, doc, "pln", &mut vec![SVal::String(text)])?;
+            }
+        }
+        if let Some(output) = state.output {
+            return Ok(output);
+        }
+        match results.len() {
+            0 => Ok(SVal::Null),
+            1 => Ok(Self::result(results.pop().unwrap())),
+            _ => Ok(SVal::Tuple(results.into_iter().map(Self::result).collect())),
+        }
+    }
+}
+
+
+/// Type of a function the runner provides to modules (imported from the "stof" module), by name.
+/// - print(ptr, len): print a UTF-8 string.
+/// - read_arg(index, ptr) -> len: copy a string or blob argument into memory (-1 if the argument isn't one).
+/// - return_str(ptr, len): return a UTF-8 string from the call.
+/// - return_blob(ptr, len): return a blob from the call.
+fn host_func_type(name: &str) -> Option<FuncType> {
+    match name {
+        "print" | "return_str" | "return_blob" => Some(FuncType::new([ValType::I32, ValType::I32], [])),
+        "read_arg" => Some(FuncType::new([ValType::I32, ValType::I32], [ValType::I32])),
+        _ => None,
+    }
+}
+
+
+/// Store state for a module instance (and the current call).
+#[derive(Default)]
+struct CallState {
+    /// Memory limits for the instance.
+    limits: StoreLimits,
+
+    /// String and blob arguments of the current call (by index).
+    inputs: Vec<Option<Vec<u8>>>,
+
+    /// Value returned with "return_str" or "return_blob".
+    output: Option<SVal>,
+
+    /// Text printed with "print".
+    printed: Vec<String>,
+}
+
+
+/// Exported memory of the calling instance.
+fn call_memory(caller: &Caller<'_, CallState>) -> Result<wasmi::Memory, wasmi::Error> {
+    caller.get_export("memory").and_then(Extern::into_memory).ok_or(wasmi::Error::new("module does not export its memory"))
+}
+
+
+/// Memory bytes at ptr and len.
+fn call_bytes(caller: &mut Caller<'_, CallState>, ptr: i32, len: i32) -> Result<Vec<u8>, wasmi::Error> {
+    let memory = call_memory(caller)?;
+    let start = ptr as u32 as usize;
+    let end = start + len as u32 as usize;
+    memory.data(&*caller).get(start..end).map(|bytes| bytes.to_vec()).ok_or(wasmi::Error::new("out of bounds memory access"))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const I32: u8 = 0x7F;
+    const I64: u8 = 0x7E;
+    const F64: u8 = 0x7C;
+    const PAGE_SIZE: usize = 65536;
+
+    fn leb(mut value: u32) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        loop {
+            let byte = (value & 0x7F) as u8;
+            value >>= 7;
+            if value == 0 {
+                bytes.push(byte);
+                return bytes;
+            }
+            bytes.push(byte | 0x80);
+        }
+    }
+
+    /// Vector of encoded items (count, then the items).
+    fn items(items: &[Vec<u8>]) -> Vec<u8> {
+        let mut bytes = leb(items.len() as u32);
+        for item in items {
+            bytes.extend(item);
+        }
+        bytes
+    }
+
+    fn name(name: &str) -> Vec<u8> {
+        let mut bytes = leb(name.len() as u32);
+        bytes.extend(name.as_bytes());
+        bytes
+    }
+
+    fn func_type(params: &[u8], results: &[u8]) -> Vec<u8> {
+        let mut bytes = vec![0x60];
+        bytes.extend(leb(params.len() as u32));
+        bytes.extend(params);
+        bytes.extend(leb(results.len() as u32));
+        bytes.extend(results);
+        bytes
+    }
+
+    /// Function body (no locals other than the params).
+    fn body(code: &[u8]) -> Vec<u8> {
+        let mut body = vec![0x00];
+        body.extend(code);
+        body.push(0x0B);
+        let mut bytes = leb(body.len() as u32);
+        bytes.extend(body);
+        bytes
+    }
+
+    /// Export of a kind (0 is a function, 2 is a memory).
+    fn export(name_str: &str, kind: u8, index: u32) -> Vec<u8> {
+        let mut bytes = name(name_str);
+        bytes.push(kind);
+        bytes.extend(leb(index));
+        bytes
+    }
+
+    /// Function import from a module, with a type index.
+    fn import(module: &str, func: &str, ty: u32) -> Vec<u8> {
+        let mut bytes = name(module);
+        bytes.extend(name(func));
+        bytes.push(0x00);
+        bytes.extend(leb(ty));
+        bytes
+    }
+
+    /// Module bytes from its sections (id and contents).
+    fn wasm(sections: &[(u8, Vec<u8>)]) -> Vec<u8> {
+        let mut bytes = b"\0asm".to_vec();
+        bytes.extend([1, 0, 0, 0]);
+        for (id, contents) in sections {
+            bytes.push(*id);
+            bytes.extend(leb(contents.len() as u32));
+            bytes.extend(contents);
+        }
+        bytes
+    }
+
+    /// Module with a single function "f", plus extra sections (memory, globals) placed before the exports.
+    fn func_module(params: &[u8], results: &[u8], code: &[u8], extra: &[(u8, Vec<u8>)]) -> Vec<u8> {
+        let mut sections = vec![
+            (1, items(&[func_type(params, results)])),
+            (3, items(&[leb(0)])),
+        ];
+        sections.extend(extra.iter().cloned());
+        sections.push((7, items(&[export("f", 0, 0)])));
+        sections.push((10, items(&[body(code)])));
+        wasm(&sections)
+    }
+
+    fn memory(pages: u32) -> (u8, Vec<u8>) {
+        let mut limits = vec![0x00];
+        limits.extend(leb(pages));
+        (5, items(&[limits]))
+    }
+
+    fn policy(max_fuel: u64, max_memory: usize) -> WasmPolicy {
+        WasmPolicy {
+            enabled: true,
+            max_fuel,
+            max_memory,
+            max_module_size: usize::MAX,
+        }
+    }
+
+    fn wasm_library(bytes: &[u8], policy: WasmPolicy) -> WasmLibrary {
+        WasmLibrary::new("test", WasmLibrary::compile(bytes).unwrap(), policy)
+    }
+
+    /// Call "f" in a module with arguments.
+    fn call(bytes: &[u8], args: Vec<SVal>) -> Result<SVal, String> {
+        let library = wasm_library(bytes, policy(1_000_000, 16 * PAGE_SIZE));
+        library.call("main", &mut SDoc::default(), "f", &mut args.clone()).map_err(|error| error.message)
+    }
+
+    fn int(val: i64) -> SVal {
+        SVal::Number(SNum::I64(val))
+    }
+
+    fn compile_error(bytes: &[u8]) -> String {
+        WasmLibrary::compile(bytes).unwrap_err()
+    }
+
+    #[test]
+    fn compile() {
+        // add(a: i32, b: i32) -> i32
+        let add = func_module(&[I32, I32], &[I32], &[0x20, 0, 0x20, 1, 0x6A], &[]);
+        let module = WasmLibrary::compile(&add).unwrap();
+        let ty = module.get_export("f").and_then(|ty| ty.func().cloned()).unwrap();
+        assert_eq!(ty.params(), &[ValType::I32, ValType::I32]);
+        assert_eq!(ty.results(), &[ValType::I32]);
+
+        // Invalid modules are rejected by the validator
+        assert!(WasmLibrary::compile(b"\0wasm\x01\0\0\0").is_err());
+        assert!(WasmLibrary::compile(b"\0asm\x02\0\0\0").is_err());
+        assert!(WasmLibrary::compile(b"\0as").is_err());
+        assert!(WasmLibrary::compile(&wasm(&[(13, vec![])])).is_err());
+        // i32.add on i64 params
+        assert!(WasmLibrary::compile(&func_module(&[I64, I64], &[I32], &[0x20, 0, 0x20, 1, 0x6A], &[])).is_err());
+        // local.get 3, with one param
+        assert!(WasmLibrary::compile(&func_module(&[I32], &[I32], &[0x20, 3], &[])).is_err());
+        // call 5
+        assert!(WasmLibrary::compile(&func_module(&[], &[], &[0x10, 5], &[])).is_err());
+        // Functions without code
+        assert!(WasmLibrary::compile(&wasm(&[(1, items(&[func_type(&[], &[])])), (3, items(&[leb(0)]))])).is_err());
+    }
+
+    #[test]
+    fn compile_imports() {
+        // Imports are only the runner's host functions, with their own types
+        let with_import = |module: &str, func: &str, ty: Vec<u8>| wasm(&[(1, items(&[ty])), (2, items(&[import(module, func, 0)]))]);
+        assert!(WasmLibrary::compile(&with_import("stof", "print", func_type(&[I32, I32], &[]))).is_ok());
+        assert!(WasmLibrary::compile(&with_import("stof", "read_arg", func_type(&[I32, I32], &[I32]))).is_ok());
+        assert_eq!(compile_error(&with_import("env", "print", func_type(&[I32, I32], &[]))), "unsupported import 'env.print'");
+        assert_eq!(compile_error(&with_import("stof", "exec", func_type(&[], &[]))), "unsupported import 'stof.exec'");
+        assert_eq!(compile_error(&with_import("stof", "print", func_type(&[I64], &[]))), "import 'stof.print' has the wrong type");
+        assert_eq!(compile_error(&with_import("stof", "read_arg", func_type(&[I32, I32], &[]))), "import 'stof.read_arg' has the wrong type");
+
+        // Memory import
+        let mut memory_import = name("env");
+        memory_import.extend(name("memory"));
+        memory_import.extend([0x02, 0x00, 0x01]);
+        assert_eq!(compile_error(&wasm(&[(2, items(&[memory_import]))])), "unsupported import 'env.memory' (only functions can be imported)");
+    }
+
+    #[test]
+    fn arguments_and_results() {
+        let add = func_module(&[I32, I32], &[I32], &[0x20, 0, 0x20, 1, 0x6A], &[]);
+        assert_eq!(call(&add, vec![int(2), int(3)]), Ok(int(5)));
+        assert_eq!(call(&add, vec![int(i32::MAX as i64), int(1)]), Ok(int(i32::MIN as i64)));
+        assert_eq!(call(&add, vec![SVal::Bool(true), SVal::String(String::from("abc"))]), Ok(int(4)));
+        assert_eq!(call(&add, vec![int(2)]), Err(String::from("f requires 2 arguments")));
+        assert!(call(&add, vec![int(2), SVal::Null]).unwrap_err().starts_with("invalid argument for f"));
+
+        // f64.add
+        let add_f64 = func_module(&[F64, F64], &[F64], &[0x20, 0, 0x20, 1, 0xA0], &[]);
+        assert_eq!(call(&add_f64, vec![SVal::Number(SNum::F64(1.5)), int(2)]), Ok(SVal::Number(SNum::F64(3.5))));
+
+        // Multiple results are a tuple, and no results are null
+        let pair = func_module(&[I32, I64], &[I64, I32], &[0x20, 1, 0x20, 0], &[]);
+        assert_eq!(call(&pair, vec![int(1), int(2)]), Ok(SVal::Tuple(vec![int(2), int(1)])));
+        let nothing = func_module(&[], &[], &[], &[]);
+        assert_eq!(call(&nothing, vec![]), Ok(SVal::Null));
+
+        // Unknown functions
+        let library = wasm_library(&nothing, policy(1000, PAGE_SIZE));
+        let error = library.call("main", &mut SDoc::default(), "g", &mut vec![]).unwrap_err();
+        assert_eq!(error.error_type.to_string(), "WasmNotFound");
+    }
+
+    #[test]
+    fn traps() {
+        // i32.div_s, i32.rem_s, i64.div_s
+        let div_s = func_module(&[I32, I32], &[I32], &[0x20, 0, 0x20, 1, 0x6D], &[]);
+        let rem_s = func_module(&[I32, I32], &[I32], &[0x20, 0, 0x20, 1, 0x6F], &[]);
+        let div_s64 = func_module(&[I64, I64], &[I64], &[0x20, 0, 0x20, 1, 0x7F], &[]);
+        assert_eq!(call(&div_s, vec![int(7), int(-2)]), Ok(int(-3)));
+        assert_eq!(call(&div_s, vec![int(7), int(0)]), Err(String::from("test.f: integer divide by zero")));
+        assert_eq!(call(&rem_s, vec![int(7), int(0)]), Err(String::from("test.f: integer divide by zero")));
+        assert_eq!(call(&div_s64, vec![int(7), int(0)]), Err(String::from("test.f: integer divide by zero")));
+        assert_eq!(call(&div_s, vec![int(i32::MIN as i64), int(-1)]), Err(String::from("test.f: integer overflow")));
+        assert_eq!(call(&rem_s, vec![int(i32::MIN as i64), int(-1)]), Ok(int(0)));
+
+        let unreachable = func_module(&[], &[], &[0x00], &[]);
+        assert!(call(&unreachable, vec![]).unwrap_err().contains("unreachable"));
+
+        // Calls itself forever
+        let recurse = func_module(&[], &[], &[0x10, 0], &[]);
+        assert!(call(&recurse, vec![]).unwrap_err().contains("call stack exhausted"));
+
+        // i32.load at the address param, with one page of memory
+        let load = func_module(&[I32], &[I32], &[0x20, 0, 0x28, 2, 0], &[memory(1)]);
+        assert_eq!(call(&load, vec![int(65532)]), Ok(int(0)));
+        assert_eq!(call(&load, vec![int(65533)]), Err(String::from("test.f: out of bounds memory access")));
+        assert_eq!(call(&load, vec![int(-1)]), Err(String::from("test.f: out of bounds memory access")));
+    }
+
+    #[test]
+    fn memory_limit() {
+        // Initial memory larger than the max
+        let big = func_module(&[], &[], &[], &[memory(17)]);
+        assert!(call(&big, vec![]).is_err());
+        assert_eq!(call(&func_module(&[], &[], &[], &[memory(16)]), vec![]), Ok(SVal::Null));
+
+        // memory.grow by the param, returning the old size (or -1 if it can't grow)
+        let grow = func_module(&[I32], &[I32], &[0x20, 0, 0x40, 0], &[memory(1)]);
+        let library = wasm_library(&grow, policy(1_000_000, 4 * PAGE_SIZE));
+        let mut doc = SDoc::default();
+        assert_eq!(library.call("main", &mut doc, "f", &mut vec![int(2)]).unwrap(), int(1));
+        assert_eq!(library.call("main", &mut doc, "f", &mut vec![int(2)]).unwrap(), int(-1));
+        assert_eq!(library.call("main", &mut doc, "f", &mut vec![int(1)]).unwrap(), int(3));
+    }
+
+    #[test]
+    fn fuel() {
+        // loop { br 0 }
+        let spin = func_module(&[], &[], &[0x03, 0x40, 0x0C, 0, 0x0B], &[]);
+        let library = wasm_library(&spin, policy(10_000, PAGE_SIZE));
+        let error = library.call("main", &mut SDoc::default(), "f", &mut vec![]).unwrap_err();
+        assert!(error.message.contains("fuel"));
+        assert_eq!(error.error_type.to_string(), "WasmTrap");
+        assert!(*library.fuel.lock().unwrap() < 100);
+
+        // Fuel is shared by every call, and calls trap once it runs out
+        let add = func_module(&[I32, I32], &[I32], &[0x20, 0, 0x20, 1, 0x6A], &[]);
+        let library = wasm_library(&add, policy(100, PAGE_SIZE));
+        let mut doc = SDoc::default();
+        assert!(library.call("main", &mut doc, "f", &mut vec![int(1), int(2)]).is_ok());
+        let remaining = *library.fuel.lock().unwrap();
+        assert!(remaining < 100 && remaining > 80);
+        let mut res = Ok(SVal::Null);
+        for _ in 0..100 {
+            res = library.call("main", &mut doc, "f", &mut vec![int(1), int(2)]);
+            if res.is_err() {
+                break;
+            }
+        }
+        assert!(res.unwrap_err().message.contains("fuel"));
+    }
+
+    #[test]
+    fn instance_state() {
+        // Mutable global counter: global.set 0 (global.get 0 + 1), then global.get 0; traps if the param is 1
+        let global = (6, items(&[vec![I32, 0x01, 0x41, 0, 0x0B]]));
+        let code = [
+            0x20, 0, 0x04, 0x40, 0x00, 0x0B, // if (param) unreachable
+            0x23, 0, 0x41, 1, 0x6A, 0x24, 0, // global = global + 1
+            0x23, 0,                         // global
+        ];
+        let counter = func_module(&[I32], &[I32], &code, &[global]);
+        let library = wasm_library(&counter, policy(10_000, PAGE_SIZE));
+        let mut doc = SDoc::default();
+        assert_eq!(library.call("main", &mut doc, "f", &mut vec![int(0)]).unwrap(), int(1));
+        assert_eq!(library.call("main", &mut doc, "f", &mut vec![int(0)]).unwrap(), int(2));
+
+        // A trap resets the instance
+        assert!(library.call("main", &mut doc, "f", &mut vec![int(1)]).is_err());
+        assert_eq!(library.call("main", &mut doc, "f", &mut vec![int(0)]).unwrap(), int(1));
+    }
+
+    #[test]
+    fn host_round_trip() {
+        // echo(text): copies its string argument into memory with read_arg, and returns it with return_str
+        let echo = [
+            0x41, 0, 0x41, 0, 0x10, 0, 0x1A, // drop(read_arg(0, 0))
+            0x41, 0, 0x20, 0, 0x10, 1,       // return_str(0, len)
+        ];
+        let bytes = wasm(&[
+            (1, items(&[func_type(&[I32, I32], &[I32]), func_type(&[I32, I32], &[]), func_type(&[I32], &[])])),
+            (2, items(&[import("stof", "read_arg", 0), import("stof", "return_str", 1)])),
+            (3, items(&[leb(2)])),
+            memory(1),
+            (7, items(&[export("echo", 0, 2), export("memory", 2, 0)])),
+            (10, items(&[body(&echo)])),
+        ]);
+
+        let library = wasm_library(&bytes, policy(1000, PAGE_SIZE));
+        let mut doc = SDoc::default();
+        let res = library.call("main", &mut doc, "echo", &mut vec![SVal::String(String::from("héllo wasm"))]);
+        assert_eq!(res.unwrap(), SVal::String(String::from("héllo wasm")));
+
+        // Numbers aren't strings, so read_arg returns -1 and nothing is copied
+        let res = library.call("main", &mut doc, "echo", &mut vec![int(0)]);
+        assert_eq!(res.unwrap(), SVal::String(String::default()));
+
+        // A string argument larger than memory traps, and the error names the function
+        let res = library.call("main", &mut doc, "echo", &mut vec![SVal::String("x".repeat(PAGE_SIZE + 1))]);
+        assert!(res.unwrap_err().message.contains("test.echo: out of bounds memory access"));
+    }
+
+    #[test]
+    fn host_memory_export() {
+        // Host functions that use memory need the module to export it
+        let code = [0x41, 0, 0x41, 0, 0x10, 0];
+        let bytes = wasm(&[
+            (1, items(&[func_type(&[I32, I32], &[]), func_type(&[], &[])])),
+            (2, items(&[import("stof", "return_str", 0)])),
+            (3, items(&[leb(1)])),
+            memory(1),
+            (7, items(&[export("f", 0, 1)])),
+            (10, items(&[body(&code)])),
+        ]);
+        assert!(call(&bytes, vec![]).unwrap_err().contains("module does not export its memory"));
+    }
+}